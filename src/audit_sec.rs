@@ -5,27 +5,52 @@
  * LICENSE file in the root directory of this source tree.
  */
 
+use std::fs;
+use std::io::ErrorKind;
 use std::io::Write;
+use std::path::PathBuf;
+use std::sync::OnceLock;
 
+use anyhow::bail;
 use anyhow::Context;
+use itertools::Itertools;
 use rustsec::advisory::Informational;
+use rustsec::database::Query;
 use rustsec::report::Report;
 use rustsec::report::Settings;
 use rustsec::Database;
 use rustsec::Lockfile;
 use rustsec::Repository;
+use rustsec::Version;
+use rustsec::VersionReq;
 use rustsec::Warning;
 use rustsec::WarningKind;
+use serde::Deserialize;
 use termcolor::Color;
 use termcolor::ColorChoice;
 use termcolor::ColorSpec;
 use termcolor::StandardStream;
 use termcolor::WriteColor;
+use url::Url;
 
+use crate::cargo;
+use crate::cargo::Manifest;
+use crate::cargo::Source;
+use crate::config::Config;
+use crate::vendor;
+use crate::Args;
 use crate::Paths;
 
-/// Check crates for known security problems. Requires an existing Cargo.lock.
-pub fn audit_sec(paths: &Paths, no_fetch: bool) -> anyhow::Result<()> {
+/// Check crates for known security problems and yanked releases. Requires an
+/// existing Cargo.lock. With `autofix`, also bump yanked packages to a
+/// compatible release.
+pub fn audit_sec(
+    config: &Config,
+    args: &Args,
+    paths: &Paths,
+    no_fetch: bool,
+    autofix: bool,
+) -> anyhow::Result<()> {
     let stdout = &mut StandardStream::stdout(ColorChoice::Auto);
     let default = ColorSpec::new();
     let mut red = ColorSpec::new();
@@ -39,17 +64,23 @@ pub fn audit_sec(paths: &Paths, no_fetch: bool) -> anyhow::Result<()> {
 
     let lockfile = Lockfile::load(&cargo_lock)
         .with_context(|| format!("loading lockfile {}", cargo_lock.display()))?;
-    let db = if no_fetch {
-        Database::open(&Repository::default_path()).context("opening repository")?
-    } else {
-        Database::fetch().context("fetching database; if this fails, try deleting ~/.cargo/advisory-db and running reindeer again")?
-    };
+    let db = open_database(config, no_fetch)?;
 
     let settings = Settings {
         informational_warnings: vec![Informational::Notice, Informational::Unmaintained],
         ..Settings::default()
     };
-    let report = Report::generate(&db, &lockfile, &settings);
+    let mut report = Report::generate(&db, &lockfile, &settings);
+
+    // rustsec leaves finding yanked packages to its caller.
+    if !no_fetch && !config.cargo.offline {
+        update_index(config, args, paths)?;
+    }
+    let index = IndexCache::new(config, paths)?;
+    let yanked = yanked_warnings(&index, &lockfile)?;
+    if !yanked.is_empty() {
+        report.warnings.insert(WarningKind::Yanked, yanked);
+    }
 
     for v in &report.vulnerabilities.list {
         let adv = &v.advisory;
@@ -126,5 +157,584 @@ pub fn audit_sec(paths: &Paths, no_fetch: bool) -> anyhow::Result<()> {
         Ok(())
     }();
 
+    if autofix {
+        let yanked = report
+            .warnings
+            .get(&WarningKind::Yanked)
+            .map_or(&[][..], Vec::as_slice);
+        autofix_yanked(config, args, paths, &index, yanked, stdout)?;
+    }
+
+    Ok(())
+}
+
+/// Load the advisory database from `[audit] advisory_db` if set, otherwise
+/// the copy in ~/.cargo/advisory-db, fetching updates unless `no_fetch` or
+/// `offline`.
+fn open_database(config: &Config, no_fetch: bool) -> anyhow::Result<Database> {
+    if let Some(advisory_db) = &config.audit.advisory_db {
+        let path = config.config_dir().join(advisory_db);
+        // An empty database would silently pass every check.
+        if !path.is_dir() {
+            bail!("advisory_db {} is not a directory", path.display());
+        }
+        Database::open(&path).with_context(|| format!("opening advisory db {}", path.display()))
+    } else if config.cargo.offline {
+        let path = Repository::default_path();
+        Database::open(&path).with_context(|| {
+            format!(
+                "opening advisory db {}; --offline can't fetch it, so set `[audit] advisory_db` or run without --offline once",
+                path.display(),
+            )
+        })
+    } else if no_fetch {
+        Database::open(&Repository::default_path()).context("opening repository")
+    } else {
+        Database::fetch().context("fetching database; if this fails, try deleting ~/.cargo/advisory-db and running reindeer again")
+    }
+}
+
+/// Check `packages` against the RustSec advisory database if `[audit]
+/// deny_vulnerable` is set, failing with a list of every vulnerable one. The
+/// database is only loaded once per run.
+pub fn check_vulnerabilities<'a>(
+    config: &Config,
+    packages: impl IntoIterator<Item = &'a Manifest>,
+) -> anyhow::Result<()> {
+    static DATABASE: OnceLock<Database> = OnceLock::new();
+
+    if !config.audit.deny_vulnerable {
+        return Ok(());
+    }
+
+    let db = match DATABASE.get() {
+        Some(db) => db,
+        None => {
+            let db = open_database(config, false)?;
+            DATABASE.get_or_init(|| db)
+        }
+    };
+
+    let mut errors = vec![];
+    for pkg in packages {
+        if pkg.source != Source::CratesIo {
+            continue;
+        }
+        let Ok(name) = pkg.name.parse() else {
+            continue;
+        };
+        let query = Query::crate_scope()
+            .package_name(name)
+            .package_version(pkg.version.clone());
+        for advisory in db.query(&query) {
+            let patched = advisory.versions.patched();
+            let suggestion = if patched.is_empty() {
+                "no patched version".to_owned()
+            } else {
+                format!("upgrade to {}", patched.iter().join(" or "))
+            };
+            let message = format!(
+                "{pkg} has {}: {} ({suggestion})",
+                advisory.metadata.id, advisory.metadata.title,
+            );
+            if config.audit.never_autofix.contains(&pkg.name) {
+                log::warn!("{message}; allowed by never_autofix");
+            } else {
+                errors.push(message);
+            }
+        }
+    }
+
+    if !errors.is_empty() {
+        bail!(
+            "Packages have security advisories (see `[audit] deny_vulnerable`):\n  {}",
+            errors.join("\n  "),
+        );
+    }
+    Ok(())
+}
+
+/// Bump every yanked package to the closest newer, semver-compatible version
+/// which isn't yanked, reporting what changed. Packages in `never_autofix` are
+/// only reported.
+fn autofix_yanked(
+    config: &Config,
+    args: &Args,
+    paths: &Paths,
+    index: &IndexCache,
+    yanked: &[Warning],
+    stdout: &mut StandardStream,
+) -> anyhow::Result<()> {
+    let mut bumped = Vec::new();
+    let mut unfixed = Vec::new();
+    for warning in yanked {
+        let pkg = &warning.package;
+        let Some(source) = &pkg.source else {
+            continue;
+        };
+        let name = pkg.name.as_str();
+        if config.audit.never_autofix.contains(name) {
+            unfixed.push(format!("{name} {} (in never_autofix)", pkg.version));
+            continue;
+        }
+        let releases = index.releases(name)?.unwrap_or_default();
+        let Some(replacement) = nearest_unyanked(&pkg.version, &releases) else {
+            unfixed.push(format!("{name} {} (no compatible release)", pkg.version));
+            continue;
+        };
+
+        // Qualify the package with its source, so the same version of a
+        // package from some other source is left alone.
+        let spec = format!("{source}#{name}@{}", pkg.version);
+        let precise = replacement.to_string();
+        log::info!("Bumping yanked {spec} to {precise}");
+        run_registry_cargo(
+            config,
+            args,
+            paths,
+            &[
+                "update",
+                "--manifest-path",
+                paths.manifest_path.to_str().unwrap(),
+                "--package",
+                &spec,
+                "--precise",
+                &precise,
+            ],
+        )
+        .with_context(|| format!("bumping yanked {spec} to {precise}"))?;
+        bumped.push(format!("{name} {} -> {replacement}", pkg.version));
+    }
+
+    for bump in &bumped {
+        writeln!(stdout, "Bumped yanked {bump}")?;
+    }
+    for unfixed in &unfixed {
+        writeln!(stdout, "Left yanked {unfixed}")?;
+    }
+    if !bumped.is_empty() {
+        writeln!(
+            stdout,
+            "Bumped {} yanked packages; re-run `reindeer vendor` to pick them up",
+            bumped.len(),
+        )?;
+    }
+
+    Ok(())
+}
+
+/// A `WarningKind::Yanked` warning for every crates.io package in `lockfile`
+/// which Cargo's index cache has as yanked.
+fn yanked_warnings(index: &IndexCache, lockfile: &Lockfile) -> anyhow::Result<Vec<Warning>> {
+    let mut warnings = Vec::new();
+    for pkg in &lockfile.packages {
+        if !pkg
+            .source
+            .as_ref()
+            .is_some_and(|source| source.is_default_registry())
+        {
+            continue;
+        }
+        let Some(releases) = index.releases(pkg.name.as_str())? else {
+            log::debug!("{} isn't in Cargo's index cache", pkg.name);
+            continue;
+        };
+        if releases
+            .iter()
+            .any(|(version, yanked)| *yanked && *version == pkg.version)
+        {
+            warnings.push(Warning::new(WarningKind::Yanked, pkg, None, None));
+        }
+    }
+    Ok(warnings)
+}
+
+/// Refresh Cargo's index cache for every package in Cargo.lock, by resolving
+/// the dependencies afresh without writing the result.
+fn update_index(config: &Config, args: &Args, paths: &Paths) -> anyhow::Result<()> {
+    run_registry_cargo(
+        config,
+        args,
+        paths,
+        &[
+            "update",
+            "--dry-run",
+            "--manifest-path",
+            paths.manifest_path.to_str().unwrap(),
+        ],
+    )
+    .context("updating the registry index")
+}
+
+/// Run Cargo against the registry which serves crates.io packages, in the
+/// same Cargo home and through the same `registry_url` mirror as vendoring.
+/// Once vendored, Reindeer's Cargo config replaces crates.io with the vendor
+/// directory, where no other version can be found, so it's set aside
+/// meanwhile.
+fn run_registry_cargo(
+    config: &Config,
+    args: &Args,
+    paths: &Paths,
+    opts: &[&str],
+) -> anyhow::Result<()> {
+    let mirror_config = vendor::mirror_config(config);
+    let mut cmdline = opts.to_vec();
+    for config in &mirror_config {
+        cmdline.extend(["--config", config]);
+    }
+
+    if config.vendor.is_none() {
+        cargo::run_cargo(config, None, &paths.third_party_dir, args, &cmdline)?;
+        return Ok(());
+    }
+
+    let cargo_config = paths.cargo_home.join("config.toml");
+    let set_aside = paths.cargo_home.join("config.toml.vendored");
+    let vendored = cargo_config.exists();
+    if vendored {
+        fs::rename(&cargo_config, &set_aside)?;
+    }
+    let result = cargo::run_cargo(
+        config,
+        Some(&paths.cargo_home),
+        &paths.third_party_dir,
+        args,
+        &cmdline,
+    );
+    if vendored {
+        fs::rename(&set_aside, &cargo_config)?;
+    }
+    result?;
     Ok(())
 }
+
+/// Cargo's cached index entries for the registry which serves crates.io
+/// packages: crates.io itself, or the `registry_url` mirror.
+struct IndexCache {
+    dirs: Vec<PathBuf>,
+}
+
+impl IndexCache {
+    fn new(config: &Config, paths: &Paths) -> anyhow::Result<Self> {
+        let cargo_home = if config.vendor.is_some() {
+            paths.cargo_home.clone()
+        } else {
+            home::cargo_home().context("finding Cargo's home directory")?
+        };
+
+        // Cargo names each registry's directory after the index's host and a
+        // hash; crates.io's index is either sparse or the older git one.
+        let hosts = match vendor::registry_url(config) {
+            Some(registry_url) => {
+                let url = registry_url.strip_prefix("sparse+").unwrap_or(registry_url);
+                let url = Url::parse(url)
+                    .with_context(|| format!("parsing registry_url {registry_url}"))?;
+                vec![url.host_str().unwrap_or_default().to_owned()]
+            }
+            None => vec!["index.crates.io".to_owned(), "github.com".to_owned()],
+        };
+
+        let index_dir = cargo_home.join("registry").join("index");
+        let entries = match fs::read_dir(&index_dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                return Ok(IndexCache { dirs: Vec::new() });
+            }
+            Err(err) => {
+                return Err(err).with_context(|| format!("reading {}", index_dir.display()));
+            }
+        };
+        let mut dirs = Vec::new();
+        for entry in entries {
+            let entry = entry?;
+            let file_name = entry.file_name();
+            if let Some((host, _hash)) = file_name.to_string_lossy().rsplit_once('-') {
+                if hosts.iter().any(|h| h == host) {
+                    dirs.push(entry.path().join(".cache"));
+                }
+            }
+        }
+        Ok(IndexCache { dirs })
+    }
+
+    /// Every release of `name` and whether it's yanked, from whichever cache
+    /// entry Cargo updated last, or `None` if it has no entry.
+    fn releases(&self, name: &str) -> anyhow::Result<Option<Vec<(Version, bool)>>> {
+        let path = index_path(name);
+        let newest = self
+            .dirs
+            .iter()
+            .map(|dir| dir.join(&path))
+            .filter_map(|path| Some((fs::metadata(&path).ok()?.modified().ok()?, path)))
+            .max();
+        let Some((_modified, path)) = newest else {
+            return Ok(None);
+        };
+        let cache = fs::read(&path).with_context(|| format!("reading {}", path.display()))?;
+        let releases =
+            parse_index_cache(&cache).with_context(|| format!("parsing {}", path.display()))?;
+        Ok(Some(releases))
+    }
+}
+
+/// Where a registry index keeps the entry for the package `name`.
+fn index_path(name: &str) -> PathBuf {
+    let name = name.to_lowercase();
+    let path = match name.len() {
+        1 => format!("1/{name}"),
+        2 => format!("2/{name}"),
+        3 => format!("3/{}/{name}", &name[..1]),
+        _ => format!("{}/{}/{name}", &name[..2], &name[2..4]),
+    };
+    PathBuf::from(path)
+}
+
+/// Parse one of Cargo's index cache files: a cache format version byte, the
+/// index format version as a little-endian u32, then the index revision and a
+/// version and JSON index entry for each release, all NUL-terminated.
+fn parse_index_cache(cache: &[u8]) -> anyhow::Result<Vec<(Version, bool)>> {
+    #[derive(Deserialize)]
+    struct IndexEntry {
+        vers: Version,
+        #[serde(default)]
+        yanked: bool,
+    }
+
+    let Some((3, rest)) = cache.split_first() else {
+        bail!("unsupported cache format");
+    };
+    let Some(rest) = rest.get(4..) else {
+        bail!("truncated cache file");
+    };
+    let mut fields = rest.split(|&byte| byte == 0);
+    let _revision = fields.next();
+    let mut releases = Vec::new();
+    while let (Some(_version), Some(entry)) = (fields.next(), fields.next()) {
+        let entry: IndexEntry = serde_json::from_slice(entry)?;
+        releases.push((entry.vers, entry.yanked));
+    }
+    Ok(releases)
+}
+
+/// The lowest release above `current`, compatible with it by Cargo's rules,
+/// which isn't yanked.
+fn nearest_unyanked(current: &Version, releases: &[(Version, bool)]) -> Option<Version> {
+    let compatible = VersionReq::parse(&format!("^{current}")).ok()?;
+    releases
+        .iter()
+        .filter(|(version, yanked)| !yanked && version > current && compatible.matches(version))
+        .map(|(version, _yanked)| version)
+        .min()
+        .cloned()
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use rustsec::Lockfile;
+    use rustsec::Version;
+
+    use super::check_vulnerabilities;
+    use super::index_path;
+    use super::nearest_unyanked;
+    use super::parse_index_cache;
+    use super::yanked_warnings;
+    use super::IndexCache;
+    use crate::cargo::Manifest;
+    use crate::config::Config;
+    use crate::testing;
+
+    /// An index cache file, in Cargo's format, for `foo` with `releases`.
+    fn index_cache(releases: &[(&str, bool)]) -> Vec<u8> {
+        let mut cache = vec![3, 2, 0, 0, 0];
+        cache.extend_from_slice(b"etag: \"1\"\0");
+        for (version, yanked) in releases {
+            let entry = serde_json::json!({
+                "name": "foo",
+                "vers": version,
+                "deps": [],
+                "cksum": "0000",
+                "features": {},
+                "yanked": yanked,
+            });
+            cache.extend_from_slice(version.as_bytes());
+            cache.push(0);
+            cache.extend_from_slice(entry.to_string().as_bytes());
+            cache.push(0);
+        }
+        cache
+    }
+
+    #[test]
+    fn test_parse_index_cache() {
+        let releases = parse_index_cache(&index_cache(&[("1.0.0", false), ("1.0.1", true)]));
+        let releases: Vec<(String, bool)> = releases
+            .unwrap()
+            .into_iter()
+            .map(|(version, yanked)| (version.to_string(), yanked))
+            .collect();
+        assert_eq!(
+            releases,
+            [("1.0.0".to_owned(), false), ("1.0.1".to_owned(), true)],
+        );
+
+        assert!(parse_index_cache(&[1, 2, 0, 0, 0]).is_err());
+        assert!(parse_index_cache(&[3, 2]).is_err());
+    }
+
+    #[test]
+    fn test_index_path() {
+        assert_eq!(index_path("a").to_str(), Some("1/a"));
+        assert_eq!(index_path("cc").to_str(), Some("2/cc"));
+        assert_eq!(index_path("Foo").to_str(), Some("3/f/foo"));
+        assert_eq!(index_path("serde").to_str(), Some("se/rd/serde"));
+    }
+
+    #[test]
+    fn test_yanked_warnings() {
+        let third_party_dir = tempfile::tempdir().unwrap();
+        let paths = testing::paths(third_party_dir.path());
+        let cache = paths
+            .cargo_home
+            .join("registry/index/index.crates.io-1949cf8c6b5b557f/.cache")
+            .join(index_path("foo"));
+        fs::create_dir_all(cache.parent().unwrap()).unwrap();
+        let releases = [("1.0.0", false), ("1.0.1", true), ("1.0.2", false)];
+        fs::write(&cache, index_cache(&releases)).unwrap();
+
+        // Only the crates.io foo is checked, not the same version from
+        // another registry, and bar has no index entry at all.
+        let lockfile: Lockfile = indoc::indoc! {r#"
+            version = 3
+
+            [[package]]
+            name = "bar"
+            version = "1.0.0"
+            source = "registry+https://github.com/rust-lang/crates.io-index"
+
+            [[package]]
+            name = "foo"
+            version = "1.0.1"
+            source = "registry+https://github.com/rust-lang/crates.io-index"
+
+            [[package]]
+            name = "foo"
+            version = "1.0.1"
+            source = "registry+https://example.com/index"
+
+            [[package]]
+            name = "top"
+            version = "0.0.0"
+            dependencies = ["bar", "foo 1.0.1 (registry+https://github.com/rust-lang/crates.io-index)", "foo 1.0.1 (registry+https://example.com/index)"]
+        "#}
+        .parse()
+        .unwrap();
+
+        let config: Config = toml::from_str("").unwrap();
+        let index = IndexCache::new(&config, &paths).unwrap();
+        let warnings = yanked_warnings(&index, &lockfile).unwrap();
+        let yanked: Vec<String> = warnings
+            .iter()
+            .map(|warning| {
+                let pkg = &warning.package;
+                format!(
+                    "{} {} {}",
+                    pkg.name,
+                    pkg.version,
+                    pkg.source.as_ref().unwrap()
+                )
+            })
+            .collect();
+        assert_eq!(
+            yanked,
+            ["foo 1.0.1 registry+https://github.com/rust-lang/crates.io-index"],
+        );
+
+        let releases = index.releases("foo").unwrap().unwrap();
+        let replacement = nearest_unyanked(&warnings[0].package.version, &releases);
+        assert_eq!(replacement.map(|v| v.to_string()).as_deref(), Some("1.0.2"));
+    }
+
+    #[test]
+    fn test_nearest_unyanked() {
+        let releases: Vec<(Version, bool)> = [
+            ("0.2.0", false),
+            ("0.2.1", true),
+            ("0.2.2", true),
+            ("0.2.3", false),
+            ("0.2.4", false),
+            ("0.3.0", false),
+            ("1.0.0", true),
+            ("1.1.0", true),
+        ]
+        .into_iter()
+        .map(|(version, yanked)| (version.parse().unwrap(), yanked))
+        .collect();
+        let nearest = |version: &str| {
+            nearest_unyanked(&version.parse().unwrap(), &releases).map(|v| v.to_string())
+        };
+
+        assert_eq!(nearest("0.2.1").as_deref(), Some("0.2.3"));
+        assert_eq!(nearest("0.2.2").as_deref(), Some("0.2.3"));
+        assert_eq!(nearest("1.0.0"), None);
+    }
+
+    #[test]
+    fn test_check_vulnerabilities() {
+        let advisory_db = tempfile::tempdir().unwrap();
+        let advisory = advisory_db.path().join("crates/foo/RUSTSEC-2020-0001.md");
+        fs::create_dir_all(advisory.parent().unwrap()).unwrap();
+        fs::write(
+            &advisory,
+            indoc::indoc! {r#"
+                ```toml
+                [advisory]
+                id = "RUSTSEC-2020-0001"
+                package = "foo"
+                date = "2020-01-01"
+
+                [versions]
+                patched = [">=1.2.0"]
+                ```
+
+                # Foo is unsound
+
+                Details.
+            "#},
+        )
+        .unwrap();
+
+        let config = |never_autofix: &str| -> Config {
+            toml::from_str(&format!(
+                "[audit]\ndeny_vulnerable = true\nadvisory_db = {:?}\nnever_autofix = [{never_autofix}]\n",
+                advisory_db.path(),
+            ))
+            .unwrap()
+        };
+        let package = |version: &str| -> Manifest {
+            serde_json::from_value(serde_json::json!({
+                "name": "foo",
+                "version": version,
+                "id": format!("foo {version}"),
+                "source": "registry+https://github.com/rust-lang/crates.io-index",
+                "dependencies": null,
+                "targets": null,
+                "manifest_path": "Cargo.toml",
+                "authors": null,
+                "edition": "2021",
+                "features": null,
+            }))
+            .unwrap()
+        };
+
+        assert!(check_vulnerabilities(&config(""), [&package("1.2.0")]).is_ok());
+        let err = check_vulnerabilities(&config(""), [&package("1.1.0")]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Packages have security advisories (see `[audit] deny_vulnerable`):\n  \
+             foo-1.1.0 has RUSTSEC-2020-0001: Foo is unsound (upgrade to >=1.2.0)",
+        );
+        assert!(check_vulnerabilities(&config(r#""foo""#), [&package("1.1.0")]).is_ok());
+    }
+}
@@ -8,25 +8,35 @@
 //! Definitions of Buck-related types
 //!
 //! Model Buck rules in a rough way. Can definitely be improved.
+use std::borrow::Cow;
 use std::cmp::Ordering;
 use std::collections::BTreeMap;
 use std::collections::BTreeSet;
+use std::collections::HashMap;
 use std::fmt;
 use std::fmt::Debug;
 use std::fmt::Display;
+use std::hash::Hash;
+use std::hash::Hasher;
 use std::io::Write;
+use std::iter;
+use std::path::Path;
 use std::path::PathBuf;
 
+use anyhow::Context;
 use semver::Version;
 use serde::ser::SerializeMap;
 use serde::ser::SerializeSeq;
 use serde::ser::Serializer;
+use serde::Deserialize;
 use serde::Serialize;
 use serde_starlark::FunctionCall;
+use serde_starlark::LineComment;
 
 use crate::collection::SelectSet;
 use crate::collection::SetOrMap;
 use crate::config::BuckConfig;
+use crate::config::PlatformRepresentation;
 use crate::platform::PlatformConfig;
 use crate::platform::PlatformExpr;
 use crate::platform::PlatformName;
@@ -45,10 +55,28 @@ impl Display for Name {
     }
 }
 
-#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+#[derive(Debug, Clone)]
 pub struct RuleRef {
     pub target: String,
     platform: Option<PlatformExpr>,
+    /// Why the rule refers to the target, written as a trailing comment with
+    /// `annotate_deps`. Not part of the reference's identity.
+    origin: Option<String>,
+}
+
+impl PartialEq for RuleRef {
+    fn eq(&self, other: &Self) -> bool {
+        self.target == other.target && self.platform == other.platform
+    }
+}
+
+impl Eq for RuleRef {}
+
+impl Hash for RuleRef {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.target.hash(state);
+        self.platform.hash(state);
+    }
 }
 
 impl From<Name> for RuleRef {
@@ -74,13 +102,21 @@ impl RuleRef {
         RuleRef {
             target,
             platform: None,
+            origin: None,
         }
     }
 
     pub fn with_platform(self, platform: Option<&PlatformExpr>) -> Self {
         RuleRef {
-            target: self.target,
             platform: platform.cloned(),
+            ..self
+        }
+    }
+
+    pub fn with_origin(self, origin: String) -> Self {
+        RuleRef {
+            origin: Some(origin),
+            ..self
         }
     }
 
@@ -88,6 +124,10 @@ impl RuleRef {
         self.platform.is_some()
     }
 
+    pub fn platform(&self) -> Option<&PlatformExpr> {
+        self.platform.as_ref()
+    }
+
     /// Return true if one of the platform_configs applies to this rule. Always returns
     /// true if this dep has no platform constraint.
     pub fn filter(&self, platform_config: &PlatformConfig) -> Result<bool, PredicateParseError> {
@@ -103,23 +143,232 @@ impl RuleRef {
     }
 }
 
-impl Serialize for RuleRef {
+/// What the rules are being rendered into, for the parts of their
+/// serialization which depend on more than the rule itself. It's handed down
+/// to each value by `Rendered`.
+pub struct RenderContext<'a> {
+    config: &'a BuckConfig,
+    /// With `split_per_package`, the BUCK file being written
+    package: Option<RenderPackage<'a>>,
+    /// Whether values are being written as JSON rather than Starlark. JSON has
+    /// no function calls or `+`, so `select()`s and the like are spelled out
+    /// as plain data instead.
+    json: bool,
+}
+
+/// With `split_per_package`, which BUCK file the rules being rendered are
+/// going in, so their paths and references to other rules can be made
+/// relative to it.
+struct RenderPackage<'a> {
+    /// Package directory, relative to the third-party dir. Empty for the
+    /// third-party dir's own BUCK file.
+    dir: PathBuf,
+    /// Path of the third-party dir from the root of its Buck cell
+    root: &'a Path,
+    /// Package directory of every rule, by name
+    rule_dirs: &'a HashMap<String, PathBuf>,
+}
+
+impl<'a> RenderContext<'a> {
+    pub fn new(config: &'a BuckConfig) -> Self {
+        RenderContext {
+            config,
+            package: None,
+            json: false,
+        }
+    }
+
+    /// Pair a value with this context, to serialize it.
+    fn with<'b, T: ?Sized>(&'b self, value: &'b T) -> Rendered<'b, T> {
+        Rendered(self, value)
+    }
+
+    /// Whether to write each `RuleRef`'s origin as a comment. Comments have
+    /// nowhere to go in JSON.
+    fn annotate_deps(&self) -> bool {
+        self.config.annotate_deps && !self.json
+    }
+
+    /// `select()` key for each platform, when `platform_representation` is
+    /// `select`.
+    fn platform_settings(&self) -> Option<&BTreeMap<PlatformName, String>> {
+        match self.config.platform_representation {
+            PlatformRepresentation::Dict => None,
+            PlatformRepresentation::Select => Some(&self.config.platform_settings),
+        }
+    }
+
+    /// Label for a rule in the same BUCK file as the one being rendered,
+    /// unless `split_per_package` put it in another.
+    fn local_label(&self, name: &str) -> String {
+        if let Some(package) = &self.package {
+            if let Some(dir) = package.rule_dirs.get(name) {
+                if *dir != package.dir {
+                    let path = package.root.join(dir);
+                    return format!("//{}:{}", path.to_string_lossy().replace('\\', "/"), name);
+                }
+            }
+        }
+        format!(":{}", name)
+    }
+}
+
+/// Serialization of a value which depends on the `RenderContext`.
+pub(crate) trait Render {
+    fn render<S: Serializer>(&self, cx: &RenderContext, ser: S) -> Result<S::Ok, S::Error>;
+}
+
+/// A value along with the context to render it in.
+struct Rendered<'a, T: ?Sized>(&'a RenderContext<'a>, &'a T);
+
+impl<T: Render + ?Sized> Serialize for Rendered<'_, T> {
     fn serialize<S: Serializer>(&self, ser: S) -> Result<S::Ok, S::Error> {
-        self.target.serialize(ser)
+        self.1.render(self.0, ser)
+    }
+}
+
+/// Render a single value as Starlark, outside of any rule.
+#[cfg(test)]
+pub(crate) fn render_starlark(config: &BuckConfig, value: &impl Render) -> String {
+    serde_starlark::to_string(&RenderContext::new(config).with(value)).unwrap()
+}
+
+/// Values which render the same whatever the context.
+macro_rules! render_plain {
+    ($($ty:ty),* $(,)?) => {
+        $(
+            impl Render for $ty {
+                fn render<S: Serializer>(&self, _cx: &RenderContext, ser: S) -> Result<S::Ok, S::Error> {
+                    self.serialize(ser)
+                }
+            }
+        )*
+    };
+}
+
+render_plain!(str, String, bool, Name, PlatformName, UniverseName);
+
+impl<T: Render + ?Sized> Render for &T {
+    fn render<S: Serializer>(&self, cx: &RenderContext, ser: S) -> Result<S::Ok, S::Error> {
+        (**self).render(cx, ser)
+    }
+}
+
+impl<T: Render> Render for Option<T> {
+    fn render<S: Serializer>(&self, cx: &RenderContext, ser: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Some(value) => ser.serialize_some(&cx.with(value)),
+            None => ser.serialize_none(),
+        }
+    }
+}
+
+impl<T: Render> Render for [T] {
+    fn render<S: Serializer>(&self, cx: &RenderContext, ser: S) -> Result<S::Ok, S::Error> {
+        ser.collect_seq(self.iter().map(|value| cx.with(value)))
+    }
+}
+
+impl<T: Render> Render for Vec<T> {
+    fn render<S: Serializer>(&self, cx: &RenderContext, ser: S) -> Result<S::Ok, S::Error> {
+        self.as_slice().render(cx, ser)
+    }
+}
+
+impl<T: Render> Render for BTreeSet<T> {
+    fn render<S: Serializer>(&self, cx: &RenderContext, ser: S) -> Result<S::Ok, S::Error> {
+        ser.collect_seq(self.iter().map(|value| cx.with(value)))
+    }
+}
+
+impl<K: Render, V: Render> Render for BTreeMap<K, V> {
+    fn render<S: Serializer>(&self, cx: &RenderContext, ser: S) -> Result<S::Ok, S::Error> {
+        ser.collect_map(
+            self.iter()
+                .map(|(key, value)| (cx.with(key), cx.with(value))),
+        )
+    }
+}
+
+impl Render for SelectSet {
+    fn render<S: Serializer>(&self, cx: &RenderContext, ser: S) -> Result<S::Ok, S::Error> {
+        if cx.json {
+            self.serialize_json(ser)
+        } else {
+            self.serialize(ser)
+        }
+    }
+}
+
+impl<T: Render + Ord> Render for SetOrMap<T> {
+    fn render<S: Serializer>(&self, cx: &RenderContext, ser: S) -> Result<S::Ok, S::Error> {
+        match self {
+            SetOrMap::Set(set) => set.render(cx, ser),
+            SetOrMap::Map(map) => map.render(cx, ser),
+        }
+    }
+}
+
+impl Render for RuleRef {
+    fn render<S: Serializer>(&self, cx: &RenderContext, ser: S) -> Result<S::Ok, S::Error> {
+        let label = match self.target.strip_prefix(':') {
+            Some(local) => {
+                let (name, subtarget) = local.split_at(local.find('[').unwrap_or(local.len()));
+                format!("{}{}", cx.local_label(name), subtarget)
+            }
+            None => self.target.clone(),
+        };
+        match &self.origin {
+            Some(origin) if cx.annotate_deps() => LineComment::new(&label, origin).serialize(ser),
+            _ => label.serialize(ser),
+        }
     }
 }
 
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct BuckPath(pub PathBuf);
 
-impl Serialize for BuckPath {
-    fn serialize<S: Serializer>(&self, ser: S) -> Result<S::Ok, S::Error> {
+impl Render for BuckPath {
+    fn render<S: Serializer>(&self, cx: &RenderContext, ser: S) -> Result<S::Ok, S::Error> {
+        let path = match &cx.package {
+            Some(package) => match self.0.strip_prefix(&package.dir) {
+                Ok(path) => path,
+                Err(_) => {
+                    return Err(serde::ser::Error::custom(format!(
+                        "path {} is outside package {}, so can't be used with `split_per_package`",
+                        self.0.display(),
+                        package.dir.display(),
+                    )));
+                }
+            },
+            None => &self.0,
+        };
+
+        // Paths in generated rules are meant to be relative to the package
+        // containing the BUCK file. Anything else is not portable.
+        if path.is_absolute() {
+            if cx.config.allow_absolute_paths {
+                log::warn!("absolute path in generated rule: {}", path.display());
+            } else {
+                return Err(serde::ser::Error::custom(format!(
+                    "absolute path in generated rule: {} (set `allow_absolute_paths = true` in [buck] config to permit)",
+                    path.display(),
+                )));
+            }
+        }
+
         // Even on Windows we want to use forward slash paths
-        match self.0.as_path().to_str() {
+        match path.to_str() {
             Some(s) => s.replace('\\', "/").serialize(ser),
-            None => Err(serde::ser::Error::custom(
-                "path contains invalid UTF-8 characters",
-            )),
+            None if cx.config.lossy_utf8_paths => {
+                let lossy = path.to_string_lossy();
+                log::warn!("path contains invalid UTF-8 characters: {}", lossy);
+                lossy.replace('\\', "/").serialize(ser)
+            }
+            None => Err(serde::ser::Error::custom(format!(
+                "path contains invalid UTF-8 characters: {} (set `lossy_utf8_paths = true` in [buck] config to permit)",
+                path.to_string_lossy(),
+            ))),
         }
     }
 }
@@ -144,20 +393,45 @@ impl PartialOrd for BuckPath {
     }
 }
 
-#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Serialize)]
-#[serde(untagged)]
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd)]
 pub enum StringOrPath {
     String(String),
     Path(BuckPath),
 }
 
-#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Serialize)]
-#[serde(untagged)]
+impl Render for StringOrPath {
+    fn render<S: Serializer>(&self, cx: &RenderContext, ser: S) -> Result<S::Ok, S::Error> {
+        match self {
+            StringOrPath::String(string) => string.serialize(ser),
+            StringOrPath::Path(path) => path.render(cx, ser),
+        }
+    }
+}
+
+impl Display for StringOrPath {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StringOrPath::String(string) => Display::fmt(string, formatter),
+            StringOrPath::Path(path) => Display::fmt(path, formatter),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd)]
 pub enum SubtargetOrPath {
     Subtarget(Subtarget),
     Path(BuckPath),
 }
 
+impl Render for SubtargetOrPath {
+    fn render<S: Serializer>(&self, cx: &RenderContext, ser: S) -> Result<S::Ok, S::Error> {
+        match self {
+            SubtargetOrPath::Subtarget(subtarget) => subtarget.render(cx, ser),
+            SubtargetOrPath::Path(path) => path.render(cx, ser),
+        }
+    }
+}
+
 impl SubtargetOrPath {
     fn is_subtarget(&self) -> bool {
         matches!(self, SubtargetOrPath::Subtarget(_))
@@ -168,22 +442,71 @@ impl SubtargetOrPath {
     }
 }
 
+/// `mapped_srcs` of a Rust rule, from source to the path it's mapped to.
+pub type MappedSrcs = BTreeMap<MappedSrc, BuckPath>;
+
+/// Key of a `mapped_srcs` entry.
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd)]
+pub enum MappedSrc {
+    Source(SubtargetOrPath),
+    /// The `OutDirFile` rule with a file generated by the package's build
+    /// script
+    OutDirFile(Name),
+}
+
+impl MappedSrc {
+    pub fn source(&self) -> Option<&SubtargetOrPath> {
+        match self {
+            MappedSrc::Source(source) => Some(source),
+            MappedSrc::OutDirFile(_) => None,
+        }
+    }
+}
+
+impl Render for MappedSrc {
+    fn render<S: Serializer>(&self, cx: &RenderContext, ser: S) -> Result<S::Ok, S::Error> {
+        match self {
+            MappedSrc::Source(source) => source.render(cx, ser),
+            MappedSrc::OutDirFile(name) => cx.local_label(&name.0).serialize(ser),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd)]
 pub struct Subtarget {
     pub target: Name,
     pub relative: BuckPath,
 }
 
-impl Serialize for Subtarget {
-    fn serialize<S: Serializer>(&self, ser: S) -> Result<S::Ok, S::Error> {
-        ser.collect_str(&format_args!(":{}[{}]", self.target, self.relative))
+impl Render for Subtarget {
+    fn render<S: Serializer>(&self, cx: &RenderContext, ser: S) -> Result<S::Ok, S::Error> {
+        ser.collect_str(&format_args!(
+            "{}[{}]",
+            cx.local_label(&self.target.0),
+            self.relative
+        ))
     }
 }
 
-#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Serialize)]
-#[serde(rename = "select")]
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd)]
 pub struct Select<K, V>(BTreeMap<K, V>);
 
+impl<K, V> Render for Select<K, V>
+where
+    K: Render,
+    V: Render,
+{
+    fn render<S: Serializer>(&self, cx: &RenderContext, ser: S) -> Result<S::Ok, S::Error> {
+        if cx.json {
+            let mut map = ser.serialize_map(Some(1))?;
+            map.serialize_entry("select", &cx.with(&self.0))?;
+            map.end()
+        } else {
+            ser.serialize_newtype_struct("select", &cx.with(&self.0))
+        }
+    }
+}
+
 #[derive(Clone, Eq, PartialEq, Ord, PartialOrd)]
 pub enum Selectable<K, V> {
     Value(V),
@@ -203,6 +526,20 @@ impl<K, V> Selectable<K, V> {
             Self::Select(..) => panic!("called `Selectable::unwrap_mut` on a `Select` value"),
         }
     }
+    pub fn unwrap(self) -> V {
+        match self {
+            Self::Value(v) => v,
+            Self::Select(..) => panic!("called `Selectable::unwrap` on a `Select` value"),
+        }
+    }
+    /// The value, or every alternative of the select.
+    pub fn values(&self) -> impl Iterator<Item = &V> {
+        let (value, select) = match self {
+            Self::Value(v) => (Some(v), None),
+            Self::Select(select) => (None, Some(select.0.values())),
+        };
+        value.into_iter().chain(select.into_iter().flatten())
+    }
 }
 
 impl<K, V> Selectable<K, V>
@@ -281,6 +618,15 @@ impl<K, V> Selectable<K, BTreeSet<V>> {
     }
 }
 
+impl<K, V> Selectable<K, Vec<V>> {
+    pub fn is_empty(&self) -> bool {
+        match self {
+            Self::Value(v) => v.is_empty(),
+            Self::Select(select) => select.0.is_empty() || select.0.values().all(Vec::is_empty),
+        }
+    }
+}
+
 impl<K, K1, V> Selectable<K, BTreeMap<K1, V>> {
     pub fn is_empty(&self) -> bool {
         match self {
@@ -320,19 +666,19 @@ where
     }
 }
 
-impl<K, V> Serialize for Selectable<K, V>
+impl<K, V> Render for Selectable<K, V>
 where
-    K: Serialize,
-    V: Serialize,
+    K: Render,
+    V: Render,
 {
-    fn serialize<S: Serializer>(&self, ser: S) -> Result<S::Ok, S::Error> {
+    fn render<S: Serializer>(&self, cx: &RenderContext, ser: S) -> Result<S::Ok, S::Error> {
         match self {
-            Self::Value(v) => v.serialize(ser),
+            Self::Value(v) => v.render(cx, ser),
             Self::Select(s) => {
                 if s.0.len() == 1 {
-                    s.0.values().next().unwrap().serialize(ser)
+                    s.0.values().next().unwrap().render(cx, ser)
                 } else {
-                    s.serialize(ser)
+                    s.render(cx, ser)
                 }
             }
         }
@@ -346,11 +692,23 @@ pub enum Visibility {
     Custom(Vec<String>),
 }
 
-impl Serialize for Visibility {
-    fn serialize<S: Serializer>(&self, ser: S) -> Result<S::Ok, S::Error> {
+impl Render for Visibility {
+    fn render<S: Serializer>(&self, cx: &RenderContext, ser: S) -> Result<S::Ok, S::Error> {
         match self {
             Visibility::Public => ["PUBLIC"].as_slice().serialize(ser),
-            Visibility::Private => (&[] as &[&str]).serialize(ser),
+            Visibility::Private => match &cx.package {
+                // Other packages' rules refer to it
+                Some(package) => {
+                    let path = package.root.to_string_lossy().replace('\\', "/");
+                    let within = if path.is_empty() {
+                        "//..."
+                    } else {
+                        &format!("//{path}/...")
+                    };
+                    [within].as_slice().serialize(ser)
+                }
+                None => (&[] as &[&str]).serialize(ser),
+            },
             Visibility::Custom(custom_visiblity) => custom_visiblity.serialize(ser),
         }
     }
@@ -361,32 +719,47 @@ pub struct Alias {
     pub name: Name,
     /// Local target that the alias refers to -- always in the same package.
     pub actual: Name,
+    /// What the alias refers to on platforms where `actual` isn't compatible.
+    pub fallback_actual: Option<AliasFallback>,
     pub visibility: Visibility,
 }
 
-impl Serialize for Alias {
-    fn serialize<S: Serializer>(&self, ser: S) -> Result<S::Ok, S::Error> {
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct AliasFallback {
+    /// The `actual` target's `target_compatible_with` constraint
+    pub constraint: RuleRef,
+    /// Stand-in for `actual` on platforms which don't satisfy `constraint`
+    pub actual: RuleRef,
+}
+
+impl Render for Alias {
+    fn render<S: Serializer>(&self, cx: &RenderContext, ser: S) -> Result<S::Ok, S::Error> {
         let Self {
             name,
             actual,
+            fallback_actual,
             visibility,
         } = self;
         let mut map = ser.serialize_map(None)?;
-        map.serialize_entry("name", name)?;
-        map.serialize_entry("actual", &NameAsLabel(actual))?;
-        map.serialize_entry("visibility", visibility)?;
+        map.serialize_entry("name", &cx.with(name))?;
+        match fallback_actual {
+            None => map.serialize_entry("actual", &cx.local_label(&actual.0))?,
+            Some(AliasFallback {
+                constraint,
+                actual: fallback,
+            }) => map.serialize_entry(
+                "actual",
+                &cx.with(&Select(BTreeMap::from([
+                    ("DEFAULT".to_owned(), fallback.target.clone()),
+                    (constraint.target.clone(), cx.local_label(&actual.0)),
+                ]))),
+            )?,
+        }
+        map.serialize_entry("visibility", &cx.with(visibility))?;
         map.end()
     }
 }
 
-struct NameAsLabel<'a>(&'a Name);
-
-impl Serialize for NameAsLabel<'_> {
-    fn serialize<S: Serializer>(&self, ser: S) -> Result<S::Ok, S::Error> {
-        ser.collect_str(&format_args!(":{}", self.0))
-    }
-}
-
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct Filegroup {
     pub name: Name,
@@ -394,21 +767,59 @@ pub struct Filegroup {
     pub visibility: Visibility,
 }
 
-impl Serialize for Filegroup {
-    fn serialize<S: Serializer>(&self, ser: S) -> Result<S::Ok, S::Error> {
+impl Render for Filegroup {
+    fn render<S: Serializer>(&self, cx: &RenderContext, ser: S) -> Result<S::Ok, S::Error> {
         let Self {
             name,
             srcs,
             visibility,
         } = self;
         let mut map = ser.serialize_map(None)?;
-        map.serialize_entry("name", name)?;
+        map.serialize_entry("name", &cx.with(name))?;
         // Pretty useless to have a filegroup without srcs,
         // but that's legit in both buck1 and buck2.
         if !srcs.is_empty() {
-            map.serialize_entry("srcs", srcs)?;
+            map.serialize_entry("srcs", &cx.with(srcs))?;
         }
-        map.serialize_entry("visibility", visibility)?;
+        map.serialize_entry("visibility", &cx.with(visibility))?;
+        map.end()
+    }
+}
+
+/// A `genrule` copying one file out of the `OUT_DIR` of a build script run,
+/// which only provides the directory as a whole. `mapped_srcs` keys are
+/// sources, so they can't pick a file out of it with `$(location)`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct OutDirFile {
+    pub name: Name,
+    pub buildscript_run: Name,
+    pub relative: BuckPath,
+    pub visibility: Visibility,
+}
+
+impl Render for OutDirFile {
+    fn render<S: Serializer>(&self, cx: &RenderContext, ser: S) -> Result<S::Ok, S::Error> {
+        let Self {
+            name,
+            buildscript_run,
+            relative,
+            visibility,
+        } = self;
+        let out = relative
+            .0
+            .file_name()
+            .unwrap_or(relative.0.as_os_str())
+            .to_string_lossy();
+        let cmd = format!(
+            "cp $(location {}[out_dir])/{} $OUT",
+            cx.local_label(&buildscript_run.0),
+            relative,
+        );
+        let mut map = ser.serialize_map(None)?;
+        map.serialize_entry("name", &cx.with(name))?;
+        map.serialize_entry("out", &out)?;
+        map.serialize_entry("cmd", &cmd)?;
+        map.serialize_entry("visibility", &cx.with(visibility))?;
         map.end()
     }
 }
@@ -424,8 +835,8 @@ pub struct HttpArchive {
     pub sort_key: Name,
 }
 
-impl Serialize for HttpArchive {
-    fn serialize<S: Serializer>(&self, ser: S) -> Result<S::Ok, S::Error> {
+impl Render for HttpArchive {
+    fn render<S: Serializer>(&self, cx: &RenderContext, ser: S) -> Result<S::Ok, S::Error> {
         let Self {
             name,
             sha256,
@@ -436,14 +847,14 @@ impl Serialize for HttpArchive {
             sort_key: _,
         } = self;
         let mut map = ser.serialize_map(None)?;
-        map.serialize_entry("name", name)?;
+        map.serialize_entry("name", &cx.with(name))?;
         map.serialize_entry("sha256", sha256)?;
-        map.serialize_entry("strip_prefix", strip_prefix)?;
+        map.serialize_entry("strip_prefix", &cx.with(strip_prefix))?;
         if !sub_targets.is_empty() {
-            map.serialize_entry("sub_targets", sub_targets)?;
+            map.serialize_entry("sub_targets", &cx.with(sub_targets))?;
         }
-        map.serialize_entry("urls", urls)?;
-        map.serialize_entry("visibility", visibility)?;
+        map.serialize_entry("urls", &cx.with(urls))?;
+        map.serialize_entry("visibility", &cx.with(visibility))?;
         map.end()
     }
 }
@@ -453,22 +864,27 @@ pub struct GitFetch {
     pub name: Name,
     pub repo: String,
     pub rev: String,
+    pub submodules: bool,
     pub visibility: Visibility,
 }
 
-impl Serialize for GitFetch {
-    fn serialize<S: Serializer>(&self, ser: S) -> Result<S::Ok, S::Error> {
+impl Render for GitFetch {
+    fn render<S: Serializer>(&self, cx: &RenderContext, ser: S) -> Result<S::Ok, S::Error> {
         let Self {
             name,
             repo,
             rev,
+            submodules,
             visibility,
         } = self;
         let mut map = ser.serialize_map(None)?;
-        map.serialize_entry("name", name)?;
-        map.serialize_entry("repo", repo)?;
-        map.serialize_entry("rev", rev)?;
-        map.serialize_entry("visibility", visibility)?;
+        map.serialize_entry("name", &cx.with(name))?;
+        map.serialize_entry("repo", &cx.with(repo))?;
+        map.serialize_entry("rev", &cx.with(rev))?;
+        if *submodules {
+            map.serialize_entry("submodules", &cx.with(submodules))?;
+        }
+        map.serialize_entry("visibility", &cx.with(visibility))?;
         map.end()
     }
 }
@@ -478,19 +894,25 @@ pub struct Common {
     pub name: Name,
     pub visibility: Visibility,
     pub licenses: BTreeSet<BuckPath>,
+    pub license_expression: Option<String>,
     pub compatible_with: Vec<RuleRef>,
+    /// Constraints the target platform must satisfy, otherwise the target is
+    /// skipped rather than built
+    pub target_compatible_with: Vec<RuleRef>,
 }
 
 // Rule attributes which could be platform-specific
 #[derive(Debug, Default, Clone, Eq, PartialEq, Ord, PartialOrd)]
 pub struct PlatformRustCommon {
     pub srcs: BTreeSet<BuckPath>,
-    pub mapped_srcs: BTreeMap<SubtargetOrPath, BuckPath>,
+    pub mapped_srcs: MappedSrcs,
     pub rustc_flags: SelectSet,
     pub features: Selectable<UniverseName, BTreeSet<String>>,
     pub deps: Selectable<UniverseName, BTreeSet<RuleRef>>,
     pub named_deps: Selectable<UniverseName, BTreeMap<String, RuleRef>>,
     pub env: Selectable<UniverseName, BTreeMap<String, StringOrPath>>,
+    /// Extra deps of the library's doctests (Libraries only)
+    pub doc_deps: BTreeSet<RuleRef>,
 
     // This isn't really "common" (Binaries only), but does need to be platform
     pub link_style: Option<String>,
@@ -499,8 +921,49 @@ pub struct PlatformRustCommon {
     pub preferred_linkage: Option<String>,
 }
 
-impl Serialize for PlatformRustCommon {
-    fn serialize<S: Serializer>(&self, ser: S) -> Result<S::Ok, S::Error> {
+impl PlatformRustCommon {
+    /// Fold the attributes of a platform into these.
+    pub fn absorb(&mut self, platform: PlatformRustCommon) {
+        let PlatformRustCommon {
+            srcs,
+            mapped_srcs,
+            rustc_flags,
+            features,
+            deps,
+            named_deps,
+            env,
+            doc_deps,
+            link_style,
+            linker_flags,
+            preferred_linkage,
+        } = platform;
+        self.srcs.extend(srcs);
+        self.mapped_srcs.extend(mapped_srcs);
+        self.rustc_flags.common.extend(rustc_flags.common);
+        for (key, flags) in rustc_flags.selects {
+            self.rustc_flags
+                .selects
+                .entry(key)
+                .or_default()
+                .extend(flags);
+        }
+        self.features.unwrap_mut().extend(features.unwrap());
+        self.deps.unwrap_mut().extend(deps.unwrap());
+        self.named_deps.unwrap_mut().extend(named_deps.unwrap());
+        self.env.unwrap_mut().extend(env.unwrap());
+        self.doc_deps.extend(doc_deps);
+        if link_style.is_some() {
+            self.link_style = link_style;
+        }
+        self.linker_flags.extend(linker_flags);
+        if preferred_linkage.is_some() {
+            self.preferred_linkage = preferred_linkage;
+        }
+    }
+}
+
+impl Render for PlatformRustCommon {
+    fn render<S: Serializer>(&self, cx: &RenderContext, ser: S) -> Result<S::Ok, S::Error> {
         let Self {
             srcs,
             mapped_srcs,
@@ -509,40 +972,44 @@ impl Serialize for PlatformRustCommon {
             deps,
             named_deps,
             env,
+            doc_deps,
             link_style,
             linker_flags,
             preferred_linkage,
         } = self;
         let mut map = ser.serialize_map(None)?;
         if !srcs.is_empty() {
-            map.serialize_entry("srcs", srcs)?;
+            map.serialize_entry("srcs", &cx.with(srcs))?;
+        }
+        if !doc_deps.is_empty() {
+            map.serialize_entry("doc_deps", &cx.with(doc_deps))?;
         }
         if !env.is_empty() {
-            map.serialize_entry("env", env)?;
+            map.serialize_entry("env", &cx.with(env))?;
         }
         if !features.is_empty() {
-            map.serialize_entry("features", features)?;
+            map.serialize_entry("features", &cx.with(features))?;
         }
         if let Some(link_style) = link_style {
-            map.serialize_entry("link_style", link_style)?;
+            map.serialize_entry("link_style", &cx.with(link_style))?;
         }
         if !linker_flags.is_empty() {
-            map.serialize_entry("linker_flags", linker_flags)?;
+            map.serialize_entry("linker_flags", &cx.with(linker_flags))?;
         }
         if !mapped_srcs.is_empty() {
-            map.serialize_entry("mapped_srcs", mapped_srcs)?;
+            map.serialize_entry("mapped_srcs", &cx.with(mapped_srcs))?;
         }
         if !named_deps.is_empty() {
-            map.serialize_entry("named_deps", named_deps)?;
+            map.serialize_entry("named_deps", &cx.with(named_deps))?;
         }
         if let Some(preferred_linkage) = preferred_linkage {
-            map.serialize_entry("preferred_linkage", preferred_linkage)?;
+            map.serialize_entry("preferred_linkage", &cx.with(preferred_linkage))?;
         }
         if !rustc_flags.is_empty() {
-            map.serialize_entry("rustc_flags", rustc_flags)?;
+            map.serialize_entry("rustc_flags", &cx.with(rustc_flags))?;
         }
         if !deps.is_empty() {
-            map.serialize_entry("deps", deps)?;
+            map.serialize_entry("deps", &cx.with(deps))?;
         }
         map.end()
     }
@@ -560,6 +1027,25 @@ pub struct RustCommon {
     pub platform: BTreeMap<PlatformName, PlatformRustCommon>,
 }
 
+impl RustCommon {
+    /// Files the rule reads as source, on any platform: its crate root, srcs,
+    /// and mapped srcs which aren't subtargets or generated.
+    pub fn source_paths(&self) -> impl Iterator<Item = &Path> {
+        let platforms = iter::once(&self.base).chain(self.platform.values());
+        iter::once(self.crate_root.0.as_path()).chain(platforms.flat_map(|platform| {
+            let mapped_srcs = platform.mapped_srcs.keys().filter_map(|src| match src {
+                MappedSrc::Source(SubtargetOrPath::Path(path)) => Some(path),
+                MappedSrc::Source(SubtargetOrPath::Subtarget(_)) | MappedSrc::OutDirFile(_) => None,
+            });
+            platform
+                .srcs
+                .iter()
+                .chain(mapped_srcs)
+                .map(|path| path.0.as_path())
+        }))
+    }
+}
+
 /// Serialize as:
 ///
 /// ```bzl
@@ -592,6 +1078,7 @@ pub struct RustCommon {
 /// ```
 fn serialize_platforms_dict<S>(
     map: &mut S,
+    cx: &RenderContext,
     platforms: &BTreeMap<PlatformName, PlatformRustCommon>,
 ) -> Result<(), S::Error>
 where
@@ -599,17 +1086,163 @@ where
 {
     struct Platforms<'a>(&'a BTreeMap<PlatformName, PlatformRustCommon>);
 
-    impl Serialize for Platforms<'_> {
-        fn serialize<S: Serializer>(&self, ser: S) -> Result<S::Ok, S::Error> {
+    impl Render for Platforms<'_> {
+        fn render<S: Serializer>(&self, cx: &RenderContext, ser: S) -> Result<S::Ok, S::Error> {
+            if cx.json {
+                return self.0.render(cx, ser);
+            }
             ser.collect_map(
                 self.0
                     .iter()
-                    .map(|(name, value)| (name, FunctionCall::new("dict", value))),
+                    .map(|(name, value)| (name, FunctionCall::new("dict", cx.with(value)))),
             )
         }
     }
 
-    map.serialize_entry("platform", &Platforms(platforms))
+    map.serialize_entry("platform", &cx.with(&Platforms(platforms)))
+}
+
+/// Each of a Rust rule's platforms which has specific attributes, as its
+/// `select()` key and those attributes folded into the common ones. Empty
+/// unless `platform_representation` is `select`.
+fn platform_selects(
+    cx: &RenderContext,
+    common: &RustCommon,
+) -> Result<Vec<(String, PlatformRustCommon)>, String> {
+    let Some(settings) = cx.platform_settings() else {
+        return Ok(Vec::new());
+    };
+    common
+        .platform
+        .iter()
+        .map(|(name, platform)| {
+            let setting = settings.get(name).ok_or_else(|| {
+                format!("platform `{name}` has no entry in `[buck] platform_settings`")
+            })?;
+            let mut attrs = common.base.clone();
+            attrs.absorb(platform.clone());
+            Ok((setting.clone(), attrs))
+        })
+        .collect()
+}
+
+/// Serialize one of a Rust rule's platform-specific attributes: as its common
+/// value, or if it's different on some platform, as a `select()` of its value
+/// on each such platform with the common value as the default.
+fn serialize_platform_attr<S, T>(
+    map: &mut S,
+    cx: &RenderContext,
+    key: &'static str,
+    base: &T,
+    selects: &[(String, PlatformRustCommon)],
+    attr: impl Fn(&PlatformRustCommon) -> &T,
+    is_empty: impl Fn(&T) -> bool,
+) -> Result<(), S::Error>
+where
+    S: SerializeMap,
+    T: Render + PartialEq,
+{
+    let mut branches: BTreeMap<&str, &T> = selects
+        .iter()
+        .map(|(setting, attrs)| (setting.as_str(), attr(attrs)))
+        .filter(|(_setting, value)| *value != base)
+        .collect();
+    if branches.is_empty() {
+        if !is_empty(base) {
+            map.serialize_entry(key, &cx.with(base))?;
+        }
+    } else {
+        branches.insert("DEFAULT", base);
+        map.serialize_entry(key, &cx.with(&Select(branches)))?;
+    }
+    Ok(())
+}
+
+/// Version of the `CrateMetadata` layout. Bump it whenever fields are added,
+/// removed or change meaning, so consumers can tell what to expect.
+pub const METADATA_SCHEMA_VERSION: u32 = 1;
+
+/// Cargo package metadata attached to library rules with `emit_metadata`.
+#[derive(Debug, Clone, Eq, PartialEq, Deserialize)]
+pub struct CrateMetadata {
+    pub schema_version: u32,
+    pub name: String,
+    pub version: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub repository: Option<String>,
+    #[serde(default)]
+    pub authors: Vec<String>,
+    /// Features enabled by resolution
+    #[serde(default)]
+    pub features: BTreeSet<String>,
+    /// Where the package came from: "registry", "git", "path" or "other"
+    #[serde(default)]
+    pub source: Option<String>,
+    /// Registry checksum from Cargo.lock
+    #[serde(default)]
+    pub checksum: Option<String>,
+}
+
+impl Serialize for CrateMetadata {
+    fn serialize<S: Serializer>(&self, ser: S) -> Result<S::Ok, S::Error> {
+        self.serialize_with_prefix("", ser)
+    }
+}
+
+impl CrateMetadata {
+    /// Serialize as a map whose keys all start with `prefix`.
+    fn serialize_with_prefix<S: Serializer>(
+        &self,
+        prefix: &str,
+        ser: S,
+    ) -> Result<S::Ok, S::Error> {
+        let Self {
+            schema_version,
+            name,
+            version,
+            description,
+            repository,
+            authors,
+            features,
+            source,
+            checksum,
+        } = self;
+        let mut map = ser.serialize_map(None)?;
+        map.serialize_entry(&format!("{prefix}schema_version"), schema_version)?;
+        map.serialize_entry(&format!("{prefix}name"), name)?;
+        map.serialize_entry(&format!("{prefix}version"), version)?;
+        if let Some(description) = description {
+            map.serialize_entry(&format!("{prefix}description"), description)?;
+        }
+        if let Some(repository) = repository {
+            map.serialize_entry(&format!("{prefix}repository"), repository)?;
+        }
+        if !authors.is_empty() {
+            map.serialize_entry(&format!("{prefix}authors"), authors)?;
+        }
+        if !features.is_empty() {
+            map.serialize_entry(&format!("{prefix}features"), features)?;
+        }
+        if let Some(source) = source {
+            map.serialize_entry(&format!("{prefix}source"), source)?;
+        }
+        if let Some(checksum) = checksum {
+            map.serialize_entry(&format!("{prefix}checksum"), checksum)?;
+        }
+        map.end()
+    }
+}
+
+/// A library's `metadata` attribute, whose keys Buck requires to be
+/// namespaced, like `"reindeer.name"`.
+struct MetadataAttr<'a>(&'a CrateMetadata);
+
+impl Serialize for MetadataAttr<'_> {
+    fn serialize<S: Serializer>(&self, ser: S) -> Result<S::Ok, S::Error> {
+        self.0.serialize_with_prefix("reindeer.", ser)
+    }
 }
 
 #[derive(Debug, Clone, Eq, PartialEq)]
@@ -619,10 +1252,13 @@ pub struct RustLibrary {
     pub dlopen_enable: bool,
     pub python_ext: Option<String>,
     pub linkable_alias: Option<String>,
+    /// Dynamic library to load in place of this one, for plugins
+    pub crate_dynamic: Option<RuleRef>,
+    pub metadata: Option<CrateMetadata>,
 }
 
-impl Serialize for RustLibrary {
-    fn serialize<S: Serializer>(&self, ser: S) -> Result<S::Ok, S::Error> {
+impl Render for RustLibrary {
+    fn render<S: Serializer>(&self, cx: &RenderContext, ser: S) -> Result<S::Ok, S::Error> {
         let Self {
             common:
                 RustCommon {
@@ -631,88 +1267,162 @@ impl Serialize for RustLibrary {
                             name,
                             visibility,
                             licenses,
+                            license_expression,
                             compatible_with,
+                            target_compatible_with,
                         },
                     krate,
                     crate_root,
                     edition,
-                    base:
-                        PlatformRustCommon {
-                            srcs,
-                            mapped_srcs,
-                            rustc_flags,
-                            features,
-                            deps,
-                            named_deps,
-                            env,
-                            link_style,
-                            linker_flags,
-                            preferred_linkage,
-                        },
+                    base,
                     platform,
                 },
             proc_macro,
             dlopen_enable,
             python_ext,
             linkable_alias,
+            crate_dynamic,
+            metadata,
         } = self;
+        let selects = platform_selects(cx, &self.common).map_err(serde::ser::Error::custom)?;
         let mut map = ser.serialize_map(None)?;
-        map.serialize_entry("name", name)?;
-        if !srcs.is_empty() {
-            map.serialize_entry("srcs", srcs)?;
-        }
+        map.serialize_entry("name", &cx.with(name))?;
+        serialize_platform_attr(
+            &mut map,
+            cx,
+            "srcs",
+            &base.srcs,
+            &selects,
+            |attrs| &attrs.srcs,
+            BTreeSet::is_empty,
+        )?;
         if !compatible_with.is_empty() {
-            map.serialize_entry("compatible_with", compatible_with)?;
+            map.serialize_entry("compatible_with", &cx.with(compatible_with))?;
         }
-        map.serialize_entry("crate", krate)?;
-        map.serialize_entry("crate_root", crate_root)?;
+        if !target_compatible_with.is_empty() {
+            map.serialize_entry("target_compatible_with", &cx.with(target_compatible_with))?;
+        }
+        map.serialize_entry("crate", &cx.with(krate))?;
+        if let Some(crate_dynamic) = crate_dynamic {
+            map.serialize_entry("crate_dynamic", &cx.with(crate_dynamic))?;
+        }
+        map.serialize_entry("crate_root", &cx.with(crate_root))?;
         if *dlopen_enable {
             map.serialize_entry("dlopen_enable", &true)?;
         }
+        serialize_platform_attr(
+            &mut map,
+            cx,
+            "doc_deps",
+            &base.doc_deps,
+            &selects,
+            |attrs| &attrs.doc_deps,
+            BTreeSet::is_empty,
+        )?;
         map.serialize_entry("edition", edition)?;
-        if !env.is_empty() {
-            map.serialize_entry("env", env)?;
-        }
-        if !features.is_empty() {
-            map.serialize_entry("features", features)?;
+        serialize_platform_attr(
+            &mut map,
+            cx,
+            "env",
+            &base.env,
+            &selects,
+            |attrs| &attrs.env,
+            |value| value.is_empty(),
+        )?;
+        serialize_platform_attr(
+            &mut map,
+            cx,
+            "features",
+            &base.features,
+            &selects,
+            |attrs| &attrs.features,
+            |value| value.is_empty(),
+        )?;
+        if let Some(license_expression) = license_expression {
+            map.serialize_entry("license_expression", &cx.with(license_expression))?;
         }
         if !licenses.is_empty() {
-            map.serialize_entry("licenses", licenses)?;
-        }
-        if let Some(link_style) = link_style {
-            map.serialize_entry("link_style", link_style)?;
-        }
-        if !linker_flags.is_empty() {
-            map.serialize_entry("linker_flags", linker_flags)?;
-        }
+            map.serialize_entry("licenses", &cx.with(licenses))?;
+        }
+        serialize_platform_attr(
+            &mut map,
+            cx,
+            "link_style",
+            &base.link_style,
+            &selects,
+            |attrs| &attrs.link_style,
+            Option::is_none,
+        )?;
+        serialize_platform_attr(
+            &mut map,
+            cx,
+            "linker_flags",
+            &base.linker_flags,
+            &selects,
+            |attrs| &attrs.linker_flags,
+            Vec::is_empty,
+        )?;
         if let Some(linkable_alias) = linkable_alias {
-            map.serialize_entry("linkable_alias", linkable_alias)?;
-        }
-        if !mapped_srcs.is_empty() {
-            map.serialize_entry("mapped_srcs", mapped_srcs)?;
-        }
-        if !named_deps.is_empty() {
-            map.serialize_entry("named_deps", named_deps)?;
-        }
-        if !platform.is_empty() {
-            serialize_platforms_dict(&mut map, platform)?;
-        }
-        if let Some(preferred_linkage) = preferred_linkage {
-            map.serialize_entry("preferred_linkage", preferred_linkage)?;
-        }
+            map.serialize_entry("linkable_alias", &cx.with(linkable_alias))?;
+        }
+        serialize_platform_attr(
+            &mut map,
+            cx,
+            "mapped_srcs",
+            &base.mapped_srcs,
+            &selects,
+            |attrs| &attrs.mapped_srcs,
+            BTreeMap::is_empty,
+        )?;
+        if let Some(metadata) = metadata {
+            map.serialize_entry("metadata", &MetadataAttr(metadata))?;
+        }
+        serialize_platform_attr(
+            &mut map,
+            cx,
+            "named_deps",
+            &base.named_deps,
+            &selects,
+            |attrs| &attrs.named_deps,
+            |value| value.is_empty(),
+        )?;
+        if !platform.is_empty() && selects.is_empty() {
+            serialize_platforms_dict(&mut map, cx, platform)?;
+        }
+        serialize_platform_attr(
+            &mut map,
+            cx,
+            "preferred_linkage",
+            &base.preferred_linkage,
+            &selects,
+            |attrs| &attrs.preferred_linkage,
+            Option::is_none,
+        )?;
         if *proc_macro {
             map.serialize_entry("proc_macro", &true)?;
         }
         if let Some(python_ext) = python_ext {
-            map.serialize_entry("python_ext", python_ext)?;
-        }
-        if !rustc_flags.is_empty() {
-            map.serialize_entry("rustc_flags", rustc_flags)?;
-        }
-        map.serialize_entry("visibility", visibility)?;
-        if !deps.is_empty() {
-            map.serialize_entry("deps", deps)?;
-        }
+            map.serialize_entry("python_ext", &cx.with(python_ext))?;
+        }
+        serialize_platform_attr(
+            &mut map,
+            cx,
+            "rustc_flags",
+            &base.rustc_flags,
+            &selects,
+            |attrs| &attrs.rustc_flags,
+            SelectSet::is_empty,
+        )?;
+        map.serialize_entry("visibility", &cx.with(visibility))?;
+        serialize_platform_attr(
+            &mut map,
+            cx,
+            "deps",
+            &base.deps,
+            &selects,
+            |attrs| &attrs.deps,
+            |value| value.is_empty(),
+        )?;
         map.end()
     }
 }
@@ -722,8 +1432,8 @@ pub struct RustBinary {
     pub common: RustCommon,
 }
 
-impl Serialize for RustBinary {
-    fn serialize<S: Serializer>(&self, ser: S) -> Result<S::Ok, S::Error> {
+impl Render for RustBinary {
+    fn render<S: Serializer>(&self, cx: &RenderContext, ser: S) -> Result<S::Ok, S::Error> {
         let Self {
             common:
                 RustCommon {
@@ -732,72 +1442,129 @@ impl Serialize for RustBinary {
                             name,
                             visibility,
                             licenses,
+                            license_expression,
                             compatible_with,
+                            target_compatible_with,
                         },
                     krate,
                     crate_root,
                     edition,
-                    base:
-                        PlatformRustCommon {
-                            srcs,
-                            mapped_srcs,
-                            rustc_flags,
-                            features,
-                            deps,
-                            named_deps,
-                            env,
-                            link_style,
-                            linker_flags,
-                            preferred_linkage,
-                        },
+                    base,
                     platform,
                 },
         } = self;
+        let selects = platform_selects(cx, &self.common).map_err(serde::ser::Error::custom)?;
         let mut map = ser.serialize_map(None)?;
-        map.serialize_entry("name", name)?;
-        if !srcs.is_empty() {
-            map.serialize_entry("srcs", srcs)?;
-        }
+        map.serialize_entry("name", &cx.with(name))?;
+        serialize_platform_attr(
+            &mut map,
+            cx,
+            "srcs",
+            &base.srcs,
+            &selects,
+            |attrs| &attrs.srcs,
+            BTreeSet::is_empty,
+        )?;
         if !compatible_with.is_empty() {
-            map.serialize_entry("compatible_with", compatible_with)?;
+            map.serialize_entry("compatible_with", &cx.with(compatible_with))?;
         }
-        map.serialize_entry("crate", krate)?;
-        map.serialize_entry("crate_root", crate_root)?;
-        map.serialize_entry("edition", edition)?;
-        if !env.is_empty() {
-            map.serialize_entry("env", env)?;
+        if !target_compatible_with.is_empty() {
+            map.serialize_entry("target_compatible_with", &cx.with(target_compatible_with))?;
         }
-        if !features.is_empty() {
-            map.serialize_entry("features", features)?;
+        map.serialize_entry("crate", &cx.with(krate))?;
+        map.serialize_entry("crate_root", &cx.with(crate_root))?;
+        map.serialize_entry("edition", edition)?;
+        serialize_platform_attr(
+            &mut map,
+            cx,
+            "env",
+            &base.env,
+            &selects,
+            |attrs| &attrs.env,
+            |value| value.is_empty(),
+        )?;
+        serialize_platform_attr(
+            &mut map,
+            cx,
+            "features",
+            &base.features,
+            &selects,
+            |attrs| &attrs.features,
+            |value| value.is_empty(),
+        )?;
+        if let Some(license_expression) = license_expression {
+            map.serialize_entry("license_expression", &cx.with(license_expression))?;
         }
         if !licenses.is_empty() {
-            map.serialize_entry("licenses", licenses)?;
-        }
-        if let Some(link_style) = link_style {
-            map.serialize_entry("link_style", link_style)?;
-        }
-        if !linker_flags.is_empty() {
-            map.serialize_entry("linker_flags", linker_flags)?;
-        }
-        if !mapped_srcs.is_empty() {
-            map.serialize_entry("mapped_srcs", mapped_srcs)?;
-        }
-        if !named_deps.is_empty() {
-            map.serialize_entry("named_deps", named_deps)?;
-        }
-        if !platform.is_empty() {
-            serialize_platforms_dict(&mut map, platform)?;
-        }
-        if let Some(preferred_linkage) = preferred_linkage {
-            map.serialize_entry("preferred_linkage", preferred_linkage)?;
-        }
-        if !rustc_flags.is_empty() {
-            map.serialize_entry("rustc_flags", rustc_flags)?;
-        }
-        map.serialize_entry("visibility", visibility)?;
-        if !deps.is_empty() {
-            map.serialize_entry("deps", deps)?;
-        }
+            map.serialize_entry("licenses", &cx.with(licenses))?;
+        }
+        serialize_platform_attr(
+            &mut map,
+            cx,
+            "link_style",
+            &base.link_style,
+            &selects,
+            |attrs| &attrs.link_style,
+            Option::is_none,
+        )?;
+        serialize_platform_attr(
+            &mut map,
+            cx,
+            "linker_flags",
+            &base.linker_flags,
+            &selects,
+            |attrs| &attrs.linker_flags,
+            Vec::is_empty,
+        )?;
+        serialize_platform_attr(
+            &mut map,
+            cx,
+            "mapped_srcs",
+            &base.mapped_srcs,
+            &selects,
+            |attrs| &attrs.mapped_srcs,
+            BTreeMap::is_empty,
+        )?;
+        serialize_platform_attr(
+            &mut map,
+            cx,
+            "named_deps",
+            &base.named_deps,
+            &selects,
+            |attrs| &attrs.named_deps,
+            |value| value.is_empty(),
+        )?;
+        if !platform.is_empty() && selects.is_empty() {
+            serialize_platforms_dict(&mut map, cx, platform)?;
+        }
+        serialize_platform_attr(
+            &mut map,
+            cx,
+            "preferred_linkage",
+            &base.preferred_linkage,
+            &selects,
+            |attrs| &attrs.preferred_linkage,
+            Option::is_none,
+        )?;
+        serialize_platform_attr(
+            &mut map,
+            cx,
+            "rustc_flags",
+            &base.rustc_flags,
+            &selects,
+            |attrs| &attrs.rustc_flags,
+            SelectSet::is_empty,
+        )?;
+        map.serialize_entry("visibility", &cx.with(visibility))?;
+        serialize_platform_attr(
+            &mut map,
+            cx,
+            "deps",
+            &base.deps,
+            &selects,
+            |attrs| &attrs.deps,
+            |value| value.is_empty(),
+        )?;
         map.end()
     }
 }
@@ -810,27 +1577,60 @@ pub struct BuildscriptGenrule {
     pub version: Version,
     pub features: Selectable<UniverseName, BTreeSet<String>>,
     pub env: BTreeMap<String, String>,
+    /// Environment variables holding a list of paths, joined with the target
+    /// platform's path separator
+    pub path_env: BTreeMap<String, Vec<String>>,
+    /// Run the build script locally rather than on remote execution, for
+    /// scripts which probe the host
+    pub local_only: bool,
+}
+
+impl BuildscriptGenrule {
+    /// `env` with `path_env` joined into it, as a select between `:` and `;`
+    /// separators if any of the lists needs one.
+    fn env(&self, windows_constraint: &str) -> Selectable<String, BTreeMap<String, String>> {
+        let joined = |sep: &str| {
+            let mut env = self.env.clone();
+            for (name, paths) in &self.path_env {
+                env.insert(name.clone(), paths.join(sep));
+            }
+            env
+        };
+        if self.path_env.values().all(|paths| paths.len() < 2) {
+            return Selectable::Value(joined(":"));
+        }
+        Selectable::Select(Select(BTreeMap::from([
+            ("DEFAULT".to_owned(), joined(":")),
+            (windows_constraint.to_owned(), joined(";")),
+        ])))
+    }
 }
 
-impl Serialize for BuildscriptGenrule {
-    fn serialize<S: Serializer>(&self, ser: S) -> Result<S::Ok, S::Error> {
-        let Self {
+impl Render for BuildscriptGenrule {
+    fn render<S: Serializer>(&self, cx: &RenderContext, ser: S) -> Result<S::Ok, S::Error> {
+        let BuildscriptGenrule {
             name,
             buildscript_rule,
             package_name,
             version,
             features,
-            env,
+            env: _,
+            path_env: _,
+            local_only,
         } = self;
+        let env = self.env(&cx.config.windows_constraint);
         let mut map = ser.serialize_map(None)?;
-        map.serialize_entry("name", name)?;
-        map.serialize_entry("package_name", package_name)?;
-        map.serialize_entry("buildscript_rule", &NameAsLabel(buildscript_rule))?;
+        map.serialize_entry("name", &cx.with(name))?;
+        map.serialize_entry("package_name", &cx.with(package_name))?;
+        map.serialize_entry("buildscript_rule", &cx.local_label(&buildscript_rule.0))?;
         if !env.is_empty() {
-            map.serialize_entry("env", env)?;
+            map.serialize_entry("env", &cx.with(&env))?;
         }
         if !features.is_empty() {
-            map.serialize_entry("features", features)?;
+            map.serialize_entry("features", &cx.with(features))?;
+        }
+        if *local_only {
+            map.serialize_entry("local_only", &cx.with(local_only))?;
         }
         map.serialize_entry("version", version)?;
         map.end()
@@ -844,84 +1644,132 @@ pub struct CxxLibrary {
     pub headers: BTreeSet<SubtargetOrPath>,
     pub exported_headers: SetOrMap<SubtargetOrPath>,
     pub compiler_flags: Vec<String>,
+    /// Flags added to `compiler_flags` on each platform which has any
+    pub platform_compiler_flags: BTreeMap<PlatformName, Vec<String>>,
     pub preprocessor_flags: Vec<String>,
+    /// Flags added to `preprocessor_flags` on each platform which has any
+    pub platform_preprocessor_flags: BTreeMap<PlatformName, Vec<String>>,
     pub header_namespace: Option<String>,
     pub include_directories: Vec<SubtargetOrPath>,
+    pub public_include_directories: Vec<SubtargetOrPath>,
     pub deps: BTreeSet<RuleRef>,
     pub preferred_linkage: Option<String>,
     pub undefined_symbols: bool,
 }
 
-impl Serialize for CxxLibrary {
-    fn serialize<S: Serializer>(&self, ser: S) -> Result<S::Ok, S::Error> {
+impl Render for CxxLibrary {
+    fn render<S: Serializer>(&self, cx: &RenderContext, ser: S) -> Result<S::Ok, S::Error> {
         let Self {
             common:
                 Common {
                     name,
                     visibility,
                     licenses,
+                    license_expression,
                     compatible_with,
+                    target_compatible_with,
                 },
             srcs,
             headers,
             exported_headers,
             compiler_flags,
+            platform_compiler_flags,
             preprocessor_flags,
+            platform_preprocessor_flags,
             header_namespace,
             include_directories,
+            public_include_directories,
             deps,
             preferred_linkage,
             undefined_symbols,
         } = self;
         let mut map = ser.serialize_map(None)?;
-        map.serialize_entry("name", name)?;
-        map.serialize_entry("srcs", srcs)?;
-        map.serialize_entry("headers", headers)?;
+        map.serialize_entry("name", &cx.with(name))?;
+        map.serialize_entry("srcs", &cx.with(srcs))?;
+        map.serialize_entry("headers", &cx.with(headers))?;
         if let Some(header_namespace) = header_namespace {
-            map.serialize_entry("header_namespace", header_namespace)?;
+            map.serialize_entry("header_namespace", &cx.with(header_namespace))?;
         }
         if !exported_headers.is_empty() {
-            map.serialize_entry("exported_headers", exported_headers)?;
+            map.serialize_entry("exported_headers", &cx.with(exported_headers))?;
+        }
+        if public_include_directories
+            .iter()
+            .any(SubtargetOrPath::is_subtarget)
+        {
+            map.serialize_entry(
+                "exported_preprocessor_flags",
+                &location_include_flags(public_include_directories),
+            )?;
         }
         if !compatible_with.is_empty() {
-            map.serialize_entry("compatible_with", compatible_with)?;
+            map.serialize_entry("compatible_with", &cx.with(compatible_with))?;
         }
+        if !target_compatible_with.is_empty() {
+            map.serialize_entry("target_compatible_with", &cx.with(target_compatible_with))?;
+        }
+        let compiler_flags =
+            cxx_platform_flags(cx, compiler_flags.clone(), platform_compiler_flags)
+                .map_err(serde::ser::Error::custom)?;
         if !compiler_flags.is_empty() {
-            map.serialize_entry("compiler_flags", compiler_flags)?;
+            map.serialize_entry("compiler_flags", &cx.with(&compiler_flags))?;
         }
         if include_directories.iter().any(SubtargetOrPath::is_path) {
             map.serialize_entry(
                 "include_directories",
-                &IncludeDirectories {
+                &cx.with(&IncludeDirectories {
                     include_directories,
-                },
+                }),
             )?;
         }
+        if let Some(license_expression) = license_expression {
+            map.serialize_entry("license_expression", &cx.with(license_expression))?;
+        }
         if !licenses.is_empty() {
-            map.serialize_entry("licenses", licenses)?;
+            map.serialize_entry("licenses", &cx.with(licenses))?;
+        }
+        if cx.platform_settings().is_none() {
+            if !platform_compiler_flags.is_empty() {
+                map.serialize_entry(
+                    "platform_compiler_flags",
+                    &platform_regex_flags(platform_compiler_flags),
+                )?;
+            }
+            if !platform_preprocessor_flags.is_empty() {
+                map.serialize_entry(
+                    "platform_preprocessor_flags",
+                    &platform_regex_flags(platform_preprocessor_flags),
+                )?;
+            }
         }
         if let Some(preferred_linkage) = preferred_linkage {
-            map.serialize_entry("preferred_linkage", preferred_linkage)?;
+            map.serialize_entry("preferred_linkage", &cx.with(preferred_linkage))?;
         }
-        if !preprocessor_flags.is_empty()
-            || include_directories
-                .iter()
-                .any(SubtargetOrPath::is_subtarget)
+        let mut base_preprocessor_flags = location_include_flags(include_directories);
+        base_preprocessor_flags.extend(preprocessor_flags.iter().cloned());
+        let preprocessor_flags =
+            cxx_platform_flags(cx, base_preprocessor_flags, platform_preprocessor_flags)
+                .map_err(serde::ser::Error::custom)?;
+        if !preprocessor_flags.is_empty() {
+            map.serialize_entry("preprocessor_flags", &cx.with(&preprocessor_flags))?;
+        }
+        if public_include_directories
+            .iter()
+            .any(SubtargetOrPath::is_path)
         {
             map.serialize_entry(
-                "preprocessor_flags",
-                &PreprocessorFlags {
-                    include_directories,
-                    preprocessor_flags,
-                },
+                "public_include_directories",
+                &cx.with(&IncludeDirectories {
+                    include_directories: public_include_directories,
+                }),
             )?;
         }
         if *undefined_symbols {
-            map.serialize_entry("undefined_symbols", undefined_symbols)?;
+            map.serialize_entry("undefined_symbols", &cx.with(undefined_symbols))?;
         }
-        map.serialize_entry("visibility", visibility)?;
+        map.serialize_entry("visibility", &cx.with(visibility))?;
         if !deps.is_empty() {
-            map.serialize_entry("deps", deps)?;
+            map.serialize_entry("deps", &cx.with(deps))?;
         }
         map.end()
     }
@@ -931,8 +1779,8 @@ struct IncludeDirectories<'a> {
     include_directories: &'a [SubtargetOrPath],
 }
 
-impl<'a> Serialize for IncludeDirectories<'a> {
-    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+impl<'a> Render for IncludeDirectories<'a> {
+    fn render<S: Serializer>(&self, cx: &RenderContext, serializer: S) -> Result<S::Ok, S::Error> {
         let len = self
             .include_directories
             .iter()
@@ -946,7 +1794,7 @@ impl<'a> Serialize for IncludeDirectories<'a> {
                     // serialized under "preprocessor_flags" because "include_directories"
                     // does not support $(location ...) macros.
                 }
-                SubtargetOrPath::Path(path) => array.serialize_element(path)?,
+                SubtargetOrPath::Path(path) => array.serialize_element(&cx.with(path))?,
             }
         }
 
@@ -954,74 +1802,127 @@ impl<'a> Serialize for IncludeDirectories<'a> {
     }
 }
 
-struct PreprocessorFlags<'a> {
-    include_directories: &'a [SubtargetOrPath],
-    preprocessor_flags: &'a [String],
-}
-
-impl<'a> Serialize for PreprocessorFlags<'a> {
-    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
-        let len = self
-            .include_directories
-            .iter()
-            .filter(|dir| dir.is_subtarget())
-            .count()
-            + self.preprocessor_flags.len();
-        let mut array = serializer.serialize_seq(Some(len))?;
-
-        for element in self.include_directories {
-            // Cannot just use `array.serialize_element(format!("-I{element}"))`:
-            // the usual serialization of Subtarget as ":target[relative]" is not
-            // appropriate for a directory. Use "$(location :target)/relative".
-            match element {
-                SubtargetOrPath::Subtarget(subtarget) => {
-                    array.serialize_element(&format!(
-                        "-I$(location :{})/{}",
-                        subtarget.target, subtarget.relative,
-                    ))?;
-                }
-                SubtargetOrPath::Path(_) => {
-                    // serialized under "include_directories"
+/// `-I` flags for the include directories which are subtargets, which go in
+/// preprocessor flags because "include_directories" does not support
+/// $(location ...) macros.
+fn location_include_flags(include_directories: &[SubtargetOrPath]) -> Vec<String> {
+    include_directories
+        .iter()
+        .filter_map(|element| match element {
+            // Cannot just use `format!("-I{element}")`: the usual serialization
+            // of Subtarget as ":target[relative]" is not appropriate for a
+            // directory. Use "$(location :target)/relative".
+            SubtargetOrPath::Subtarget(subtarget) => Some(format!(
+                "-I$(location :{})/{}",
+                subtarget.target, subtarget.relative,
+            )),
+            // serialized under "include_directories"
+            SubtargetOrPath::Path(_) => None,
+        })
+        .collect()
+}
+
+/// A C++ library's flags with their platform-specific additions, when
+/// `platform_representation` is `select`: a `select()` of the whole list on
+/// each platform which adds some, with the common flags as the default.
+/// Otherwise only the common flags, and the additions are written separately
+/// by `platform_regex_flags`.
+fn cxx_platform_flags(
+    cx: &RenderContext,
+    base: Vec<String>,
+    platform: &BTreeMap<PlatformName, Vec<String>>,
+) -> Result<Selectable<String, Vec<String>>, String> {
+    let Some(settings) = cx.platform_settings() else {
+        return Ok(Selectable::Value(base));
+    };
+    let mut branches = BTreeMap::new();
+    for (name, flags) in platform {
+        let setting = settings.get(name).ok_or_else(|| {
+            format!("platform `{name}` has no entry in `[buck] platform_settings`")
+        })?;
+        let mut value = base.clone();
+        value.extend(flags.iter().cloned());
+        branches.insert(setting.clone(), value);
+    }
+    branches.insert("DEFAULT".to_owned(), base);
+    Ok(Selectable::Select(Select(branches)))
+}
+
+/// Platform-specific flags the way native `cxx_library` takes them, as a list
+/// of `(regex, flags)` pairs whose regex is matched against the name of the
+/// C++ platform being built for. Each Reindeer platform's flags get a regex
+/// matching exactly its name.
+fn platform_regex_flags(
+    platform: &BTreeMap<PlatformName, Vec<String>>,
+) -> Vec<(String, &[String])> {
+    platform
+        .iter()
+        .map(|(name, flags)| {
+            let mut regex = "^".to_owned();
+            for ch in name.to_string().chars() {
+                if "\\.+*?()|[]{}^$".contains(ch) {
+                    regex.push('\\');
                 }
+                regex.push(ch);
             }
-        }
-
-        for element in self.preprocessor_flags {
-            array.serialize_element(element)?;
-        }
-
-        array.end()
-    }
+            regex.push('$');
+            (regex, flags.as_slice())
+        })
+        .collect()
 }
 
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct PrebuiltCxxLibrary {
     pub common: Common,
     pub static_lib: SubtargetOrPath,
+    /// Preprocessor flags for the library's dependents, such as defines its
+    /// headers expect
+    pub exported_preprocessor_flags: Vec<String>,
+    /// Directories of headers shipped with the library, for its dependents
+    pub header_dirs: Vec<SubtargetOrPath>,
 }
 
-impl Serialize for PrebuiltCxxLibrary {
-    fn serialize<S: Serializer>(&self, ser: S) -> Result<S::Ok, S::Error> {
+impl Render for PrebuiltCxxLibrary {
+    fn render<S: Serializer>(&self, cx: &RenderContext, ser: S) -> Result<S::Ok, S::Error> {
         let Self {
             common:
                 Common {
                     name,
                     visibility,
                     licenses,
+                    license_expression,
                     compatible_with,
+                    target_compatible_with,
                 },
             static_lib,
+            exported_preprocessor_flags,
+            header_dirs,
         } = self;
         let mut map = ser.serialize_map(None)?;
-        map.serialize_entry("name", name)?;
+        map.serialize_entry("name", &cx.with(name))?;
         if !compatible_with.is_empty() {
-            map.serialize_entry("compatible_with", compatible_with)?;
+            map.serialize_entry("compatible_with", &cx.with(compatible_with))?;
+        }
+        if !target_compatible_with.is_empty() {
+            map.serialize_entry("target_compatible_with", &cx.with(target_compatible_with))?;
+        }
+        if !exported_preprocessor_flags.is_empty() {
+            map.serialize_entry(
+                "exported_preprocessor_flags",
+                &cx.with(exported_preprocessor_flags),
+            )?;
+        }
+        if !header_dirs.is_empty() {
+            map.serialize_entry("header_dirs", &cx.with(header_dirs))?;
+        }
+        if let Some(license_expression) = license_expression {
+            map.serialize_entry("license_expression", &cx.with(license_expression))?;
         }
         if !licenses.is_empty() {
-            map.serialize_entry("licenses", licenses)?;
+            map.serialize_entry("licenses", &cx.with(licenses))?;
         }
-        map.serialize_entry("static_lib", static_lib)?;
-        map.serialize_entry("visibility", visibility)?;
+        map.serialize_entry("static_lib", &cx.with(static_lib))?;
+        map.serialize_entry("visibility", &cx.with(visibility))?;
         map.end()
     }
 }
@@ -1036,6 +1937,7 @@ pub enum Rule {
     Library(RustLibrary),
     BuildscriptBinary(RustBinary),
     BuildscriptGenrule(BuildscriptGenrule),
+    OutDirFile(OutDirFile),
     CxxLibrary(CxxLibrary),
     PrebuiltCxxLibrary(PrebuiltCxxLibrary),
     RootPackage(RustLibrary),
@@ -1078,6 +1980,7 @@ fn rule_sort_key(rule: &Rule) -> impl Ord + '_ {
         | Rule::Library(_)
         | Rule::BuildscriptBinary(_)
         | Rule::BuildscriptGenrule(_)
+        | Rule::OutDirFile(_)
         | Rule::CxxLibrary(_)
         | Rule::PrebuiltCxxLibrary(_) => RuleSortKey::Other(rule.get_name(), 2),
         Rule::RootPackage(_) => RuleSortKey::RootPackage,
@@ -1091,6 +1994,36 @@ impl Ord for Rule {
 }
 
 impl Rule {
+    /// Move all platform-specific attributes of Rust rules into their common
+    /// attributes. Only valid when generating for a single platform.
+    pub fn collapse_platforms(self) -> Self {
+        fn collapse(common: &mut RustCommon) {
+            for (_name, platform) in std::mem::take(&mut common.platform) {
+                common.base.absorb(platform);
+            }
+        }
+
+        match self {
+            Rule::Binary(mut bin) => {
+                collapse(&mut bin.common);
+                Rule::Binary(bin)
+            }
+            Rule::BuildscriptBinary(mut bin) => {
+                collapse(&mut bin.common);
+                Rule::BuildscriptBinary(bin)
+            }
+            Rule::Library(mut lib) => {
+                collapse(&mut lib.common);
+                Rule::Library(lib)
+            }
+            Rule::RootPackage(mut lib) => {
+                collapse(&mut lib.common);
+                Rule::RootPackage(lib)
+            }
+            rule => rule,
+        }
+    }
+
     pub fn get_name(&self) -> &Name {
         match self {
             Rule::Alias(Alias { name, .. })
@@ -1122,6 +2055,7 @@ impl Rule {
                 ..
             })
             | Rule::BuildscriptGenrule(BuildscriptGenrule { name, .. })
+            | Rule::OutDirFile(OutDirFile { name, .. })
             | Rule::CxxLibrary(CxxLibrary {
                 common: Common { name, .. },
                 ..
@@ -1141,45 +2075,166 @@ impl Rule {
         }
     }
 
-    pub fn render(&self, config: &BuckConfig, out: &mut impl Write) -> anyhow::Result<()> {
-        use serde_starlark::Serializer;
-        let serialized = match self {
-            Rule::Alias(alias) => FunctionCall::new(&config.alias, alias).serialize(Serializer),
-            Rule::Filegroup(filegroup) => {
-                FunctionCall::new(&config.filegroup, filegroup).serialize(Serializer)
+    /// Targets of the other rules this one refers to, including for sources
+    /// and `$(location)` macros, without their subtargets.
+    pub fn rule_refs(&self) -> BTreeSet<String> {
+        fn common_refs(common: &Common, refs: &mut BTreeSet<String>) {
+            let Common {
+                compatible_with,
+                target_compatible_with,
+                ..
+            } = common;
+            for rule_ref in compatible_with.iter().chain(target_compatible_with) {
+                refs.insert(rule_ref.target.clone());
             }
-            Rule::HttpArchive(http_archive) => {
-                FunctionCall::new(&config.http_archive, http_archive).serialize(Serializer)
+        }
+        fn rust_refs(rust: &RustCommon, refs: &mut BTreeSet<String>) {
+            common_refs(&rust.common, refs);
+            for attrs in iter::once(&rust.base).chain(rust.platform.values()) {
+                for deps in attrs.deps.values() {
+                    refs.extend(deps.iter().map(|dep| dep.target.clone()));
+                }
+                for named_deps in attrs.named_deps.values() {
+                    refs.extend(named_deps.values().map(|dep| dep.target.clone()));
+                }
+                refs.extend(attrs.doc_deps.iter().map(|dep| dep.target.clone()));
+                for src in attrs.mapped_srcs.keys() {
+                    match src {
+                        MappedSrc::Source(SubtargetOrPath::Subtarget(subtarget)) => {
+                            refs.insert(format!(":{}", subtarget.target));
+                        }
+                        MappedSrc::OutDirFile(name) => {
+                            refs.insert(format!(":{}", name));
+                        }
+                        MappedSrc::Source(SubtargetOrPath::Path(_)) => {}
+                    }
+                }
+                for env in attrs.env.values() {
+                    for value in env.values() {
+                        if let StringOrPath::String(value) = value {
+                            location_refs(value, refs);
+                        }
+                    }
+                }
+            }
+        }
+        /// Targets of the `$(location)` macros in an attribute value, except
+        /// escaped ones.
+        fn location_refs(value: &str, refs: &mut BTreeSet<String>) {
+            const LOCATION: &str = "$(location ";
+            for (i, _) in value.match_indices(LOCATION) {
+                if value[..i].ends_with('\\') {
+                    continue;
+                }
+                let rest = &value[i + LOCATION.len()..];
+                if let Some(end) = rest.find(')') {
+                    refs.insert(rest[..end].trim().to_owned());
+                }
             }
-            Rule::GitFetch(git_fetch) => {
-                FunctionCall::new(&config.git_fetch, git_fetch).serialize(Serializer)
+        }
+
+        let mut refs = BTreeSet::new();
+        match self {
+            Rule::Alias(Alias {
+                actual,
+                fallback_actual,
+                ..
+            }) => {
+                refs.insert(format!(":{}", actual));
+                if let Some(AliasFallback { constraint, actual }) = fallback_actual {
+                    refs.insert(constraint.target.clone());
+                    refs.insert(actual.target.clone());
+                }
             }
-            Rule::Binary(bin) => FunctionCall::new(&config.rust_binary, bin).serialize(Serializer),
+            Rule::Binary(bin) | Rule::BuildscriptBinary(bin) => rust_refs(&bin.common, &mut refs),
             Rule::Library(lib) | Rule::RootPackage(lib) => {
-                FunctionCall::new(&config.rust_library, lib).serialize(Serializer)
+                rust_refs(&lib.common, &mut refs);
+                refs.extend(lib.crate_dynamic.iter().map(|dep| dep.target.clone()));
             }
-            Rule::BuildscriptBinary(bin) => {
-                let buildscript_binary = config
-                    .buildscript_binary
-                    .as_ref()
-                    .unwrap_or(&config.rust_binary);
-                FunctionCall::new(buildscript_binary, bin).serialize(Serializer)
+            Rule::BuildscriptGenrule(BuildscriptGenrule {
+                buildscript_rule,
+                env,
+                ..
+            }) => {
+                refs.insert(format!(":{}", buildscript_rule));
+                for value in env.values() {
+                    location_refs(value, &mut refs);
+                }
             }
-            Rule::BuildscriptGenrule(lib) => {
-                FunctionCall::new(&config.buildscript_genrule, lib).serialize(Serializer)
+            Rule::OutDirFile(OutDirFile {
+                buildscript_run, ..
+            }) => {
+                refs.insert(format!(":{}", buildscript_run));
             }
-            Rule::CxxLibrary(lib) => {
-                FunctionCall::new(&config.cxx_library, lib).serialize(Serializer)
+            Rule::CxxLibrary(CxxLibrary { common, deps, .. }) => {
+                common_refs(common, &mut refs);
+                refs.extend(deps.iter().map(|dep| dep.target.clone()));
             }
-            Rule::PrebuiltCxxLibrary(lib) => {
-                FunctionCall::new(&config.prebuilt_cxx_library, lib).serialize(Serializer)
+            Rule::PrebuiltCxxLibrary(PrebuiltCxxLibrary { common, .. }) => {
+                common_refs(common, &mut refs);
             }
-        }?;
+            Rule::Filegroup(_) | Rule::HttpArchive(_) | Rule::GitFetch(_) => {}
+        }
+        refs.into_iter()
+            .map(|target| match target.split_once('[') {
+                Some((target, _subtarget)) => target.to_owned(),
+                None => target,
+            })
+            .collect()
+    }
+
+    /// The macro the BUCK file calls to define the rule.
+    fn rule_type<'a>(&self, config: &'a BuckConfig) -> &'a str {
+        match self {
+            Rule::Alias(_) => &config.alias,
+            Rule::Filegroup(_) => &config.filegroup,
+            Rule::HttpArchive(_) => &config.http_archive,
+            Rule::GitFetch(_) => &config.git_fetch,
+            Rule::Binary(_) => &config.rust_binary,
+            Rule::Library(_) | Rule::RootPackage(_) => &config.rust_library,
+            Rule::BuildscriptBinary(_) => config
+                .buildscript_binary
+                .as_deref()
+                .unwrap_or(&config.rust_binary),
+            Rule::BuildscriptGenrule(_) => &config.buildscript_genrule,
+            Rule::OutDirFile(_) => &config.genrule,
+            Rule::CxxLibrary(_) => &config.cxx_library,
+            Rule::PrebuiltCxxLibrary(_) => &config.prebuilt_cxx_library,
+        }
+    }
+
+    pub fn render(&self, config: &BuckConfig, out: &mut impl Write) -> anyhow::Result<()> {
+        self.render_in(&RenderContext::new(config), out)
+    }
+
+    fn render_in(&self, cx: &RenderContext, out: &mut impl Write) -> anyhow::Result<()> {
+        use serde_starlark::Serializer;
+        let serialized =
+            FunctionCall::new(self.rule_type(cx.config), cx.with(self)).serialize(Serializer)?;
         out.write_all(serialized.as_bytes())?;
         Ok(())
     }
 }
 
+/// A rule renders as its attributes, the arguments of the macro which defines
+/// it.
+impl Render for Rule {
+    fn render<S: Serializer>(&self, cx: &RenderContext, ser: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Rule::Alias(alias) => alias.render(cx, ser),
+            Rule::Filegroup(filegroup) => filegroup.render(cx, ser),
+            Rule::HttpArchive(http_archive) => http_archive.render(cx, ser),
+            Rule::GitFetch(git_fetch) => git_fetch.render(cx, ser),
+            Rule::Binary(bin) | Rule::BuildscriptBinary(bin) => bin.render(cx, ser),
+            Rule::Library(lib) | Rule::RootPackage(lib) => lib.render(cx, ser),
+            Rule::BuildscriptGenrule(rule) => rule.render(cx, ser),
+            Rule::OutDirFile(rule) => rule.render(cx, ser),
+            Rule::CxxLibrary(lib) => lib.render(cx, ser),
+            Rule::PrebuiltCxxLibrary(lib) => lib.render(cx, ser),
+        }
+    }
+}
+
 /// Buildifier's preferred sort order for sortable string arrays, regardless of
 /// whether they are arrays of filepaths or labels.
 ///
@@ -1201,27 +2256,1147 @@ fn buildifier_cmp(a: &str, b: &str) -> Ordering {
     })
 }
 
+/// The configured `buckfile_imports`, or else whichever prelude imports the
+/// rules actually use.
+fn buckfile_imports<'a>(config: &'a BuckConfig, rules: &[&Rule]) -> Cow<'a, str> {
+    if !config.buckfile_imports.is_default {
+        return Cow::Borrowed(&config.buckfile_imports);
+    }
+
+    let mut buckfile_imports = String::new();
+    if config.prelude_imports.buildscript_run
+        && rules
+            .iter()
+            .any(|rule| matches!(rule, Rule::BuildscriptGenrule(_)))
+    {
+        buckfile_imports
+            .push_str("load(\"@prelude//rust:cargo_buildscript.bzl\", \"buildscript_run\")\n");
+    }
+    if config.prelude_imports.cargo
+        && rules.iter().any(|rule| match rule {
+            Rule::Binary(_) | Rule::Library(_) | Rule::RootPackage(_) => true,
+            Rule::BuildscriptBinary(_) => config.buildscript_binary.is_none(),
+            _ => false,
+        })
+    {
+        buckfile_imports.push_str("load(\"@prelude//rust:cargo_package.bzl\", \"cargo\")\n");
+    }
+    Cow::Owned(buckfile_imports)
+}
+
 pub fn write_buckfile<'a>(
     config: &BuckConfig,
     rules: impl Iterator<Item = &'a Rule>,
     out: &mut impl Write,
 ) -> anyhow::Result<()> {
+    write_buckfile_in(&RenderContext::new(config), rules, out)
+}
+
+fn write_buckfile_in<'a>(
+    cx: &RenderContext,
+    rules: impl Iterator<Item = &'a Rule>,
+    out: &mut impl Write,
+) -> anyhow::Result<()> {
+    let config = cx.config;
     out.write_all(config.generated_file_header.as_bytes())?;
     if !config.generated_file_header.is_empty() {
         out.write_all(b"\n")?;
     }
 
-    out.write_all(config.buckfile_imports.as_bytes())?;
-    if !config.buckfile_imports.is_empty() {
+    let rules: Vec<&Rule> = rules.collect();
+    let buckfile_imports = buckfile_imports(config, &rules);
+    out.write_all(buckfile_imports.as_bytes())?;
+    if !buckfile_imports.is_empty() {
         out.write_all(b"\n")?;
     }
 
-    for (i, rule) in rules.enumerate() {
+    for (i, rule) in rules.into_iter().enumerate() {
         if i > 0 {
             out.write_all(b"\n")?;
         }
-        rule.render(config, out)?;
+        rule.render_in(cx, out)?;
+    }
+
+    Ok(())
+}
+
+/// Write `rules` as JSON, for BXL scripts which define the targets
+/// themselves: a list of `{"rule_type": ..., "attrs": {...}}`, where
+/// `rule_type` is the macro the BUCK file would call and `attrs` are the
+/// arguments it would pass, with each `select()` as `{"select": {...}}` and
+/// each platform's attributes in `platform` as a plain object.
+pub fn write_rules_json<'a>(
+    config: &BuckConfig,
+    rules: impl Iterator<Item = &'a Rule>,
+    out: &mut impl Write,
+) -> anyhow::Result<()> {
+    #[derive(Serialize)]
+    struct Entry<'a> {
+        rule_type: &'a str,
+        attrs: Rendered<'a, Rule>,
     }
 
+    let cx = RenderContext {
+        json: true,
+        ..RenderContext::new(config)
+    };
+    let entries: Vec<Entry> = rules
+        .map(|rule| Entry {
+            rule_type: rule.rule_type(config),
+            attrs: cx.with(rule),
+        })
+        .collect();
+    serde_json::to_writer_pretty(&mut *out, &entries)?;
+    out.write_all(b"\n")?;
     Ok(())
 }
+
+/// Render `rules` into a BUCK file for each vendored package directory they
+/// belong in, keyed by the directory relative to the third-party dir, which is
+/// `root` from its Buck cell's root. Rules not from a vendored package stay in
+/// the third-party dir's own BUCK file, under an empty path.
+pub fn write_split_buckfiles<'a>(
+    config: &BuckConfig,
+    root: &Path,
+    vendordir: &Path,
+    rules: impl Iterator<Item = &'a Rule>,
+) -> anyhow::Result<BTreeMap<PathBuf, Vec<u8>>> {
+    let rules: Vec<&Rule> = rules.collect();
+
+    // Vendored package dirs, like `foo-1.0.0`, which are also the prefix of
+    // the names of the package's rules.
+    let package_dirs: BTreeSet<&str> = rules
+        .iter()
+        .filter_map(|rule| {
+            let crate_root = match rule {
+                Rule::Binary(RustBinary { common })
+                | Rule::BuildscriptBinary(RustBinary { common })
+                | Rule::Library(RustLibrary { common, .. })
+                | Rule::RootPackage(RustLibrary { common, .. }) => &common.crate_root.0,
+                _ => return None,
+            };
+            crate_root
+                .strip_prefix(vendordir)
+                .ok()?
+                .iter()
+                .next()?
+                .to_str()
+        })
+        .collect();
+
+    let mut rule_dirs = HashMap::new();
+    let mut packages: BTreeMap<PathBuf, Vec<&Rule>> = BTreeMap::new();
+    for rule in rules {
+        let name = &rule.get_name().0;
+        let dir = package_dirs
+            .iter()
+            .filter(|dir| {
+                name.strip_prefix(**dir)
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with('-'))
+            })
+            .max_by_key(|dir| dir.len())
+            .map(|dir| vendordir.join(dir))
+            .unwrap_or_default();
+        rule_dirs.insert(name.clone(), dir.clone());
+        packages.entry(dir).or_default().push(rule);
+    }
+
+    let mut buckfiles = BTreeMap::new();
+    for (dir, rules) in packages {
+        let cx = RenderContext {
+            package: Some(RenderPackage {
+                dir: dir.clone(),
+                root,
+                rule_dirs: &rule_dirs,
+            }),
+            ..RenderContext::new(config)
+        };
+        let mut out = Vec::new();
+        write_buckfile_in(&cx, rules.into_iter(), &mut out)
+            .with_context(|| format!("rendering rules for {}", dir.display()))?;
+        buckfiles.insert(dir, out);
+    }
+    Ok(buckfiles)
+}
+
+/// Name of the file written by `metadata_aggregate`, next to the BUCK file.
+pub const METADATA_FILE_NAME: &str = "METADATA.bzl";
+
+/// Write `metadata` as a single `METADATA` dict, keyed by "name-version" and
+/// ordered by crate name and then version.
+pub fn write_metadata_file<'a>(
+    config: &BuckConfig,
+    metadata: impl IntoIterator<Item = &'a CrateMetadata>,
+    out: &mut impl Write,
+) -> anyhow::Result<()> {
+    struct Aggregate<'a>(Vec<&'a CrateMetadata>);
+
+    impl Serialize for Aggregate<'_> {
+        fn serialize<S: Serializer>(&self, ser: S) -> Result<S::Ok, S::Error> {
+            let mut map = ser.serialize_map(Some(self.0.len()))?;
+            for metadata in &self.0 {
+                let key = format!("{}-{}", metadata.name, metadata.version);
+                map.serialize_entry(&key, metadata)?;
+            }
+            map.end()
+        }
+    }
+
+    let mut metadata: Vec<&CrateMetadata> = metadata.into_iter().collect();
+    metadata.sort_by(|a, b| {
+        let version = |metadata: &CrateMetadata| Version::parse(&metadata.version).ok();
+        a.name
+            .cmp(&b.name)
+            .then_with(|| version(a).cmp(&version(b)))
+            .then_with(|| a.version.cmp(&b.version))
+    });
+
+    out.write_all(config.generated_file_header.as_bytes())?;
+    if !config.generated_file_header.is_empty() {
+        out.write_all(b"\n")?;
+    }
+    let serialized = serde_starlark::Assignment::new("METADATA", Aggregate(metadata))
+        .serialize(serde_starlark::Serializer)?;
+    out.write_all(serialized.as_bytes())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+    use std::collections::BTreeSet;
+    use std::path::Path;
+    use std::path::PathBuf;
+
+    use indoc::indoc;
+
+    use super::write_buckfile;
+    use super::write_metadata_file;
+    use super::write_rules_json;
+    use super::write_split_buckfiles;
+    use super::Alias;
+    use super::AliasFallback;
+    use super::BuckPath;
+    use super::BuildscriptGenrule;
+    use super::Common;
+    use super::CrateMetadata;
+    use super::CxxLibrary;
+    use super::MappedSrc;
+    use super::Name;
+    use super::PlatformRustCommon;
+    use super::Rule;
+    use super::RuleRef;
+    use super::RustBinary;
+    use super::RustCommon;
+    use super::RustLibrary;
+    use super::Selectable;
+    use super::StringOrPath;
+    use super::Subtarget;
+    use super::SubtargetOrPath;
+    use super::Visibility;
+    use super::METADATA_SCHEMA_VERSION;
+    use crate::cargo::Edition;
+    use crate::collection::SelectSet;
+    use crate::collection::SetOrMap;
+    use crate::config::read_config;
+    use crate::config::BuckConfig;
+    use crate::config::PlatformRepresentation;
+
+    #[test]
+    fn metadata_round_trip() {
+        let metadata = CrateMetadata {
+            schema_version: METADATA_SCHEMA_VERSION,
+            name: "foo".to_owned(),
+            version: "1.2.3".to_owned(),
+            description: Some("Foo".to_owned()),
+            repository: None,
+            authors: vec!["Someone".to_owned()],
+            features: ["default".to_owned(), "std".to_owned()].into(),
+            source: Some("registry".to_owned()),
+            checksum: Some("abcd".to_owned()),
+        };
+        let json = serde_json::to_string(&metadata).unwrap();
+        assert_eq!(
+            serde_json::from_str::<CrateMetadata>(&json).unwrap(),
+            metadata,
+        );
+    }
+
+    #[test]
+    fn metadata_attr_namespaced() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let config = read_config(tempdir.path()).unwrap();
+        let library = Rule::Library(RustLibrary {
+            common: RustCommon {
+                common: Common {
+                    name: Name("foo-1.2.3".to_owned()),
+                    visibility: Visibility::Private,
+                    licenses: Default::default(),
+                    license_expression: None,
+                    compatible_with: vec![],
+                    target_compatible_with: vec![],
+                },
+                krate: "foo".to_owned(),
+                crate_root: BuckPath(PathBuf::from("vendor/foo-1.2.3/src/lib.rs")),
+                edition: Edition::Rust2021,
+                base: Default::default(),
+                platform: Default::default(),
+            },
+            proc_macro: false,
+            dlopen_enable: false,
+            python_ext: None,
+            linkable_alias: None,
+            crate_dynamic: None,
+            metadata: Some(CrateMetadata {
+                schema_version: METADATA_SCHEMA_VERSION,
+                name: "foo".to_owned(),
+                version: "1.2.3".to_owned(),
+                description: None,
+                repository: None,
+                authors: vec![],
+                features: ["std".to_owned()].into(),
+                source: Some("registry".to_owned()),
+                checksum: None,
+            }),
+        });
+
+        let mut out = Vec::new();
+        library.render(&config.buck, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            indoc! {r#"
+                cargo.rust_library(
+                    name = "foo-1.2.3",
+                    crate = "foo",
+                    crate_root = "vendor/foo-1.2.3/src/lib.rs",
+                    edition = "2021",
+                    metadata = {
+                        "reindeer.schema_version": 1,
+                        "reindeer.name": "foo",
+                        "reindeer.version": "1.2.3",
+                        "reindeer.features": ["std"],
+                        "reindeer.source": "registry",
+                    },
+                    visibility = [],
+                )
+            "#},
+        );
+    }
+
+    #[test]
+    fn metadata_file_sorted_by_crate() {
+        let metadata = |name: &str, version: &str| CrateMetadata {
+            schema_version: METADATA_SCHEMA_VERSION,
+            name: name.to_owned(),
+            version: version.to_owned(),
+            description: None,
+            repository: None,
+            authors: vec![],
+            features: Default::default(),
+            source: Some("registry".to_owned()),
+            checksum: None,
+        };
+        let crates = [
+            metadata("serde", "1.0.0"),
+            metadata("libc", "0.2.10"),
+            metadata("libc", "0.2.9"),
+        ];
+
+        let mut out = Vec::new();
+        write_metadata_file(&BuckConfig::default(), &crates, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            indoc! {r#"
+                # @generated by `reindeer buckify`
+
+                METADATA = {
+                    "libc-0.2.9": {
+                        "schema_version": 1,
+                        "name": "libc",
+                        "version": "0.2.9",
+                        "source": "registry",
+                    },
+                    "libc-0.2.10": {
+                        "schema_version": 1,
+                        "name": "libc",
+                        "version": "0.2.10",
+                        "source": "registry",
+                    },
+                    "serde-1.0.0": {
+                        "schema_version": 1,
+                        "name": "serde",
+                        "version": "1.0.0",
+                        "source": "registry",
+                    },
+                }
+            "#},
+        );
+    }
+
+    #[test]
+    fn prelude_imports_only_when_used() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let config = read_config(tempdir.path()).unwrap();
+        let alias = Rule::Alias(Alias {
+            name: Name("foo".to_owned()),
+            actual: Name("foo-1.0.0".to_owned()),
+            fallback_actual: None,
+            visibility: Visibility::Public,
+        });
+        let genrule = Rule::BuildscriptGenrule(BuildscriptGenrule {
+            name: Name("foo-1.0.0-build-script-run".to_owned()),
+            buildscript_rule: Name("foo-1.0.0-build-script-build".to_owned()),
+            package_name: "foo".to_owned(),
+            version: "1.0.0".parse().unwrap(),
+            features: Default::default(),
+            env: Default::default(),
+            path_env: Default::default(),
+            local_only: false,
+        });
+        let header = |rules: &[&Rule]| {
+            let mut out = Vec::new();
+            write_buckfile(&config.buck, rules.iter().copied(), &mut out).unwrap();
+            let out = String::from_utf8(out).unwrap();
+            out.lines()
+                .take_while(|line| {
+                    !line.starts_with("alias") && !line.starts_with("buildscript_run")
+                })
+                .collect::<Vec<_>>()
+                .join("\n")
+        };
+
+        assert_eq!(header(&[&alias]), "# @generated by `reindeer buckify`\n");
+        assert_eq!(
+            header(&[&alias, &genrule]),
+            indoc! {r#"
+                # @generated by `reindeer buckify`
+
+                load("@prelude//rust:cargo_buildscript.bzl", "buildscript_run")
+            "#},
+        );
+    }
+
+    #[test]
+    fn buildscript_binary_defaults_to_rust_binary() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let mut config = read_config(tempdir.path()).unwrap();
+        let buildscript = Rule::BuildscriptBinary(RustBinary {
+            common: RustCommon {
+                common: Common {
+                    name: Name("foo-1.0.0-build-script-build".to_owned()),
+                    visibility: Visibility::Private,
+                    licenses: Default::default(),
+                    license_expression: None,
+                    compatible_with: vec![],
+                    target_compatible_with: vec![],
+                },
+                krate: "build_script_build".to_owned(),
+                crate_root: BuckPath(PathBuf::from("foo-1.0.0.crate/build.rs")),
+                edition: Edition::Rust2021,
+                base: PlatformRustCommon::default(),
+                platform: BTreeMap::new(),
+            },
+        });
+        let render = |config: &BuckConfig| {
+            let mut out = Vec::new();
+            write_buckfile(config, [&buildscript].into_iter(), &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        assert!(config.buck.buildscript_binary.is_none());
+        let out = render(&config.buck);
+        assert!(
+            out.contains("load(\"@prelude//rust:cargo_package.bzl\", \"cargo\")\n"),
+            "{out}",
+        );
+        assert!(out.contains("\ncargo.rust_binary(\n"), "{out}");
+
+        config.buck.buildscript_binary = Some("third_party_buildscript".to_owned());
+        let out = render(&config.buck);
+        assert!(!out.contains("cargo_package.bzl"), "{out}");
+        assert!(out.contains("\nthird_party_buildscript(\n"), "{out}");
+    }
+
+    #[test]
+    fn split_per_package() {
+        let library = |name: &str, deps: &[&str]| {
+            let mut common = RustCommon {
+                common: Common {
+                    name: Name(name.to_owned()),
+                    visibility: Visibility::Private,
+                    licenses: Default::default(),
+                    license_expression: None,
+                    compatible_with: vec![],
+                    target_compatible_with: vec![],
+                },
+                krate: name.split('-').next().unwrap().to_owned(),
+                crate_root: BuckPath(PathBuf::from(format!("vendor/{name}/src/lib.rs"))),
+                edition: Edition::Rust2021,
+                base: Default::default(),
+                platform: Default::default(),
+            };
+            common
+                .base
+                .deps
+                .unwrap_mut()
+                .extend(deps.iter().map(|dep| RuleRef::new(format!(":{dep}"))));
+            Rule::Library(RustLibrary {
+                common,
+                proc_macro: false,
+                dlopen_enable: false,
+                python_ext: None,
+                linkable_alias: None,
+                crate_dynamic: None,
+                metadata: None,
+            })
+        };
+        let rules = [
+            Rule::Alias(Alias {
+                name: Name("foo".to_owned()),
+                actual: Name("foo-1.0.0".to_owned()),
+                fallback_actual: None,
+                visibility: Visibility::Public,
+            }),
+            library("bar-2.0.0", &[]),
+            library("foo-1.0.0", &["bar-2.0.0"]),
+        ];
+
+        let buckfiles = write_split_buckfiles(
+            &Default::default(),
+            Path::new("third-party"),
+            Path::new("vendor"),
+            rules.iter(),
+        )
+        .unwrap();
+        let buckfile = |dir: &str| String::from_utf8(buckfiles[Path::new(dir)].clone()).unwrap();
+
+        assert_eq!(buckfiles.len(), 3);
+        assert!(buckfile("").contains(r#"actual = "//third-party/vendor/foo-1.0.0:foo-1.0.0","#));
+        let foo = buckfile("vendor/foo-1.0.0");
+        assert!(foo.contains(r#"crate_root = "src/lib.rs","#));
+        assert!(foo.contains(r#"visibility = ["//third-party/..."],"#));
+        assert!(foo.contains(r#"deps = ["//third-party/vendor/bar-2.0.0:bar-2.0.0"],"#));
+    }
+
+    #[test]
+    fn path_env_joined_per_platform() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let config = read_config(tempdir.path()).unwrap();
+        let mut genrule = BuildscriptGenrule {
+            name: Name("foo-1.0.0-build-script-run".to_owned()),
+            buildscript_rule: Name("foo-1.0.0-build-script-build".to_owned()),
+            package_name: "foo".to_owned(),
+            version: "1.0.0".parse().unwrap(),
+            features: Default::default(),
+            env: BTreeMap::from([("FOO".to_owned(), "foo".to_owned())]),
+            path_env: BTreeMap::from([("INCLUDE".to_owned(), vec!["include".to_owned()])]),
+            local_only: false,
+        };
+        let render = |genrule: &BuildscriptGenrule| {
+            let mut out = Vec::new();
+            Rule::BuildscriptGenrule(genrule.clone())
+                .render(&config.buck, &mut out)
+                .unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        assert_eq!(
+            render(&genrule),
+            indoc! {r#"
+                buildscript_run(
+                    name = "foo-1.0.0-build-script-run",
+                    package_name = "foo",
+                    buildscript_rule = ":foo-1.0.0-build-script-build",
+                    env = {
+                        "FOO": "foo",
+                        "INCLUDE": "include",
+                    },
+                    version = "1.0.0",
+                )
+            "#},
+        );
+
+        genrule.path_env.insert(
+            "PATH".to_owned(),
+            vec!["bin".to_owned(), "$(location :tool)".to_owned()],
+        );
+        assert_eq!(
+            render(&genrule),
+            indoc! {r#"
+                buildscript_run(
+                    name = "foo-1.0.0-build-script-run",
+                    package_name = "foo",
+                    buildscript_rule = ":foo-1.0.0-build-script-build",
+                    env = select({
+                        "DEFAULT": {
+                            "FOO": "foo",
+                            "INCLUDE": "include",
+                            "PATH": "bin:$(location :tool)",
+                        },
+                        "prelude//os:windows": {
+                            "FOO": "foo",
+                            "INCLUDE": "include",
+                            "PATH": "bin;$(location :tool)",
+                        },
+                    }),
+                    version = "1.0.0",
+                )
+            "#},
+        );
+    }
+
+    #[test]
+    fn alias_with_fallback_actual() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let config = read_config(tempdir.path()).unwrap();
+        let alias = Rule::Alias(Alias {
+            name: Name("winapi".to_owned()),
+            actual: Name("winapi-0.3.9".to_owned()),
+            fallback_actual: Some(AliasFallback {
+                constraint: RuleRef::new("prelude//os:windows".to_owned()),
+                actual: RuleRef::new("//third-party:empty".to_owned()),
+            }),
+            visibility: Visibility::Public,
+        });
+        let mut out = Vec::new();
+        alias.render(&config.buck, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            indoc! {r#"
+                alias(
+                    name = "winapi",
+                    actual = select({
+                        "DEFAULT": "//third-party:empty",
+                        "prelude//os:windows": ":winapi-0.3.9",
+                    }),
+                    visibility = ["PUBLIC"],
+                )
+            "#},
+        );
+        assert_eq!(
+            alias.rule_refs().into_iter().collect::<Vec<_>>(),
+            [
+                "//third-party:empty",
+                ":winapi-0.3.9",
+                "prelude//os:windows"
+            ],
+        );
+    }
+
+    #[test]
+    fn platform_attrs_as_selects() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let mut config = read_config(tempdir.path()).unwrap();
+        let linux = config
+            .platform
+            .keys()
+            .find(|name| name.to_string() == "linux-x86_64")
+            .unwrap()
+            .clone();
+
+        let mut common = RustCommon {
+            common: Common {
+                name: Name("foo-1.0.0".to_owned()),
+                visibility: Visibility::Private,
+                licenses: Default::default(),
+                license_expression: None,
+                compatible_with: vec![],
+                target_compatible_with: vec![],
+            },
+            krate: "foo".to_owned(),
+            crate_root: BuckPath(PathBuf::from("vendor/foo-1.0.0/src/lib.rs")),
+            edition: Edition::Rust2021,
+            base: Default::default(),
+            platform: Default::default(),
+        };
+        common
+            .base
+            .deps
+            .unwrap_mut()
+            .insert(RuleRef::new(":bar-1.0.0".to_owned()));
+        let linux_attrs = common.platform.entry(linux.clone()).or_default();
+        linux_attrs
+            .deps
+            .unwrap_mut()
+            .insert(RuleRef::new(":libc-0.2.0".to_owned()));
+        linux_attrs.features.unwrap_mut().insert("epoll".to_owned());
+        let library = Rule::Library(RustLibrary {
+            common,
+            proc_macro: false,
+            dlopen_enable: false,
+            python_ext: None,
+            linkable_alias: None,
+            crate_dynamic: None,
+            metadata: None,
+        });
+
+        config.buck.platform_representation = PlatformRepresentation::Select;
+        config
+            .buck
+            .platform_settings
+            .insert(linux, "//platforms:linux-x86_64".to_owned());
+        let mut out = Vec::new();
+        library.render(&config.buck, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            indoc! {r#"
+                cargo.rust_library(
+                    name = "foo-1.0.0",
+                    crate = "foo",
+                    crate_root = "vendor/foo-1.0.0/src/lib.rs",
+                    edition = "2021",
+                    features = select({
+                        "//platforms:linux-x86_64": ["epoll"],
+                        "DEFAULT": [],
+                    }),
+                    visibility = [],
+                    deps = select({
+                        "//platforms:linux-x86_64": [
+                            ":bar-1.0.0",
+                            ":libc-0.2.0",
+                        ],
+                        "DEFAULT": [":bar-1.0.0"],
+                    }),
+                )
+            "#},
+        );
+
+        config.buck.platform_settings.clear();
+        let err = library.render(&config.buck, &mut Vec::new()).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("platform `linux-x86_64` has no entry in `[buck] platform_settings`"),
+            "{err}",
+        );
+    }
+
+    #[test]
+    fn cxx_library_platform_flags() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let mut config = read_config(tempdir.path()).unwrap();
+        let linux = config
+            .platform
+            .keys()
+            .find(|name| name.to_string() == "linux-x86_64")
+            .unwrap()
+            .clone();
+
+        let library = Rule::CxxLibrary(CxxLibrary {
+            common: Common {
+                name: Name("foo-1.0.0-native".to_owned()),
+                visibility: Visibility::Private,
+                licenses: Default::default(),
+                license_expression: None,
+                compatible_with: vec![],
+                target_compatible_with: vec![],
+            },
+            srcs: BTreeSet::new(),
+            headers: BTreeSet::new(),
+            exported_headers: SetOrMap::Set(BTreeSet::new()),
+            compiler_flags: vec!["-O2".to_owned()],
+            platform_compiler_flags: BTreeMap::from([(linux.clone(), vec!["-pthread".to_owned()])]),
+            preprocessor_flags: vec![],
+            platform_preprocessor_flags: BTreeMap::from([(
+                linux.clone(),
+                vec!["-DFOO_LINUX".to_owned()],
+            )]),
+            header_namespace: None,
+            include_directories: vec![],
+            public_include_directories: vec![],
+            deps: BTreeSet::new(),
+            preferred_linkage: None,
+            undefined_symbols: false,
+        });
+
+        let mut out = Vec::new();
+        library.render(&config.buck, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            indoc! {r#"
+                cxx_library(
+                    name = "foo-1.0.0-native",
+                    srcs = [],
+                    headers = [],
+                    compiler_flags = ["-O2"],
+                    platform_compiler_flags = [("^linux-x86_64$", ["-pthread"])],
+                    platform_preprocessor_flags = [("^linux-x86_64$", ["-DFOO_LINUX"])],
+                    visibility = [],
+                )
+            "#},
+        );
+
+        config.buck.platform_representation = PlatformRepresentation::Select;
+        config
+            .buck
+            .platform_settings
+            .insert(linux, "//platforms:linux-x86_64".to_owned());
+        let mut out = Vec::new();
+        library.render(&config.buck, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            indoc! {r#"
+                cxx_library(
+                    name = "foo-1.0.0-native",
+                    srcs = [],
+                    headers = [],
+                    compiler_flags = select({
+                        "//platforms:linux-x86_64": [
+                            "-O2",
+                            "-pthread",
+                        ],
+                        "DEFAULT": ["-O2"],
+                    }),
+                    preprocessor_flags = select({
+                        "//platforms:linux-x86_64": ["-DFOO_LINUX"],
+                        "DEFAULT": [],
+                    }),
+                    visibility = [],
+                )
+            "#},
+        );
+    }
+
+    #[test]
+    fn rules_as_json() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let mut config = read_config(tempdir.path()).unwrap();
+        let linux = config
+            .platform
+            .keys()
+            .find(|name| name.to_string() == "linux-x86_64")
+            .unwrap()
+            .clone();
+
+        let mut common = RustCommon {
+            common: Common {
+                name: Name("foo-1.0.0".to_owned()),
+                visibility: Visibility::Private,
+                licenses: Default::default(),
+                license_expression: None,
+                compatible_with: vec![],
+                target_compatible_with: vec![],
+            },
+            krate: "foo".to_owned(),
+            crate_root: BuckPath(PathBuf::from("vendor/foo-1.0.0/src/lib.rs")),
+            edition: Edition::Rust2021,
+            base: Default::default(),
+            platform: Default::default(),
+        };
+        common.base.deps.unwrap_mut().insert(
+            RuleRef::new(":bar-1.0.0".to_owned())
+                .with_origin("from [target.'cfg(unix)'.dependencies]".to_owned()),
+        );
+        common.base.rustc_flags = SelectSet {
+            common: BTreeSet::from(["--cfg=foo".to_owned()]),
+            selects: BTreeMap::from([
+                ("DEFAULT".to_owned(), BTreeSet::new()),
+                (
+                    "//os:linux".to_owned(),
+                    BTreeSet::from(["-Crelocation-model=pic".to_owned()]),
+                ),
+            ]),
+        };
+        let linux_attrs = common.platform.entry(linux.clone()).or_default();
+        linux_attrs.features.unwrap_mut().insert("epoll".to_owned());
+        let rules = [
+            Rule::Alias(Alias {
+                name: Name("foo".to_owned()),
+                actual: Name("foo-1.0.0".to_owned()),
+                fallback_actual: None,
+                visibility: Visibility::Public,
+            }),
+            Rule::Library(RustLibrary {
+                common,
+                proc_macro: false,
+                dlopen_enable: false,
+                python_ext: None,
+                linkable_alias: None,
+                crate_dynamic: None,
+                metadata: None,
+            }),
+        ];
+
+        config.buck.annotate_deps = true;
+        let rustc_flags = serde_json::json!({
+            "select": {
+                "//os:linux": ["--cfg=foo", "-Crelocation-model=pic"],
+                "DEFAULT": ["--cfg=foo"],
+            },
+        });
+
+        // Platforms are a plain object of each one's attributes.
+        let mut out = Vec::new();
+        write_rules_json(&config.buck, rules.iter(), &mut out).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(
+            json[1]["attrs"],
+            serde_json::json!({
+                "name": "foo-1.0.0",
+                "crate": "foo",
+                "crate_root": "vendor/foo-1.0.0/src/lib.rs",
+                "edition": "2021",
+                "platform": {
+                    "linux-x86_64": {
+                        "features": ["epoll"],
+                    },
+                },
+                "visibility": [],
+                "deps": [":bar-1.0.0"],
+                "rustc_flags": rustc_flags,
+            }),
+        );
+
+        config.buck.platform_representation = PlatformRepresentation::Select;
+        config
+            .buck
+            .platform_settings
+            .insert(linux, "//platforms:linux-x86_64".to_owned());
+        let mut out = Vec::new();
+        write_rules_json(&config.buck, rules.iter(), &mut out).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!([
+                {
+                    "rule_type": "alias",
+                    "attrs": {
+                        "name": "foo",
+                        "actual": ":foo-1.0.0",
+                        "visibility": ["PUBLIC"],
+                    },
+                },
+                {
+                    "rule_type": "cargo.rust_library",
+                    "attrs": {
+                        "name": "foo-1.0.0",
+                        "crate": "foo",
+                        "crate_root": "vendor/foo-1.0.0/src/lib.rs",
+                        "edition": "2021",
+                        "features": {
+                            "select": {
+                                "//platforms:linux-x86_64": ["epoll"],
+                                "DEFAULT": [],
+                            },
+                        },
+                        "visibility": [],
+                        "deps": [":bar-1.0.0"],
+                        "rustc_flags": rustc_flags,
+                    },
+                },
+            ]),
+        );
+
+        // The BUCK file still gets its selects afterwards.
+        let mut out = Vec::new();
+        rules[1].render(&config.buck, &mut out).unwrap();
+        let rendered = String::from_utf8(out).unwrap();
+        assert!(rendered.contains("select({"), "{rendered}");
+        assert!(rendered.contains("] + select("), "{rendered}");
+    }
+
+    #[test]
+    fn annotated_deps() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let mut config = read_config(tempdir.path()).unwrap();
+        let mut common = RustCommon {
+            common: Common {
+                name: Name("foo-1.0.0".to_owned()),
+                visibility: Visibility::Private,
+                licenses: Default::default(),
+                license_expression: None,
+                compatible_with: vec![],
+                target_compatible_with: vec![],
+            },
+            krate: "foo".to_owned(),
+            crate_root: BuckPath(PathBuf::from("vendor/foo-1.0.0/src/lib.rs")),
+            edition: Edition::Rust2021,
+            base: Default::default(),
+            platform: Default::default(),
+        };
+        common.base.deps.unwrap_mut().extend([
+            RuleRef::new(":bar-1.0.0".to_owned()),
+            RuleRef::new(":libc-0.2.0".to_owned())
+                .with_origin("from [target.'cfg(unix)'.dependencies]".to_owned()),
+        ]);
+        let library = Rule::Library(RustLibrary {
+            common,
+            proc_macro: false,
+            dlopen_enable: false,
+            python_ext: None,
+            linkable_alias: None,
+            crate_dynamic: None,
+            metadata: None,
+        });
+        let render = |config: &BuckConfig| {
+            let mut out = Vec::new();
+            library.render(config, &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        assert!(!render(&config.buck).contains('#'));
+
+        config.buck.annotate_deps = true;
+        assert_eq!(
+            render(&config.buck),
+            indoc! {r#"
+                cargo.rust_library(
+                    name = "foo-1.0.0",
+                    crate = "foo",
+                    crate_root = "vendor/foo-1.0.0/src/lib.rs",
+                    edition = "2021",
+                    visibility = [],
+                    deps = [
+                        ":bar-1.0.0",
+                        ":libc-0.2.0",  # from [target.'cfg(unix)'.dependencies]
+                    ],
+                )
+            "#},
+        );
+    }
+
+    #[test]
+    fn crate_dynamic_plugin() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let config = read_config(tempdir.path()).unwrap();
+        let mut common = RustCommon {
+            common: Common {
+                name: Name("plugin-1.0.0".to_owned()),
+                visibility: Visibility::Private,
+                licenses: Default::default(),
+                license_expression: None,
+                compatible_with: vec![],
+                target_compatible_with: vec![],
+            },
+            krate: "plugin".to_owned(),
+            crate_root: BuckPath(PathBuf::from("vendor/plugin-1.0.0/src/lib.rs")),
+            edition: Edition::Rust2021,
+            base: Default::default(),
+            platform: Default::default(),
+        };
+        common.base.preferred_linkage = Some("shared".to_owned());
+        let library = Rule::Library(RustLibrary {
+            common,
+            proc_macro: false,
+            dlopen_enable: true,
+            python_ext: None,
+            linkable_alias: None,
+            crate_dynamic: Some(RuleRef::new("//plugins:plugin-dylib".to_owned())),
+            metadata: None,
+        });
+
+        let mut out = Vec::new();
+        library.render(&config.buck, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            indoc! {r#"
+                cargo.rust_library(
+                    name = "plugin-1.0.0",
+                    crate = "plugin",
+                    crate_dynamic = "//plugins:plugin-dylib",
+                    crate_root = "vendor/plugin-1.0.0/src/lib.rs",
+                    dlopen_enable = True,
+                    edition = "2021",
+                    preferred_linkage = "shared",
+                    visibility = [],
+                )
+            "#},
+        );
+        assert!(library.rule_refs().contains("//plugins:plugin-dylib"),);
+    }
+
+    /// A library `foo-1.0.0` with no attributes beyond the required ones.
+    fn library(base: PlatformRustCommon) -> Rule {
+        Rule::Library(RustLibrary {
+            common: RustCommon {
+                common: Common {
+                    name: Name("foo-1.0.0".to_owned()),
+                    visibility: Visibility::Private,
+                    licenses: Default::default(),
+                    license_expression: None,
+                    compatible_with: vec![],
+                    target_compatible_with: vec![],
+                },
+                krate: "foo".to_owned(),
+                crate_root: BuckPath(PathBuf::from("vendor/foo-1.0.0/src/lib.rs")),
+                edition: Edition::Rust2021,
+                base,
+                platform: Default::default(),
+            },
+            proc_macro: false,
+            dlopen_enable: false,
+            python_ext: None,
+            linkable_alias: None,
+            crate_dynamic: None,
+            metadata: None,
+        })
+    }
+
+    #[test]
+    fn rule_refs_doc_deps() {
+        let library = library(PlatformRustCommon {
+            doc_deps: BTreeSet::from([RuleRef::new(":tempfile-3.8.0".to_owned())]),
+            ..Default::default()
+        });
+        assert_eq!(
+            library.rule_refs().into_iter().collect::<Vec<_>>(),
+            [":tempfile-3.8.0"],
+        );
+    }
+
+    #[test]
+    fn rule_refs_mapped_srcs() {
+        let library = library(PlatformRustCommon {
+            mapped_srcs: BTreeMap::from([
+                (
+                    MappedSrc::Source(SubtargetOrPath::Subtarget(Subtarget {
+                        target: Name("foo-1.0.0-build-script-run".to_owned()),
+                        relative: BuckPath(PathBuf::from("out_dir")),
+                    })),
+                    BuckPath(PathBuf::from("vendor/foo-1.0.0/out")),
+                ),
+                (
+                    MappedSrc::OutDirFile(Name("foo-1.0.0-bindings.rs".to_owned())),
+                    BuckPath(PathBuf::from("vendor/foo-1.0.0/src/bindings.rs")),
+                ),
+                (
+                    MappedSrc::Source(SubtargetOrPath::Path(BuckPath(PathBuf::from(
+                        "vendor/foo-1.0.0/src/ffi.rs",
+                    )))),
+                    BuckPath(PathBuf::from("vendor/foo-1.0.0/src/sys.rs")),
+                ),
+            ]),
+            ..Default::default()
+        });
+        assert_eq!(
+            library.rule_refs().into_iter().collect::<Vec<_>>(),
+            [":foo-1.0.0-bindings.rs", ":foo-1.0.0-build-script-run"],
+        );
+    }
+
+    #[test]
+    fn rule_refs_env_locations() {
+        let library = library(PlatformRustCommon {
+            env: Selectable::Value(BTreeMap::from([
+                (
+                    "OUT_DIR".to_owned(),
+                    StringOrPath::String(
+                        "$(location :foo-1.0.0-build-script-run[out_dir])".to_owned(),
+                    ),
+                ),
+                (
+                    "PROTOC".to_owned(),
+                    StringOrPath::String("$(location //tools:protoc)/bin/protoc".to_owned()),
+                ),
+                (
+                    "DESCRIPTION".to_owned(),
+                    StringOrPath::String("\\$(location :not-a-macro)".to_owned()),
+                ),
+            ])),
+            ..Default::default()
+        });
+        assert_eq!(
+            library.rule_refs().into_iter().collect::<Vec<_>>(),
+            ["//tools:protoc", ":foo-1.0.0-build-script-run"],
+        );
+    }
+}
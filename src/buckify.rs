@@ -16,6 +16,8 @@ use std::hash::Hash;
 use std::hash::Hasher;
 use std::io;
 use std::io::Write;
+use std::iter;
+use std::mem;
 use std::path::Component;
 use std::path::Path;
 use std::path::PathBuf;
@@ -31,11 +33,14 @@ use url::Url;
 
 use crate::buck;
 use crate::buck::Alias;
+use crate::buck::AliasFallback;
 use crate::buck::BuckPath;
 use crate::buck::Common;
+use crate::buck::CrateMetadata;
 use crate::buck::Filegroup;
 use crate::buck::GitFetch;
 use crate::buck::HttpArchive;
+use crate::buck::MappedSrc;
 use crate::buck::Name;
 use crate::buck::PlatformRustCommon;
 use crate::buck::Rule;
@@ -46,27 +51,38 @@ use crate::buck::RustLibrary;
 use crate::buck::StringOrPath;
 use crate::buck::SubtargetOrPath;
 use crate::buck::Visibility;
+use crate::buck::METADATA_SCHEMA_VERSION;
 use crate::cargo::cargo_get_lockfile_and_metadata;
+use crate::cargo::cargo_get_normal_features;
+use crate::cargo::workspace_resolver;
 use crate::cargo::ArtifactKind;
 use crate::cargo::Edition;
 use crate::cargo::Manifest;
 use crate::cargo::ManifestTarget;
+use crate::cargo::NodeDepKind;
 use crate::cargo::PkgId;
+use crate::cargo::Resolver;
 use crate::cargo::Source;
 use crate::cargo::TargetReq;
 use crate::collection::SetOrMap;
 use crate::config::Config;
 use crate::fixups::ExportSources;
+use crate::fixups::FixupUsage;
 use crate::fixups::Fixups;
+use crate::fixups::UnresolvedFixup;
 use crate::glob::Globs;
 use crate::glob::NO_EXCLUDE;
 use crate::index;
+use crate::license::LicenseExpr;
 use crate::lockfile::Lockfile;
 use crate::lockfile::LockfilePackage;
 use crate::platform::platform_names_for_expr;
 use crate::platform::PlatformExpr;
 use crate::platform::PlatformName;
+use crate::platform::PlatformPredicate;
+use crate::remap::CargoEnvValue;
 use crate::srcfiles::crate_srcfiles;
+use crate::stats::RuleStats;
 use crate::universe::UniverseName;
 use crate::Args;
 use crate::Paths;
@@ -149,13 +165,410 @@ fn unzip_platform<T: Clone>(
     Ok(())
 }
 
+/// Environment variables through which Cargo gives a crate the path of an
+/// artifact dependency's target: `CARGO_<KIND>_FILE_<DEP>_<NAME>`, and
+/// `CARGO_<KIND>_FILE_<DEP>` as well if the target is named after the
+/// dependency.
+fn artifact_env(artifact: ArtifactKind, dep_name: &str, target_name: &str) -> Vec<String> {
+    let kind = match artifact {
+        ArtifactKind::Bin => "BIN",
+        ArtifactKind::Staticlib => "STATICLIB",
+        ArtifactKind::Cdylib => "CDYLIB",
+    };
+    let dep_upper = dep_name.to_uppercase().replace('-', "_");
+    let mut env = vec![format!("CARGO_{kind}_FILE_{dep_upper}_{target_name}")];
+    if target_name.replace('-', "_") == dep_name.replace('-', "_") {
+        env.push(format!("CARGO_{kind}_FILE_{dep_upper}"));
+    }
+    env
+}
+
+/// Add a dependency to `rule`: as environment variables holding the path of
+/// its `artifact_name` target if it's an artifact dependency, under
+/// `named_deps` if it's renamed, otherwise under `deps`.
+fn add_dep(
+    rule: &mut PlatformRustCommon,
+    dep: RuleRef,
+    rename: Option<&str>,
+    dep_kind: &NodeDepKind,
+    artifact_name: Option<&str>,
+) {
+    if let Some(artifact) = dep_kind.artifact {
+        let target_name = artifact_name.expect("missing target for artifact dependency");
+        let dep_name = dep_kind
+            .extern_name
+            .as_deref()
+            .or(rename)
+            .expect("missing extern_name for artifact dependency");
+        // Binaries get rules of their own, and the library's rule provides
+        // its native library crate types as subtargets.
+        let location = match artifact {
+            ArtifactKind::Bin => format!("$(location {}-{})", dep.target, target_name),
+            ArtifactKind::Staticlib => format!("$(location {}[staticlib])", dep.target),
+            ArtifactKind::Cdylib => format!("$(location {}[cdylib])", dep.target),
+        };
+        for env in artifact_env(artifact, dep_name, target_name) {
+            rule.env
+                .unwrap_mut()
+                .insert(env, StringOrPath::String(location.clone()));
+        }
+    } else if let Some(rename) = rename {
+        rule.named_deps.unwrap_mut().insert(rename.to_owned(), dep);
+    } else {
+        rule.deps.unwrap_mut().insert(dep);
+    }
+}
+
+/// Add a dependency to `base`, or if it's platform-specific, to the rule of
+/// each configured platform it applies to. Returns whether it was added
+/// anywhere.
+fn place_dep(
+    config: &Config,
+    base: &mut PlatformRustCommon,
+    perplat: &mut BTreeMap<PlatformName, PlatformRustCommon>,
+    dep: RuleRef,
+    rename: Option<&str>,
+    dep_kind: &NodeDepKind,
+    artifact_name: Option<&str>,
+) -> anyhow::Result<bool> {
+    if !dep.has_platform() {
+        // Otherwise this is not platform-specific and can go into the
+        // generic dependencies.
+        add_dep(base, dep, rename, dep_kind, artifact_name);
+        return Ok(true);
+    }
+
+    // If this is a platform-specific dependency, find the matching supported
+    // platform(s) and insert it into the appropriate dependency. If the name
+    // is DEFAULT_PLATFORM then just put it in the normal generic deps.
+    let mut placed = false;
+    for (name, platform) in &config.platform {
+        log::debug!(
+            "dep {:?} platform ({}, {:?}) filter {:?}",
+            dep,
+            name,
+            platform,
+            dep.filter(platform)
+        );
+
+        if dep.filter(platform)? {
+            let recipient = if name.is_default() {
+                // Just use normal deps
+                &mut *base
+            } else {
+                perplat.entry(name.clone()).or_default()
+            };
+            add_dep(recipient, dep.clone(), rename, dep_kind, artifact_name);
+            placed = true;
+        }
+    }
+    Ok(placed)
+}
+
+/// Like `place_dep`, for the deps of a proc macro. Buck builds proc macros for
+/// the host, whichever platform is the target, so with a `host_platform`
+/// configured their platform-specific deps are the ones which apply to it, and
+/// go in `base`. That's only right if every build runs proc macros on that one
+/// exec platform; with more than one, `host_platform` is left unset and the
+/// deps stay per platform for Buck to select in the exec configuration.
+fn place_proc_macro_dep(
+    config: &Config,
+    base: &mut PlatformRustCommon,
+    perplat: &mut BTreeMap<PlatformName, PlatformRustCommon>,
+    dep: RuleRef,
+    rename: Option<&str>,
+    dep_kind: &NodeDepKind,
+    artifact_name: Option<&str>,
+) -> anyhow::Result<bool> {
+    match config.host_platform() {
+        Some(host) if dep.has_platform() => {
+            let placed = dep.filter(host)?;
+            if placed {
+                add_dep(base, dep, rename, dep_kind, artifact_name);
+            }
+            Ok(placed)
+        }
+        _ => place_dep(config, base, perplat, dep, rename, dep_kind, artifact_name),
+    }
+}
+
+/// A binary's `required-features` which are the package's own features, or
+/// `None` if it needs a dependency's feature which isn't enabled. Cargo's
+/// `dep/feature` form is checked against the features resolved for the
+/// dependency, on any platform.
+fn own_required_features(
+    index: &index::Index,
+    pkg: &Manifest,
+    tgt: &ManifestTarget,
+) -> Option<BTreeSet<String>> {
+    let mut own = BTreeSet::new();
+    for feature in &tgt.required_features {
+        if feature.starts_with("dep:") || feature.contains("?/") {
+            log::warn!(
+                "pkg {} target {}: ignoring required feature `{}`, which Cargo doesn't allow there",
+                pkg,
+                tgt.name,
+                feature,
+            );
+        } else if let Some((dep, dep_feature)) = feature.split_once('/') {
+            let extern_name = dep.replace('-', "_");
+            let enabled = index.resolved_deps_for_target(pkg, tgt).any(|resolved| {
+                resolved.rename == extern_name
+                    && index
+                        .resolved_features(resolved.package)
+                        .any(|enabled| enabled == dep_feature)
+            });
+            if !enabled {
+                return None;
+            }
+        } else {
+            own.insert(feature.clone());
+        }
+    }
+    Some(own)
+}
+
+/// The platforms on which every one of a binary's `required-features` is
+/// enabled, going by the features in its `base` and `perplat` attributes, or
+/// `None` if that's all of them.
+fn required_features_platforms<'a>(
+    config: &'a Config,
+    required_features: &BTreeSet<String>,
+    base: &PlatformRustCommon,
+    perplat: &BTreeMap<PlatformName, PlatformRustCommon>,
+) -> Option<Vec<&'a PlatformName>> {
+    let missing: Vec<&String> = required_features
+        .iter()
+        .filter(|feature| !base.features.unwrap_ref().contains(*feature))
+        .collect();
+    if missing.is_empty() {
+        return None;
+    }
+    let platforms = config
+        .platform
+        .keys()
+        .filter(|name| {
+            perplat.get(*name).is_some_and(|attrs| {
+                missing
+                    .iter()
+                    .all(|feature| attrs.features.unwrap_ref().contains(*feature))
+            })
+        })
+        .collect();
+    Some(platforms)
+}
+
+/// Move platform-specific deps which every configured platform ends up with
+/// into `base`, such as a dep under both `cfg(unix)` and `cfg(windows)` when
+/// those are the only platforms. If there's a default platform, whose deps go
+/// straight into `base`, a dep has to be there already to count as covering it.
+fn hoist_common_deps(
+    config: &Config,
+    base: &mut PlatformRustCommon,
+    perplat: &mut BTreeMap<PlatformName, PlatformRustCommon>,
+) {
+    let has_default = config.platform.keys().any(PlatformName::is_default);
+    let mut platforms = Vec::new();
+    for name in config.platform.keys().filter(|name| !name.is_default()) {
+        match perplat.get(name) {
+            Some(rule) => platforms.push(rule),
+            None => return,
+        }
+    }
+    let Some((first, rest)) = platforms.split_first() else {
+        return;
+    };
+
+    let has_dep = |rule: &PlatformRustCommon, target: &str| {
+        rule.deps
+            .unwrap_ref()
+            .iter()
+            .any(|dep| dep.target == target)
+    };
+    let has_named_dep = |rule: &PlatformRustCommon, rename: &str, target: &str| {
+        rule.named_deps
+            .unwrap_ref()
+            .get(rename)
+            .is_some_and(|dep| dep.target == target)
+    };
+
+    let common_deps: BTreeSet<String> = first
+        .deps
+        .unwrap_ref()
+        .iter()
+        .filter(|dep| rest.iter().all(|rule| has_dep(rule, &dep.target)))
+        .filter(|dep| !has_default || has_dep(base, &dep.target))
+        .map(|dep| dep.target.clone())
+        .collect();
+    let common_named_deps: BTreeMap<String, String> = first
+        .named_deps
+        .unwrap_ref()
+        .iter()
+        .filter(|(rename, dep)| {
+            rest.iter()
+                .all(|rule| has_named_dep(rule, rename, &dep.target))
+        })
+        .filter(|(rename, dep)| !has_default || has_named_dep(base, rename, &dep.target))
+        .map(|(rename, dep)| (rename.clone(), dep.target.clone()))
+        .collect();
+
+    for rule in perplat.values_mut() {
+        rule.deps
+            .unwrap_mut()
+            .retain(|dep| !common_deps.contains(&dep.target));
+        rule.named_deps
+            .unwrap_mut()
+            .retain(|rename, _| !common_named_deps.contains_key(rename));
+    }
+    for target in common_deps {
+        if !has_dep(base, &target) {
+            base.deps.unwrap_mut().insert(RuleRef::new(target));
+        }
+    }
+    for (rename, target) in common_named_deps {
+        base.named_deps
+            .unwrap_mut()
+            .entry(rename)
+            .or_insert_with(|| RuleRef::new(target));
+    }
+}
+
+/// Describe which configured platforms each of a target's deps applies to, as
+/// a table of the dep, its platform expression, the predicate parsed from
+/// that, and the platforms the predicate is true for.
+fn explain_dep_platforms<'a>(
+    config: &Config,
+    pkg: &Manifest,
+    tgt: &ManifestTarget,
+    deps: impl IntoIterator<Item = &'a RuleRef>,
+) -> String {
+    let platform_names: Vec<_> = config.platform.keys().collect();
+
+    let mut rows = Vec::new();
+    for dep in deps {
+        let row = match dep.platform() {
+            None => [
+                dep.target.clone(),
+                "-".to_owned(),
+                "-".to_owned(),
+                "all".to_owned(),
+            ],
+            Some(expr) => match PlatformPredicate::parse(expr) {
+                Ok(pred) => {
+                    let matching = platform_names
+                        .iter()
+                        .filter(|name| pred.eval(&config.platform[**name]))
+                        .join(", ");
+                    [
+                        dep.target.clone(),
+                        expr.to_string(),
+                        pred.to_string(),
+                        if matching.is_empty() {
+                            "(none)".to_owned()
+                        } else {
+                            matching
+                        },
+                    ]
+                }
+                Err(err) => [
+                    dep.target.clone(),
+                    expr.to_string(),
+                    err.to_string(),
+                    "(none)".to_owned(),
+                ],
+            },
+        };
+        rows.push(row);
+    }
+
+    let header = ["DEP", "PLATFORM", "PREDICATE", "PLATFORMS"].map(str::to_owned);
+    let mut widths = [0; 3];
+    for row in iter::once(&header).chain(&rows) {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+
+    let mut explanation = format!("Platforms of deps of {} target {}:\n", pkg, tgt.name);
+    for [dep, expr, pred, platforms] in iter::once(&header).chain(&rows) {
+        explanation += &format!(
+            "  {dep:w0$}  {expr:w1$}  {pred:w2$}  {platforms}\n",
+            w0 = widths[0],
+            w1 = widths[1],
+            w2 = widths[2],
+        );
+    }
+    explanation
+}
+
+/// `target_compatible_with` constraints for packages which are only reachable
+/// on some of the configured platforms. A package gets one if all the platforms
+/// it's reachable on share a `target_os` with an entry in `[buck]
+/// os_constraints`, and some other configured platform doesn't. Packages built
+/// for the execution platform, as build dependencies or proc macros or their
+/// dependencies, don't, since that needn't be the platform being targeted.
+fn platform_gates<'meta>(
+    config: &Config,
+    index: &index::Index<'meta>,
+) -> HashMap<&'meta PkgId, RuleRef> {
+    let mut reachable_on: HashMap<&PkgId, Vec<&PlatformName>> = HashMap::new();
+    for (name, platform) in &config.platform {
+        let reachable = index.reachable_packages(|expr| {
+            // Unparseable expressions get reported when computing deps.
+            PlatformPredicate::parse(expr).map_or(true, |pred| pred.eval(platform))
+        });
+        for pkgid in reachable {
+            reachable_on.entry(pkgid).or_default().push(name);
+        }
+    }
+
+    let exec_packages = index.exec_packages();
+    let mut gates = HashMap::new();
+    for (pkgid, platforms) in reachable_on {
+        if platforms.len() == config.platform.len() || exec_packages.contains(pkgid) {
+            continue;
+        }
+        let Ok(target_os) = platforms
+            .iter()
+            .map(|name| config.platform[*name].target_os())
+            .dedup()
+            .exactly_one()
+        else {
+            continue;
+        };
+        let Some(target_os) = target_os else {
+            continue;
+        };
+        let excludes_some = config
+            .platform
+            .values()
+            .any(|platform| platform.target_os() != Some(target_os));
+        if let Some(constraint) = config.buck.os_constraints.get(target_os) {
+            if excludes_some {
+                gates.insert(pkgid, RuleRef::new(constraint.clone()));
+            }
+        }
+    }
+    gates
+}
+
 /// Constant context for generating rules
-struct RuleContext<'meta> {
+pub(crate) struct RuleContext<'meta> {
     config: &'meta Config,
     paths: &'meta Paths,
     index: index::Index<'meta>,
     lockfile: Lockfile,
+    /// `[env]` table from `.cargo/config.toml`, minus excluded variables
+    cargo_env: BTreeMap<String, CargoEnvValue>,
     done: Mutex<HashSet<(&'meta PkgId, TargetReq<'meta>)>>,
+    /// Packages with fixup files, for `--stats`
+    with_fixups: Mutex<HashSet<&'meta PkgId>>,
+    /// Build scripts without fixups, collected when writing a report
+    unresolved: &'meta Mutex<Vec<UnresolvedFixup>>,
+    /// Crate whose dependencies' platforms to explain on stderr
+    explain_platform: Option<&'meta str>,
+    /// `target_compatible_with` for packages only reachable on some platforms
+    platform_gates: HashMap<&'meta PkgId, RuleRef>,
 }
 
 /// Generate rules for a set of dependencies
@@ -186,14 +599,42 @@ fn generate_rules<'scope>(
     pkg: &'scope Manifest,
     target_req: TargetReq<'scope>,
 ) {
-    if let TargetReq::Sources = target_req {
-        if let Some(nonvendored_sources) =
-            generate_nonvendored_sources_archive(context, pkg).transpose()
-        {
-            let _ = rule_tx.send(nonvendored_sources);
+    match buckify_package(context, pkg, target_req) {
+        Ok((rules, deps)) => {
+            for rule in rules {
+                let _ = rule_tx.send(Ok(rule));
+            }
+            generate_dep_rules(context, scope, rule_tx, deps);
         }
-        return;
+        Err(err) => {
+            let _ = rule_tx.send(Err(err));
+        }
+    }
+}
+
+/// Package targets which some generated rules depend on
+pub(crate) type PackageDeps<'meta> = Vec<(&'meta Manifest, TargetReq<'meta>)>;
+
+/// Generate the rules for the targets of one package selected by
+/// `target_req`, along with the package targets they depend on, which need
+/// rules of their own.
+///
+/// This doesn't recurse into the dependencies, and only reads `context`
+/// (other than through its `Mutex`es), so it's fine to call it for many
+/// packages in parallel, as `buckify` does. Doing the same target twice
+/// results in duplicate rules.
+pub(crate) fn buckify_package<'scope>(
+    context: &'scope RuleContext<'scope>,
+    pkg: &'scope Manifest,
+    target_req: TargetReq<'scope>,
+) -> anyhow::Result<(Vec<Rule>, PackageDeps<'scope>)> {
+    if let TargetReq::Sources = target_req {
+        let rules = generate_nonvendored_sources_archive(context, pkg)?;
+        return Ok((rules.into_iter().collect(), Vec::new()));
     }
+
+    let mut all_rules = Vec::new();
+    let mut all_deps = Vec::new();
     for tgt in &pkg.targets {
         let matching_kind = match target_req {
             TargetReq::Lib => tgt.kind_lib() || tgt.kind_proc_macro() || tgt.kind_cdylib(),
@@ -207,7 +648,7 @@ fn generate_rules<'scope>(
         if !matching_kind {
             continue;
         }
-        match generate_target_rules(context, pkg, tgt) {
+        match generate_target_rules(context, pkg, tgt, target_req) {
             Ok((rules, _)) if rules.is_empty() => {
                 // Don't generate rules for dependencies if we're not emitting
                 // any rules for this target.
@@ -216,14 +657,12 @@ fn generate_rules<'scope>(
                 let is_private_root_pkg =
                     context.index.is_root_package(pkg) && !context.index.is_public_package(pkg);
                 if !is_private_root_pkg {
-                    for rule in rules {
-                        let _ = rule_tx.send(Ok(rule));
-                    }
+                    all_rules.extend(rules);
                     if context.config.vendor.is_none() {
                         deps.push((pkg, TargetReq::Sources));
                     }
                 }
-                generate_dep_rules(context, scope, rule_tx.clone(), deps);
+                all_deps.extend(deps);
             }
             Err(err) => {
                 log::error!(
@@ -232,10 +671,57 @@ fn generate_rules<'scope>(
                     tgt.name,
                     err
                 );
-                let _ = rule_tx.send(Err(err));
+                return Err(err);
             }
         }
     }
+
+    Ok((all_rules, all_deps))
+}
+
+/// Path of a package's `license-file` within its manifest dir, if it's there.
+fn license_file_in_package(manifest_dir: &Path, license_file: &Path) -> Option<PathBuf> {
+    let path = if license_file.components().contains(&Component::ParentDir) {
+        // Buck rejects `..` in a source path: "Error when treated as a
+        // path: expected a normalized path but got an un-normalized path
+        // instead: `vendor/libcst_derive-0.1.0/../../LICENSE`". A license file
+        // outside the package, such as at the top of its workspace, gets
+        // copied to the top of the package by `cargo package`.
+        PathBuf::from(license_file.file_name()?)
+    } else {
+        // Normalize to get rid of `.`
+        // (e.g. `vendor/polars-arrow-0.34.2/./LICENSE`).
+        normalize_path(license_file)
+    };
+    manifest_dir.join(&path).is_file().then_some(path)
+}
+
+/// Cargo package metadata for `emit_metadata`.
+fn crate_metadata(context: &RuleContext, pkg: &Manifest) -> CrateMetadata {
+    let source = match &pkg.source {
+        Source::CratesIo => "registry",
+        Source::Git { .. } => "git",
+        Source::Local => "path",
+        Source::Unrecognized(_) => "other",
+    };
+    CrateMetadata {
+        schema_version: METADATA_SCHEMA_VERSION,
+        name: pkg.name.clone(),
+        version: pkg.version.to_string(),
+        description: pkg.description.clone(),
+        repository: pkg.repository.clone(),
+        authors: pkg.authors.clone(),
+        features: context
+            .index
+            .resolved_features(pkg)
+            .map(str::to_owned)
+            .collect(),
+        source: Some(source.to_owned()),
+        checksum: context
+            .lockfile
+            .find(pkg)
+            .and_then(|lockfile_package| lockfile_package.checksum.clone()),
+    }
 }
 
 fn generate_nonvendored_sources_archive<'scope>(
@@ -261,7 +747,7 @@ fn generate_nonvendored_sources_archive<'scope>(
         Source::CratesIo => generate_http_archive(context, pkg, lockfile_package).map(Some),
         Source::Git {
             repo, commit_hash, ..
-        } => generate_git_fetch(repo, commit_hash).map(Some),
+        } => generate_git_fetch(pkg, repo, commit_hash).map(Some),
         Source::Unrecognized(_) => {
             bail!(
                 "`vendor = false` mode is supported only with exclusively crates.io and https git dependencies. \"{}\" {} is coming from some other source",
@@ -305,13 +791,19 @@ fn generate_http_archive<'scope>(
     }))
 }
 
-fn generate_git_fetch(repo: &str, commit_hash: &str) -> anyhow::Result<Rule> {
+fn generate_git_fetch(pkg: &Manifest, repo: &str, commit_hash: &str) -> anyhow::Result<Rule> {
     let short_name = short_name_for_git_repo(repo)?;
 
+    // Cargo always checks out submodules of git dependencies, so Buck needs to
+    // as well if the repo has any.
+    let submodules = find_repository_root(pkg.manifest_dir())
+        .is_ok_and(|repository_root| repository_root.join(".gitmodules").exists());
+
     Ok(Rule::GitFetch(GitFetch {
         name: Name(format!("{}.git", short_name)),
         repo: repo.to_owned(),
         rev: commit_hash.to_owned(),
+        submodules,
         visibility: Visibility::Private,
     }))
 }
@@ -388,13 +880,50 @@ fn srcfiles(manifest_dir: PathBuf, path: PathBuf) -> Vec<PathBuf> {
     }
 }
 
+/// Normalize a package's declared SPDX license, warning about anything we
+/// don't understand. Unparseable licenses are passed through as-is.
+fn license_expression(pkg: &Manifest, license: &str) -> String {
+    match LicenseExpr::parse(license) {
+        Ok(expr) => {
+            for id in expr.unknown_identifiers() {
+                log::warn!(
+                    "Package {} has unknown SPDX license identifier `{}`",
+                    pkg,
+                    id
+                );
+            }
+            expr.to_string()
+        }
+        Err(err) => {
+            log::warn!("Package {} has invalid SPDX license: {}", pkg, err);
+            license.to_owned()
+        }
+    }
+}
+
+/// Path of a target's root module, given the directory its package is mapped
+/// to. Every target has its own root, which Cargo resolves from the `path` in
+/// `[lib]` or `[[bin]]`, or else by convention (`src/lib.rs`, `src/main.rs`,
+/// `src/bin/<name>.rs`), so a package's binaries never share the library's.
+fn target_crate_root(
+    mapped_manifest_dir: &Path,
+    manifest_dir: &Path,
+    tgt: &ManifestTarget,
+) -> PathBuf {
+    // Cargo joins `path` onto the manifest dir as written, which may leave
+    // `.` or `..` components that Buck rejects.
+    let src_path = normalize_path(&tgt.src_path);
+    mapped_manifest_dir.join(relative_path(manifest_dir, &src_path))
+}
+
 /// Generate rules for a target. Returns the rules, and the
 /// packages we depend on for further rule generation.
 fn generate_target_rules<'scope>(
     context: &'scope RuleContext<'scope>,
     pkg: &'scope Manifest,
     tgt: &'scope ManifestTarget,
-) -> anyhow::Result<(Vec<Rule>, Vec<(&'scope Manifest, TargetReq<'scope>)>)> {
+    target_req: TargetReq<'scope>,
+) -> anyhow::Result<(Vec<Rule>, PackageDeps<'scope>)> {
     let RuleContext {
         config,
         paths,
@@ -405,11 +934,21 @@ fn generate_target_rules<'scope>(
     log::debug!("Generating rules for package {} target {}", pkg, tgt.name);
 
     let fixups = Fixups::new(config, paths, index, pkg, tgt)?;
+    if fixups.has_fixup_file() {
+        context.with_fixups.lock().unwrap().insert(&pkg.id);
+    }
 
     if fixups.omit_target() {
         return Ok((vec![], vec![]));
     }
 
+    // Binaries which something depends on as an artifact are requested by
+    // name, so only skip the ones we'd emit just for being there.
+    if target_req == TargetReq::EveryBin && !fixups.emit_binary() {
+        log::debug!("pkg {} target {}: binaries not emitted", pkg, tgt.name);
+        return Ok((vec![], vec![]));
+    }
+
     log::debug!("pkg {} target {} fixups {:#?}", pkg, tgt.name, fixups);
 
     let manifest_dir = pkg.manifest_dir();
@@ -424,8 +963,35 @@ fn generate_target_rules<'scope>(
         } else {
             PathBuf::from(format!("{}-{}.crate", pkg.name, pkg.version))
         };
-    let crate_root = mapped_manifest_dir.join(relative_path(manifest_dir, &tgt.src_path));
-    let edition = tgt.edition.unwrap_or(pkg.edition);
+    let crate_root = target_crate_root(&mapped_manifest_dir, manifest_dir, tgt);
+    let edition = match fixups.edition() {
+        Some(edition) => edition,
+        None => match (
+            tgt.edition.unwrap_or(pkg.edition),
+            config.cargo.default_edition,
+        ) {
+            (Edition::Rust2015, Some(default_edition)) if !pkg.declares_edition()? => {
+                log::debug!(
+                    "{} has no edition in its manifest, using default {}",
+                    pkg,
+                    default_edition,
+                );
+                default_edition
+            }
+            (edition, _) => edition,
+        },
+    };
+    if let Some(rust_version) = pkg.parsed_rust_version() {
+        if rust_version < edition.min_rust_version() {
+            log::warn!(
+                "{} declares rust-version {} but uses edition {}, which needs Rust {} or later",
+                pkg,
+                rust_version,
+                edition,
+                edition.min_rust_version(),
+            );
+        }
+    }
 
     let mut licenses = BTreeSet::new();
     if config.vendor.is_none() {
@@ -441,19 +1007,47 @@ fn generate_target_rules<'scope>(
         for path in license_globs.walk(manifest_dir) {
             licenses.insert(BuckPath(rel_manifest.join(path)));
         }
+        // The authoritative license file goes in whatever the patterns match.
         if let Some(license_file) = &pkg.license_file {
-            // Buck rejects `..` in a source path: "Error when treated as a
-            // path: expected a normalized path but got an un-normalized path
-            // instead: `vendor/libcst_derive-0.1.0/../../LICENSE`"
-            if !license_file.components().contains(&Component::ParentDir) {
-                // But still normalize to get rid of `.`
-                // (e.g. `vendor/polars-arrow-0.34.2/./LICENSE`).
-                let license_path = normalize_path(&rel_manifest.join(license_file));
-                licenses.insert(BuckPath(license_path));
+            match license_file_in_package(manifest_dir, license_file) {
+                Some(path) => {
+                    licenses.insert(BuckPath(rel_manifest.join(path)));
+                }
+                None => log::warn!(
+                    "{pkg}: license-file {} isn't in the package",
+                    license_file.display(),
+                ),
             }
         }
     };
 
+    // Build scripts run on the exec platform, so only the crate's library and
+    // binaries are gated to the platforms it's reachable on.
+    let target_compatible_with: Vec<RuleRef> = match fixups.target_compatible_with() {
+        Some(constraints) => constraints.iter().cloned().map(RuleRef::new).collect(),
+        None => context
+            .platform_gates
+            .get(&pkg.id)
+            .cloned()
+            .into_iter()
+            .collect(),
+    };
+    let fallback_actual = match (&config.buck.fallback_actual, &target_compatible_with[..]) {
+        (Some(fallback), [constraint]) => Some(AliasFallback {
+            constraint: constraint.clone(),
+            actual: RuleRef::new(fallback.clone()),
+        }),
+        _ => None,
+    };
+
+    let license_expression = if config.buck.emit_license_expression {
+        pkg.license
+            .as_deref()
+            .map(|license| license_expression(pkg, license))
+    } else {
+        None
+    };
+
     // Get a list of the most obvious sources for the crate. This is either a list of
     // filename, or a list of globs.
     // If we're configured to get precise sources and we're using 2018+ edition source, then
@@ -474,6 +1068,8 @@ fn generate_target_rules<'scope>(
         srcs.push(relative_path(manifest_dir, dir_containing_src).join("**/*.rs"));
     }
 
+    fixups.check_unstable_rustc_flags()?;
+
     // Platform-specific rule bits which are common to all platforms
     let mut base = PlatformRustCommon::default();
     // Per platform rule bits
@@ -582,6 +1178,27 @@ fn generate_target_rules<'scope>(
     )
     .context("features")?;
 
+    base.env
+        .unwrap_mut()
+        .extend(fixups.compute_cargo_pkg_env(config.cargo.cargo_pkg_env)?);
+
+    // Environment from Cargo's `[env]` config. Fixups take precedence over
+    // these, unless the variable is marked `force`.
+    let cargo_env_value = |value: &CargoEnvValue| {
+        if value.relative() {
+            StringOrPath::Path(BuckPath(PathBuf::from(value.value())))
+        } else {
+            StringOrPath::String(value.value().to_owned())
+        }
+    };
+    for (name, value) in &context.cargo_env {
+        if !value.force() {
+            base.env
+                .unwrap_mut()
+                .insert(name.clone(), cargo_env_value(value));
+        }
+    }
+
     unzip_platform(
         config,
         &mut base,
@@ -594,15 +1211,37 @@ fn generate_target_rules<'scope>(
     )
     .context("env")?;
 
+    for (name, value) in &context.cargo_env {
+        if value.force() {
+            base.env
+                .unwrap_mut()
+                .insert(name.clone(), cargo_env_value(value));
+            for rule in perplat.values_mut() {
+                rule.env.unwrap_mut().remove(name);
+            }
+        }
+    }
+
     // Compute set of dependencies any rule we generate here will need. They will only
     // be emitted if we actually emit some rules below.
     let mut dep_pkgs = Vec::new();
-    for (deppkg, dep, rename, dep_kind) in fixups.compute_deps()? {
+    let deps = fixups.compute_deps()?;
+    if context.explain_platform == Some(pkg.name.as_str()) {
+        let deps = deps.iter().map(|(_, dep, _, _)| dep);
+        eprint!("{}", explain_dep_platforms(config, pkg, tgt, deps));
+    }
+    for (deppkg, dep, rename, dep_kind) in deps {
         let target_req = dep_kind.target_req();
-        if let TargetReq::Cdylib = target_req {
-            let artifact = &dep_kind.artifact;
-            bail!("unsupported artifact kind {artifact:?} for dependency {dep:?}");
-        }
+        let artifact_name = match target_req {
+            TargetReq::Bin(bin_name) => Some(bin_name),
+            TargetReq::Staticlib => deppkg
+                .and_then(|deppkg| deppkg.targets.iter().find(|tgt| tgt.kind_staticlib()))
+                .map(|tgt| tgt.name.as_str()),
+            TargetReq::Cdylib => deppkg
+                .and_then(|deppkg| deppkg.targets.iter().find(|tgt| tgt.kind_cdylib()))
+                .map(|tgt| tgt.name.as_str()),
+            _ => None,
+        };
         if let Some(compile_target) = &dep_kind.compile_target {
             // Compile_target is not implemented yet.
             //
@@ -610,81 +1249,43 @@ fn generate_target_rules<'scope>(
             // binary built for a different architecture.
             bail!("unsupported compile_target {compile_target:?} for dependency {dep:?}");
         }
-        if dep.has_platform() {
-            // If this is a platform-specific dependency, find the
-            // matching supported platform(s) and insert it into the appropriate
-            // dependency.
-            // If the name is DEFAULT_PLATFORM then just put it in the normal generic deps
-            for (name, platform) in &config.platform {
-                let is_default = name.is_default();
-
-                log::debug!(
-                    "pkg {} target {} dep {:?} platform ({}, {:?}) filter {:?}",
-                    pkg,
-                    tgt.name,
-                    dep,
-                    name,
-                    platform,
-                    dep.filter(platform)
-                );
-
-                if dep.filter(platform)? {
-                    let dep = dep.clone();
-
-                    let recipient = if is_default {
-                        // Just use normal deps
-                        &mut base
-                    } else {
-                        perplat.entry(name.clone()).or_default()
-                    };
-
-                    if dep_kind.artifact == Some(ArtifactKind::Bin) {
-                        let target_name = dep.target.strip_prefix(':').unwrap();
-                        let bin_name = dep_kind.bin_name.as_ref().unwrap();
-                        let env = format!("{}-{}", target_name, bin_name);
-                        let location = format!("$(location {}-{}#check)", dep.target, bin_name);
-                        recipient
-                            .env
-                            .unwrap_mut()
-                            .insert(env, StringOrPath::String(location));
-                    } else if let Some(rename) = rename {
-                        recipient
-                            .named_deps
-                            .unwrap_mut()
-                            .insert(rename.to_owned(), dep);
-                    } else {
-                        recipient.deps.unwrap_mut().insert(dep);
-                    }
-                    if let Some(deppkg) = deppkg {
-                        dep_pkgs.push((deppkg, target_req));
-                    }
-                }
-            }
+        let placed = if tgt.kind_proc_macro() {
+            place_proc_macro_dep(
+                config,
+                &mut base,
+                &mut perplat,
+                dep,
+                rename,
+                dep_kind,
+                artifact_name,
+            )?
         } else {
-            // Otherwise this is not platform-specific and can go into the
-            // generic dependencies.
-            if dep_kind.artifact == Some(ArtifactKind::Bin) {
-                let target_name = dep.target.strip_prefix(':').unwrap();
-                let bin_name = dep_kind.bin_name.as_ref().unwrap();
-                let env = format!("{}-{}", target_name, bin_name);
-                let location = format!("$(location {}-{}#check)", dep.target, bin_name);
-                base.env
-                    .unwrap_mut()
-                    .insert(env, StringOrPath::String(location));
-            } else if let Some(rename) = rename {
-                base.named_deps.unwrap_mut().insert(rename.to_owned(), dep);
-            } else {
-                base.deps.unwrap_mut().insert(dep);
-            }
+            place_dep(
+                config,
+                &mut base,
+                &mut perplat,
+                dep,
+                rename,
+                dep_kind,
+                artifact_name,
+            )?
+        };
+        if placed {
             if let Some(deppkg) = deppkg {
                 dep_pkgs.push((deppkg, target_req));
             }
         }
     }
 
+    hoist_common_deps(config, &mut base, &mut perplat);
+
     // "link_style" only really applies to binaries, so maintain separate binary base & perplat
     let mut bin_base = base.clone();
     let mut bin_perplat = perplat.clone();
+    bin_base.link_style = config
+        .buck
+        .default_binary_link_style
+        .map(|link_style| link_style.to_string());
 
     unzip_platform(
         config,
@@ -720,6 +1321,12 @@ fn generate_target_rules<'scope>(
     let mut lib_base = base.clone();
     let mut lib_perplat = perplat.clone();
 
+    // A crate which can only be built as a cdylib has to be linked shared.
+    // Fixups can still override this.
+    if tgt.kind_cdylib() && tgt.crate_cdylib_only() {
+        lib_base.preferred_linkage = Some("shared".to_owned());
+    }
+
     unzip_platform(
         config,
         &mut lib_base,
@@ -736,6 +1343,34 @@ fn generate_target_rules<'scope>(
         fixups.compute_preferred_linkage(),
     )
     .context("preferred_linkage")?;
+    if tgt.kind_lib() {
+        if let Some(warning) = fixups.mixed_native_linkage() {
+            log::warn!("{warning}");
+        }
+    }
+
+    // Only the library has doctests, unless they're turned off.
+    if tgt.kind_lib() && tgt.doctest {
+        let doc_deps = fixups.compute_doc_deps()?;
+        for (deppkg, _dep) in &doc_deps {
+            if let Some(deppkg) = deppkg {
+                dep_pkgs.push((*deppkg, TargetReq::Lib));
+            }
+        }
+        unzip_platform(
+            config,
+            &mut lib_base,
+            &mut lib_perplat,
+            |rule, dep| {
+                log::debug!("pkg {} target {}: doc dep {:?}", pkg, tgt.name, dep);
+                rule.doc_deps.insert(dep);
+            },
+            doc_deps
+                .into_iter()
+                .map(|(_deppkg, dep)| (dep.platform().cloned(), dep)),
+        )
+        .context("doc_deps")?;
+    }
 
     // Standalone binary - binary for a package always takes the package's library as a dependency
     // if there is one
@@ -765,10 +1400,12 @@ fn generate_target_rules<'scope>(
             rules.push(Rule::Alias(Alias {
                 name: index.public_rule_name(pkg),
                 actual: index.private_rule_name(pkg),
+                fallback_actual,
                 visibility: fixups.public_visibility(),
             }));
         }
 
+        let python_ext = fixups.python_ext();
         let rust_library = RustLibrary {
             common: RustCommon {
                 common: Common {
@@ -783,7 +1420,9 @@ fn generate_target_rules<'scope>(
                         Visibility::Private
                     },
                     licenses,
+                    license_expression,
                     compatible_with: vec![],
+                    target_compatible_with: target_compatible_with.clone(),
                 },
                 krate: tgt.name.replace('-', "_"),
                 crate_root: BuckPath(crate_root),
@@ -792,15 +1431,21 @@ fn generate_target_rules<'scope>(
                 platform: lib_perplat,
             },
             proc_macro: tgt.crate_proc_macro(),
-            dlopen_enable: tgt.kind_cdylib() && fixups.python_ext().is_none(),
-            python_ext: fixups.python_ext().map(str::to_string),
+            dlopen_enable: tgt.kind_cdylib() && python_ext.is_none(),
             linkable_alias: if index.is_public_target(pkg, TargetReq::Lib)
-                && (tgt.kind_cdylib() || fixups.python_ext().is_some())
+                && (tgt.kind_cdylib() || python_ext.is_some())
             {
                 Some(index.public_rule_name(pkg).0)
             } else {
                 None
             },
+            python_ext,
+            crate_dynamic: fixups.crate_dynamic(),
+            metadata: if config.emit_metadata {
+                Some(crate_metadata(context, pkg))
+            } else {
+                None
+            },
         };
 
         rules.push(if index.is_root_package(pkg) {
@@ -821,7 +1466,9 @@ fn generate_target_rules<'scope>(
                     name: Name(format!("{}-{}", pkg, tgt.name)),
                     visibility: Visibility::Private,
                     licenses: Default::default(),
+                    license_expression: None,
                     compatible_with: vec![],
+                    target_compatible_with: vec![],
                 },
                 krate: tgt.name.replace('-', "_"),
                 crate_root: BuckPath(crate_root),
@@ -835,8 +1482,54 @@ fn generate_target_rules<'scope>(
                 platform: bin_perplat,
             },
         };
-        fixups.emit_buildscript_rules(buildscript, config)?
+        fixups.emit_buildscript_rules(buildscript, config, context.unresolved)?
     } else if tgt.kind_bin() && tgt.crate_bin() {
+        // Cargo leaves out binaries whose `required-features` aren't enabled,
+        // so they only get built where they are.
+        let platforms = match own_required_features(index, pkg, tgt) {
+            Some(required) => {
+                required_features_platforms(config, &required, &bin_base, &bin_perplat)
+            }
+            None => Some(vec![]),
+        };
+        let compatible_with = match platforms {
+            None => vec![],
+            Some(platforms) if platforms.is_empty() => {
+                log::debug!(
+                    "pkg {} target {}: skipping, required features {:?} aren't enabled",
+                    pkg,
+                    tgt.name,
+                    tgt.required_features,
+                );
+                return Ok((vec![], vec![]));
+            }
+            Some(platforms) => {
+                let settings: Option<Vec<RuleRef>> = platforms
+                    .iter()
+                    .map(|name| {
+                        config
+                            .buck
+                            .platform_settings
+                            .get(*name)
+                            .cloned()
+                            .map(RuleRef::new)
+                    })
+                    .collect();
+                if settings.is_none() {
+                    log::warn!(
+                        "pkg {} target {}: required features {:?} are only enabled on \
+                         platforms {}, but without a `[buck] platform_settings` entry \
+                         for each the binary can't be limited to them",
+                        pkg,
+                        tgt.name,
+                        tgt.required_features,
+                        platforms.iter().join(", "),
+                    );
+                }
+                settings.unwrap_or_default()
+            }
+        };
+
         let mut rules = vec![];
         let actual = Name(format!("{}-{}", index.private_rule_name(pkg), tgt.name));
 
@@ -844,6 +1537,7 @@ fn generate_target_rules<'scope>(
             rules.push(Rule::Alias(Alias {
                 name: Name(format!("{}-{}", index.public_rule_name(pkg), tgt.name)),
                 actual: actual.clone(),
+                fallback_actual,
                 visibility: fixups.public_visibility(),
             }));
         }
@@ -854,7 +1548,9 @@ fn generate_target_rules<'scope>(
                     name: actual,
                     visibility: Visibility::Private,
                     licenses,
-                    compatible_with: vec![],
+                    license_expression,
+                    compatible_with,
+                    target_compatible_with,
                 },
                 krate: tgt.name.replace('-', "_"),
                 crate_root: BuckPath(crate_root),
@@ -927,18 +1623,71 @@ fn generate_target_rules<'scope>(
         rules.push(Rule::Filegroup(rule));
     }
 
+    if let Some(filegroup) = fixups.data_filegroup()? {
+        rules.push(Rule::Filegroup(filegroup));
+    }
+
     Ok((rules, dep_pkgs))
 }
 
+/// Resolve features with `cargo metadata --filter-platform` for the target
+/// triple of each configured platform, for `per_platform_features`. Platforms
+/// with the same triple share one resolution.
+fn resolve_platform_features(
+    config: &Config,
+    args: &Args,
+    paths: &Paths,
+    features: &str,
+    default_features: bool,
+) -> anyhow::Result<BTreeMap<String, HashMap<PkgId, BTreeSet<String>>>> {
+    let mut triples = BTreeSet::new();
+    for (name, platform) in &config.platform {
+        if name.is_default() {
+            continue;
+        }
+        let triple = platform.target_triple().with_context(|| {
+            format!(
+                "`per_platform_features` needs platform `{name}` to have exactly one target triple key, like `x86_64-unknown-linux-gnu = []`",
+            )
+        })?;
+        triples.insert(triple);
+    }
+
+    let mut platform_features = BTreeMap::new();
+    for triple in triples {
+        log::info!("Running `cargo metadata` for target {triple}...");
+        let (_lockfile, metadata) = cargo_get_lockfile_and_metadata(
+            config,
+            args,
+            paths,
+            features.to_owned(),
+            default_features,
+            Some(triple),
+        )?;
+        let features = metadata
+            .resolve
+            .nodes
+            .into_iter()
+            .map(|node| (node.id, node.features))
+            .collect();
+        platform_features.insert(triple.to_owned(), features);
+    }
+    Ok(platform_features)
+}
+
 fn buckify_for_universe(
     config: &Config,
     args: &Args,
     paths: &Paths,
     universe: &UniverseName,
+    unresolved: &Mutex<Vec<UnresolvedFixup>>,
+    fixup_usage: &mut FixupUsage,
+    explain_platform: Option<&str>,
 ) -> anyhow::Result<BTreeSet<Rule>> {
     let universe_config = &config.universe[universe];
     let features = universe_config.features.iter().join(",");
-    let (lockfile, metadata) = {
+    let is_default = *universe == Default::default();
+    let (lockfile, metadata, platform_features, normal_features) = {
         let _guard = if universe_config.include_crates.is_empty() {
             None
         } else {
@@ -949,21 +1698,69 @@ fn buckify_for_universe(
         };
         log::info!("Running `cargo metadata` for universe {universe}...");
         measure_time::info_time!("Running `cargo metadata`");
-        let is_default = *universe == Default::default();
-        cargo_get_lockfile_and_metadata(config, args, paths, features, is_default)?
+        let (lockfile, metadata) = cargo_get_lockfile_and_metadata(
+            config,
+            args,
+            paths,
+            features.clone(),
+            is_default,
+            None,
+        )?;
+        let platform_features = if config.cargo.per_platform_features {
+            resolve_platform_features(config, args, paths, &features, is_default)?
+        } else {
+            BTreeMap::new()
+        };
+        let resolver = match config.cargo.resolver {
+            Some(resolver) => resolver,
+            None => workspace_resolver(&paths.manifest_path)?,
+        };
+        let normal_features = if resolver == Resolver::V2 {
+            Some(cargo_get_normal_features(
+                config, args, paths, &features, is_default,
+            )?)
+        } else {
+            None
+        };
+        (lockfile, metadata, platform_features, normal_features)
     };
 
     log::trace!("Metadata {:#?}", metadata);
 
-    let index = index::Index::new(config.include_top_level, &metadata)?;
+    fixup_usage.record(&metadata.packages);
+
+    let mut index = index::Index::new(config.include_top_level, &metadata)?;
+    if let Some(template) = &config.buck.alias_name_template {
+        index.apply_alias_name_template(template)?;
+    }
+    index.set_platform_features(platform_features);
+    if let Some(normal_features) = &normal_features {
+        index.exclude_dev_features(normal_features);
+    }
     crate::universe::validate_universe_config(universe, universe_config, &index)?;
 
+    let cargo_env = match crate::vendor::read_cargo_config(paths)? {
+        Some(remap_config) => remap_config
+            .env
+            .into_iter()
+            .filter(|(name, _)| !config.cargo.env_exclude.contains(name))
+            .collect(),
+        None => BTreeMap::new(),
+    };
+
+    let platform_gates = platform_gates(config, &index);
+
     let context = &RuleContext {
         config,
         paths,
         index,
         lockfile,
+        cargo_env,
         done: Mutex::new(HashSet::new()),
+        with_fixups: Mutex::new(HashSet::new()),
+        unresolved,
+        explain_platform,
+        platform_gates,
     };
 
     let (tx, rx) = mpsc::channel();
@@ -1000,6 +1797,43 @@ fn buckify_for_universe(
         }
     };
 
+    // With only one platform there's no need for any platform-specific blocks.
+    if config.platforms_restricted && config.platform.len() == 1 {
+        rules = rules.into_iter().map(Rule::collapse_platforms).collect();
+    }
+
+    // Check the licenses and advisories of every package which made it into
+    // the rules.
+    let included: HashSet<&PkgId> = context
+        .done
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(pkgid, _)| *pkgid)
+        .collect();
+    crate::license::check_licenses(
+        &config.audit,
+        metadata
+            .packages
+            .iter()
+            .filter(|pkg| included.contains(&pkg.id)),
+    )?;
+    crate::audit_sec::check_vulnerabilities(
+        config,
+        metadata
+            .packages
+            .iter()
+            .filter(|pkg| included.contains(&pkg.id)),
+    )?;
+
+    let with_fixups = context.with_fixups.lock().unwrap();
+    fixup_usage.record_with_fixups(
+        metadata
+            .packages
+            .iter()
+            .filter(|pkg| with_fixups.contains(&pkg.id)),
+    );
+
     // Fill in all http_archive rules with all the sub_targets which got
     // mentioned by fixups.
     if config.vendor.is_none() {
@@ -1016,15 +1850,31 @@ fn buckify_for_universe(
         for rule in &rules {
             match rule {
                 Rule::Binary(rule) | Rule::BuildscriptBinary(rule) => {
-                    rule.common.base.mapped_srcs.keys().for_each(&mut insert);
+                    rule.common
+                        .base
+                        .mapped_srcs
+                        .keys()
+                        .filter_map(MappedSrc::source)
+                        .for_each(&mut insert);
                     for plat in rule.common.platform.values() {
-                        plat.mapped_srcs.keys().for_each(&mut insert);
+                        plat.mapped_srcs
+                            .keys()
+                            .filter_map(MappedSrc::source)
+                            .for_each(&mut insert);
                     }
                 }
                 Rule::Library(rule) => {
-                    rule.common.base.mapped_srcs.keys().for_each(&mut insert);
+                    rule.common
+                        .base
+                        .mapped_srcs
+                        .keys()
+                        .filter_map(MappedSrc::source)
+                        .for_each(&mut insert);
                     for plat in rule.common.platform.values() {
-                        plat.mapped_srcs.keys().for_each(&mut insert);
+                        plat.mapped_srcs
+                            .keys()
+                            .filter_map(MappedSrc::source)
+                            .for_each(&mut insert);
                     }
                 }
                 Rule::CxxLibrary(rule) => {
@@ -1035,7 +1885,11 @@ fn buckify_for_universe(
                         SetOrMap::Map(map) => map.values().for_each(&mut insert),
                     }
                 }
-                Rule::PrebuiltCxxLibrary(rule) => insert(&rule.static_lib),
+                Rule::Filegroup(rule) => rule.srcs.values().for_each(&mut insert),
+                Rule::PrebuiltCxxLibrary(rule) => {
+                    insert(&rule.static_lib);
+                    rule.header_dirs.iter().for_each(&mut insert);
+                }
                 _ => {}
             }
         }
@@ -1055,18 +1909,222 @@ fn buckify_for_universe(
     Ok(rules)
 }
 
-pub(crate) fn buckify(
+/// Generate the rules for every universe, without writing them anywhere or
+/// reporting unresolved fixups.
+pub(crate) fn generate_all_rules(
     config: &Config,
     args: &Args,
     paths: &Paths,
-    stdout: bool,
-) -> anyhow::Result<()> {
+) -> anyhow::Result<Vec<Rule>> {
+    let unresolved = Mutex::new(Vec::new());
+    let mut rules = Vec::new();
+    for universe in config.universe.keys() {
+        rules.extend(buckify_for_universe(
+            config,
+            args,
+            paths,
+            universe,
+            &unresolved,
+            &mut FixupUsage::default(),
+            None,
+        )?);
+    }
+    Ok(rules)
+}
+
+fn buckify_universes(
+    config: &Config,
+    args: &Args,
+    paths: &Paths,
+    unresolved: &Mutex<Vec<UnresolvedFixup>>,
+    fixup_usage: &mut FixupUsage,
+    explain_platform: Option<&str>,
+) -> anyhow::Result<BTreeMap<UniverseName, BTreeSet<Rule>>> {
     let mut rules = BTreeMap::new();
     for universe in config.universe.keys().cloned() {
-        let universe_rules = buckify_for_universe(config, args, paths, &universe)?;
+        let universe_rules = buckify_for_universe(
+            config,
+            args,
+            paths,
+            &universe,
+            unresolved,
+            fixup_usage,
+            explain_platform,
+        )?;
         rules.insert(universe, universe_rules);
     }
-    let rules = crate::universe::merge_universes(&config.universe, rules)?;
+    Ok(rules)
+}
+
+/// Generate the rules again from a copy of `config` whose hash-based
+/// collections iterate in a different order, and check they render the same
+/// as `rules`.
+fn check_determinism(
+    config: &Config,
+    args: &Args,
+    paths: &Paths,
+    rules: &BTreeSet<Rule>,
+) -> anyhow::Result<()> {
+    measure_time::info_time!("Checking output is deterministic");
+
+    let rehashed = config.rehash();
+    let unresolved = Mutex::new(Vec::new());
+    let universes = buckify_universes(
+        &rehashed,
+        args,
+        paths,
+        &unresolved,
+        &mut FixupUsage::default(),
+        None,
+    )?;
+    let rules_again = crate::universe::merge_universes(&rehashed.universe, universes)?;
+
+    let mut first = Vec::new();
+    buck::write_buckfile(&config.buck, rules.iter(), &mut first)?;
+    let mut second = Vec::new();
+    buck::write_buckfile(&config.buck, rules_again.iter(), &mut second)?;
+    compare_renders(&first, &second)
+}
+
+fn compare_renders(first: &[u8], second: &[u8]) -> anyhow::Result<()> {
+    let first = String::from_utf8_lossy(first);
+    let second = String::from_utf8_lossy(second);
+    for (i, lines) in first.lines().zip_longest(second.lines()).enumerate() {
+        let (a, b) = lines.map_any(Some, Some).or(None, None);
+        if a != b {
+            bail!(
+                "Generated rules are nondeterministic, differing at line {}:\n  first:  {}\n  second: {}",
+                i + 1,
+                a.unwrap_or("<end of file>"),
+                b.unwrap_or("<end of file>"),
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Check every target the rules refer to is either one of the rules or
+/// matches one of the `[buck] external_targets` patterns.
+fn verify_rule_refs(config: &Config, rules: &BTreeSet<Rule>) -> anyhow::Result<()> {
+    let generated: HashSet<String> = rules
+        .iter()
+        .map(|rule| format!(":{}", rule.get_name()))
+        .collect();
+
+    let mut dangling = Vec::new();
+    for rule in rules {
+        for target in rule.rule_refs() {
+            let known = if target.starts_with(':') {
+                generated.contains(&target)
+            } else {
+                config
+                    .buck
+                    .external_targets
+                    .iter()
+                    .any(|pattern| target_pattern_matches(pattern, &target))
+            };
+            if !known {
+                dangling.push(format!("  {} -> {}\n", rule.get_name(), target));
+            }
+        }
+    }
+
+    if !dangling.is_empty() {
+        bail!(
+            "Generated rules refer to targets which don't exist:\n{}",
+            dangling.concat(),
+        );
+    }
+    Ok(())
+}
+
+/// Match a target against a Buck target pattern: `cell//dir/...` for anything
+/// in or below `dir`, `cell//dir:` for anything in the package, or a single
+/// target.
+fn target_pattern_matches(pattern: &str, target: &str) -> bool {
+    if let Some(prefix) = pattern.strip_suffix("...") {
+        if prefix.ends_with("//") {
+            return target.starts_with(prefix);
+        }
+        let Some(dir) = prefix.strip_suffix('/') else {
+            return false;
+        };
+        target
+            .strip_prefix(dir)
+            .is_some_and(|rest| rest.starts_with(['/', ':']))
+    } else if pattern.ends_with(':') {
+        target
+            .strip_prefix(pattern)
+            .is_some_and(|name| !name.is_empty())
+    } else {
+        pattern == target
+    }
+}
+
+pub(crate) fn buckify(
+    config: &Config,
+    args: &Args,
+    paths: &Paths,
+    stdout: bool,
+    explain_platform: Option<&str>,
+    check_determinism: bool,
+    verify: bool,
+) -> anyhow::Result<RuleStats> {
+    let unresolved = Mutex::new(Vec::new());
+    let mut fixup_usage = FixupUsage::default();
+    let rules = buckify_universes(
+        config,
+        args,
+        paths,
+        &unresolved,
+        &mut fixup_usage,
+        explain_platform,
+    )?;
+
+    if config.report_unused_fixups {
+        let unused = fixup_usage.unused(config, paths)?;
+        for unused in &unused {
+            log::warn!("Unused fixup: {unused}");
+        }
+        if unused.is_empty() {
+            log::info!("No unused fixups");
+        } else {
+            log::warn!("{} unused fixups", unused.len());
+        }
+    }
+
+    if let Some(report_path) = &config.unresolved_fixup_report {
+        let mut unresolved = unresolved.into_inner().unwrap();
+        unresolved.sort();
+        unresolved.dedup();
+        write_unresolved_fixup_report(&paths.third_party_dir.join(report_path), &unresolved)?;
+        if config.unresolved_fixup_error && !unresolved.is_empty() {
+            if let Some(custom_err_msg) = config.unresolved_fixup_error_message.as_ref() {
+                log::warn!(
+                    "Additional info on how to fix unresolved fixup errors: {}",
+                    custom_err_msg
+                );
+            }
+            bail!(
+                "{} unresolved fixups, see {}; fix them and rerun buckify.",
+                unresolved.len(),
+                report_path.display(),
+            );
+        }
+    }
+    let mut rules = crate::universe::merge_universes(&config.universe, rules)?;
+
+    if check_determinism {
+        self::check_determinism(config, args, paths, &rules)?;
+    }
+
+    if verify {
+        verify_rule_refs(config, &rules)?;
+    }
+
+    let mut stats = RuleStats::tally(&rules);
+    stats.need_fixups = fixup_usage.packages_with_fixups();
+    let metadata = take_aggregate_metadata(config, &mut rules);
 
     // Emit build rules to stdout
     if stdout {
@@ -1075,34 +2133,297 @@ pub(crate) fn buckify(
         // Ignore error, for example pipe closed resulting from
         // `reindeer buckify --stdout | head`.
         let _ = io::stdout().write_all(&out);
-        return Ok(());
+        return Ok(stats);
     }
 
-    // Write build rules to file
-    let buckpath = paths.third_party_dir.join(&config.buck.file_name);
-    {
-        measure_time::trace_time!("Write build rules to file");
+    if let Some(rules_json) = &config.rules_json {
+        let mut out = Vec::new();
+        buck::write_rules_json(&config.buck, rules.iter(), &mut out)
+            .context("writing rules json")?;
+        fs::write(rules_json, out)
+            .with_context(|| format!("Failed to write {}", rules_json.display()))?;
+    }
+
+    // Write build rules to file. Only files whose content changed get
+    // rewritten, so with `split_per_package` a small update leaves most of
+    // them alone.
+    measure_time::trace_time!("Write build rules to file");
+    let files = render_files(config, paths, &rules, &metadata)?;
+    let total = files.len();
+    let mut changed = 0;
+    for (path, out) in files {
+        let path = paths.third_party_dir.join(path);
+        if write_if_changed(&path, &out)? {
+            log::debug!("Rewrote {}", path.display());
+            changed += 1;
+        }
+    }
+    log::info!("{changed} of {total} generated files changed");
+
+    Ok(stats)
+}
+
+/// With `metadata_aggregate`, pull the metadata out of the rules to go in its
+/// own file.
+fn take_aggregate_metadata(config: &Config, rules: &mut BTreeSet<Rule>) -> Vec<CrateMetadata> {
+    let mut metadata = Vec::new();
+    if config.emit_metadata && config.buck.metadata_aggregate {
+        *rules = mem::take(rules)
+            .into_iter()
+            .map(|mut rule| {
+                if let Rule::Library(lib) | Rule::RootPackage(lib) = &mut rule {
+                    metadata.extend(lib.metadata.take());
+                }
+                rule
+            })
+            .collect();
+    }
+    metadata
+}
 
+/// Render every generated file, keyed by its path relative to the third-party
+/// dir: the BUCK file, or one per vendored package with `split_per_package`,
+/// and METADATA.bzl with `metadata_aggregate`.
+fn render_files(
+    config: &Config,
+    paths: &Paths,
+    rules: &BTreeSet<Rule>,
+    metadata: &[CrateMetadata],
+) -> anyhow::Result<Vec<(PathBuf, Vec<u8>)>> {
+    let mut files = Vec::new();
+    if config.buck.split_per_package && config.vendor.is_some() {
+        let root = buck_cell_path(&paths.third_party_dir)?;
+        let buckfiles =
+            buck::write_split_buckfiles(&config.buck, &root, Path::new("vendor"), rules.iter())
+                .context("writing buck files")?;
+        for (dir, out) in buckfiles {
+            files.push((dir.join(&config.buck.file_name), out));
+        }
+    } else {
+        if config.buck.split_per_package {
+            log::warn!("Ignoring `split_per_package`, which only applies to vendored packages");
+        }
         let mut out = Vec::new();
         buck::write_buckfile(&config.buck, rules.iter(), &mut out).context("writing buck file")?;
-        if !fs::read(&buckpath).is_ok_and(|x| x == out) {
-            fs::write(&buckpath, out)
-                .with_context(|| format!("write {} file", buckpath.display()))?;
-        }
+        files.push((PathBuf::from(&*config.buck.file_name), out));
+    }
+
+    if config.emit_metadata && config.buck.metadata_aggregate {
+        let mut out = Vec::new();
+        buck::write_metadata_file(&config.buck, metadata, &mut out)
+            .context("writing metadata file")?;
+        let path = Path::new(&*config.buck.file_name).with_file_name(buck::METADATA_FILE_NAME);
+        files.push((path, out));
+    }
+
+    Ok(files)
+}
+
+/// `reindeer check`: generate the rules in memory and report every way the
+/// committed files fall short, failing if there's anything to report. Checks
+/// that the generated files are up to date, that every target the rules refer
+/// to exists, and that no build script is missing its fixup.
+pub(crate) fn check(config: &Config, args: &Args, paths: &Paths) -> anyhow::Result<()> {
+    let unresolved = Mutex::new(Vec::new());
+    let universes = buckify_universes(
+        config,
+        args,
+        paths,
+        &unresolved,
+        &mut FixupUsage::default(),
+        None,
+    )?;
+    let mut rules = crate::universe::merge_universes(&config.universe, universes)?;
+
+    let mut problems = Vec::new();
+
+    let metadata = take_aggregate_metadata(config, &mut rules);
+    let stale: Vec<String> = render_files(config, paths, &rules, &metadata)?
+        .into_iter()
+        .filter(|(path, out)| {
+            fs::read(paths.third_party_dir.join(path)).map_or(true, |existing| existing != *out)
+        })
+        .map(|(path, _out)| format!("  {}\n", path.display()))
+        .collect();
+    if !stale.is_empty() {
+        problems.push(format!(
+            "{} generated files are out of date; rerun `reindeer buckify`:\n{}",
+            stale.len(),
+            stale.concat(),
+        ));
+    }
+
+    if let Err(err) = verify_rule_refs(config, &rules) {
+        problems.push(err.to_string());
+    }
+
+    let mut unresolved = unresolved.into_inner().unwrap();
+    unresolved.sort();
+    unresolved.dedup();
+    if !unresolved.is_empty() {
+        let list: Vec<String> = unresolved
+            .iter()
+            .map(|fixup| {
+                format!(
+                    "  {} {}: {}\n",
+                    fixup.package,
+                    fixup.version,
+                    fixup.fixup_file.display(),
+                )
+            })
+            .collect();
+        problems.push(format!(
+            "{} build scripts need fixups:\n{}",
+            unresolved.len(),
+            list.concat(),
+        ));
+    }
+
+    if !problems.is_empty() {
+        bail!(
+            "reindeer check found {} problems:\n\n{}",
+            problems.len(),
+            problems.join("\n"),
+        );
+    }
+    Ok(())
+}
+
+/// Write a generated file unless it already has exactly this content, leaving
+/// its modification time alone. Returns whether it was written.
+fn write_if_changed(path: &Path, content: &[u8]) -> anyhow::Result<bool> {
+    if fs::read(path).is_ok_and(|existing| existing == content) {
+        return Ok(false);
     }
+    fs::write(path, content).with_context(|| format!("write {} file", path.display()))?;
+    Ok(true)
+}
 
-    log::trace!(
-        "{} file written to {}",
-        config.buck.file_name,
-        buckpath.display()
+/// Path of `third_party_dir` from the root of its Buck cell, which is the
+/// nearest directory above it with a .buckconfig.
+fn buck_cell_path(third_party_dir: &Path) -> anyhow::Result<PathBuf> {
+    for dir in third_party_dir.ancestors() {
+        if dir.join(".buckconfig").is_file() {
+            return Ok(relative_path(dir, third_party_dir));
+        }
+    }
+    bail!(
+        "`split_per_package` needs a .buckconfig in or above {} to work out labels",
+        third_party_dir.display(),
     );
+}
 
+fn write_unresolved_fixup_report(
+    path: &Path,
+    unresolved: &[UnresolvedFixup],
+) -> anyhow::Result<()> {
+    let mut out = serde_json::to_vec_pretty(unresolved)?;
+    out.push(b'\n');
+    fs::write(path, out).with_context(|| format!("write {}", path.display()))?;
+    log::info!(
+        "Wrote {} unresolved fixups to {}",
+        unresolved.len(),
+        path.display()
+    );
     Ok(())
 }
 
 #[cfg(test)]
 mod test {
+    use std::collections::BTreeMap;
+    use std::collections::BTreeSet;
+    use std::fs;
+    use std::path::Path;
+    use std::path::PathBuf;
+
+    use clap::Parser as _;
+
+    use super::compare_renders;
+    use super::generate_all_rules;
+    use super::hoist_common_deps;
+    use super::license_file_in_package;
+    use super::place_dep;
+    use super::place_proc_macro_dep;
+    use super::platform_gates;
+    use super::render_files;
+    use super::required_features_platforms;
     use super::short_name_for_git_repo;
+    use super::target_crate_root;
+    use super::target_pattern_matches;
+    use super::verify_rule_refs;
+    use super::write_if_changed;
+    use crate::buck::Alias;
+    use crate::buck::Name;
+    use crate::buck::PlatformRustCommon;
+    use crate::buck::Rule;
+    use crate::buck::RuleRef;
+    use crate::buck::StringOrPath;
+    use crate::buck::Visibility;
+    use crate::cargo::ArtifactKind;
+    use crate::cargo::CrateType;
+    use crate::cargo::Edition;
+    use crate::cargo::ManifestTarget;
+    use crate::cargo::NodeDepKind;
+    use crate::cargo::TargetKind;
+    use crate::config::Config;
+    use crate::index::Index;
+    use crate::platform::PlatformExpr;
+    use crate::testing::cargo_metadata;
+    use crate::testing::package;
+    use crate::testing::paths;
+    use crate::testing::write;
+    use crate::Args;
+
+    fn target(
+        name: &str,
+        kind: TargetKind,
+        crate_type: CrateType,
+        src_path: &str,
+    ) -> ManifestTarget {
+        ManifestTarget {
+            name: name.to_owned(),
+            kind: BTreeSet::from([kind]),
+            crate_types: BTreeSet::from([crate_type]),
+            src_path: PathBuf::from(src_path),
+            edition: None,
+            required_features: BTreeSet::new(),
+            doctest: false,
+        }
+    }
+
+    #[test]
+    fn crate_roots_for_lib_and_bins() {
+        let manifest_dir = Path::new("/vendor/dual-1.0.0");
+        let mapped_manifest_dir = Path::new("vendor/dual-1.0.0");
+        let lib = target(
+            "dual",
+            TargetKind::Lib,
+            CrateType::Lib,
+            "/vendor/dual-1.0.0/src/lib.rs",
+        );
+        let cli = target(
+            "dual-cli",
+            TargetKind::Bin,
+            CrateType::Bin,
+            "/vendor/dual-1.0.0/./tools/cli/entry.rs",
+        );
+        let helper = target(
+            "helper",
+            TargetKind::Bin,
+            CrateType::Bin,
+            "/vendor/dual-1.0.0/src/bin/../../helper/main.rs",
+        );
+        let crate_root = |tgt| target_crate_root(mapped_manifest_dir, manifest_dir, tgt);
+        assert_eq!(crate_root(&lib), Path::new("vendor/dual-1.0.0/src/lib.rs"),);
+        assert_eq!(
+            crate_root(&cli),
+            Path::new("vendor/dual-1.0.0/tools/cli/entry.rs"),
+        );
+        assert_eq!(
+            crate_root(&helper),
+            Path::new("vendor/dual-1.0.0/helper/main.rs"),
+        );
+    }
 
     #[test]
     fn hashes_with_same_repo_variations() {
@@ -1126,4 +2447,539 @@ mod test {
             "gilrs-bbe0e8b5f013041b",
         );
     }
+
+    #[test]
+    fn hoists_deps_covering_every_platform() {
+        let config: Config = toml::from_str(
+            "[platform.linux]\ntarget_family = [\"unix\"]\n\
+             [platform.windows]\ntarget_family = [\"windows\"]\n",
+        )
+        .unwrap();
+        let dep = |target: &str, platform: &str| {
+            RuleRef::new(target.to_owned())
+                .with_platform(Some(&PlatformExpr::from(platform.to_owned())))
+        };
+
+        let mut base = PlatformRustCommon::default();
+        let mut perplat = BTreeMap::new();
+        for name in config.platform.keys() {
+            let (family, only) = if name.to_string() == "linux" {
+                ("cfg(unix)", dep(":libc", "cfg(unix)"))
+            } else {
+                ("cfg(windows)", dep(":windows-sys", "cfg(windows)"))
+            };
+            let mut rule = PlatformRustCommon::default();
+            rule.deps.unwrap_mut().insert(dep(":errno", family));
+            rule.deps.unwrap_mut().insert(only);
+            rule.named_deps
+                .unwrap_mut()
+                .insert("sys".to_owned(), dep(":errno-sys", family));
+            perplat.insert(name.clone(), rule);
+        }
+
+        hoist_common_deps(&config, &mut base, &mut perplat);
+
+        let targets = |rule: &PlatformRustCommon| {
+            rule.deps
+                .unwrap_ref()
+                .iter()
+                .map(|dep| dep.target.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(targets(&base), [":errno"]);
+        assert_eq!(base.named_deps.unwrap_ref()["sys"].target, ":errno-sys");
+        for (name, rule) in &perplat {
+            let only = if name.to_string() == "linux" {
+                ":libc"
+            } else {
+                ":windows-sys"
+            };
+            assert_eq!(targets(rule), [only]);
+            assert!(rule.named_deps.unwrap_ref().is_empty());
+        }
+    }
+
+    #[test]
+    fn proc_macro_host_deps() {
+        let mut config: Config = toml::from_str(
+            "host_platform = \"linux-x86_64\"\n\n\
+             [platform.linux-x86_64]\ntarget_family = [\"unix\"]\ntarget_os = [\"linux\"]\n\n\
+             [platform.my-embedded]\ntarget_family = []\ntarget_os = [\"none\"]\n",
+        )
+        .unwrap();
+        // Only building for the embedded target still builds proc macros for
+        // the host.
+        config
+            .restrict_platforms(&["my-embedded".to_owned()])
+            .unwrap();
+
+        let mut base = PlatformRustCommon::default();
+        let mut perplat = BTreeMap::new();
+        let mut place = |target: &str, expr: &str| {
+            let dep = RuleRef::new(target.to_owned())
+                .with_platform(Some(&PlatformExpr::from(expr.to_owned())));
+            place_proc_macro_dep(
+                &config,
+                &mut base,
+                &mut perplat,
+                dep,
+                None,
+                &NodeDepKind::ORDINARY,
+                None,
+            )
+            .unwrap()
+        };
+        assert!(place(":proc-macro-crate-3.0.0", "cfg(unix)"));
+        assert!(!place(":windows-sys-0.59.0", "cfg(windows)"));
+        assert!(!place(":embedded-hal-1.0.0", "cfg(target_os = \"none\")"));
+
+        let targets: Vec<_> = base
+            .deps
+            .unwrap_ref()
+            .iter()
+            .map(|dep| dep.target.as_str())
+            .collect();
+        assert_eq!(targets, [":proc-macro-crate-3.0.0"]);
+        assert!(perplat.is_empty());
+    }
+
+    #[test]
+    fn feature_gated_binary() {
+        let config: Config = toml::from_str(
+            "[platform.linux]\ntarget_os = [\"linux\"]\n\n\
+             [platform.windows]\ntarget_os = [\"windows\"]\n",
+        )
+        .unwrap();
+        let linux = config
+            .platform
+            .keys()
+            .find(|name| name.to_string() == "linux")
+            .unwrap();
+
+        // `cli = { required-features = ["clap", "epoll"] }`, where `clap` is
+        // enabled everywhere and `epoll` only on Linux.
+        let mut base = PlatformRustCommon::default();
+        base.features.unwrap_mut().insert("clap".to_owned());
+        let mut perplat = BTreeMap::new();
+        perplat
+            .entry(linux.clone())
+            .or_insert_with(PlatformRustCommon::default)
+            .features
+            .unwrap_mut()
+            .insert("epoll".to_owned());
+        let required = |features: &[&str]| {
+            let features: BTreeSet<String> = features.iter().map(|f| f.to_string()).collect();
+            required_features_platforms(&config, &features, &base, &perplat)
+        };
+
+        assert_eq!(required(&[]), None);
+        assert_eq!(required(&["clap"]), None);
+        assert_eq!(required(&["clap", "epoll"]), Some(vec![linux]));
+        assert_eq!(required(&["clap", "unstable"]), Some(vec![]));
+    }
+
+    #[test]
+    fn feature_gated_binary_rules() {
+        let tempdir = tempfile::tempdir().unwrap();
+        package(
+            tempdir.path(),
+            "dep",
+            "[features]\nextra = []\nother = []\n",
+        );
+        let bin = |name: &str, feature: &str| {
+            format!(
+                "[[bin]]\nname = \"{name}\"\npath = \"src/main.rs\"\n\
+                 required-features = [\"{feature}\"]\n\n"
+            )
+        };
+        package(
+            tempdir.path(),
+            "tool",
+            &format!(
+                "[features]\ndefault = [\"cli\"]\ncli = []\nunused = []\n\n\
+                 [dependencies]\ndep = {{ path = \"../dep\", features = [\"extra\"] }}\n\n\
+                 {}{}{}{}",
+                bin("with-cli", "cli"),
+                bin("with-unused", "unused"),
+                bin("with-dep-extra", "dep/extra"),
+                bin("with-dep-other", "dep/other"),
+            ),
+        );
+        let third_party_dir = tempdir.path().join("tool");
+        write(&third_party_dir.join("src/main.rs"), "fn main() {}\n");
+
+        let config: Config = toml::from_str("vendor = false\ninclude_top_level = true\n").unwrap();
+        let args = Args::parse_from(["reindeer", "buckify"]);
+        let rules = generate_all_rules(&config, &args, &paths(&third_party_dir)).unwrap();
+        let binaries: Vec<&str> = rules
+            .iter()
+            .filter_map(|rule| match rule {
+                Rule::Binary(binary) => Some(binary.common.common.name.0.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            binaries,
+            ["tool-1.0.0-with-cli", "tool-1.0.0-with-dep-extra"]
+        );
+    }
+
+    #[test]
+    fn edition_2024_rule() {
+        let tempdir = tempfile::tempdir().unwrap();
+        write(
+            &tempdir.path().join("new/Cargo.toml"),
+            "cargo-features = [\"edition2024\"]\n\n\
+             [package]\nname = \"new\"\nversion = \"1.0.0\"\nedition = \"2024\"\n",
+        );
+        write(&tempdir.path().join("new/src/lib.rs"), "");
+        package(
+            tempdir.path(),
+            "top",
+            "[dependencies]\nnew = { path = \"../new\" }\n",
+        );
+
+        let config: Config = toml::from_str("vendor = false\n").unwrap();
+        let args = Args::parse_from(["reindeer", "buckify"]);
+        let rules =
+            generate_all_rules(&config, &args, &paths(&tempdir.path().join("top"))).unwrap();
+        let editions: Vec<_> = rules
+            .iter()
+            .filter_map(|rule| match rule {
+                Rule::Library(lib) => Some((lib.common.common.name.0.as_str(), lib.common.edition)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(editions, [("new-1.0.0", Edition::Rust2024)]);
+    }
+
+    #[test]
+    fn platform_gated_named_dep() {
+        let config: Config = toml::from_str("").unwrap();
+        let mut base = PlatformRustCommon::default();
+        let mut perplat = BTreeMap::new();
+
+        let winfoo = RuleRef::new(":foo-1.0.0".to_owned())
+            .with_platform(Some(&PlatformExpr::from("cfg(windows)".to_owned())));
+        let placed = place_dep(
+            &config,
+            &mut base,
+            &mut perplat,
+            winfoo,
+            Some("winfoo"),
+            &NodeDepKind::ORDINARY,
+            None,
+        )
+        .unwrap();
+        assert!(placed);
+        let bar = RuleRef::new(":bar-1.0.0".to_owned());
+        place_dep(
+            &config,
+            &mut base,
+            &mut perplat,
+            bar,
+            Some("renamed_bar"),
+            &NodeDepKind::ORDINARY,
+            None,
+        )
+        .unwrap();
+
+        hoist_common_deps(&config, &mut base, &mut perplat);
+
+        let named = |rule: &PlatformRustCommon| {
+            rule.named_deps
+                .unwrap_ref()
+                .iter()
+                .map(|(rename, dep)| (rename.clone(), dep.target.clone()))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            named(&base),
+            [("renamed_bar".to_owned(), ":bar-1.0.0".to_owned())],
+        );
+        assert!(base.deps.unwrap_ref().is_empty());
+        let windows: Vec<_> = perplat.keys().map(ToString::to_string).collect();
+        assert_eq!(windows, ["windows-gnu", "windows-msvc"]);
+        for rule in perplat.values() {
+            assert_eq!(
+                named(rule),
+                [("winfoo".to_owned(), ":foo-1.0.0".to_owned())],
+            );
+            assert!(rule.deps.unwrap_ref().is_empty());
+        }
+    }
+
+    #[test]
+    fn artifact_bin_dep_env() {
+        let config: Config = toml::from_str("").unwrap();
+        let mut base = PlatformRustCommon::default();
+        let mut perplat = BTreeMap::new();
+
+        // protoc-gen = { version = "1", artifact = "bin" }, which has binaries
+        // `protoc-gen` and `protoc-gen-lint`
+        for bin_name in ["protoc-gen", "protoc-gen-lint"] {
+            let dep_kind = NodeDepKind {
+                artifact: Some(ArtifactKind::Bin),
+                extern_name: Some("protoc_gen".to_owned()),
+                bin_name: Some(bin_name.to_owned()),
+                ..NodeDepKind::ORDINARY
+            };
+            let dep = RuleRef::new(":protoc-gen-1.0.0".to_owned());
+            place_dep(
+                &config,
+                &mut base,
+                &mut perplat,
+                dep,
+                None,
+                &dep_kind,
+                Some(bin_name),
+            )
+            .unwrap();
+        }
+
+        assert!(base.deps.unwrap_ref().is_empty());
+        assert!(base.named_deps.unwrap_ref().is_empty());
+        let location =
+            |bin: &str| StringOrPath::String(format!("$(location :protoc-gen-1.0.0-{bin})"));
+        assert_eq!(
+            *base.env.unwrap_ref(),
+            BTreeMap::from([
+                (
+                    "CARGO_BIN_FILE_PROTOC_GEN".to_owned(),
+                    location("protoc-gen")
+                ),
+                (
+                    "CARGO_BIN_FILE_PROTOC_GEN_protoc-gen".to_owned(),
+                    location("protoc-gen"),
+                ),
+                (
+                    "CARGO_BIN_FILE_PROTOC_GEN_protoc-gen-lint".to_owned(),
+                    location("protoc-gen-lint"),
+                ),
+            ]),
+        );
+    }
+
+    #[test]
+    fn artifact_lib_dep_env() {
+        let config: Config = toml::from_str("").unwrap();
+        let env = |artifact: ArtifactKind| {
+            let mut base = PlatformRustCommon::default();
+            let dep_kind = NodeDepKind {
+                artifact: Some(artifact),
+                extern_name: Some("zstd_ffi".to_owned()),
+                ..NodeDepKind::ORDINARY
+            };
+            let dep = RuleRef::new(":zstd-ffi-0.5.0".to_owned());
+            place_dep(
+                &config,
+                &mut base,
+                &mut BTreeMap::new(),
+                dep,
+                None,
+                &dep_kind,
+                Some("zstd_ffi"),
+            )
+            .unwrap();
+            assert!(base.deps.unwrap_ref().is_empty());
+            base.env.unwrap_ref().clone()
+        };
+        let location = |subtarget: &str| {
+            StringOrPath::String(format!("$(location :zstd-ffi-0.5.0[{subtarget}])"))
+        };
+
+        assert_eq!(
+            env(ArtifactKind::Staticlib),
+            BTreeMap::from([
+                (
+                    "CARGO_STATICLIB_FILE_ZSTD_FFI".to_owned(),
+                    location("staticlib")
+                ),
+                (
+                    "CARGO_STATICLIB_FILE_ZSTD_FFI_zstd_ffi".to_owned(),
+                    location("staticlib"),
+                ),
+            ]),
+        );
+        assert_eq!(
+            env(ArtifactKind::Cdylib),
+            BTreeMap::from([
+                ("CARGO_CDYLIB_FILE_ZSTD_FFI".to_owned(), location("cdylib")),
+                (
+                    "CARGO_CDYLIB_FILE_ZSTD_FFI_zstd_ffi".to_owned(),
+                    location("cdylib"),
+                ),
+            ]),
+        );
+    }
+
+    #[test]
+    fn compare_renders_reports_first_difference() {
+        let first = b"rule(\n    name = \"a\",\n    deps = [\":x\", \":y\"],\n)\n";
+        let second = b"rule(\n    name = \"a\",\n    deps = [\":y\", \":x\"],\n)\n";
+        assert!(compare_renders(first, first).is_ok());
+
+        let err = compare_renders(first, second).unwrap_err().to_string();
+        assert!(err.contains("line 3"), "{err}");
+        assert!(err.contains(r#"deps = [":y", ":x"],"#), "{err}");
+
+        let err = compare_renders(first, &first[..first.len() - 2]).unwrap_err();
+        assert!(err.to_string().contains("<end of file>"), "{err}");
+    }
+
+    #[test]
+    fn gates_crates_reachable_on_one_os() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let package = |name: &str, extra: &str| package(tempdir.path(), name, extra);
+        package("everywhere", "");
+        package("winhelper", "");
+        package("unixonly", "");
+        package("winbuild", "");
+        package("winmacro", "[lib]\nproc-macro = true\n");
+        package(
+            "winonly",
+            "[dependencies]\nwinhelper = { path = \"../winhelper\" }\n\
+             winmacro = { path = \"../winmacro\" }\n\n\
+             [build-dependencies]\nwinbuild = { path = \"../winbuild\" }\n",
+        );
+        package(
+            "app",
+            "[dependencies]\neverywhere = { path = \"../everywhere\" }\n\n\
+             [target.'cfg(windows)'.dependencies]\nwinonly = { path = \"../winonly\" }\n\n\
+             [target.'cfg(unix)'.dependencies]\nunixonly = { path = \"../unixonly\" }\n",
+        );
+
+        let metadata = cargo_metadata(&tempdir.path().join("app"));
+        let index = Index::new(false, &metadata).unwrap();
+
+        let config: Config = toml::from_str(
+            "[buck]\nos_constraints = { windows = \"prelude//os:windows\", linux = \"prelude//os:linux\" }\n",
+        )
+        .unwrap();
+        let gates: BTreeMap<_, _> = platform_gates(&config, &index)
+            .into_iter()
+            .map(|(pkgid, constraint)| {
+                let pkg = metadata
+                    .packages
+                    .iter()
+                    .find(|pkg| pkg.id == *pkgid)
+                    .unwrap();
+                (pkg.name.as_str(), constraint.target)
+            })
+            .collect();
+        assert_eq!(
+            gates,
+            BTreeMap::from([
+                ("winhelper", "prelude//os:windows".to_owned()),
+                ("winonly", "prelude//os:windows".to_owned()),
+            ]),
+        );
+    }
+
+    #[test]
+    fn test_target_pattern_matches() {
+        assert!(target_pattern_matches(
+            "prelude//...",
+            "prelude//os:windows"
+        ));
+        assert!(!target_pattern_matches(
+            "prelude//...",
+            "preludes//os:windows"
+        ));
+        assert!(target_pattern_matches(
+            "//third-party/...",
+            "//third-party:foo"
+        ));
+        assert!(target_pattern_matches(
+            "//third-party/...",
+            "//third-party/cxx:foo"
+        ));
+        assert!(!target_pattern_matches(
+            "//third-party/...",
+            "//third-party-extra:foo"
+        ));
+        assert!(target_pattern_matches(
+            "//third-party:",
+            "//third-party:foo"
+        ));
+        assert!(!target_pattern_matches(
+            "//third-party:",
+            "//third-party/cxx:foo"
+        ));
+        assert!(target_pattern_matches("//cxx:foo", "//cxx:foo"));
+        assert!(!target_pattern_matches("//cxx:foo", "//cxx:foobar"));
+    }
+
+    #[test]
+    fn verify_reports_dangling_refs() {
+        let alias = |name: &str, actual: &str| {
+            Rule::Alias(Alias {
+                name: Name(name.to_owned()),
+                actual: Name(actual.to_owned()),
+                fallback_actual: None,
+                visibility: Visibility::Public,
+            })
+        };
+        let rules = BTreeSet::from([
+            alias("foo", "bar"),
+            alias("bar", "baz"),
+            alias("qux", "foo"),
+        ]);
+        let config: Config = toml::from_str("").unwrap();
+        let err = verify_rule_refs(&config, &rules).unwrap_err().to_string();
+        assert_eq!(
+            err,
+            "Generated rules refer to targets which don't exist:\n  bar -> :baz\n",
+        );
+    }
+
+    #[test]
+    fn license_file_within_package() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let manifest_dir = tempdir.path();
+        fs::create_dir_all(manifest_dir.join("legal")).unwrap();
+        fs::write(manifest_dir.join("legal/COPYING.txt"), "").unwrap();
+        fs::write(manifest_dir.join("LICENSE-WORKSPACE"), "").unwrap();
+
+        let find =
+            |license_file: &str| license_file_in_package(manifest_dir, Path::new(license_file));
+        assert_eq!(
+            find("./legal/COPYING.txt"),
+            Some(PathBuf::from("legal/COPYING.txt")),
+        );
+        assert_eq!(
+            find("../../LICENSE-WORKSPACE"),
+            Some(PathBuf::from("LICENSE-WORKSPACE")),
+        );
+        assert_eq!(find("LICENSE"), None);
+        assert_eq!(find("../LICENSE"), None);
+    }
+
+    #[test]
+    fn write_if_changed_skips_identical_content() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("BUCK");
+
+        assert!(write_if_changed(&path, b"first").unwrap());
+        assert!(!write_if_changed(&path, b"first").unwrap());
+        assert!(write_if_changed(&path, b"second").unwrap());
+        assert_eq!(fs::read(&path).unwrap(), b"second");
+    }
+
+    #[test]
+    fn render_files_puts_metadata_beside_buckfile() {
+        let config: Config = toml::from_str(
+            "vendor = false\nemit_metadata = true\n\n\
+             [buck]\nfile_name = \"rust/BUCK.v2\"\nmetadata_aggregate = true\n",
+        )
+        .unwrap();
+        let paths = paths(Path::new("/third-party"));
+
+        let files = render_files(&config, &paths, &BTreeSet::new(), &[]).unwrap();
+        let files: Vec<&Path> = files.iter().map(|(path, _out)| path.as_path()).collect();
+        assert_eq!(
+            files,
+            [Path::new("rust/BUCK.v2"), Path::new("rust/METADATA.bzl")],
+        );
+    }
 }
@@ -11,10 +11,12 @@
 //! get metadata about a crate. It also defines all the types for deserializing from Cargo's
 //! JSON output.
 
+use std::collections::BTreeMap;
 use std::collections::BTreeSet;
 use std::env;
 use std::fmt;
 use std::fmt::Display;
+use std::fs;
 use std::io::BufRead;
 use std::io::BufReader;
 use std::path::Path;
@@ -23,6 +25,7 @@ use std::process::Command;
 use std::process::Stdio;
 use std::thread;
 
+use anyhow::bail;
 use anyhow::Context;
 use serde::de::DeserializeOwned;
 use serde::Deserialize;
@@ -41,6 +44,7 @@ pub fn cargo_get_lockfile_and_metadata(
     paths: &Paths,
     features: String,
     default_features: bool,
+    filter_platform: Option<&str>,
 ) -> anyhow::Result<(Lockfile, Metadata)> {
     let mut cargo_flags = vec![
         "metadata",
@@ -57,6 +61,9 @@ pub fn cargo_get_lockfile_and_metadata(
     if !default_features {
         cargo_flags.push("--no-default-features");
     }
+    if let Some(triple) = filter_platform {
+        cargo_flags.extend(["--filter-platform", triple]);
+    }
 
     let cargo_home;
     let lockfile;
@@ -67,6 +74,8 @@ pub fn cargo_get_lockfile_and_metadata(
         // Read it after the `cargo metadata` invocation. In non-vendoring mode
         // we allow `reindeer buckify` to make changes to the lockfile. In
         // vendoring mode `reindeer vendor` would have done the same changes.
+        // Offline, the lockfile can't change.
+        cargo_flags.extend(offline_flags(config, paths)?);
         lockfile = None;
     } else {
         cargo_home = Some(paths.cargo_home.as_path());
@@ -96,6 +105,120 @@ pub fn cargo_get_lockfile_and_metadata(
     Ok((lockfile, metadata))
 }
 
+/// Flags for `cargo tree` to print the features of every package reachable
+/// through normal and build dependencies on any target, as one
+/// `name vX.Y.Z (source)|feature,...` line for each time it's built.
+/// crates.io packages have no `(source)`, and proc macros have `(proc-macro)`
+/// before it.
+pub const TREE_FEATURES_FLAGS: &[&str] = &[
+    "tree",
+    "--edges",
+    "normal,build",
+    "--target",
+    "all",
+    "--prefix",
+    "none",
+    "--format",
+    "{p}|{f}",
+];
+
+/// Features of each package, keyed by name, version and source as `cargo tree`
+/// shows them (see `Manifest::is_tree_source`).
+pub type TreeFeatures = BTreeMap<(String, semver::Version, Option<String>), BTreeSet<String>>;
+
+/// Features of each package, as Cargo resolves them without dev-dependencies. `cargo metadata` can't leave those out, but under
+/// resolver 2 the features only they enable aren't part of normal builds.
+/// A package built both normally and for build scripts has the features of
+/// both, since it gets one rule.
+pub fn cargo_get_normal_features(
+    config: &Config,
+    args: &Args,
+    paths: &Paths,
+    features: &str,
+    default_features: bool,
+) -> anyhow::Result<TreeFeatures> {
+    let mut cargo_flags = TREE_FEATURES_FLAGS.to_vec();
+    cargo_flags.extend([
+        "--manifest-path",
+        paths.manifest_path.to_str().unwrap(),
+        "--features",
+        features,
+    ]);
+    if !default_features {
+        cargo_flags.push("--no-default-features");
+    }
+
+    let cargo_home = if config.vendor.is_none() {
+        cargo_flags.extend(offline_flags(config, paths)?);
+        None
+    } else {
+        cargo_flags.extend(["--frozen", "--locked", "--offline"]);
+        Some(paths.cargo_home.as_path())
+    };
+
+    let output = run_cargo(
+        config,
+        cargo_home,
+        &paths.third_party_dir,
+        args,
+        &cargo_flags,
+    )?;
+    let output = String::from_utf8(output).context("`cargo tree` output isn't UTF-8")?;
+    parse_tree_features(&output)
+}
+
+/// Parse the output of `cargo tree` with `TREE_FEATURES_FLAGS`.
+pub fn parse_tree_features(output: &str) -> anyhow::Result<TreeFeatures> {
+    let mut packages = TreeFeatures::new();
+    for line in output.lines().filter(|line| !line.is_empty()) {
+        let parse = || {
+            let (package, features) = line.rsplit_once('|')?;
+            let (name, rest) = package.split_once(' ')?;
+            let (version, rest) = rest.split_once(' ').unwrap_or((rest, ""));
+            let version = version.strip_prefix('v')?.parse().ok()?;
+            let rest = rest.strip_prefix("(proc-macro)").unwrap_or(rest).trim();
+            let source = match rest {
+                "" => None,
+                _ => Some(rest.strip_prefix('(')?.strip_suffix(')')?.to_owned()),
+            };
+            // Repeated subtrees are marked with ` (*)`.
+            let features = features.split_whitespace().next().unwrap_or("");
+            Some((name, version, source, features))
+        };
+        let (name, version, source, features) =
+            parse().with_context(|| format!("unexpected `cargo tree` output: {line}"))?;
+        packages
+            .entry((name.to_owned(), version, source))
+            .or_default()
+            .extend(
+                features
+                    .split(',')
+                    .filter(|f| !f.is_empty())
+                    .map(str::to_owned),
+            );
+    }
+    Ok(packages)
+}
+
+/// Flags for a Cargo command which resolves dependencies, with `offline` set.
+/// Resolving anything new would need the registry, so Cargo.lock has to exist
+/// and be up to date; check it does rather than leave Cargo to report it.
+pub(crate) fn offline_flags(
+    config: &Config,
+    paths: &Paths,
+) -> anyhow::Result<&'static [&'static str]> {
+    if !config.cargo.offline {
+        return Ok(&[]);
+    }
+    if !paths.lockfile_path.exists() {
+        bail!(
+            "--offline needs an up-to-date {}; run `reindeer update` without --offline first",
+            paths.lockfile_path.display(),
+        );
+    }
+    Ok(&["--locked"])
+}
+
 // Run a cargo command
 pub(crate) fn run_cargo(
     config: &Config,
@@ -115,6 +238,17 @@ pub(crate) fn run_cargo(
         cmdline.push("-Zbindeps");
         envs.push(("RUSTC_BOOTSTRAP", "1"));
     }
+    if config.cargo.offline && !cmdline.contains(&"--offline") {
+        cmdline.push("--offline");
+    }
+    cmdline.extend(config.cargo.cargo_flags.iter().map(String::as_str));
+    envs.extend(
+        config
+            .cargo
+            .cargo_env
+            .iter()
+            .map(|(var, value)| (var.as_str(), value.as_str())),
+    );
 
     log::debug!(
         "Running Cargo command {:?} in {}",
@@ -125,7 +259,7 @@ pub(crate) fn run_cargo(
     let mut cargo_command = if let Some(cargo_path) = args.cargo_path.as_ref() {
         Command::new(cargo_path)
     } else if let Some(bin) = config.cargo.cargo.as_ref() {
-        Command::new(config.config_path.join(bin))
+        Command::new(config.config_dir().join(bin))
     } else {
         Command::new("cargo")
     };
@@ -148,7 +282,7 @@ pub(crate) fn run_cargo(
             }
         }
     } else if let Some(bin) = config.cargo.rustc.as_ref() {
-        cargo_command.env("RUSTC", config.config_path.join(bin));
+        cargo_command.env("RUSTC", config.config_dir().join(bin));
     }
 
     if let Some(cargo_home) = cargo_home {
@@ -197,7 +331,14 @@ pub(crate) fn run_cargo(
     let stderr = stderr_thr.join().expect("stderr thread join failed");
 
     if !child.wait()?.success() {
-        anyhow::bail!("`{:?}` failed:\n{}", cargo_command, stderr);
+        if config.cargo.offline {
+            bail!(
+                "`{:?}` failed:\n{}\nWith --offline, Cargo.lock must be up to date and every package already downloaded",
+                cargo_command,
+                stderr,
+            );
+        }
+        bail!("`{:?}` failed:\n{}", cargo_command, stderr);
     }
 
     Ok(stdout.into_bytes())
@@ -256,10 +397,14 @@ pub struct Manifest {
     pub version: semver::Version,
     /// Canonical ID for package
     pub id: PkgId,
+    /// SPDX license expression
+    pub license: Option<String>,
     /// Path to license
     pub license_file: Option<PathBuf>,
     /// Package description
     pub description: Option<String>,
+    /// Native library linked by the package's build script
+    pub links: Option<String>,
     /// Source registry for package
     pub source: Source,
     /// Package dependencies (unresolved)
@@ -275,8 +420,17 @@ pub struct Manifest {
     pub authors: Vec<String>,
     /// Source repository
     pub repository: Option<String>,
+    /// Package home page
+    pub homepage: Option<String>,
+    /// Path to the package's readme
+    pub readme: Option<PathBuf>,
+    /// Minimum supported Rust version
+    pub rust_version: Option<String>,
     /// Default edition for the package (if targets don't have it)
     pub edition: Edition,
+    /// Features declared by the package, and what each one enables
+    #[serde(deserialize_with = "deserialize_default_from_null")]
+    pub features: BTreeMap<String, Vec<String>>,
 }
 
 impl Manifest {
@@ -287,10 +441,104 @@ impl Manifest {
             .find(|tgt| tgt.kind_lib() || tgt.kind_proc_macro())
     }
 
+    /// `rust-version` as a semver version, filling in missing components
+    pub fn parsed_rust_version(&self) -> Option<semver::Version> {
+        let rust_version = self.rust_version.as_deref()?;
+        let mut parts = rust_version.split('.').map(str::parse::<u64>);
+        let major = parts.next()?.ok()?;
+        let minor = parts.next().unwrap_or(Ok(0)).ok()?;
+        let patch = parts.next().unwrap_or(Ok(0)).ok()?;
+        Some(semver::Version::new(major, minor, patch))
+    }
+
+    /// Whether `cargo tree` shows this package's source as `source`, from
+    /// `TreeFeatures`: nothing for crates.io, the directory for a local
+    /// package, or the repo and abbreviated commit for a git one.
+    pub fn is_tree_source(&self, source: Option<&str>) -> bool {
+        match (&self.source, source) {
+            (Source::CratesIo, None) => true,
+            (Source::Local, Some(source)) => self.manifest_dir() == Path::new(source),
+            (Source::Git { repo, commit_hash }, Some(source)) => {
+                source.rsplit_once('#').is_some_and(|(url, short_hash)| {
+                    url.split('?').next() == Some(repo.as_str())
+                        && commit_hash.starts_with(short_hash)
+                })
+            }
+            // Other registries are shown by name, which only Cargo's config
+            // knows.
+            (Source::Unrecognized(_), Some(_)) => true,
+            _ => false,
+        }
+    }
+
     /// Return full path to manifest dir (ie, top of package)
     pub fn manifest_dir(&self) -> &Path {
         self.manifest_path.parent().unwrap()
     }
+
+    /// Whether the package's Cargo.toml sets `edition`, as opposed to getting
+    /// Cargo's default. `cargo metadata` doesn't distinguish the two.
+    pub fn declares_edition(&self) -> anyhow::Result<bool> {
+        let content = fs::read_to_string(&self.manifest_path)
+            .with_context(|| format!("Failed to read {}", self.manifest_path.display()))?;
+        declares_edition(&content)
+            .with_context(|| format!("Failed to parse {}", self.manifest_path.display()))
+    }
+}
+
+fn declares_edition(content: &str) -> anyhow::Result<bool> {
+    let manifest: toml::Table = toml::from_str(content)?;
+    Ok(manifest
+        .get("package")
+        .and_then(|package| package.get("edition"))
+        .is_some())
+}
+
+/// Cargo's feature resolver, from `resolver` in the workspace's Cargo.toml
+// https://doc.rust-lang.org/cargo/reference/resolver.html#feature-resolver-version-2
+#[derive(Debug, Clone, Copy, Deserialize, Eq, PartialEq)]
+pub enum Resolver {
+    /// Features are unified across every use of a package
+    #[serde(rename = "1")]
+    V1,
+    /// Features enabled only by dev-dependencies or build-dependencies aren't
+    /// unified into normal dependencies. Resolver 3 resolves features the same
+    /// way.
+    #[serde(rename = "2", alias = "3")]
+    V2,
+}
+
+/// The resolver a workspace uses: its `resolver` setting, or else the one
+/// implied by the root package's edition.
+pub fn workspace_resolver(manifest_path: &Path) -> anyhow::Result<Resolver> {
+    let content = fs::read_to_string(manifest_path)
+        .with_context(|| format!("Failed to read {}", manifest_path.display()))?;
+    manifest_resolver(&content)
+        .with_context(|| format!("Failed to parse {}", manifest_path.display()))
+}
+
+fn manifest_resolver(content: &str) -> anyhow::Result<Resolver> {
+    let manifest: toml::Table = toml::from_str(content)?;
+    let workspace = manifest.get("workspace");
+    let package = manifest.get("package");
+    fn setting<'a>(table: Option<&'a toml::Value>, key: &str) -> Option<&'a toml::Value> {
+        table.and_then(|table| table.get(key))
+    }
+
+    if let Some(resolver) = setting(workspace, "resolver").or(setting(package, "resolver")) {
+        return Ok(Resolver::deserialize(resolver.clone())?);
+    }
+
+    let mut edition = setting(package, "edition");
+    if edition.is_some_and(|edition| edition.get("workspace").is_some()) {
+        edition = setting(setting(workspace, "package"), "edition");
+    }
+    Ok(match edition {
+        Some(edition) if Edition::deserialize(edition.clone())? >= Edition::Rust2021 => {
+            Resolver::V2
+        }
+        _ => Resolver::V1,
+    })
 }
 
 impl Eq for Manifest {}
@@ -361,6 +609,17 @@ pub enum DepKind {
     Build,
 }
 
+impl DepKind {
+    /// Manifest table the dependency is declared in.
+    pub fn table(&self) -> &'static str {
+        match self {
+            DepKind::Normal => "dependencies",
+            DepKind::Dev => "dev-dependencies",
+            DepKind::Build => "build-dependencies",
+        }
+    }
+}
+
 #[derive(Debug, Deserialize, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub struct ArtifactDep {
     kinds: Vec<ArtifactKind>,
@@ -466,6 +725,11 @@ impl ManifestTarget {
         self.crate_types.contains(&CrateType::Cdylib)
     }
 
+    /// Only usable as a C-compatible shared library, not by other Rust crates
+    pub fn crate_cdylib_only(&self) -> bool {
+        self.crate_cdylib() && !self.crate_lib() && !self.crate_staticlib()
+    }
+
     pub fn crate_lib(&self) -> bool {
         self.crate_rlib() || self.crate_dylib() || self.crate_types.contains(&CrateType::Lib)
     }
@@ -634,17 +898,7 @@ pub enum BuildKind {
     Host,
 }
 
-#[derive(
-    Debug,
-    Clone,
-    Copy,
-    Deserialize,
-    Serialize,
-    Eq,
-    PartialEq,
-    Ord,
-    PartialOrd
-)]
+#[derive(Debug, Clone, Copy, Serialize, Eq, PartialEq, Ord, PartialOrd)]
 pub enum Edition {
     #[serde(rename = "2015")]
     Rust2015,
@@ -652,6 +906,27 @@ pub enum Edition {
     Rust2018,
     #[serde(rename = "2021")]
     Rust2021,
+    #[serde(rename = "2024")]
+    Rust2024,
+}
+
+impl Edition {
+    const ALL: [Edition; 4] = [
+        Edition::Rust2015,
+        Edition::Rust2018,
+        Edition::Rust2021,
+        Edition::Rust2024,
+    ];
+
+    /// The first Rust release which supports this edition.
+    pub fn min_rust_version(self) -> semver::Version {
+        match self {
+            Edition::Rust2015 => semver::Version::new(1, 0, 0),
+            Edition::Rust2018 => semver::Version::new(1, 31, 0),
+            Edition::Rust2021 => semver::Version::new(1, 56, 0),
+            Edition::Rust2024 => semver::Version::new(1, 85, 0),
+        }
+    }
 }
 
 impl Display for Edition {
@@ -660,11 +935,45 @@ impl Display for Edition {
             Edition::Rust2015 => "2015",
             Edition::Rust2018 => "2018",
             Edition::Rust2021 => "2021",
+            Edition::Rust2024 => "2024",
         };
         fmt.write_str(edition)
     }
 }
 
+impl<'de> Deserialize<'de> for Edition {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let edition = String::deserialize(deserializer)?;
+        if let Some(known) = Edition::ALL
+            .iter()
+            .find(|known| known.to_string() == edition)
+        {
+            return Ok(*known);
+        }
+        let known = Edition::ALL
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+        let newest = Edition::ALL[Edition::ALL.len() - 1].to_string();
+        Err(serde::de::Error::custom(
+            if edition.parse::<u32>().is_ok() && edition > newest {
+                format!(
+                    "edition {edition} is newer than this version of Reindeer supports \
+                     (up to {newest}); upgrade Reindeer to use it",
+                )
+            } else {
+                format!(
+                    "unknown edition {edition:?}, expected one of {}",
+                    known.join(", "),
+                )
+            },
+        ))
+    }
+}
+
 #[derive(Debug, Eq, Ord, PartialEq, PartialOrd)]
 pub enum Source {
     Local,
@@ -712,8 +1021,27 @@ fn parse_source(source: &str) -> Option<Source> {
 
 #[cfg(test)]
 mod test {
+    use std::fs;
+
+    use clap::Parser;
+
+    use super::cargo_get_normal_features;
+    use super::declares_edition;
+    use super::manifest_resolver;
+    use super::offline_flags;
     use super::parse_source;
+    use super::Edition;
+    use super::Manifest;
+    use super::Resolver;
     use super::Source;
+    use crate::config::Config;
+    use crate::testing::cargo;
+    use crate::testing::cargo_metadata;
+    use crate::testing::package;
+    use crate::testing::paths;
+    use crate::testing::write;
+    use crate::Args;
+    use crate::Paths;
 
     #[test]
     fn test_parses_source_git() {
@@ -727,4 +1055,171 @@ mod test {
             }),
         );
     }
+
+    #[test]
+    fn test_manifest_resolver() {
+        let resolver = |content: &str| manifest_resolver(content).unwrap();
+        assert_eq!(resolver("[package]\nname = \"foo\"\n"), Resolver::V1);
+        assert_eq!(
+            resolver("[package]\nname = \"foo\"\nedition = \"2021\"\n"),
+            Resolver::V2,
+        );
+        assert_eq!(
+            resolver("[package]\nname = \"foo\"\nedition = \"2021\"\nresolver = \"1\"\n"),
+            Resolver::V1,
+        );
+        assert_eq!(
+            resolver("[workspace]\nmembers = [\"foo\"]\nresolver = \"3\"\n"),
+            Resolver::V2,
+        );
+        assert_eq!(
+            resolver(
+                "[package]\nname = \"foo\"\nedition.workspace = true\n\n\
+                 [workspace.package]\nedition = \"2024\"\n",
+            ),
+            Resolver::V2,
+        );
+        assert_eq!(resolver("[workspace]\nmembers = [\"foo\"]\n"), Resolver::V1,);
+    }
+
+    #[test]
+    fn test_declares_edition() {
+        let explicit = "[package]\nname = \"foo\"\nedition = \"2015\"\n";
+        assert!(declares_edition(explicit).unwrap());
+        let inherited = "[package]\nname = \"foo\"\nedition.workspace = true\n";
+        assert!(declares_edition(inherited).unwrap());
+        let missing = "[package]\nname = \"foo\"\n\n[lib]\nedition = \"2021\"\n";
+        assert!(!declares_edition(missing).unwrap());
+    }
+
+    #[test]
+    fn test_edition_2024() {
+        let manifest = |edition: &str| {
+            serde_json::from_value::<Manifest>(serde_json::json!({
+                "name": "foo",
+                "version": "1.0.0",
+                "id": "foo 1.0.0",
+                "source": null,
+                "dependencies": null,
+                "targets": null,
+                "manifest_path": "Cargo.toml",
+                "authors": null,
+                "edition": edition,
+                "features": null,
+            }))
+        };
+        let pkg = manifest("2024").unwrap();
+        assert_eq!(pkg.edition, Edition::Rust2024);
+        assert_eq!(pkg.edition.to_string(), "2024");
+        assert!(pkg.edition > Edition::Rust2021);
+        assert_eq!(
+            pkg.edition.min_rust_version(),
+            semver::Version::new(1, 85, 0),
+        );
+        assert_eq!(pkg.parsed_rust_version(), None);
+
+        let err = manifest("2027").unwrap_err().to_string();
+        assert!(
+            err.contains("edition 2027 is newer than this version of Reindeer supports"),
+            "{err}",
+        );
+        let err = manifest("2020").unwrap_err().to_string();
+        assert!(
+            err.contains(r#"unknown edition "2020", expected one of 2015, 2018, 2021, 2024"#),
+            "{err}",
+        );
+    }
+
+    #[test]
+    fn offline_needs_lockfile() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let paths = Paths {
+            third_party_dir: tempdir.path().to_owned(),
+            manifest_path: tempdir.path().join("Cargo.toml"),
+            lockfile_path: tempdir.path().join("Cargo.lock"),
+            cargo_home: tempdir.path().join(".cargo"),
+        };
+        let mut config: Config = toml::from_str("").unwrap();
+        assert!(offline_flags(&config, &paths).unwrap().is_empty());
+
+        config = toml::from_str("[cargo]\noffline = true\n").unwrap();
+        let err = offline_flags(&config, &paths).unwrap_err().to_string();
+        assert!(err.starts_with("--offline needs an up-to-date "), "{err}");
+
+        fs::write(&paths.lockfile_path, "version = 3\n").unwrap();
+        assert_eq!(offline_flags(&config, &paths).unwrap(), ["--locked"]);
+    }
+
+    #[test]
+    fn normal_features_by_source() {
+        // A local foo and a vendored crates.io foo of the same version, with
+        // different features, and one more for the local foo's dev-dependency.
+        let tempdir = tempfile::tempdir().unwrap();
+        let third_party_dir = tempdir.path().join("third-party");
+        package(
+            tempdir.path(),
+            "third-party",
+            "[dependencies]\n\
+             foo = { path = \"foo\", features = [\"a\"] }\n\
+             foo-registry = { package = \"foo\", version = \"1\", features = [\"b\"] }\n\
+             [dev-dependencies]\n\
+             foo = { path = \"foo\", features = [\"c\"] }\n",
+        );
+        let features = "[features]\na = []\nb = []\nc = []\n";
+        package(&third_party_dir, "foo", features);
+        package(&third_party_dir.join("vendor"), "foo", features);
+        write(
+            &third_party_dir.join("vendor/foo/.cargo-checksum.json"),
+            r#"{"files":{},"package":null}"#,
+        );
+        write(
+            &third_party_dir.join(".cargo/config.toml"),
+            "[source.crates-io]\nreplace-with = \"vendored-sources\"\n\n\
+             [source.vendored-sources]\ndirectory = \"vendor\"\n",
+        );
+        cargo(&third_party_dir, &["generate-lockfile"]);
+
+        let config: Config = toml::from_str("").unwrap();
+        let args = Args::parse_from(["reindeer", "buckify"]);
+        let paths = paths(&third_party_dir);
+        let features = cargo_get_normal_features(&config, &args, &paths, "", true).unwrap();
+        let described: Vec<String> = features
+            .iter()
+            .map(|((name, version, source), features)| {
+                let source = match source {
+                    Some(source) if source == third_party_dir.to_str().unwrap() => "local",
+                    Some(source) if source.ends_with("/foo") => "local foo",
+                    Some(source) => source,
+                    None => "crates.io",
+                };
+                let features: Vec<&str> = features.iter().map(String::as_str).collect();
+                format!("{name} {version} {source}: {}", features.join(","))
+            })
+            .collect();
+        assert_eq!(
+            described,
+            [
+                "foo 1.0.0 crates.io: b",
+                "foo 1.0.0 local foo: a",
+                "third-party 1.0.0 local: ",
+            ],
+        );
+
+        // Each foo from `cargo metadata` finds only its own features.
+        let metadata = cargo_metadata(&third_party_dir);
+        for pkg in metadata.packages.iter().filter(|pkg| pkg.name == "foo") {
+            let matched: Vec<&str> = features
+                .iter()
+                .filter(|((name, _version, source), _features)| {
+                    *name == pkg.name && pkg.is_tree_source(source.as_deref())
+                })
+                .flat_map(|(_key, features)| features.iter().map(String::as_str))
+                .collect();
+            let expected = match pkg.source {
+                Source::Local => "a",
+                _ => "b",
+            };
+            assert_eq!(matched, [expected]);
+        }
+    }
 }
@@ -120,6 +120,8 @@ fn parse_predicate<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
             map(operator("not", parse_predicate), |pred| Not(Box::new(pred))),
             map(keyword("unix"), |_| Unix),
             map(keyword("windows"), |_| Windows),
+            map(keyword("true"), |_| True),
+            map(keyword("false"), |_| False),
             map(
                 separated_pair(atom, sep('='), cut(preceded(sp, string))),
                 |(key, value)| Value { key, value },
@@ -289,4 +291,82 @@ mod test {
             ))
         )
     }
+
+    #[test]
+    fn test_target_has_atomic() {
+        let res = cfg::parse::<(_, nom::error::ErrorKind)>("cfg(target_has_atomic = \"ptr\")");
+        println!("res = {:?}", res);
+        assert_eq!(
+            res,
+            Ok((
+                "",
+                Value {
+                    key: "target_has_atomic",
+                    value: "ptr"
+                }
+            ))
+        )
+    }
+
+    #[test]
+    fn test_panic() {
+        let res = cfg::parse::<(_, nom::error::ErrorKind)>("cfg(not(panic = \"abort\"))");
+        println!("res = {:?}", res);
+        assert_eq!(
+            res,
+            Ok((
+                "",
+                Not(Box::new(Value {
+                    key: "panic",
+                    value: "abort"
+                }))
+            ))
+        )
+    }
+
+    #[test]
+    fn test_literals() {
+        let res = cfg::parse::<(_, nom::error::ErrorKind)>("cfg(any(true, false, truest))");
+        println!("res = {:?}", res);
+        assert_eq!(
+            res,
+            Ok(("", Any(vec![True, False, Bool { key: "truest" }])))
+        )
+    }
+
+    #[test]
+    fn test_getrandom() {
+        let res = cfg::parse::<(_, nom::error::ErrorKind)>(
+            "cfg(all(any(target_os = \"linux\", target_os = \"android\"), not(any(getrandom_backend = \"custom\", getrandom_backend = \"rdrand\"))))",
+        );
+        println!("res = {:?}", res);
+        assert_eq!(
+            res,
+            Ok((
+                "",
+                All(vec![
+                    Any(vec![
+                        Value {
+                            key: "target_os",
+                            value: "linux"
+                        },
+                        Value {
+                            key: "target_os",
+                            value: "android"
+                        }
+                    ]),
+                    Not(Box::new(Any(vec![
+                        Value {
+                            key: "getrandom_backend",
+                            value: "custom"
+                        },
+                        Value {
+                            key: "getrandom_backend",
+                            value: "rdrand"
+                        }
+                    ])))
+                ])
+            ))
+        )
+    }
 }
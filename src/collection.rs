@@ -10,6 +10,7 @@ use std::collections::BTreeSet;
 use std::fmt;
 use std::marker::PhantomData;
 
+use anyhow::bail;
 use serde::de::value::MapAccessDeserializer;
 use serde::de::value::SeqAccessDeserializer;
 use serde::de::Deserialize;
@@ -18,6 +19,7 @@ use serde::de::MapAccess;
 use serde::de::SeqAccess;
 use serde::de::Visitor;
 use serde::ser::Serialize;
+use serde::ser::SerializeMap;
 use serde::ser::SerializeSeq;
 use serde::ser::SerializeTupleStruct;
 use serde::ser::Serializer;
@@ -46,6 +48,41 @@ impl<T> SetOrMap<T> {
             SetOrMap::Map(map) => map.is_empty(),
         }
     }
+
+    /// Combine with another, from a different source. Sets are unioned and
+    /// maps merged, but a key mapped to two different values is an error, as
+    /// is combining a non-empty set with a non-empty map.
+    pub fn merge(&mut self, other: Self) -> anyhow::Result<()>
+    where
+        T: Ord + fmt::Debug,
+    {
+        if other.is_empty() {
+            return Ok(());
+        }
+        if self.is_empty() {
+            *self = other;
+            return Ok(());
+        }
+        match (self, other) {
+            (SetOrMap::Set(set), SetOrMap::Set(other)) => set.extend(other),
+            (SetOrMap::Map(map), SetOrMap::Map(other)) => {
+                for (key, value) in other {
+                    match map.get(&key) {
+                        Some(existing) if *existing != value => {
+                            bail!("`{key}` is mapped to both {existing:?} and {value:?}");
+                        }
+                        _ => {
+                            map.insert(key, value);
+                        }
+                    }
+                }
+            }
+            (SetOrMap::Set(_), SetOrMap::Map(_)) | (SetOrMap::Map(_), SetOrMap::Set(_)) => {
+                bail!("a list can't be combined with a map");
+            }
+        }
+        Ok(())
+    }
 }
 
 impl<T> Serialize for SetOrMap<T>
@@ -134,6 +171,25 @@ impl SelectSet {
     pub fn is_empty(&self) -> bool {
         self.common.is_empty() && self.selects.is_empty()
     }
+
+    /// Serialize for JSON, which can't add lists, so each branch gets the
+    /// common values too, like `{"select": {"DEFAULT": [...], ...}}`.
+    pub fn serialize_json<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        if self.selects.is_empty() {
+            return self.common.serialize(serializer);
+        }
+        let selects: BTreeMap<&String, BTreeSet<&String>> = self
+            .selects
+            .iter()
+            .map(|(key, values)| (key, self.common.iter().chain(values).collect()))
+            .collect();
+        let mut map = serializer.serialize_map(Some(1))?;
+        map.serialize_entry("select", &selects)?;
+        map.end()
+    }
 }
 
 // Inspired by SelectSet (see link) but much simplified. If you stumble here
@@ -297,4 +353,59 @@ mod test {
             expected,
         );
     }
+
+    #[test]
+    fn set_or_map_merge_sets() {
+        let mut set = SetOrMap::Set(BTreeSet::from(["a", "b"]));
+        set.merge(SetOrMap::Set(BTreeSet::from(["b", "c"])))
+            .unwrap();
+        assert_eq!(set, SetOrMap::Set(BTreeSet::from(["a", "b", "c"])));
+    }
+
+    #[test]
+    fn set_or_map_merge_maps() {
+        let mut map = SetOrMap::Map(BTreeMap::from([
+            ("a.h".to_owned(), "src/a.h"),
+            ("b.h".to_owned(), "src/b.h"),
+        ]));
+        map.merge(SetOrMap::Map(BTreeMap::from([
+            ("b.h".to_owned(), "src/b.h"),
+            ("c.h".to_owned(), "src/c.h"),
+        ])))
+        .unwrap();
+        assert_eq!(
+            map,
+            SetOrMap::Map(BTreeMap::from([
+                ("a.h".to_owned(), "src/a.h"),
+                ("b.h".to_owned(), "src/b.h"),
+                ("c.h".to_owned(), "src/c.h"),
+            ])),
+        );
+
+        let err = map
+            .merge(SetOrMap::Map(BTreeMap::from([(
+                "a.h".to_owned(),
+                "gen/a.h",
+            )])))
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            r#"`a.h` is mapped to both "src/a.h" and "gen/a.h""#,
+        );
+    }
+
+    #[test]
+    fn set_or_map_merge_set_with_map() {
+        let mut set = SetOrMap::Set(BTreeSet::from(["a.h"]));
+        let map = SetOrMap::Map(BTreeMap::from([("b.h".to_owned(), "src/b.h")]));
+        let err = set.clone().merge(map.clone()).unwrap_err();
+        assert_eq!(err.to_string(), "a list can't be combined with a map");
+
+        // Empty defaults combine with either
+        let mut empty = SetOrMap::default();
+        empty.merge(map.clone()).unwrap();
+        assert_eq!(empty, map);
+        set.merge(SetOrMap::Map(BTreeMap::new())).unwrap();
+        assert_eq!(set, SetOrMap::Set(BTreeSet::from(["a.h"])));
+    }
 }
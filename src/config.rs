@@ -11,6 +11,7 @@ use std::collections::BTreeMap;
 use std::collections::BTreeSet;
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::env;
 use std::fmt;
 use std::fmt::Debug;
 use std::fmt::Display;
@@ -23,6 +24,7 @@ use std::path::Path;
 use std::path::PathBuf;
 
 use anyhow::Context;
+use itertools::Itertools as _;
 use monostate::MustBe;
 use serde::de::value::MapAccessDeserializer;
 use serde::de::Deserializer;
@@ -31,6 +33,9 @@ use serde::de::Visitor;
 use serde::Deserialize;
 use serde::Serialize;
 
+use crate::cargo::Edition;
+use crate::cargo::Resolver;
+use crate::glob::SerializableGlobSet as GlobSet;
 use crate::platform::PlatformConfig;
 use crate::platform::PlatformName;
 use crate::universe::UniverseConfig;
@@ -43,6 +48,24 @@ pub struct Config {
     #[serde(skip)]
     pub config_path: PathBuf,
 
+    /// Set when `platform` has been cut down to a subset with
+    /// `--target-platforms`
+    #[serde(skip)]
+    pub platforms_restricted: bool,
+
+    /// Set by `--report-unused-fixups`
+    #[serde(skip)]
+    pub report_unused_fixups: bool,
+
+    /// Set by `reindeer check`, to gather every unresolved fixup as
+    /// `unresolved_fixup_report` does, without writing a report
+    #[serde(skip)]
+    pub collect_unresolved_fixups: bool,
+
+    /// Set by `--rules-json`, to also write the rules as JSON to this path
+    #[serde(skip)]
+    pub rules_json: Option<PathBuf>,
+
     /// Try to compute a precise list of sources rather than using globbing
     #[serde(default)]
     pub precise_srcs: bool,
@@ -55,6 +78,22 @@ pub struct Config {
     #[serde(default)]
     pub fixup_templates: bool,
 
+    /// Fixups shared by every package whose name matches a glob, such as
+    /// `windows_*`, mapped to the directory under `fixups/` holding their
+    /// fixups.toml. They're merged in order of increasing literal prefix
+    /// length, and a package's own fixups take priority over all of them.
+    #[serde(default)]
+    pub fixup_globs: BTreeMap<String, String>,
+
+    /// Emit Cargo package metadata into library rules
+    #[serde(default)]
+    pub emit_metadata: bool,
+
+    /// Give `-sys` crates which declare `links` a `lib<links>` alias for the
+    /// native library built by their fixups
+    #[serde(default)]
+    pub sys_linkable_aliases: bool,
+
     /// Fail buckify if there are unresolved fixups
     #[serde(default)]
     pub unresolved_fixup_error: bool,
@@ -63,6 +102,11 @@ pub struct Config {
     #[serde(default)]
     pub unresolved_fixup_error_message: Option<String>,
 
+    /// Write every unresolved fixup to this JSON file (relative to the
+    /// third-party directory) instead of stopping at the first one
+    #[serde(default)]
+    pub unresolved_fixup_report: Option<PathBuf>,
+
     /// Include root package as top-level public target in Buck file
     #[serde(default)]
     pub include_top_level: bool,
@@ -77,6 +121,9 @@ pub struct Config {
     #[serde(default)]
     pub buck: BuckConfig,
 
+    #[serde(default)]
+    pub audit: AuditConfig,
+
     #[serde(
         default = "default_vendor_config",
         deserialize_with = "deserialize_vendor_config"
@@ -84,12 +131,102 @@ pub struct Config {
     pub vendor: Option<VendorConfig>,
 
     #[serde(default = "default_platforms")]
-    pub platform: HashMap<PlatformName, PlatformConfig>,
+    pub platform: BTreeMap<PlatformName, PlatformConfig>,
+
+    /// The platform Buck runs proc macros on, whose config decides which of
+    /// their platform-specific deps apply. Only for setups with a single exec
+    /// platform.
+    #[serde(default)]
+    pub host_platform: Option<PlatformName>,
+
+    /// The host platform's config, kept when `--target-platforms` leaves it
+    /// out of `platform`
+    #[serde(skip)]
+    restricted_host_platform: Option<PlatformConfig>,
 
     #[serde(default = "default_universes")]
     pub universe: BTreeMap<UniverseName, UniverseConfig>,
 }
 
+impl Config {
+    /// Directory of the config file, which relative paths in it are relative
+    /// to.
+    pub fn config_dir(&self) -> &Path {
+        self.config_path.parent().unwrap_or(Path::new(""))
+    }
+
+    /// Expand `${VAR}` and `${VAR:-default}` in the config's paths.
+    fn expand_path_vars(&mut self, lookup: impl Fn(&str) -> Option<String>) -> anyhow::Result<()> {
+        let lookup = &lookup;
+        let expand = |path: &mut PathBuf| -> anyhow::Result<()> {
+            *path = expand_vars(path, lookup)?;
+            Ok(())
+        };
+        let Config {
+            unresolved_fixup_report,
+            cargo,
+            audit,
+            vendor,
+            ..
+        } = self;
+        unresolved_fixup_report.iter_mut().try_for_each(expand)?;
+        cargo.cargo.iter_mut().try_for_each(expand)?;
+        cargo.rustc.iter_mut().try_for_each(expand)?;
+        audit.advisory_db.iter_mut().try_for_each(expand)?;
+        if let Some(vendor) = vendor {
+            vendor.gitignore_checksum_exclude = vendor
+                .gitignore_checksum_exclude
+                .iter()
+                .map(|path| expand_vars(path, lookup))
+                .collect::<anyhow::Result<_>>()?;
+        }
+        Ok(())
+    }
+
+    /// The config of `host_platform`, if one is set.
+    pub fn host_platform(&self) -> Option<&PlatformConfig> {
+        let name = self.host_platform.as_ref()?;
+        self.platform
+            .get(name)
+            .or(self.restricted_host_platform.as_ref())
+    }
+
+    /// Only generate rules for the named platforms.
+    pub fn restrict_platforms(&mut self, names: &[String]) -> anyhow::Result<()> {
+        for name in names {
+            if !self
+                .platform
+                .keys()
+                .any(|platform| platform.to_string() == *name)
+            {
+                anyhow::bail!(
+                    "Unknown platform `{}`, expected one of: {}",
+                    name,
+                    self.platform.keys().map(ToString::to_string).join(", "),
+                );
+            }
+        }
+        self.restricted_host_platform = self.host_platform().cloned();
+        self.platform
+            .retain(|platform, _| names.contains(&platform.to_string()));
+        self.platforms_restricted = true;
+        Ok(())
+    }
+
+    /// Copy of this config with its hash-based collections rebuilt, so that
+    /// they iterate in a different order. Used to shake out output which
+    /// depends on iteration order.
+    pub fn rehash(&self) -> Config {
+        let mut config = self.clone();
+        config.platform = self
+            .platform
+            .iter()
+            .map(|(name, platform)| (name.clone(), platform.rehash()))
+            .collect();
+        config
+    }
+}
+
 #[derive(Debug, Clone, Default, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct CargoConfig {
@@ -101,6 +238,151 @@ pub struct CargoConfig {
     /// Support Cargo's unstable "artifact dependencies" functionality, RFC 3028.
     #[serde(default)]
     pub bindeps: bool,
+    /// Variables from the `[env]` table of `.cargo/config.toml` which should
+    /// not be propagated into the generated rules
+    #[serde(default)]
+    pub env_exclude: BTreeSet<String>,
+    /// Always include the `default` feature in generated rules, even for
+    /// packages which don't declare it or where it wasn't enabled. Ordinarily
+    /// `default` only appears when the package declares it and the dependency
+    /// graph enabled it.
+    #[serde(default)]
+    pub include_default_feature: bool,
+    /// Edition for packages whose manifest doesn't specify one, instead of
+    /// Cargo's default of 2015
+    #[serde(default)]
+    pub default_edition: Option<Edition>,
+    /// Which of the `CARGO_PKG_*` variables Cargo provides at compile time to
+    /// set in the `env` of every generated Rust rule
+    #[serde(default)]
+    pub cargo_pkg_env: CargoPkgEnv,
+    /// Never access the network: pass `--offline` to every Cargo invocation,
+    /// and fail rather than fetch anything. Cargo.lock has to be up to date.
+    /// Also set by the `--offline` flag.
+    #[serde(default)]
+    pub offline: bool,
+    /// Resolve features separately for each platform's target triple, with
+    /// `cargo metadata --filter-platform`, so that features enabled only on
+    /// some platforms are only set for those
+    #[serde(default)]
+    pub per_platform_features: bool,
+    /// Cargo's feature resolver to follow when computing features, instead of
+    /// the one the workspace's Cargo.toml asks for. Only `"1"` changes
+    /// anything, keeping dev-dependency features in a resolver 2 workspace.
+    #[serde(default)]
+    pub resolver: Option<Resolver>,
+    /// Extra flags for every Cargo invocation, such as `--config` overrides
+    /// or `-Z` flags
+    #[serde(default)]
+    pub cargo_flags: Vec<String>,
+    /// Extra environment variables for every Cargo invocation
+    #[serde(default)]
+    pub cargo_env: BTreeMap<String, String>,
+}
+
+/// Cargo flags which Reindeer sets itself, and the config to use instead
+/// where there is one.
+const RESERVED_CARGO_FLAGS: &[(&str, Option<&str>)] = &[
+    ("--manifest-path", None),
+    ("--format-version", None),
+    ("--features", Some("`features` in `[universe]`")),
+    ("--all-features", None),
+    ("--filter-platform", Some("`per_platform_features = true`")),
+    ("--no-default-features", None),
+    ("--frozen", None),
+    ("--locked", None),
+    ("--offline", Some("`offline = true`")),
+    ("--versioned-dirs", None),
+    ("--no-delete", Some("`reindeer vendor --no-delete`")),
+    ("-Zbindeps", Some("`bindeps = true`")),
+];
+
+/// Environment variables which Reindeer sets for Cargo itself.
+const RESERVED_CARGO_ENV: &[(&str, Option<&str>)] =
+    &[("CARGO_HOME", None), ("RUSTC", Some("`rustc`"))];
+
+fn reserved(what: &str, name: &str, instead: Option<&str>) -> anyhow::Error {
+    match instead {
+        Some(instead) => {
+            anyhow::anyhow!("{what} `{name}`, which Reindeer sets itself; use {instead}")
+        }
+        None => anyhow::anyhow!("{what} `{name}`, which Reindeer sets itself"),
+    }
+}
+
+impl CargoConfig {
+    /// Check `cargo_flags` and `cargo_env` don't set anything Reindeer
+    /// controls.
+    fn check_passthrough(&self) -> anyhow::Result<()> {
+        let mut flags = self.cargo_flags.iter().peekable();
+        while let Some(flag) = flags.next() {
+            let mut name = flag.split('=').next().unwrap_or(flag).to_owned();
+            if name == "-Z" {
+                if let Some(unstable) = flags.peek() {
+                    name.push_str(unstable);
+                }
+            }
+            if let Some((_, instead)) = RESERVED_CARGO_FLAGS
+                .iter()
+                .find(|(reserved, _)| name == *reserved)
+            {
+                return Err(reserved("`cargo_flags` can't include", &name, *instead));
+            }
+        }
+        for (var, instead) in RESERVED_CARGO_ENV {
+            if self.cargo_env.contains_key(*var) {
+                return Err(reserved("`cargo_env` can't set", var, *instead));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// How platform-specific attributes of Rust rules are written.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PlatformRepresentation {
+    /// A `platform` dict from platform name to attributes, for the rule
+    /// macros to pick from
+    #[default]
+    Dict,
+    /// A `select()` on each attribute which differs between platforms, keyed
+    /// by the platforms' `platform_settings`
+    Select,
+}
+
+/// How a `rust_binary` links its Rust and C/C++ dependencies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LinkStyle {
+    Static,
+    Shared,
+    StaticPic,
+}
+
+impl Display for LinkStyle {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str(match self {
+            LinkStyle::Static => "static",
+            LinkStyle::Shared => "shared",
+            LinkStyle::StaticPic => "static_pic",
+        })
+    }
+}
+
+/// How much of the `CARGO_PKG_*` family of environment variables to emit.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CargoPkgEnv {
+    /// None of them
+    None,
+    /// `CARGO_PKG_NAME` and `CARGO_PKG_VERSION`, which are what most crates
+    /// need to compile
+    #[default]
+    Minimal,
+    /// All of them, including `CARGO_PKG_AUTHORS` which may contain email
+    /// addresses
+    Full,
 }
 
 #[derive(Debug, Clone, Default, Deserialize)]
@@ -119,6 +401,11 @@ pub struct BuckConfig {
     #[serde(default)]
     pub buckfile_imports: StringWithDefault<MustBe!("")>,
 
+    /// Prelude imports to put in the BUCK file in place of an unset
+    /// `buckfile_imports`, each only if some generated rule uses it
+    #[serde(skip)]
+    pub prelude_imports: PreludeImports,
+
     /// Rule name for alias
     #[serde(default)]
     pub alias: StringWithDefault<MustBe!("alias")>,
@@ -143,25 +430,173 @@ pub struct BuckConfig {
     /// Rule name for prebuilt_cxx_library
     #[serde(default)]
     pub prebuilt_cxx_library: StringWithDefault<MustBe!("prebuilt_cxx_library")>,
-    /// Rule name for the rust_binary of a build script
+    /// Rule name for the rust_binary of a build script. Defaults to
+    /// `rust_binary`, so crates' build scripts always have a rule to use.
     pub buildscript_binary: Option<String>,
     /// Rule name for a build script invocation
     #[serde(default)]
     pub buildscript_genrule: StringWithDefault<MustBe!("buildscript_run")>,
+    /// Rule name for copying a file out of a build script's `OUT_DIR`
+    #[serde(default)]
+    pub genrule: StringWithDefault<MustBe!("genrule")>,
+
+    /// Permit absolute paths in generated rules, with a warning, instead of
+    /// failing. They make the BUCK file non-hermetic and non-portable.
+    #[serde(default)]
+    pub allow_absolute_paths: bool,
+
+    /// Render paths which aren't valid UTF-8 with replacement characters, with
+    /// a warning, instead of failing.
+    #[serde(default)]
+    pub lossy_utf8_paths: bool,
+
+    /// Emit each crate's declared SPDX `license` as a `license_expression`
+    /// attribute on its Rust rules. The rule macros need to accept it.
+    #[serde(default)]
+    pub emit_license_expression: bool,
+
+    /// Globs for files to leave out of every crate's `srcs`, rooted in the
+    /// crate's manifest dir. A crate's fixup can replace these.
+    #[serde(default)]
+    pub srcs_exclude: GlobSet,
+
+    /// Compiler flags for every `cxx_library` generated from a build script
+    /// fixup, ahead of the fixup's own `compiler_flags`.
+    #[serde(default)]
+    pub cxx_compiler_flags: Vec<String>,
+
+    /// Declare each crate's features and known cfgs with `--check-cfg` in its
+    /// `rustc_flags`, so that newer rustc doesn't warn about unexpected cfgs.
+    #[serde(default)]
+    pub emit_check_cfg: bool,
+
+    /// With `emit_metadata`, write every crate's metadata into a single
+    /// METADATA.bzl next to the BUCK file, rather than into each rule.
+    #[serde(default)]
+    pub metadata_aggregate: bool,
+
+    /// Write each vendored package's rules to a BUCK file in its own
+    /// directory, leaving the public aliases in the top-level one
+    #[serde(default)]
+    pub split_per_package: bool,
+
+    /// Constraint for Windows targets, used to pick `;` rather than `:` to
+    /// join list-valued `path_env` entries of build script runs. The select
+    /// is resolved in the target configuration, while build scripts run on
+    /// the exec platform, so the separator is only right when the two agree
+    /// on being Windows.
+    #[serde(default)]
+    pub windows_constraint: WindowsConstraint,
+
+    /// Constraint for each `target_os`. A crate which is only reachable on
+    /// platforms with one `target_os` in this table gets that constraint as
+    /// its `target_compatible_with`, so Buck skips it elsewhere.
+    #[serde(default)]
+    pub os_constraints: BTreeMap<String, String>,
+
+    /// Target for the public alias of a crate with a `target_compatible_with`
+    /// constraint to refer to on platforms without it, such as an empty
+    /// library, so the alias can be depended on unconditionally.
+    #[serde(default)]
+    pub fallback_actual: Option<String>,
+
+    /// Name for the public alias of each crate, with `{crate}` standing for
+    /// the crate's (possibly renamed) name and `{version}` for its version.
+    /// Defaults to the bare crate name.
+    #[serde(default)]
+    pub alias_name_template: Option<String>,
+
+    /// Whether platform-specific attributes are written as a `platform` dict
+    /// or as `select()`s
+    #[serde(default)]
+    pub platform_representation: PlatformRepresentation,
+
+    /// Config setting label for each platform, used as its `select()` key
+    /// with `platform_representation = "select"`
+    #[serde(default)]
+    pub platform_settings: BTreeMap<PlatformName, String>,
+
+    /// Write a comment after each platform-specific dependency and each one
+    /// added by fixups, saying where it came from
+    #[serde(default)]
+    pub annotate_deps: bool,
+
+    /// `link_style` for every generated `rust_binary`, including build
+    /// scripts, unless a fixup sets one. Libraries don't get it.
+    #[serde(default)]
+    pub default_binary_link_style: Option<LinkStyle>,
+
+    /// Generate `rust_binary` rules, and their aliases, for the binaries of
+    /// workspace members (default true). A package's `emit_binary` fixup
+    /// overrides it. Build scripts, and binaries used as artifact
+    /// dependencies, are generated regardless.
+    #[serde(default)]
+    pub emit_binaries: Option<bool>,
+
+    /// Buck target patterns, like `prelude//...` or `//third-party/cxx:`,
+    /// for targets outside the generated rules which `buckify --verify`
+    /// accepts references to.
+    #[serde(default)]
+    pub external_targets: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PreludeImports {
+    /// `buildscript_run`, for the default `buildscript_genrule`
+    pub buildscript_run: bool,
+    /// `cargo`, for the default `rust_library` and `rust_binary`
+    pub cargo: bool,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AuditConfig {
+    /// SPDX license identifiers which are not acceptable. Buckify fails if a
+    /// package's declared license can't be satisfied without one of these.
+    #[serde(default)]
+    pub deny_licenses: BTreeSet<String>,
+    /// Per-package exceptions to `deny_licenses`, keyed by package name
+    #[serde(default)]
+    pub license_exceptions: BTreeMap<String, BTreeSet<String>>,
+    /// Packages which `auditsec --autofix` only reports, never changing the
+    /// locked version
+    #[serde(default)]
+    pub never_autofix: BTreeSet<String>,
+    /// Fail buckify if any package in the rules has a RustSec advisory against
+    /// it. Packages in `never_autofix` only get a warning.
+    #[serde(default)]
+    pub deny_vulnerable: bool,
+    /// Local checkout of the RustSec advisory database, relative to this
+    /// config file, to use instead of fetching it
+    #[serde(default)]
+    pub advisory_db: Option<PathBuf>,
 }
 
 #[derive(Debug, Default, Clone, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct VendorConfig {
     /// List of .gitignore files to use to filter checksum files, relative to
-    /// this config file.
+    /// this config file. Each applies to its own directory, with the same
+    /// precedence and `!` negation as git.
     #[serde(default)]
     pub gitignore_checksum_exclude: HashSet<PathBuf>,
     /// Set of globs to remove from Cargo's checksun files in vendored dirs
     #[serde(default)]
     pub checksum_exclude: HashSet<String>,
+    /// After vendoring, delete files which no generated rule uses from
+    /// vendored crates with neither a build script nor fixups. Needs
+    /// `precise_srcs`. Vendoring with this off restores them.
+    #[serde(default)]
+    pub minimal_sources: bool,
+    /// Fetch crates.io packages from this registry mirror instead, such as
+    /// `sparse+https://mirror.example.com/index/`. It has to serve the same
+    /// crates, since Cargo.lock's checksums still apply.
+    #[serde(default)]
+    pub registry_url: Option<String>,
 }
 
+pub type WindowsConstraint = StringWithDefault<MustBe!("prelude//os:windows")>;
+
 #[derive(Clone)]
 pub struct StringWithDefault<T> {
     pub value: String,
@@ -240,12 +675,12 @@ fn default_vendor_config() -> Option<VendorConfig> {
     Some(VendorConfig::default())
 }
 
-fn default_platforms() -> HashMap<PlatformName, PlatformConfig> {
+fn default_platforms() -> BTreeMap<PlatformName, PlatformConfig> {
     const DEFAULT_PLATFORMS_TOML: &str = include_str!("default_platforms.toml");
 
     #[derive(Deserialize)]
     struct DefaultConfig {
-        platform: HashMap<PlatformName, PlatformConfig>,
+        platform: BTreeMap<PlatformName, PlatformConfig>,
     }
 
     toml::from_str::<DefaultConfig>(DEFAULT_PLATFORMS_TOML)
@@ -292,34 +727,150 @@ where
     deserializer.deserialize_any(VendorConfigVisitor)
 }
 
+/// Replace `${VAR}` in a path with the variable's value, or with `default` for
+/// `${VAR:-default}` if it's unset or empty, like the shell. Any other `$` is
+/// left alone.
+fn expand_vars(path: &Path, lookup: impl Fn(&str) -> Option<String>) -> anyhow::Result<PathBuf> {
+    let Some(mut rest) = path.to_str() else {
+        return Ok(path.to_path_buf());
+    };
+    let mut out = String::new();
+    while let Some(i) = rest.find("${") {
+        out.push_str(&rest[..i]);
+        let after = &rest[i + 2..];
+        let end = after
+            .find('}')
+            .with_context(|| format!("Unterminated `${{` in {}", path.display()))?;
+        let (var, default) = match after[..end].split_once(":-") {
+            Some((var, default)) => (var, Some(default)),
+            None => (&after[..end], None),
+        };
+        match (lookup(var), default) {
+            (Some(value), Some(default)) if value.is_empty() => out.push_str(default),
+            (Some(value), _) => out.push_str(&value),
+            (None, Some(default)) => out.push_str(default),
+            (None, None) => {
+                anyhow::bail!(
+                    "Environment variable `{var}` in {} is not set",
+                    path.display(),
+                );
+            }
+        }
+        rest = &after[end + 1..];
+    }
+    out.push_str(rest);
+    Ok(PathBuf::from(out))
+}
+
+/// Read `reindeer.toml` from a directory, or use the defaults if it has none.
 pub fn read_config(dir: &Path) -> anyhow::Result<Config> {
-    let reindeer_toml = dir.join("reindeer.toml");
-    let mut config = try_read_config(&reindeer_toml)?;
+    read_config_from(&dir.join("reindeer.toml"))
+}
 
-    config.config_path = dir.to_path_buf();
+/// Read a config file, which may be named something other than
+/// `reindeer.toml`, or use the defaults if it doesn't exist. Relative paths in
+/// it are relative to its directory.
+pub fn read_config_from(path: &Path) -> anyhow::Result<Config> {
+    let mut config = try_read_config(path)?;
 
-    if config.buck.buckfile_imports.is_default {
-        // Fill in some prelude imports so Reindeer generates working targets
-        // out of the box.
-        let mut buckfile_imports = String::new();
+    config.config_path = path.to_path_buf();
+    config
+        .expand_path_vars(|var| env::var(var).ok())
+        .with_context(|| format!("Failed to expand variables in {}", path.display()))?;
 
-        if config.buck.buildscript_genrule.is_default {
-            buckfile_imports
-                .push_str("load(\"@prelude//rust:cargo_buildscript.bzl\", \"buildscript_run\")\n");
-        }
+    if config.buck.buckfile_imports.is_default {
+        // Use some prelude imports so Reindeer generates working targets out
+        // of the box. Which ones the BUCK file needs depends on the rules.
+        config.buck.prelude_imports.buildscript_run = config.buck.buildscript_genrule.is_default;
 
         if config.buck.rust_library.is_default && config.buck.rust_binary.is_default {
-            buckfile_imports.push_str("load(\"@prelude//rust:cargo_package.bzl\", \"cargo\")\n");
+            config.buck.prelude_imports.cargo = true;
             config.buck.rust_library = "cargo.rust_library".to_owned().into();
             config.buck.rust_binary = "cargo.rust_binary".to_owned().into();
         }
-
-        config.buck.buckfile_imports = buckfile_imports.into();
     }
 
     Ok(config)
 }
 
+/// Paths which are relative to the directory of the config file they're in,
+/// as `(table, key)`.
+const FILE_RELATIVE_PATHS: &[(&str, &str)] = &[
+    ("cargo", "cargo"),
+    ("cargo", "rustc"),
+    ("audit", "advisory_db"),
+];
+
+/// Make a base config's file-relative paths relative to `dir`, the directory of
+/// the base config relative to the one extending it. Variables are expanded
+/// first, since they may make a path absolute.
+fn rebase_config_paths(path: &Path, table: &mut toml::Table, dir: &Path) -> anyhow::Result<()> {
+    for (section, key) in FILE_RELATIVE_PATHS {
+        let Some(toml::Value::Table(section)) = table.get_mut(*section) else {
+            continue;
+        };
+        let Some(toml::Value::String(value)) = section.get_mut(*key) else {
+            continue;
+        };
+        let expanded = expand_vars(Path::new(value), |var| env::var(var).ok())
+            .with_context(|| format!("Failed to expand variables in {}", path.display()))?;
+        *value = dir.join(expanded).to_string_lossy().into_owned();
+    }
+    Ok(())
+}
+
+/// Overlay a config file's table on the config named by its `extends` key, if
+/// any, after doing the same for that one. The path is relative to the file.
+/// Tables are merged key by key, recursively, and any other value, including a
+/// list, replaces the base config's. The base's relative paths are rebased
+/// first, so they stay relative to its own directory. `chain` holds the
+/// canonical paths of the files leading to this one, for detecting cycles.
+fn extend_config(
+    path: &Path,
+    table: toml::Table,
+    chain: &mut Vec<PathBuf>,
+) -> anyhow::Result<toml::Table> {
+    let extends = match table.get("extends") {
+        None => return Ok(table),
+        Some(toml::Value::String(extends)) => PathBuf::from(extends),
+        Some(_) => anyhow::bail!("{}: `extends` must be a path", path.display()),
+    };
+    let base_path = path.parent().unwrap_or(Path::new("")).join(&extends);
+    let canonical = dunce::canonicalize(&base_path)
+        .with_context(|| format!("Failed to read config {}", base_path.display()))?;
+    if chain.contains(&canonical) {
+        anyhow::bail!(
+            "Config `extends` cycle: {} -> {}",
+            chain.iter().map(|path| path.display()).join(" -> "),
+            canonical.display(),
+        );
+    }
+    chain.push(canonical);
+
+    let file = fs::read_to_string(&base_path)
+        .with_context(|| format!("Failed to read config {}", base_path.display()))?;
+    let base: toml::Table =
+        toml::from_str(&file).context(format!("Failed to parse {}", base_path.display()))?;
+    let mut base = extend_config(&base_path, base, chain)?;
+    let base_dir = extends.parent().unwrap_or(Path::new(""));
+    rebase_config_paths(&base_path, &mut base, base_dir)?;
+    merge_config_tables(&mut base, table);
+    Ok(base)
+}
+
+pub fn merge_config_tables(base: &mut toml::Table, overlay: toml::Table) {
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(overlay)) => {
+                merge_config_tables(base, overlay);
+            }
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
 fn try_read_config(path: &Path) -> anyhow::Result<Config> {
     let file = match fs::read_to_string(path) {
         Ok(file) => file,
@@ -330,10 +881,309 @@ fn try_read_config(path: &Path) -> anyhow::Result<Config> {
         Err(err) => return Err(err).context(format!("Failed to read config {}", path.display())),
     };
 
-    let config: Config =
+    let table: toml::Table =
         toml::from_str(&file).context(format!("Failed to parse {}", path.display()))?;
+    let config: Config = if table.contains_key("extends") {
+        let mut chain = vec![dunce::canonicalize(path)?];
+        let mut table = extend_config(path, table, &mut chain)?;
+        table.remove("extends");
+        Config::deserialize(table).context(format!("Failed to parse {}", path.display()))?
+    } else {
+        // Straight from the text, for errors with line numbers
+        toml::from_str(&file).context(format!("Failed to parse {}", path.display()))?
+    };
 
     log::debug!("Read config {:#?}", config);
 
+    for (name, platform) in &config.platform {
+        let unrecognized = platform.unrecognized_keys();
+        if !unrecognized.is_empty() {
+            log::warn!(
+                "{}: platform `{}` has unrecognized cfg keys: {}",
+                path.display(),
+                name,
+                unrecognized
+                    .iter()
+                    .map(|(key, suggestion)| match suggestion {
+                        Some(suggestion) => format!("`{key}` (did you mean `{suggestion}`?)"),
+                        None => format!("`{key}`"),
+                    })
+                    .join(", "),
+            );
+        }
+    }
+
+    if let Some(host) = &config.host_platform {
+        if !config.platform.contains_key(host) {
+            anyhow::bail!(
+                "{}: `host_platform = \"{}\"` isn't one of the configured platforms: {}",
+                path.display(),
+                host,
+                config.platform.keys().join(", "),
+            );
+        }
+    }
+
+    config
+        .cargo
+        .check_passthrough()
+        .with_context(|| format!("{}: invalid `[cargo]` config", path.display()))?;
+
+    if config.buck.platform_representation == PlatformRepresentation::Select {
+        if config.universe.len() > 1 {
+            anyhow::bail!(
+                "{}: `platform_representation = \"select\"` can't be used with multiple universes",
+                path.display(),
+            );
+        }
+        let missing: Vec<_> = config
+            .platform
+            .keys()
+            .filter(|name| !config.buck.platform_settings.contains_key(name))
+            .collect();
+        if !missing.is_empty() {
+            anyhow::bail!(
+                "{}: `platform_representation = \"select\"` needs a `platform_settings` entry for platforms {}",
+                path.display(),
+                missing.iter().map(|name| format!("`{name}`")).join(", "),
+            );
+        }
+        // Each platform's attributes are a branch of the `select()`, so two
+        // platforms can't share a key.
+        let mut by_setting = HashMap::new();
+        for name in config.platform.keys() {
+            let setting = &config.buck.platform_settings[name];
+            if let Some(other) = by_setting.insert(setting, name) {
+                anyhow::bail!(
+                    "{}: platforms `{other}` and `{name}` have the same `platform_settings` entry `{setting}`, \
+                     which `platform_representation = \"select\"` needs to be different",
+                    path.display(),
+                );
+            }
+        }
+    }
+
     Ok(config)
 }
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::path::Path;
+
+    use super::expand_vars;
+    use super::read_config;
+    use super::read_config_from;
+    use super::LinkStyle;
+    use crate::platform::PlatformExpr;
+    use crate::platform::PlatformPredicate;
+
+    #[test]
+    fn config_path_is_file_read() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("configs/linux.toml");
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, "[cargo]\ncargo = \"bin/cargo\"\n").unwrap();
+
+        let config = read_config_from(&path).unwrap();
+        assert_eq!(config.config_path, path);
+        assert_eq!(config.config_dir(), tempdir.path().join("configs"));
+        assert!(config.cargo.cargo.is_some());
+
+        // A directory without reindeer.toml gets the defaults
+        let config = read_config(tempdir.path()).unwrap();
+        assert_eq!(config.config_path, tempdir.path().join("reindeer.toml"));
+        assert_eq!(config.config_dir(), tempdir.path());
+        assert!(config.cargo.cargo.is_none());
+    }
+
+    #[test]
+    fn extends_overlays_base_config() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let write = |path: &str, content: &str| {
+            let path = tempdir.path().join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, content).unwrap();
+            path
+        };
+        write(
+            "common.toml",
+            "precise_srcs = true\nlicense_patterns = [\"LICENSE\"]\n\n\
+             [cargo]\ncargo = \"bin/cargo\"\nrustc = \"/opt/rust/bin/rustc\"\n\n\
+             [buck]\nfile_name = \"BUCK.common\"\nexternal_targets = [\"prelude//...\"]\n\n\
+             [platform.linux]\ntarget_os = [\"linux\"]\n",
+        );
+        let path = write(
+            "tree/reindeer.toml",
+            "extends = \"../common.toml\"\nprecise_srcs = false\n\n\
+             [buck]\nexternal_targets = [\"//cxx:\"]\n\n\
+             [platform.linux]\ntarget_family = [\"unix\"]\n\n\
+             [platform.windows]\ntarget_os = [\"windows\"]\n",
+        );
+
+        let config = read_config_from(&path).unwrap();
+        assert!(!config.precise_srcs);
+        // Still relative to the base config's directory
+        assert_eq!(
+            config
+                .config_dir()
+                .join(config.cargo.cargo.as_ref().unwrap()),
+            tempdir.path().join("tree/../bin/cargo"),
+        );
+        assert_eq!(
+            config.cargo.rustc.as_deref(),
+            Some(Path::new("/opt/rust/bin/rustc")),
+        );
+        assert_eq!(
+            config.license_patterns.iter().collect::<Vec<_>>(),
+            ["LICENSE"],
+        );
+        assert_eq!(&*config.buck.file_name, "BUCK.common");
+        assert_eq!(config.buck.external_targets, ["//cxx:"]);
+        let linux = config
+            .platform
+            .iter()
+            .find_map(|(name, platform)| (name.to_string() == "linux").then_some(platform))
+            .unwrap();
+        assert_eq!(linux.target_os(), Some("linux"));
+        let unix = PlatformExpr::from("cfg(unix)".to_owned());
+        assert!(PlatformPredicate::parse(&unix).unwrap().eval(linux));
+        assert_eq!(
+            config
+                .platform
+                .keys()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>(),
+            ["linux", "windows"],
+        );
+    }
+
+    #[test]
+    fn extends_cycle() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let a = tempdir.path().join("a.toml");
+        let b = tempdir.path().join("b.toml");
+        fs::write(&a, "extends = \"b.toml\"\n").unwrap();
+        fs::write(&b, "extends = \"a.toml\"\n").unwrap();
+
+        let err = read_config_from(&a).unwrap_err().to_string();
+        assert!(err.starts_with("Config `extends` cycle: "), "{err}");
+        assert!(err.ends_with("a.toml"), "{err}");
+    }
+
+    #[test]
+    fn expands_path_vars() {
+        let lookup = |var: &str| match var {
+            "CARGO_HOME" => Some("/home/me/.cargo".to_owned()),
+            "EMPTY" => Some(String::new()),
+            _ => None,
+        };
+        let expand = |path: &str| {
+            expand_vars(Path::new(path), lookup)
+                .map(|path| path.display().to_string())
+                .map_err(|err| err.to_string())
+        };
+        assert_eq!(
+            expand("${CARGO_HOME}/bin/cargo").unwrap(),
+            "/home/me/.cargo/bin/cargo",
+        );
+        assert_eq!(
+            expand("${CI_TOOLS:-tools}/rustc-$VERSION").unwrap(),
+            "tools/rustc-$VERSION",
+        );
+        assert_eq!(expand("${EMPTY:-tools}/bin").unwrap(), "tools/bin");
+        assert_eq!(expand("${EMPTY}bin").unwrap(), "bin");
+        assert_eq!(
+            expand("${CI_TOOLS}/rustc").unwrap_err(),
+            "Environment variable `CI_TOOLS` in ${CI_TOOLS}/rustc is not set",
+        );
+        assert_eq!(
+            expand("${CARGO_HOME/bin").unwrap_err(),
+            "Unterminated `${` in ${CARGO_HOME/bin",
+        );
+    }
+
+    #[test]
+    fn cargo_passthrough_conflicts() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("reindeer.toml");
+        let read = |cargo: &str| {
+            fs::write(&path, format!("[cargo]\n{cargo}")).unwrap();
+            read_config_from(&path).map_err(|err| format!("{err:#}"))
+        };
+
+        let config = read(
+            "cargo_flags = [\"--config\", \"net.git-fetch-with-cli=true\", \"-Zno-index-update\"]\n\
+             cargo_env = { RUSTC_BOOTSTRAP = \"1\" }\n",
+        )
+        .unwrap();
+        assert_eq!(config.cargo.cargo_flags.len(), 3);
+        assert_eq!(config.cargo.cargo_env["RUSTC_BOOTSTRAP"], "1");
+
+        let err = read("cargo_flags = [\"--locked\"]\n").unwrap_err();
+        assert!(
+            err.ends_with("`cargo_flags` can't include `--locked`, which Reindeer sets itself"),
+            "{err}",
+        );
+        let err = read("cargo_flags = [\"-Z\", \"bindeps\"]\n").unwrap_err();
+        assert!(err.ends_with("use `bindeps = true`"), "{err}");
+        let err = read("cargo_flags = [\"--features=foo\"]\n").unwrap_err();
+        assert!(err.ends_with("use `features` in `[universe]`"), "{err}");
+        let err = read("cargo_env = { CARGO_HOME = \"/tmp\" }\n").unwrap_err();
+        assert!(
+            err.ends_with("`cargo_env` can't set `CARGO_HOME`, which Reindeer sets itself"),
+            "{err}",
+        );
+    }
+
+    #[test]
+    fn platform_settings_collide() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("reindeer.toml");
+        fs::write(
+            &path,
+            "[platform.linux-gnu]\n\
+             target_os = [\"linux\"]\n\
+             [platform.linux-musl]\n\
+             target_os = [\"linux\"]\n\
+             [buck]\n\
+             platform_representation = \"select\"\n\
+             platform_settings = { linux-gnu = \"//platforms:linux\", linux-musl = \"//platforms:linux\" }\n",
+        )
+        .unwrap();
+        let err = format!("{:#}", read_config_from(&path).unwrap_err());
+        assert!(
+            err.ends_with(
+                "platforms `linux-gnu` and `linux-musl` have the same `platform_settings` entry \
+                 `//platforms:linux`, which `platform_representation = \"select\"` needs to be different"
+            ),
+            "{err}",
+        );
+    }
+
+    #[test]
+    fn default_binary_link_style() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("reindeer.toml");
+        let read = |buck: &str| {
+            fs::write(&path, format!("[buck]\n{buck}")).unwrap();
+            read_config_from(&path).map_err(|err| format!("{err:#}"))
+        };
+
+        let config = read("").unwrap();
+        assert_eq!(config.buck.default_binary_link_style, None);
+        let config = read("default_binary_link_style = \"static_pic\"\n").unwrap();
+        assert_eq!(
+            config.buck.default_binary_link_style,
+            Some(LinkStyle::StaticPic),
+        );
+        assert_eq!(LinkStyle::StaticPic.to_string(), "static_pic");
+        let err = read("default_binary_link_style = \"dynamic\"\n").unwrap_err();
+        assert!(
+            err.contains(
+                "unknown variant `dynamic`, expected one of `static`, `shared`, `static_pic`"
+            ),
+            "{err}",
+        );
+    }
+}
@@ -13,19 +13,30 @@ use std::collections::HashMap;
 use std::collections::HashSet;
 use std::fmt;
 use std::fs;
+use std::iter;
 use std::path::Path;
 use std::path::PathBuf;
+use std::sync::Mutex;
 
 use anyhow::anyhow;
 use anyhow::bail;
 use anyhow::Context;
+use indexmap::IndexMap;
+use semver::Version;
+use serde::Deserialize;
+use serde::Serialize;
+use strum::IntoEnumIterator as _;
 
 use crate::buck;
 use crate::buck::Alias;
 use crate::buck::BuckPath;
 use crate::buck::BuildscriptGenrule;
 use crate::buck::Common;
+use crate::buck::Filegroup;
+use crate::buck::MappedSrc;
+use crate::buck::MappedSrcs;
 use crate::buck::Name;
+use crate::buck::OutDirFile;
 use crate::buck::Rule;
 use crate::buck::RuleRef;
 use crate::buck::RustBinary;
@@ -36,12 +47,15 @@ use crate::buck::Visibility;
 use crate::buckify::normalize_path;
 use crate::buckify::relative_path;
 use crate::buckify::short_name_for_git_repo;
+use crate::cargo::Edition;
 use crate::cargo::Manifest;
 use crate::cargo::ManifestTarget;
 use crate::cargo::NodeDepKind;
 use crate::cargo::Source;
 use crate::collection::SetOrMap;
+use crate::config::CargoPkgEnv;
 use crate::config::Config;
+use crate::glob::literal_prefix;
 use crate::glob::Globs;
 use crate::glob::SerializableGlobSet as GlobSet;
 use crate::glob::NO_EXCLUDE;
@@ -49,6 +63,7 @@ use crate::index::Index;
 use crate::index::ResolvedDep;
 use crate::platform::platform_names_for_expr;
 use crate::platform::PlatformExpr;
+use crate::platform::PlatformName;
 use crate::platform::PlatformPredicate;
 use crate::Paths;
 
@@ -60,10 +75,249 @@ use buildscript::CxxLibraryFixup;
 use buildscript::GenSrcs;
 use buildscript::PrebuiltCxxLibraryFixup;
 use buildscript::RustcFlags;
+use buildscript::SkipBuildscript;
+use config::buildscript_cfgs;
+use config::buildscript_env;
+use config::buildscript_link_libs;
 use config::CargoEnv;
 pub use config::ExportSources;
 use config::FixupConfigFile;
 
+/// A build script which needs a fixup that hasn't been written yet
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub struct UnresolvedFixup {
+    pub package: String,
+    pub version: Version,
+    /// Fixup file to add the directive to, relative to the third-party dir
+    pub fixup_file: PathBuf,
+    /// Section which needs to be filled in
+    pub directive: &'static str,
+    pub message: String,
+}
+
+/// A fixup file, or a section of one, which doesn't apply to anything in the
+/// dependency graph
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct UnusedFixup {
+    /// Fixup file, relative to the third-party dir
+    pub fixup_file: PathBuf,
+    /// Section of the file, or `None` if none of it applies
+    pub section: Option<String>,
+    pub reason: String,
+}
+
+impl fmt::Display for UnusedFixup {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{}", self.fixup_file.display())?;
+        if let Some(section) = &self.section {
+            write!(fmt, " {section}")?;
+        }
+        write!(fmt, ": {}", self.reason)
+    }
+}
+
+/// The versions of every package in the dependency graphs buckify saw, for
+/// finding fixups which no longer apply to any of them.
+///
+/// This covers every package cargo resolved, including ones only reachable on
+/// platforms which aren't configured or are left out by `--target-platforms`,
+/// so their fixups aren't mistaken for unused.
+#[derive(Debug, Default)]
+pub struct FixupUsage {
+    packages: BTreeMap<String, BTreeSet<Version>>,
+    /// Packages buckify generated rules for which have fixup files
+    with_fixups: BTreeSet<(String, Version)>,
+}
+
+impl FixupUsage {
+    pub fn record<'a>(&mut self, packages: impl IntoIterator<Item = &'a Manifest>) {
+        for package in packages {
+            self.packages
+                .entry(package.name.clone())
+                .or_default()
+                .insert(package.version.clone());
+        }
+    }
+
+    pub fn record_with_fixups<'a>(&mut self, packages: impl IntoIterator<Item = &'a Manifest>) {
+        for package in packages {
+            self.with_fixups
+                .insert((package.name.clone(), package.version.clone()));
+        }
+    }
+
+    /// Number of packages, across universes, which buckify generated rules
+    /// for using fixup files.
+    pub fn packages_with_fixups(&self) -> usize {
+        self.with_fixups.len()
+    }
+
+    /// Fixup files for packages which aren't in the graph, and sections of the
+    /// rest which can't apply to any version of their package that is, or to
+    /// any configured platform.
+    pub fn unused(&self, config: &Config, paths: &Paths) -> anyhow::Result<Vec<UnusedFixup>> {
+        let fixups_dir = paths.third_party_dir.join("fixups");
+        let entries = match fs::read_dir(&fixups_dir) {
+            Ok(entries) => entries,
+            Err(_) => return Ok(Vec::new()),
+        };
+
+        // Versions of the packages each fixup dir applies to, directly or
+        // through `fixup_globs`.
+        let mut applies_to: BTreeMap<&str, BTreeSet<Version>> = BTreeMap::new();
+        for (name, versions) in &self.packages {
+            let dirs = iter::once(name.as_str()).chain(fixup_glob_dirs(config, name)?);
+            for dir in dirs {
+                applies_to
+                    .entry(dir)
+                    .or_default()
+                    .extend(versions.iter().cloned());
+            }
+        }
+
+        let mut unused = Vec::new();
+        for entry in entries {
+            let entry = entry.with_context(|| format!("reading {}", fixups_dir.display()))?;
+            let Ok(files) = fs::read_dir(entry.path()) else {
+                continue;
+            };
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let versions = applies_to.get(name.as_str());
+
+            for file in files {
+                let file = file.with_context(|| format!("reading {}", entry.path().display()))?;
+                if !file.file_name().to_str().is_some_and(is_fixup_file_name) {
+                    continue;
+                }
+                let fixup_path = file.path();
+                let content = fs::read_to_string(&fixup_path)
+                    .with_context(|| format!("reading {}", fixup_path.display()))?;
+                let fixup_file = relative_path(&paths.third_party_dir, &fixup_path);
+
+                let Some(versions) = versions else {
+                    let reason = if config.fixup_globs.values().any(|dir| *dir == name) {
+                        "no package matching its `fixup_globs` in the dependency graph".to_owned()
+                    } else {
+                        format!("no package named `{name}` in the dependency graph")
+                    };
+                    unused.push(UnusedFixup {
+                        fixup_file,
+                        section: None,
+                        reason,
+                    });
+                    continue;
+                };
+
+                let fixup_config = FixupConfigFile::parse(&fixup_file, &content)?;
+                for (section, reason) in fixup_config.unused_sections(config, versions)? {
+                    unused.push(UnusedFixup {
+                        fixup_file: fixup_file.clone(),
+                        section: Some(section),
+                        reason,
+                    });
+                }
+            }
+        }
+
+        unused.sort();
+        Ok(unused)
+    }
+}
+
+/// Directories under `fixups/` from `fixup_globs` whose glob matches the
+/// package name, in order of priority, lowest first: those with a shorter
+/// literal prefix, then by glob.
+pub fn fixup_glob_dirs<'a>(config: &'a Config, package_name: &str) -> anyhow::Result<Vec<&'a str>> {
+    let mut matching = Vec::new();
+    for (glob, dir) in &config.fixup_globs {
+        let matcher = globset::Glob::new(glob)
+            .with_context(|| format!("invalid glob `{glob}` in `fixup_globs`"))?
+            .compile_matcher();
+        if matcher.is_match(package_name) {
+            let literal_prefix = glob.find(['*', '?', '[', '{', '\\']).unwrap_or(glob.len());
+            matching.push((literal_prefix, glob, dir.as_str()));
+        }
+    }
+    matching.sort();
+    Ok(matching.into_iter().map(|(_, _, dir)| dir).collect())
+}
+
+/// `fixups.toml`, or a version-specific `fixups-*.toml`
+fn is_fixup_file_name(file_name: &str) -> bool {
+    file_name == "fixups.toml" || (file_name.starts_with("fixups-") && file_name.ends_with(".toml"))
+}
+
+/// Whether a package's fixup directory has any fixup files, for any version.
+pub fn has_fixup_files(fixup_dir: &Path) -> bool {
+    fs::read_dir(fixup_dir).is_ok_and(|entries| {
+        entries
+            .flatten()
+            .any(|entry| entry.file_name().to_str().is_some_and(is_fixup_file_name))
+    })
+}
+
+/// The package's own fixups, relative to the third-party dir, with its
+/// contents. That's whichever of the package's fixup files has a top-level
+/// `version` matching the package, or else `fixups.toml`. Version-specific
+/// files are named `fixups-*.toml`, like `fixups-0.4.toml`.
+fn package_fixup_file(
+    third_party_dir: &Path,
+    fixup_dir: &Path,
+    package: &Manifest,
+) -> anyhow::Result<Option<(PathBuf, String)>> {
+    let entries = match fs::read_dir(fixup_dir) {
+        Ok(entries) => entries,
+        Err(_) => return Ok(None),
+    };
+
+    let mut unversioned = None;
+    let mut matching = Vec::new();
+    for entry in entries {
+        let entry = entry.with_context(|| format!("reading {}", fixup_dir.display()))?;
+        let file_name = entry.file_name();
+        let Some(file_name) = file_name.to_str() else {
+            continue;
+        };
+        if !is_fixup_file_name(file_name) {
+            continue;
+        }
+        let versioned = file_name != "fixups.toml";
+
+        let path = entry.path();
+        let display_path = relative_path(third_party_dir, &path);
+        let content = fs::read_to_string(&path)
+            .with_context(|| format!("reading fixups from {}", path.display()))?;
+        match FixupConfigFile::parse(&display_path, &content)?.version() {
+            Some(req) if req.matches(&package.version) => {
+                matching.push((display_path, content));
+            }
+            None if versioned => {
+                bail!(
+                    "{}: version-specific fixups need a top-level `version`",
+                    display_path.display(),
+                );
+            }
+            // A `fixups.toml` which doesn't match may still have sections
+            // which do.
+            _ if versioned => {}
+            _ => unversioned = Some((display_path, content)),
+        }
+    }
+
+    if matching.len() > 1 {
+        matching.sort();
+        bail!(
+            "{package} matches the `version` of more than one fixup file: {}",
+            matching
+                .iter()
+                .map(|(path, _)| path.display().to_string())
+                .collect::<Vec<_>>()
+                .join(", "),
+        );
+    }
+    Ok(matching.pop().or(unversioned))
+}
+
 /// Fixups for a specific package & target
 pub struct Fixups<'meta> {
     config: &'meta Config,
@@ -72,6 +326,12 @@ pub struct Fixups<'meta> {
     package: &'meta Manifest,
     target: &'meta ManifestTarget,
     fixup_dir: PathBuf,
+    /// The package's own fixup file, relative to the third-party dir, or
+    /// where it would go
+    fixup_file: PathBuf,
+    /// Whether the package has a fixup file of its own or shared by glob,
+    /// rather than just the default template
+    from_file: bool,
     fixup_config: FixupConfigFile,
     manifest_dir: &'meta Path,
 }
@@ -83,6 +343,8 @@ impl<'meta> fmt::Debug for Fixups<'meta> {
             .field("target", &self.target)
             .field("third_party_dir", &self.third_party_dir)
             .field("fixup_dir", &self.fixup_dir)
+            .field("fixup_file", &self.fixup_file)
+            .field("from_file", &self.from_file)
             .field("manifest_dir", &self.manifest_dir)
             .field("fixup_config", &self.fixup_config)
             .finish()
@@ -101,9 +363,38 @@ impl<'meta> Fixups<'meta> {
         let fixup_dir = paths.third_party_dir.join("fixups").join(&package.name);
         let fixup_path = fixup_dir.join("fixups.toml");
 
-        let fixup_config: FixupConfigFile = if let Ok(file) = fs::read_to_string(&fixup_path) {
-            log::debug!("read fixups from {}", fixup_path.display());
-            toml::from_str(&file).context(format!("Failed to parse {}", fixup_path.display()))?
+        let mut files = Vec::new();
+        for dir in fixup_glob_dirs(config, &package.name)? {
+            let path = paths
+                .third_party_dir
+                .join("fixups")
+                .join(dir)
+                .join("fixups.toml");
+            let file = fs::read_to_string(&path)
+                .with_context(|| format!("reading fixups for {package} from {}", path.display()))?;
+            files.push((relative_path(&paths.third_party_dir, &path), file));
+        }
+
+        let own_fixups = package_fixup_file(&paths.third_party_dir, &fixup_dir, package)?;
+        let from_file = own_fixups.is_some() || !files.is_empty();
+        let fixup_file = match &own_fixups {
+            Some((display_path, _)) => display_path.clone(),
+            None => relative_path(&paths.third_party_dir, &fixup_path),
+        };
+        let fixup_config: FixupConfigFile = if let Some((display_path, file)) = own_fixups {
+            log::debug!("read fixups from {}", display_path.display());
+            if files.is_empty() {
+                FixupConfigFile::parse(&display_path, &file)?
+            } else {
+                files.push((display_path, file));
+                FixupConfigFile::parse_merged(&files)?
+            }
+        } else if !files.is_empty() {
+            log::debug!(
+                "no fixups at {}, only ones shared by glob",
+                fixup_path.display()
+            );
+            FixupConfigFile::parse_merged(&files)?
         } else {
             log::debug!("no fixups at {}", fixup_path.display());
             let fixup = FixupConfigFile::template(&paths.third_party_dir, target);
@@ -119,7 +410,9 @@ impl<'meta> Fixups<'meta> {
                     fixup_path.display()
                 );
 
-                let file = toml::to_string_pretty(&fixup)?;
+                let mut file =
+                    FixupConfigFile::template_header(&paths.third_party_dir, package, target);
+                file += &toml::to_string_pretty(&fixup)?;
                 fs::create_dir_all(fixup_path.parent().unwrap())?;
                 fs::write(&fixup_path, file)?;
             }
@@ -140,6 +433,8 @@ impl<'meta> Fixups<'meta> {
             package,
             target,
             fixup_dir,
+            fixup_file,
+            from_file,
             fixup_config,
             config,
         })
@@ -191,29 +486,144 @@ impl<'meta> Fixups<'meta> {
 
     pub fn public_visibility(&self) -> Visibility {
         match self.fixup_config.custom_visibility.as_deref() {
-            Some(visibility) => Visibility::Custom(visibility.to_vec()),
+            Some(visibility) => {
+                Visibility::Custom(visibility.iter().map(|pattern| pattern.0.clone()).collect())
+            }
             None => Visibility::Public,
         }
     }
 
-    pub fn python_ext(&self) -> Option<&str> {
-        self.fixup_config.python_ext.as_deref()
+    /// Python extension module name, either from the fixup or detected for
+    /// a cdylib built with pyo3's `extension-module` feature.
+    pub fn python_ext(&self) -> Option<String> {
+        if let Some(python_ext) = &self.fixup_config.python_ext {
+            return Some(python_ext.clone());
+        }
+
+        if !self.target.kind_cdylib() {
+            return None;
+        }
+        let pyo3 = self
+            .index
+            .resolved_deps_for_target(self.package, self.target)
+            .find(|dep| dep.package.name == "pyo3")?;
+        if !self
+            .index
+            .resolved_features(pyo3.package)
+            .any(|feature| feature == "extension-module")
+        {
+            return None;
+        }
+
+        let python_ext = maturin_module_name(self.manifest_dir, self.target);
+        log::debug!("{}: detected python_ext {}", self.package, python_ext);
+        Some(python_ext)
+    }
+
+    /// Dynamic library for the crate's `rust_library` to load in its place.
+    pub fn crate_dynamic(&self) -> Option<RuleRef> {
+        let crate_dynamic = self.fixup_config.crate_dynamic.as_ref()?;
+        Some(RuleRef::new(crate_dynamic.clone()))
+    }
+
+    /// Name of the alias to emit for the package's native library, if any.
+    fn linkable_alias(&self) -> Option<String> {
+        if let Some(linkable_alias) = &self.fixup_config.linkable_alias {
+            return Some(linkable_alias.clone());
+        }
+        match &self.package.links {
+            Some(links)
+                if self.config.sys_linkable_aliases && self.package.name.ends_with("-sys") =>
+            {
+                Some(format!("lib{links}"))
+            }
+            _ => None,
+        }
+    }
+
+    /// Namespace for the headers exported by a `-sys` crate's C++ libraries,
+    /// so they're included as `<{links}/foo.h>`.
+    fn default_header_namespace(&self) -> Option<String> {
+        match &self.package.links {
+            Some(links) if self.package.name.ends_with("-sys") => Some(links.clone()),
+            _ => None,
+        }
+    }
+
+    /// Whether any fixup file applies to the package.
+    pub fn has_fixup_file(&self) -> bool {
+        self.from_file
     }
 
     pub fn omit_target(&self) -> bool {
         self.fixup_config.omit_targets.contains(&self.target.name)
     }
 
+    /// Whether to generate rules for the package's binaries when they're only
+    /// wanted because the package is a workspace member.
+    pub fn emit_binary(&self) -> bool {
+        self.fixup_config
+            .emit_binary
+            .or(self.config.buck.emit_binaries)
+            .unwrap_or(true)
+    }
+
     pub fn export_sources(&self) -> Option<&ExportSources> {
         self.fixup_config.export_sources.as_ref()
     }
 
+    /// A `filegroup` of the crate's runtime data files, if the fixup declares
+    /// any.
+    pub fn data_filegroup(&self) -> anyhow::Result<Option<Filegroup>> {
+        let data = &self.fixup_config.data;
+        if data.is_empty() {
+            return Ok(None);
+        }
+        let mut globs = Globs::new(data, NO_EXCLUDE).context("data")?;
+        let srcs = globs
+            .walk(self.manifest_dir)
+            .map(|path| {
+                let source = self.subtarget_or_path(&path)?;
+                Ok((BuckPath(path), source))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        if self.config.strict_globs {
+            globs.check_all_globs_used()?;
+        }
+        Ok(Some(Filegroup {
+            name: Name(format!(
+                "{}-data",
+                self.index.private_rule_name(self.package),
+            )),
+            srcs,
+            visibility: Visibility::Private,
+        }))
+    }
+
+    /// Constraints the fixup sets for the crate's `target_compatible_with`.
+    pub fn target_compatible_with(&self) -> Option<&[String]> {
+        self.fixup_config.target_compatible_with.as_deref()
+    }
+
+    /// Edition to build the target with, if overridden from the manifest's.
+    pub fn edition(&self) -> Option<Edition> {
+        self.fixup_config.edition
+    }
+
     pub fn precise_srcs(&self) -> bool {
         self.fixup_config
             .precise_srcs
             .unwrap_or(self.config.precise_srcs)
     }
 
+    /// Globs for files to leave out of the crate's srcs.
+    fn srcs_exclude(&self) -> &GlobSet {
+        self.fixup_config
+            .srcs_exclude
+            .as_ref()
+            .unwrap_or(&self.config.buck.srcs_exclude)
+    }
+
     fn buildscript_target(&self) -> Option<&ManifestTarget> {
         self.package
             .targets
@@ -242,6 +652,61 @@ impl<'meta> Fixups<'meta> {
             .map(|tgt| Name(format!("{}-{}", self.package, tgt.name)))
     }
 
+    /// Name of the rule copying a file out of the build script's `OUT_DIR`,
+    /// like `foo-1.0.0-build-script-run-bindings-ffi.rs` for `bindings/ffi.rs`.
+    fn out_dir_file_name(&self, relative: &Path) -> Name {
+        let mut name = self.buildscript_genrule_name();
+        for component in relative.components() {
+            name.0.push('-');
+            name.0.push_str(&component.as_os_str().to_string_lossy());
+        }
+        name
+    }
+
+    /// Flags for each configured platform, from flags for the platforms
+    /// matching each of some predicates. A platform matching several gets
+    /// their flags in the order the predicates were written.
+    fn flags_by_platform(
+        &self,
+        flags: &IndexMap<PlatformExpr, Vec<String>>,
+    ) -> anyhow::Result<BTreeMap<PlatformName, Vec<String>>> {
+        let mut by_platform = BTreeMap::new();
+        for (expr, platform_flags) in flags {
+            for platform in platform_names_for_expr(self.config, expr)? {
+                by_platform
+                    .entry(platform.clone())
+                    .or_insert_with(Vec::new)
+                    .extend(platform_flags.iter().cloned());
+            }
+        }
+        Ok(by_platform)
+    }
+
+    /// Exported headers named by their install directory joined with their
+    /// path below the literal prefix of the glob which matched them.
+    fn mapped_exported_headers(
+        &self,
+        mapped: &BTreeMap<String, String>,
+        exclude: &[String],
+    ) -> anyhow::Result<SetOrMap<SubtargetOrPath>> {
+        let mut headers = SetOrMap::Map(BTreeMap::new());
+        for (install_dir, glob) in mapped {
+            let prefix = literal_prefix(glob);
+            let mut globs = Globs::new([glob], exclude).context("C++ mapped exported headers")?;
+            let mut matched = BTreeMap::new();
+            for path in globs.walk(self.manifest_dir) {
+                let below = path.strip_prefix(&prefix).unwrap_or(&path);
+                let header = BuckPath(Path::new(install_dir).join(below)).to_string();
+                matched.insert(header, self.subtarget_or_path(&path)?);
+            }
+            if self.config.strict_globs {
+                globs.check_all_globs_used()?;
+            }
+            headers.merge(SetOrMap::Map(matched))?;
+        }
+        Ok(headers)
+    }
+
     /// Return buildscript-related rules
     /// The rules may be platform specific, but they're emitted unconditionally. (The
     /// dependencies referencing them are conditional).
@@ -249,6 +714,7 @@ impl<'meta> Fixups<'meta> {
         &self,
         buildscript: RustBinary,
         config: &'meta Config,
+        unresolved: &Mutex<Vec<UnresolvedFixup>>,
     ) -> anyhow::Result<Vec<Rule>> {
         let mut res = Vec::new();
 
@@ -297,7 +763,11 @@ impl<'meta> Fixups<'meta> {
             .configs(&self.package.version)
             .flat_map(|(_platform, fixup)| fixup.buildscript.iter());
 
+        self.check_skipped_buildscript()?;
+
         let mut buildscript_run = None;
+        // Native library rules, with the name of the fixup they came from.
+        let mut native_libs = Vec::new();
         let default_genrule = || BuildscriptGenrule {
             name: self.buildscript_genrule_name(),
             buildscript_rule: buildscript_rule_name.clone(),
@@ -305,6 +775,8 @@ impl<'meta> Fixups<'meta> {
             version: self.package.version.clone(),
             features: buck::Selectable::Value(features.clone()),
             env: BTreeMap::new(),
+            path_env: BTreeMap::new(),
+            local_only: false,
         };
 
         for fix in fixes {
@@ -334,24 +806,48 @@ impl<'meta> Fixups<'meta> {
             match fix {
                 // Build and run it, and filter the output for --cfg options
                 // for the main target's rustc command line
-                BuildscriptFixup::RustcFlags(RustcFlags { env, .. }) => {
+                BuildscriptFixup::RustcFlags(RustcFlags {
+                    env,
+                    path_env,
+                    local_only,
+                    ..
+                }) => {
                     // Emit the build script itself
                     res.push(Rule::BuildscriptBinary(buildscript.clone()));
 
                     // Emit rule to get its stdout and filter it into args
                     let buildscript_run = buildscript_run.get_or_insert_with(default_genrule);
-                    buildscript_run.env.extend(env.clone());
+                    self.extend_buildscript_env(buildscript_run, env, path_env)?;
+                    buildscript_run.local_only |= local_only;
                 }
 
                 // Generated source files - given a list, set up rules to extract them from
                 // the buildscript.
-                BuildscriptFixup::GenSrcs(GenSrcs { env, .. }) => {
+                BuildscriptFixup::GenSrcs(GenSrcs {
+                    env,
+                    path_env,
+                    local_only,
+                    mapped_srcs,
+                    ..
+                }) => {
                     // Emit the build script itself
                     res.push(Rule::BuildscriptBinary(buildscript.clone()));
 
+                    // Copy the files mapped into the crate's sources out of
+                    // the script's output directory
+                    for generated in mapped_srcs.keys() {
+                        res.push(Rule::OutDirFile(OutDirFile {
+                            name: self.out_dir_file_name(generated),
+                            buildscript_run: self.buildscript_genrule_name(),
+                            relative: BuckPath(generated.clone()),
+                            visibility: Visibility::Private,
+                        }));
+                    }
+
                     // Emit rules to extract generated sources
                     let buildscript_run = buildscript_run.get_or_insert_with(default_genrule);
-                    buildscript_run.env.extend(env.clone());
+                    self.extend_buildscript_env(buildscript_run, env, path_env)?;
+                    buildscript_run.local_only |= local_only;
                 }
 
                 // Emit a C++ library build rule (elsewhere - add a dependency to it)
@@ -360,12 +856,16 @@ impl<'meta> Fixups<'meta> {
                     srcs,
                     headers,
                     exported_headers,
+                    mapped_exported_headers,
                     public,
                     include_paths,
+                    public_include_paths,
                     fixup_include_paths,
                     exclude,
                     compiler_flags,
+                    platform_compiler_flags,
                     preprocessor_flags,
+                    platform_preprocessor_flags,
                     header_namespace,
                     deps,
                     compatible_with,
@@ -387,21 +887,24 @@ impl<'meta> Fixups<'meta> {
                                 name,
                             )),
                             actual: actual.clone(),
+                            fallback_actual: None,
                             visibility: self.public_visibility(),
                         });
                         res.push(rule);
                     }
 
-                    let rule = buck::CxxLibrary {
+                    let mut rule = buck::CxxLibrary {
                         common: Common {
                             name: actual,
                             visibility: Visibility::Private,
                             licenses: Default::default(),
+                            license_expression: None,
                             compatible_with: compatible_with
                                 .iter()
                                 .cloned()
                                 .map(RuleRef::new)
                                 .collect(),
+                            target_compatible_with: vec![],
                         },
                         // Just collect the sources, excluding things in the exclude list
                         srcs: {
@@ -435,28 +938,42 @@ impl<'meta> Fixups<'meta> {
 
                             headers
                         },
-                        exported_headers: match exported_headers {
-                            SetOrMap::Set(exported_headers) => {
-                                let mut exported_header_globs =
-                                    Globs::new(exported_headers, exclude)
-                                        .context("C++ exported headers")?;
-                                let exported_headers = exported_header_globs
-                                    .walk(self.manifest_dir)
-                                    .map(|path| self.subtarget_or_path(&path))
-                                    .collect::<anyhow::Result<_>>()?;
-                                if self.config.strict_globs {
-                                    exported_header_globs.check_all_globs_used()?;
+                        exported_headers: {
+                            let mut exported = match exported_headers {
+                                SetOrMap::Set(exported_headers) => {
+                                    let mut exported_header_globs =
+                                        Globs::new(exported_headers, exclude)
+                                            .context("C++ exported headers")?;
+                                    let exported_headers = exported_header_globs
+                                        .walk(self.manifest_dir)
+                                        .map(|path| self.subtarget_or_path(&path))
+                                        .collect::<anyhow::Result<_>>()?;
+                                    if self.config.strict_globs {
+                                        exported_header_globs.check_all_globs_used()?;
+                                    }
+                                    SetOrMap::Set(exported_headers)
                                 }
-                                SetOrMap::Set(exported_headers)
-                            }
-                            SetOrMap::Map(exported_headers) => SetOrMap::Map(
-                                exported_headers
-                                    .iter()
-                                    .map(|(name, path)| {
-                                        Ok((name.clone(), self.subtarget_or_path(Path::new(path))?))
-                                    })
-                                    .collect::<anyhow::Result<_>>()?,
-                            ),
+                                SetOrMap::Map(exported_headers) => SetOrMap::Map(
+                                    exported_headers
+                                        .iter()
+                                        .map(|(name, path)| {
+                                            Ok((
+                                                name.clone(),
+                                                self.subtarget_or_path(Path::new(path))?,
+                                            ))
+                                        })
+                                        .collect::<anyhow::Result<_>>()?,
+                                ),
+                            };
+                            let mapped = self
+                                .mapped_exported_headers(mapped_exported_headers, exclude)
+                                .with_context(|| {
+                                    format!("cxx_library {name} mapped_exported_headers")
+                                })?;
+                            exported
+                                .merge(mapped)
+                                .with_context(|| format!("cxx_library {name} exported_headers"))?;
+                            exported
                         },
                         include_directories: fixup_include_paths
                             .iter()
@@ -467,14 +984,37 @@ impl<'meta> Fixups<'meta> {
                                     .map(|path| self.subtarget_or_path(path)),
                             )
                             .collect::<anyhow::Result<_>>()?,
-                        compiler_flags: compiler_flags.clone(),
+                        public_include_directories: public_include_paths
+                            .iter()
+                            .map(|path| self.subtarget_or_path(path))
+                            .collect::<anyhow::Result<_>>()?,
+                        // Global flags, then the fixup's own. Its per-platform flags
+                        // follow these in the `cxx_library` macro.
+                        compiler_flags: self
+                            .config
+                            .buck
+                            .cxx_compiler_flags
+                            .iter()
+                            .chain(compiler_flags)
+                            .cloned()
+                            .collect(),
+                        platform_compiler_flags: self
+                            .flags_by_platform(platform_compiler_flags)
+                            .context("platform_compiler_flags")?,
                         preprocessor_flags: preprocessor_flags.clone(),
+                        platform_preprocessor_flags: self
+                            .flags_by_platform(platform_preprocessor_flags)
+                            .context("platform_preprocessor_flags")?,
                         header_namespace: header_namespace.clone(),
                         deps: deps.iter().cloned().map(RuleRef::new).collect(),
                         preferred_linkage: preferred_linkage.clone(),
                         undefined_symbols: undefined_symbols.clone(),
                     };
+                    if rule.header_namespace.is_none() && !rule.exported_headers.is_empty() {
+                        rule.header_namespace = self.default_header_namespace();
+                    }
 
+                    native_libs.push((name.as_str(), rule.common.name.clone()));
                     res.push(Rule::CxxLibrary(rule));
                 }
 
@@ -484,8 +1024,14 @@ impl<'meta> Fixups<'meta> {
                     static_libs,
                     public,
                     compatible_with,
+                    exported_preprocessor_flags,
+                    header_dirs,
                     ..
                 }) => {
+                    let header_dirs = header_dirs
+                        .iter()
+                        .map(|path| self.subtarget_or_path(path))
+                        .collect::<anyhow::Result<Vec<_>>>()?;
                     let mut static_lib_globs =
                         Globs::new(static_libs, NO_EXCLUDE).context("Static libraries")?;
                     for static_lib in static_lib_globs.walk(self.manifest_dir) {
@@ -505,6 +1051,7 @@ impl<'meta> Fixups<'meta> {
                                     static_lib.file_name().unwrap().to_string_lossy(),
                                 )),
                                 actual: actual.clone(),
+                                fallback_actual: None,
                                 visibility: self.public_visibility(),
                             });
                             res.push(rule);
@@ -515,14 +1062,19 @@ impl<'meta> Fixups<'meta> {
                                 name: actual,
                                 visibility: Visibility::Private,
                                 licenses: Default::default(),
+                                license_expression: None,
                                 compatible_with: compatible_with
                                     .iter()
                                     .cloned()
                                     .map(RuleRef::new)
                                     .collect(),
+                                target_compatible_with: vec![],
                             },
                             static_lib: self.subtarget_or_path(&static_lib)?,
+                            exported_preprocessor_flags: exported_preprocessor_flags.clone(),
+                            header_dirs: header_dirs.clone(),
                         };
+                        native_libs.push((name.as_str(), rule.common.name.clone()));
                         res.push(Rule::PrebuiltCxxLibrary(rule));
                     }
                     if self.config.strict_globs {
@@ -530,13 +1082,29 @@ impl<'meta> Fixups<'meta> {
                     }
                 }
 
+                // Nothing to build or run; the targets get the cfgs and env
+                // directly.
+                BuildscriptFixup::Skip(_) => {}
+
                 // Complain and omit
                 BuildscriptFixup::Unresolved(msg) => {
                     let unresolved_package_msg = format!(
                         "{} has a build script, but I don't know what to do with it: {}",
                         self.package, msg
                     );
-                    if config.unresolved_fixup_error {
+                    if config.unresolved_fixup_report.is_some() || config.collect_unresolved_fixups
+                    {
+                        // Errors are deferred until the report has been
+                        // written, so that it's complete.
+                        log::warn!("{}", unresolved_package_msg);
+                        unresolved.lock().unwrap().push(UnresolvedFixup {
+                            package: self.package.name.clone(),
+                            version: self.package.version.clone(),
+                            fixup_file: self.fixup_file.clone(),
+                            directive: "buildscript",
+                            message: msg.clone(),
+                        });
+                    } else if config.unresolved_fixup_error {
                         log::error!("{}", unresolved_package_msg);
                         return Err(anyhow!(
                             "Unresolved fix up errors, fix them and rerun buckify."
@@ -552,6 +1120,24 @@ impl<'meta> Fixups<'meta> {
             res.push(Rule::BuildscriptGenrule(buildscript_run));
         }
 
+        if let Some(linkable_alias) = self.linkable_alias() {
+            native_libs.dedup();
+            match native_linkable(self.package.links.as_deref(), &native_libs) {
+                Some(actual) => res.push(Rule::Alias(Alias {
+                    name: Name(linkable_alias),
+                    actual: actual.clone(),
+                    fallback_actual: None,
+                    visibility: self.public_visibility(),
+                })),
+                None if !native_libs.is_empty() => log::warn!(
+                    "{}: not emitting `{}` alias, since it's not clear which of its native libraries it should refer to",
+                    self.package,
+                    linkable_alias,
+                ),
+                None => {}
+            }
+        }
+
         Ok(res)
     }
 
@@ -559,8 +1145,8 @@ impl<'meta> Fixups<'meta> {
     /// and additional ones defined in the fixup.
     pub fn compute_features(
         &self,
-    ) -> anyhow::Result<HashMap<Option<PlatformExpr>, BTreeSet<String>>> {
-        let mut ret = HashMap::new();
+    ) -> anyhow::Result<BTreeMap<Option<PlatformExpr>, BTreeSet<String>>> {
+        let mut ret = BTreeMap::new();
 
         let mut platform_omits = HashMap::new();
         for (platform, fixup) in self.fixup_config.configs(&self.package.version) {
@@ -568,7 +1154,7 @@ impl<'meta> Fixups<'meta> {
             for feature in &fixup.omit_features {
                 platform_omits
                     .entry(feature.as_str())
-                    .or_insert_with(HashSet::new)
+                    .or_insert_with(BTreeSet::new)
                     .insert(platform);
             }
 
@@ -579,10 +1165,17 @@ impl<'meta> Fixups<'meta> {
             }
         }
 
-        for feature in self.index.resolved_features(self.package) {
+        for (triple, feature) in self.resolved_features() {
+            // Only keep `default` if the package actually declares it, so
+            // that the output doesn't depend on how it came to be resolved.
+            if feature == "default" && !self.package.features.contains_key(feature) {
+                continue;
+            }
+
             let Some(omitted_platforms) = platform_omits.get(feature) else {
-                // Feature is unconditionally included on all platforms.
-                ret.entry(None)
+                // Feature is unconditionally included on all platforms (with
+                // its triple, if any).
+                ret.entry(triple)
                     .or_insert_with(BTreeSet::new)
                     .insert(feature.to_owned());
                 continue;
@@ -608,6 +1201,11 @@ impl<'meta> Fixups<'meta> {
 
             assert!(!excludes.is_empty());
 
+            // A feature resolved only for some triples stays limited to them.
+            if let Some(triple) = &triple {
+                excludes.push(PlatformPredicate::parse(triple)?);
+            }
+
             let platform_pred = PlatformPredicate::All(excludes);
             let platform_expr: PlatformExpr = format!("cfg({})", platform_pred).into();
             ret.entry(Some(platform_expr))
@@ -615,9 +1213,46 @@ impl<'meta> Fixups<'meta> {
                 .insert(feature.to_owned());
         }
 
+        if self.config.cargo.include_default_feature {
+            ret.entry(None)
+                .or_insert_with(BTreeSet::new)
+                .insert("default".to_owned());
+        }
+
         Ok(ret)
     }
 
+    /// The package's resolved features, each with the target triple it's
+    /// resolved for if that's not every platform's. Without
+    /// `per_platform_features`, these are all features unified across
+    /// platforms. With it, features resolved for every triple the package is
+    /// built for are common, and the rest are specific to their triple.
+    fn resolved_features(&self) -> Vec<(Option<PlatformExpr>, &'meta str)> {
+        let platform_features = self.index.platform_features(self.package);
+        let Some(((_, first), rest)) = platform_features.split_first() else {
+            return self
+                .index
+                .resolved_features(self.package)
+                .map(|feature| (None, feature))
+                .collect();
+        };
+
+        let common: BTreeSet<&str> = first
+            .iter()
+            .copied()
+            .filter(|feature| rest.iter().all(|(_, features)| features.contains(feature)))
+            .collect();
+        let mut resolved: Vec<_> = common.iter().map(|feature| (None, *feature)).collect();
+        for (triple, features) in &platform_features {
+            for feature in features {
+                if !common.contains(feature) {
+                    resolved.push((Some(PlatformExpr::from((*triple).to_owned())), *feature));
+                }
+            }
+        }
+        resolved
+    }
+
     fn buildscript_rustc_flags(
         &self,
     ) -> Vec<(
@@ -631,17 +1266,52 @@ impl<'meta> Fixups<'meta> {
 
         for (platform, config) in self.fixup_config.configs(&self.package.version) {
             let mut flags = vec![];
+            let mut runs = false;
+            let mut link_libs = false;
 
             for buildscript in &config.buildscript {
                 if !self.target_match(buildscript) {
                     continue;
                 }
-                if let BuildscriptFixup::RustcFlags(_) = buildscript {
-                    flags.push(format!(
-                        "@$(location :{}[rustc_flags])",
-                        self.buildscript_genrule_name()
-                    ))
+                match buildscript {
+                    BuildscriptFixup::RustcFlags(RustcFlags {
+                        cfgs,
+                        link_libs: libs,
+                        ..
+                    }) => {
+                        runs = true;
+                        link_libs |= libs;
+                        flags.extend(cfgs.iter().map(|cfg| format!("--cfg={}", cfg)));
+                    }
+                    // Generating sources still runs the script, so the crate
+                    // gets what it prints too, like `cargo:rustc-env`.
+                    BuildscriptFixup::GenSrcs(GenSrcs {
+                        link_libs: libs, ..
+                    }) => {
+                        runs = true;
+                        link_libs |= libs;
+                    }
+                    BuildscriptFixup::Skip(SkipBuildscript { cfgs, .. }) => {
+                        flags.extend(cfgs.iter().map(|cfg| format!("--cfg={}", cfg)));
+                    }
+                    _ => {}
+                }
+            }
+            if runs {
+                let genrule = self.buildscript_genrule_name();
+                flags.insert(0, format!("@$(location :{genrule}[rustc_flags])"));
+            }
+            if runs && link_libs {
+                // The `buildscript_run` rule drops the script's linking
+                // directives, so pass on the ones spelled out in its source.
+                let libs = self.native_link_libs();
+                // Static libraries a build script links are almost always
+                // ones it built in OUT_DIR.
+                if libs.iter().any(|lib| lib.starts_with("static")) {
+                    let genrule = self.buildscript_genrule_name();
+                    flags.push(format!("-Lnative=$(location :{genrule}[out_dir])"));
                 }
+                flags.extend(libs.iter().map(|lib| format!("-l{lib}")));
             }
 
             if !flags.is_empty() {
@@ -652,6 +1322,16 @@ impl<'meta> Fixups<'meta> {
         ret
     }
 
+    /// Substitute `{version}`, `{major}`, `{minor}` and `{patch}` in a rustc
+    /// flag with the package's version.
+    fn expand_version(&self, flag: &str) -> String {
+        let version = &self.package.version;
+        flag.replace("{version}", &version.to_string())
+            .replace("{major}", &version.major.to_string())
+            .replace("{minor}", &version.minor.to_string())
+            .replace("{patch}", &version.patch.to_string())
+    }
+
     /// Return extra command-line options, with platform annotation if needed
     pub fn compute_cmdline(
         &self,
@@ -664,9 +1344,21 @@ impl<'meta> Fixups<'meta> {
         for (platform, config) in self.fixup_config.configs(&self.package.version) {
             let mut flags = vec![];
 
-            flags.extend(config.rustc_flags.clone());
+            flags.extend(
+                config
+                    .rustc_flags
+                    .iter()
+                    .map(|flag| self.expand_version(flag)),
+            );
             flags.extend(config.cfgs.iter().map(|cfg| format!("--cfg={}", cfg)));
-            let flags_select = config.rustc_flags_select.clone();
+            let flags_select: BTreeMap<_, _> = config
+                .rustc_flags_select
+                .iter()
+                .map(|(key, flags)| {
+                    let flags = flags.iter().map(|flag| self.expand_version(flag));
+                    (key.clone(), flags.collect())
+                })
+                .collect();
 
             if !flags.is_empty() || !flags_select.is_empty() {
                 ret.push((platform.cloned(), (flags, flags_select)));
@@ -675,9 +1367,66 @@ impl<'meta> Fixups<'meta> {
 
         ret.extend(self.buildscript_rustc_flags());
 
+        if self.config.buck.emit_check_cfg {
+            ret.push((None, (self.check_cfg_flags(), BTreeMap::new())));
+        }
+
+        if !self.fixup_config.rustc_flags_remove.is_empty() {
+            let remove: HashSet<String> = self
+                .fixup_config
+                .rustc_flags_remove
+                .iter()
+                .map(|flag| self.expand_version(flag))
+                .collect();
+            for (_platform, (flags, flags_select)) in &mut ret {
+                flags.retain(|flag| !remove.contains(flag));
+                for flags in flags_select.values_mut() {
+                    flags.retain(|flag| !remove.contains(flag));
+                }
+                flags_select.retain(|_key, flags| !flags.is_empty());
+            }
+            ret.retain(|(_platform, (flags, flags_select))| {
+                !flags.is_empty() || !flags_select.is_empty()
+            });
+        }
+
         ret
     }
 
+    /// `--check-cfg` flags for the package's features, the cfgs set by its
+    /// fixups on any platform, and the cfgs its build script appears to set.
+    fn check_cfg_flags(&self) -> Vec<String> {
+        let mut cfgs = Vec::new();
+        for (_platform, config) in self.fixup_config.configs(&self.package.version) {
+            cfgs.extend(config.cfgs.iter().map(String::as_str));
+            for buildscript in &config.buildscript {
+                if let BuildscriptFixup::RustcFlags(RustcFlags {
+                    cfgs: buildscript_cfgs,
+                    ..
+                })
+                | BuildscriptFixup::Skip(SkipBuildscript {
+                    cfgs: buildscript_cfgs,
+                    ..
+                }) = buildscript
+                {
+                    cfgs.extend(buildscript_cfgs.iter().map(String::as_str));
+                }
+            }
+        }
+
+        // We can't tell what values the build script gives its cfgs.
+        let any_value = match self.buildscript_target() {
+            Some(buildscript) => match fs::read_to_string(&buildscript.src_path) {
+                Ok(source) => buildscript_cfgs(&source),
+                Err(_) => BTreeSet::new(),
+            },
+            None => BTreeSet::new(),
+        };
+
+        let features = self.package.features.keys().map(String::as_str);
+        check_cfg_flags(cfgs, &any_value, features)
+    }
+
     /// Generate the set of deps for the target. This could just return the unmodified
     /// depenedencies, or it could add/remove them. This returns the Buck rule reference
     /// and the corresponding package if there is one (so the caller can limit its enumeration
@@ -694,7 +1443,7 @@ impl<'meta> Fixups<'meta> {
     > {
         let mut ret = vec![];
 
-        let mut omits = HashMap::new();
+        let mut omits = BTreeMap::new();
         let mut all_omits = HashSet::new();
         // Pre-compute the list of all filtered dependencies. If a platform filters a dependency
         // added by the base, we need to filter it from the base and add it to all other platforms.
@@ -769,7 +1518,11 @@ impl<'meta> Fixups<'meta> {
                     ret.push((
                         Some(package),
                         RuleRef::from(self.index.private_rule_name(package))
-                            .with_platform(Some(&platform_expr)),
+                            .with_platform(Some(&platform_expr))
+                            .with_origin(format!(
+                                "from [{}], omitted on some platforms by fixups",
+                                dep_kind.kind.table(),
+                            )),
                         rename,
                         dep_kind,
                     ));
@@ -781,20 +1534,25 @@ impl<'meta> Fixups<'meta> {
             }
 
             // No filtering involved? Just insert it like normal.
-            ret.push((
-                Some(package),
-                RuleRef::from(self.index.private_rule_name(package))
-                    .with_platform(platform.as_ref()),
-                rename,
-                dep_kind,
-            ))
+            let mut rule_ref = RuleRef::from(self.index.private_rule_name(package))
+                .with_platform(platform.as_ref());
+            if let Some(platform) = &platform {
+                rule_ref = rule_ref.with_origin(format!(
+                    "from [target.'{}'.{}]",
+                    platform,
+                    dep_kind.kind.table(),
+                ));
+            }
+            ret.push((Some(package), rule_ref, rename, dep_kind))
         }
 
         for (platform, config) in self.fixup_config.configs(&self.package.version) {
             ret.extend(config.extra_deps.iter().map(|dep| {
                 (
                     None,
-                    RuleRef::new(dep.to_string()).with_platform(platform),
+                    RuleRef::new(dep.to_string())
+                        .with_platform(platform)
+                        .with_origin("from fixups extra_deps".to_owned()),
                     None,
                     &NodeDepKind::ORDINARY,
                 )
@@ -816,7 +1574,8 @@ impl<'meta> Fixups<'meta> {
                             self.index.private_rule_name(self.package),
                             name
                         ))
-                        .with_platform(platform),
+                        .with_platform(platform)
+                        .with_origin("from fixups cxx_library".to_owned()),
                         None,
                         &NodeDepKind::ORDINARY,
                     ));
@@ -839,7 +1598,8 @@ impl<'meta> Fixups<'meta> {
                                 name,
                                 static_lib.file_name().unwrap().to_string_lossy(),
                             ))
-                            .with_platform(platform),
+                            .with_platform(platform)
+                            .with_origin("from fixups prebuilt_cxx_library".to_owned()),
                             None,
                             &NodeDepKind::ORDINARY,
                         ));
@@ -851,81 +1611,435 @@ impl<'meta> Fixups<'meta> {
         Ok(ret)
     }
 
-    /// Additional environment
-    pub fn compute_env(
-        &self,
-    ) -> anyhow::Result<Vec<(Option<PlatformExpr>, BTreeMap<String, StringOrPath>)>> {
-        let mut ret = vec![];
-
-        for (platform, config) in self.fixup_config.configs(&self.package.version) {
-            let mut map: BTreeMap<String, StringOrPath> = config
-                .env
-                .iter()
-                .map(|(k, v)| (k.clone(), StringOrPath::String(v.clone())))
-                .collect();
-
-            for cargo_env in config.cargo_env.iter() {
-                let v = match cargo_env {
-                    CargoEnv::CARGO_MANIFEST_DIR => {
-                        if self.config.vendor.is_some()
-                            || matches!(self.package.source, Source::Local)
-                        {
-                            StringOrPath::Path(BuckPath(relative_path(
-                                &self.third_party_dir,
-                                self.manifest_dir,
-                            )))
-                        } else if let Source::Git { repo, .. } = &self.package.source {
-                            let short_name = short_name_for_git_repo(repo)?;
-                            StringOrPath::String(short_name.to_owned())
-                        } else {
-                            StringOrPath::String(format!(
-                                "{}-{}.crate",
-                                self.package.name, self.package.version,
-                            ))
-                        }
-                    }
-                    CargoEnv::CARGO_PKG_AUTHORS => {
-                        StringOrPath::String(self.package.authors.join(":"))
-                    }
-                    CargoEnv::CARGO_PKG_DESCRIPTION => {
-                        StringOrPath::String(self.package.description.clone().unwrap_or_default())
-                    }
-                    CargoEnv::CARGO_PKG_REPOSITORY => {
-                        StringOrPath::String(self.package.repository.clone().unwrap_or_default())
-                    }
-                    CargoEnv::CARGO_PKG_VERSION => {
-                        StringOrPath::String(self.package.version.to_string())
-                    }
-                    CargoEnv::CARGO_PKG_VERSION_MAJOR => {
-                        StringOrPath::String(self.package.version.major.to_string())
-                    }
-                    CargoEnv::CARGO_PKG_VERSION_MINOR => {
-                        StringOrPath::String(self.package.version.minor.to_string())
-                    }
-                    CargoEnv::CARGO_PKG_VERSION_PATCH => {
-                        StringOrPath::String(self.package.version.patch.to_string())
-                    }
-                    CargoEnv::CARGO_PKG_NAME => StringOrPath::String(self.package.name.clone()),
-                };
-                map.insert(cargo_env.to_string(), v);
+    /// Value of one of the environment variables Cargo sets for the package.
+    fn cargo_env_value(&self, cargo_env: CargoEnv) -> anyhow::Result<StringOrPath> {
+        let string = |value: &Option<String>| {
+            StringOrPath::String(escape_macros(value.as_deref().unwrap_or_default()))
+        };
+        let path = |value: &Option<PathBuf>| {
+            StringOrPath::String(
+                value
+                    .as_ref()
+                    .map(|path| path.to_string_lossy().into_owned())
+                    .unwrap_or_default(),
+            )
+        };
+        Ok(match cargo_env {
+            CargoEnv::CARGO_MANIFEST_DIR => {
+                if self.config.vendor.is_some() || matches!(self.package.source, Source::Local) {
+                    StringOrPath::Path(BuckPath(relative_path(
+                        &self.third_party_dir,
+                        self.manifest_dir,
+                    )))
+                } else if let Source::Git { repo, .. } = &self.package.source {
+                    let short_name = short_name_for_git_repo(repo)?;
+                    StringOrPath::String(short_name.to_owned())
+                } else {
+                    StringOrPath::String(format!(
+                        "{}-{}.crate",
+                        self.package.name, self.package.version,
+                    ))
+                }
+            }
+            CargoEnv::CARGO_PKG_AUTHORS => {
+                StringOrPath::String(escape_macros(&self.package.authors.join(":")))
+            }
+            CargoEnv::CARGO_PKG_DESCRIPTION => string(&self.package.description),
+            CargoEnv::CARGO_PKG_HOMEPAGE => string(&self.package.homepage),
+            CargoEnv::CARGO_PKG_LICENSE => string(&self.package.license),
+            CargoEnv::CARGO_PKG_LICENSE_FILE => path(&self.package.license_file),
+            CargoEnv::CARGO_PKG_NAME => StringOrPath::String(self.package.name.clone()),
+            CargoEnv::CARGO_PKG_README => path(&self.package.readme),
+            CargoEnv::CARGO_PKG_REPOSITORY => string(&self.package.repository),
+            CargoEnv::CARGO_PKG_RUST_VERSION => string(&self.package.rust_version),
+            CargoEnv::CARGO_PKG_VERSION => StringOrPath::String(self.package.version.to_string()),
+            CargoEnv::CARGO_PKG_VERSION_MAJOR => {
+                StringOrPath::String(self.package.version.major.to_string())
             }
+            CargoEnv::CARGO_PKG_VERSION_MINOR => {
+                StringOrPath::String(self.package.version.minor.to_string())
+            }
+            CargoEnv::CARGO_PKG_VERSION_PATCH => {
+                StringOrPath::String(self.package.version.patch.to_string())
+            }
+            CargoEnv::CARGO_PKG_VERSION_PRE => {
+                StringOrPath::String(self.package.version.pre.to_string())
+            }
+        })
+    }
 
-            if !map.is_empty() {
-                ret.push((platform.cloned(), map));
+    /// Substitute `$OUT_DIR`, `$CARGO_MANIFEST_DIR` and the `$CARGO_PKG_*`
+    /// variables in a fixup's `env` value for one of the package's own rules.
+    /// A value which is a path in the package stays a path.
+    fn interpolate_env(&self, value: &str) -> anyhow::Result<StringOrPath> {
+        for var in ["$CARGO_MANIFEST_DIR", "${CARGO_MANIFEST_DIR}"] {
+            let Some(rest) = value.strip_prefix(var) else {
+                continue;
+            };
+            if let StringOrPath::Path(BuckPath(dir)) =
+                self.cargo_env_value(CargoEnv::CARGO_MANIFEST_DIR)?
+            {
+                if rest.is_empty() {
+                    return Ok(StringOrPath::Path(BuckPath(dir)));
+                } else if let Some(rest) = rest.strip_prefix('/') {
+                    return Ok(StringOrPath::Path(BuckPath(dir.join(rest))));
+                }
             }
         }
 
-        Ok(ret)
+        let value = substitute_vars(value, |var| {
+            if var == "OUT_DIR" {
+                if !self.runs_buildscript() {
+                    bail!(
+                        "`$OUT_DIR` is only set for packages whose build script is run by a \
+                         fixup; write `$$OUT_DIR` for the text itself"
+                    );
+                }
+                return Ok(Some(format!(
+                    "$(location :{}[out_dir])",
+                    self.buildscript_genrule_name(),
+                )));
+            }
+            match var.parse() {
+                Ok(cargo_env) => Ok(Some(self.cargo_env_value(cargo_env)?.to_string())),
+                Err(_) => Ok(None),
+            }
+        })?;
+        Ok(StringOrPath::String(value))
     }
 
-    /// Given a glob for the srcs, walk the filesystem to get the full set.
-    /// `srcs` is the normal source glob rooted at the package's manifest dir.
-    pub fn compute_srcs(
-        &self,
-        srcs: Vec<PathBuf>,
-    ) -> anyhow::Result<Vec<(Option<PlatformExpr>, BTreeSet<PathBuf>)>> {
-        let mut ret: Vec<(Option<PlatformExpr>, BTreeSet<PathBuf>)> = vec![];
-
+    /// Substitute `$CARGO_MANIFEST_DIR` and the `$CARGO_PKG_*` variables in a
+    /// fixup's `env` value for the package's build script run. Build scripts
+    /// run in their package's directory, so that stands in for
+    /// `$CARGO_MANIFEST_DIR`. The run creates `$OUT_DIR`, so it can't be used.
+    fn interpolate_buildscript_env(&self, value: &str) -> anyhow::Result<String> {
+        substitute_vars(value, |var| match var {
+            "CARGO_MANIFEST_DIR" => Ok(Some(".".to_owned())),
+            "OUT_DIR" => bail!("`$OUT_DIR` isn't known until the build script has run"),
+            _ => match var.parse() {
+                Ok(cargo_env) => Ok(Some(self.cargo_env_value(cargo_env)?.to_string())),
+                Err(_) => Ok(None),
+            },
+        })
+    }
+
+    /// With a `buildscript.skip` fixup, check that nothing else needs the
+    /// build script to run, and that every cfg it's seen to set is either
+    /// supplied or deliberately left out.
+    fn check_skipped_buildscript(&self) -> anyhow::Result<()> {
+        let fixes: Vec<&BuildscriptFixup> = self
+            .fixup_config
+            .configs(&self.package.version)
+            .flat_map(|(_platform, fixup)| fixup.buildscript.iter())
+            .collect();
+        if !fixes
+            .iter()
+            .any(|fix| matches!(fix, BuildscriptFixup::Skip(_)))
+        {
+            return Ok(());
+        }
+        if self.runs_buildscript() {
+            bail!(
+                "{}: `buildscript.skip` can't be combined with fixups which run the build script",
+                self.package,
+            );
+        }
+
+        let mut provided: HashSet<&str> = HashSet::new();
+        for (_platform, config) in self.fixup_config.configs(&self.package.version) {
+            provided.extend(config.cfgs.iter().map(String::as_str));
+        }
+        for fix in fixes {
+            if let BuildscriptFixup::Skip(SkipBuildscript {
+                cfgs, omit_cfgs, ..
+            }) = fix
+            {
+                provided.extend(cfgs.iter().chain(omit_cfgs).map(String::as_str));
+            }
+        }
+        // Cfgs with a value, like `foo="bar"`, provide `foo`.
+        let provided: HashSet<&str> = provided
+            .into_iter()
+            .map(|cfg| cfg.split_once('=').map_or(cfg, |(name, _value)| name))
+            .collect();
+
+        let source = match self.buildscript_target() {
+            Some(buildscript) => fs::read_to_string(&buildscript.src_path).unwrap_or_default(),
+            None => return Ok(()),
+        };
+        let missing: Vec<String> = buildscript_cfgs(&source)
+            .into_iter()
+            .filter(|cfg| !provided.contains(cfg.as_str()))
+            .collect();
+        if !missing.is_empty() {
+            bail!(
+                "{}: the build script is skipped, but it sets cfgs which aren't in \
+                 `buildscript.skip` `cfgs` or `omit_cfgs`: {}",
+                self.package,
+                missing.join(", "),
+            );
+        }
+
+        // Without them `env!` fails to compile.
+        let mut provided_env: HashSet<&str> = HashSet::new();
+        for (_platform, config) in self.fixup_config.configs(&self.package.version) {
+            provided_env.extend(config.env.keys().map(String::as_str));
+            for fix in &config.buildscript {
+                if let BuildscriptFixup::Skip(SkipBuildscript { env, .. }) = fix {
+                    provided_env.extend(env.keys().map(String::as_str));
+                }
+            }
+        }
+        let missing: Vec<String> = buildscript_env(&source)
+            .into_iter()
+            .filter(|name| !provided_env.contains(name.as_str()))
+            .collect();
+        if !missing.is_empty() {
+            bail!(
+                "{}: the build script is skipped, but it sets environment variables \
+                 with `cargo:rustc-env` which aren't in `buildscript.skip` `env`: {}",
+                self.package,
+                missing.join(", "),
+            );
+        }
+        Ok(())
+    }
+
+    /// Unstable `-Z` flags in `rustc_flags` or `rustc_flags_select` only work
+    /// on nightly toolchains, so every platform they apply to needs
+    /// `nightly = true`.
+    pub fn check_unstable_rustc_flags(&self) -> anyhow::Result<()> {
+        for (platform, config) in self.fixup_config.configs(&self.package.version) {
+            let unstable: Vec<&str> = config
+                .rustc_flags
+                .iter()
+                .chain(config.rustc_flags_select.values().flatten())
+                .map(String::as_str)
+                .filter(|flag| flag.starts_with("-Z"))
+                .collect();
+            if unstable.is_empty() {
+                continue;
+            }
+            let (section, platforms) = match platform {
+                Some(expr) => (
+                    format!("`platform_fixup.'{expr}'`"),
+                    platform_names_for_expr(self.config, expr)?,
+                ),
+                None => (
+                    "the fixups for every platform".to_owned(),
+                    self.config.platform.keys().collect(),
+                ),
+            };
+            let stable: Vec<String> = platforms
+                .into_iter()
+                .filter(|name| !self.config.platform[*name].nightly)
+                .map(ToString::to_string)
+                .collect();
+            if !stable.is_empty() {
+                bail!(
+                    "{}: unstable rustc flags {} in {} would apply on platforms without \
+                     `nightly = true`: {}",
+                    self.package,
+                    unstable.join(" "),
+                    section,
+                    stable.join(", "),
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Deps of the library's doctests: the fixups' `doc_deps`, and the
+    /// package's dev-dependencies which its doc comments use, with their
+    /// packages.
+    pub fn compute_doc_deps(&self) -> anyhow::Result<Vec<(Option<&'meta Manifest>, RuleRef)>> {
+        let mut ret = vec![];
+
+        let mut dev_deps: Vec<_> = self.index.resolved_dev_deps(self.package).collect();
+        if !dev_deps.is_empty() {
+            let src_dir = self.target.src_path.parent().unwrap();
+            let mut sources = Globs::new(["**/*.rs"], NO_EXCLUDE)?;
+            for path in sources.walk(src_dir) {
+                let Ok(source) = fs::read_to_string(src_dir.join(path)) else {
+                    continue;
+                };
+                dev_deps.retain(|(rename, dep_kind, package)| {
+                    if !doc_comments_use(&source, rename) {
+                        return true;
+                    }
+                    let rule_ref = RuleRef::from(self.index.private_rule_name(package))
+                        .with_platform(dep_kind.target.as_ref());
+                    ret.push((Some(*package), rule_ref));
+                    false
+                });
+            }
+        }
+
+        for (platform, config) in self.fixup_config.configs(&self.package.version) {
+            ret.extend(
+                config
+                    .doc_deps
+                    .iter()
+                    .map(|dep| (None, RuleRef::new(dep.to_string()).with_platform(platform))),
+            );
+        }
+
+        Ok(ret)
+    }
+
+    /// `cargo:rustc-link-lib` values spelled out in the build script.
+    fn native_link_libs(&self) -> BTreeSet<String> {
+        match self.buildscript_target() {
+            Some(buildscript) => match fs::read_to_string(&buildscript.src_path) {
+                Ok(source) => buildscript_link_libs(&source),
+                Err(_) => BTreeSet::new(),
+            },
+            None => BTreeSet::new(),
+        }
+    }
+
+    /// A warning if the build script links both static and shared native
+    /// libraries, where the crate's `preferred_linkage` decides which of them
+    /// dependents get right, and no fixup sets it.
+    pub fn mixed_native_linkage(&self) -> Option<String> {
+        if !self.compute_preferred_linkage().is_empty() {
+            return None;
+        }
+        let mut kinds: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+        let link_libs = self.native_link_libs();
+        for lib in &link_libs {
+            // `KIND[:MODIFIERS]=NAME`; without a kind it's a dylib.
+            let (kind, name) = match lib.split_once('=') {
+                Some((kind, name)) => (kind.split(':').next().unwrap_or(kind), name),
+                None => ("dylib", lib.as_str()),
+            };
+            kinds.entry(kind).or_default().push(name);
+        }
+        if !kinds.contains_key("static") || kinds.keys().all(|kind| *kind == "static") {
+            return None;
+        }
+        let kinds: Vec<String> = kinds
+            .into_iter()
+            .map(|(kind, names)| format!("{kind}: {}", names.join(", ")))
+            .collect();
+        Some(format!(
+            "{}: build script links both static and shared native libraries ({}); \
+             set `preferred_linkage` in its fixups to say how dependents should link it",
+            self.package,
+            kinds.join("; "),
+        ))
+    }
+
+    /// Whether a fixup runs the build script, making a `[out_dir]` to refer
+    /// to.
+    fn runs_buildscript(&self) -> bool {
+        self.buildscript_rule_name().is_some()
+            && self
+                .fixup_config
+                .configs(&self.package.version)
+                .flat_map(|(_platform, fixup)| &fixup.buildscript)
+                .any(|fix| {
+                    matches!(
+                        fix,
+                        BuildscriptFixup::RustcFlags(_) | BuildscriptFixup::GenSrcs(_)
+                    )
+                })
+    }
+
+    fn extend_buildscript_env(
+        &self,
+        buildscript_run: &mut BuildscriptGenrule,
+        env: &BTreeMap<String, String>,
+        path_env: &BTreeMap<String, Vec<String>>,
+    ) -> anyhow::Result<()> {
+        for (k, v) in env {
+            let v = self
+                .interpolate_buildscript_env(v)
+                .with_context(|| format!("buildscript env {k} = {v:?}"))?;
+            buildscript_run.env.insert(k.clone(), v);
+        }
+        for (k, paths) in path_env {
+            let paths = paths
+                .iter()
+                .map(|v| self.interpolate_buildscript_env(v))
+                .collect::<anyhow::Result<_>>()
+                .with_context(|| format!("buildscript path_env {k} = {paths:?}"))?;
+            buildscript_run.path_env.insert(k.clone(), paths);
+        }
+        Ok(())
+    }
+
+    /// The `CARGO_PKG_*` variables Cargo sets when compiling the package, or
+    /// as many of them as `which` asks for.
+    pub fn compute_cargo_pkg_env(
+        &self,
+        which: CargoPkgEnv,
+    ) -> anyhow::Result<BTreeMap<String, StringOrPath>> {
+        let mut env = BTreeMap::new();
+        for cargo_env in CargoEnv::iter() {
+            let wanted = match which {
+                CargoPkgEnv::None => false,
+                CargoPkgEnv::Minimal => matches!(
+                    cargo_env,
+                    CargoEnv::CARGO_PKG_NAME | CargoEnv::CARGO_PKG_VERSION
+                ),
+                CargoPkgEnv::Full => cargo_env != CargoEnv::CARGO_MANIFEST_DIR,
+            };
+            if wanted {
+                env.insert(cargo_env.to_string(), self.cargo_env_value(cargo_env)?);
+            }
+        }
+        Ok(env)
+    }
+
+    /// Additional environment
+    pub fn compute_env(
+        &self,
+    ) -> anyhow::Result<Vec<(Option<PlatformExpr>, BTreeMap<String, StringOrPath>)>> {
+        let mut ret = vec![];
+
+        for (platform, config) in self.fixup_config.configs(&self.package.version) {
+            let mut map = BTreeMap::new();
+            for (k, v) in &config.env {
+                let v = self
+                    .interpolate_env(v)
+                    .with_context(|| format!("env {k} = {v:?}"))?;
+                map.insert(k.clone(), v);
+            }
+
+            for cargo_env in config.cargo_env.iter() {
+                map.insert(cargo_env.to_string(), self.cargo_env_value(cargo_env)?);
+            }
+
+            for buildscript in &config.buildscript {
+                if let BuildscriptFixup::Skip(SkipBuildscript { env, .. }) = buildscript {
+                    if self.buildscript_target().is_some() && self.target_match(buildscript) {
+                        for (k, v) in env {
+                            let v = self
+                                .interpolate_env(v)
+                                .with_context(|| format!("buildscript.skip env {k} = {v:?}"))?;
+                            map.insert(k.clone(), v);
+                        }
+                    }
+                }
+            }
+
+            if !map.is_empty() {
+                ret.push((platform.cloned(), map));
+            }
+        }
+
+        Ok(ret)
+    }
+
+    /// Given a glob for the srcs, walk the filesystem to get the full set.
+    /// `srcs` is the normal source glob rooted at the package's manifest dir.
+    pub fn compute_srcs(
+        &self,
+        srcs: Vec<PathBuf>,
+    ) -> anyhow::Result<Vec<(Option<PlatformExpr>, BTreeSet<PathBuf>)>> {
+        let mut ret: Vec<(Option<PlatformExpr>, BTreeSet<PathBuf>)> = vec![];
+
         // This function is only used in vendoring mode, so it's guaranteed that
         // manifest_dir is a subdirectory of third_party_dir.
         assert!(self.config.vendor.is_some() || matches!(self.package.source, Source::Local));
@@ -957,7 +2071,14 @@ impl<'meta> Fixups<'meta> {
 
         let mut common_files = HashSet::new();
         let mut srcs_globs = Globs::new(srcs_globs, NO_EXCLUDE).context("Srcs")?;
-        for path in srcs_globs.walk(self.manifest_dir) {
+        let paths: Vec<_> = if self.config.strict_globs {
+            // Leave out files which wouldn't be committed, like build
+            // artifacts left in the crate's directory.
+            srcs_globs.walk_unignored(self.manifest_dir).collect()
+        } else {
+            srcs_globs.walk(self.manifest_dir).collect()
+        };
+        for path in paths {
             common_files.insert(manifest_rel.join(path));
         }
         if self.config.strict_globs {
@@ -982,6 +2103,8 @@ impl<'meta> Fixups<'meta> {
                 }
             };
 
+        let srcs_exclude = self.srcs_exclude();
+
         if !needs_per_platform_srcs {
             let mut set = BTreeSet::new();
 
@@ -989,6 +2112,7 @@ impl<'meta> Fixups<'meta> {
                 let path_in_crate = relative_path(&manifest_rel, file);
                 if !common_overlay_files.contains(&path_in_crate)
                     && !common_omit_srcs.is_match(&path_in_crate)
+                    && !srcs_exclude.is_match(&path_in_crate)
                 {
                     set.insert(file.clone());
                 }
@@ -1017,6 +2141,7 @@ impl<'meta> Fixups<'meta> {
                 if !overlay_files.contains(&path_in_crate)
                     && !common_omit_srcs.is_match(&path_in_crate)
                     && !config.omit_srcs.is_match(&path_in_crate)
+                    && !srcs_exclude.is_match(&path_in_crate)
                 {
                     set.insert(file);
                 }
@@ -1096,7 +2221,7 @@ impl<'meta> Fixups<'meta> {
     pub fn compute_mapped_srcs(
         &self,
         mapped_manifest_dir: &Path,
-    ) -> anyhow::Result<Vec<(Option<PlatformExpr>, BTreeMap<SubtargetOrPath, BuckPath>)>> {
+    ) -> anyhow::Result<Vec<(Option<PlatformExpr>, MappedSrcs)>> {
         let mut ret = vec![];
 
         for (platform, config) in self.fixup_config.configs(&self.package.version) {
@@ -1106,15 +2231,36 @@ impl<'meta> Fixups<'meta> {
                 map.insert(
                     // If the mapped source is target-like, take it as-is since
                     // we have nothing to resolve or find.
-                    if k.starts_with(':') || k.contains("//") {
+                    MappedSrc::Source(if k.starts_with(':') || k.contains("//") {
                         SubtargetOrPath::Path(BuckPath(PathBuf::from(k)))
                     } else {
                         self.subtarget_or_path(Path::new(k))?
-                    },
+                    }),
                     BuckPath(mapped_manifest_dir.join(v)),
                 );
             }
 
+            for fix in &config.buildscript {
+                let BuildscriptFixup::GenSrcs(GenSrcs { mapped_srcs, .. }) = fix else {
+                    continue;
+                };
+                if mapped_srcs.is_empty() || !self.target_match(fix) {
+                    continue;
+                }
+                if self.buildscript_rule_name().is_none() {
+                    bail!(
+                        "{}: `buildscript.gen_srcs` `mapped_srcs` needs a build script to generate them",
+                        self.package,
+                    );
+                }
+                for (generated, path) in mapped_srcs {
+                    map.insert(
+                        MappedSrc::OutDirFile(self.out_dir_file_name(generated)),
+                        BuckPath(mapped_manifest_dir.join(path)),
+                    );
+                }
+            }
+
             if let Some(overlay) = &config.overlay {
                 let overlay_dir = self.fixup_dir.join(overlay);
                 let relative_overlay_dir = relative_path(&self.third_party_dir, &overlay_dir);
@@ -1130,7 +2276,9 @@ impl<'meta> Fixups<'meta> {
 
                 for file in overlay_files {
                     map.insert(
-                        SubtargetOrPath::Path(BuckPath(relative_overlay_dir.join(&file))),
+                        MappedSrc::Source(SubtargetOrPath::Path(BuckPath(
+                            relative_overlay_dir.join(&file),
+                        ))),
                         BuckPath(mapped_manifest_dir.join(&file)),
                     );
                 }
@@ -1201,3 +2349,1363 @@ impl<'meta> Fixups<'meta> {
         ret
     }
 }
+
+/// Pick the native library rule which a `-sys` crate's linkable alias refers
+/// to: the only one, or else the one whose fixup is named after `links`.
+fn native_linkable<'a>(links: Option<&str>, native_libs: &'a [(&str, Name)]) -> Option<&'a Name> {
+    match native_libs {
+        [(_, actual)] => Some(actual),
+        _ => {
+            let mut named = native_libs
+                .iter()
+                .filter(|(name, _)| Some(*name) == links)
+                .map(|(_, actual)| actual);
+            match (named.next(), named.next()) {
+                (Some(actual), None) => Some(actual),
+                _ => None,
+            }
+        }
+    }
+}
+
+/// Whether the doc comments in `source` use crate `krate`, as doc examples
+/// do with `krate::` paths or `extern crate krate`.
+fn doc_comments_use(source: &str, krate: &str) -> bool {
+    source
+        .lines()
+        .map(str::trim_start)
+        .filter_map(|line| line.strip_prefix("///").or(line.strip_prefix("//!")))
+        .any(|doc| {
+            doc.match_indices(krate).any(|(i, _)| {
+                let before = &doc[..i];
+                let after = &doc[i + krate.len()..];
+                !before.ends_with(|c: char| c.is_alphanumeric() || c == '_')
+                    && (after.starts_with("::") || before.ends_with("extern crate "))
+            })
+        })
+}
+
+/// Format `--check-cfg` flags the way Cargo does, declaring `cfgs` (each `name`
+/// or `name="value"`), `features`, and the names in `any_value` with any value.
+fn check_cfg_flags<'a>(
+    cfgs: impl IntoIterator<Item = &'a str>,
+    any_value: &BTreeSet<String>,
+    features: impl IntoIterator<Item = &'a str>,
+) -> Vec<String> {
+    let mut values: BTreeMap<&str, BTreeSet<Option<&str>>> = BTreeMap::new();
+    for cfg in cfgs {
+        let (name, value) = match cfg.split_once('=') {
+            Some((name, value)) => (name.trim(), Some(value.trim())),
+            None => (cfg.trim(), None),
+        };
+        if !any_value.contains(name) {
+            values.entry(name).or_default().insert(value);
+        }
+    }
+
+    let features: Vec<_> = features.into_iter().map(|f| format!("{f:?}")).collect();
+    let mut flags = vec![
+        "--check-cfg=cfg(docsrs,test)".to_owned(),
+        format!("--check-cfg=cfg(feature, values({}))", features.join(", ")),
+    ];
+    for (name, values) in values {
+        if values.iter().all(Option::is_none) {
+            flags.push(format!("--check-cfg=cfg({name})"));
+        } else {
+            let values: Vec<_> = values
+                .into_iter()
+                .map(|value| value.unwrap_or("none()"))
+                .collect();
+            flags.push(format!(
+                "--check-cfg=cfg({name}, values({}))",
+                values.join(", ")
+            ));
+        }
+    }
+    for name in any_value {
+        flags.push(format!("--check-cfg=cfg({name}, values(any()))"));
+    }
+    flags
+}
+
+/// The name maturin would give the extension module: the last component of
+/// `tool.maturin.module-name` in pyproject.toml, or else the lib name.
+fn maturin_module_name(manifest_dir: &Path, target: &ManifestTarget) -> String {
+    #[derive(Deserialize, Default)]
+    struct PyProject {
+        #[serde(default)]
+        tool: PyProjectTool,
+    }
+
+    #[derive(Deserialize, Default)]
+    struct PyProjectTool {
+        #[serde(default)]
+        maturin: Maturin,
+    }
+
+    #[derive(Deserialize, Default)]
+    struct Maturin {
+        #[serde(rename = "module-name")]
+        module_name: Option<String>,
+    }
+
+    let pyproject_path = manifest_dir.join("pyproject.toml");
+    let module_name = match fs::read_to_string(&pyproject_path) {
+        Ok(content) => match toml::from_str::<PyProject>(&content) {
+            Ok(pyproject) => pyproject.tool.maturin.module_name,
+            Err(err) => {
+                log::warn!("Failed to parse {}: {}", pyproject_path.display(), err);
+                None
+            }
+        },
+        Err(_) => None,
+    };
+
+    match module_name {
+        Some(module_name) => module_name.rsplit('.').next().unwrap().to_owned(),
+        None => target.name.replace('-', "_"),
+    }
+}
+
+/// Escape `$(` in text from a manifest, so that Buck doesn't take it for the
+/// start of a macro like `$(location ...)`.
+fn escape_macros(value: &str) -> String {
+    value.replace("$(", "\\$(")
+}
+
+/// Replace each `$NAME` or `${NAME}` in `value` for which `lookup` has a
+/// value. Others, and things like `$(location ...)`, are left as they are.
+/// `$$` is a literal `$`, for values which mean `$OUT_DIR` and the like as is.
+fn substitute_vars(
+    value: &str,
+    mut lookup: impl FnMut(&str) -> anyhow::Result<Option<String>>,
+) -> anyhow::Result<String> {
+    let mut out = String::new();
+    let mut rest = value;
+    while let Some(i) = rest.find('$') {
+        out.push_str(&rest[..i]);
+        let after = &rest[i + 1..];
+        if let Some(escaped) = after.strip_prefix('$') {
+            out.push('$');
+            rest = escaped;
+            continue;
+        }
+        let (var, len) = match after.strip_prefix('{') {
+            Some(braced) => match braced.find('}') {
+                Some(end) => (&braced[..end], end + 2),
+                None => ("", 0),
+            },
+            None => {
+                let end = after
+                    .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                    .unwrap_or(after.len());
+                (&after[..end], end)
+            }
+        };
+        let substitute = if var.is_empty() { None } else { lookup(var)? };
+        match substitute {
+            Some(substitute) => {
+                out.push_str(&substitute);
+                rest = &after[len..];
+            }
+            None => {
+                out.push('$');
+                rest = after;
+            }
+        }
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+    use std::collections::BTreeSet;
+    use std::collections::HashMap;
+    use std::fs;
+    use std::path::Path;
+    use std::path::PathBuf;
+    use std::sync::Mutex;
+
+    use indoc::indoc;
+    use tempfile::TempDir;
+
+    use super::check_cfg_flags;
+    use super::doc_comments_use;
+    use super::has_fixup_files;
+    use super::native_linkable;
+    use super::substitute_vars;
+    use super::FixupUsage;
+    use super::Fixups;
+    use crate::buck::render_starlark;
+    use crate::buck::BuckPath;
+    use crate::buck::BuildscriptGenrule;
+    use crate::buck::Common;
+    use crate::buck::CxxLibrary;
+    use crate::buck::MappedSrc;
+    use crate::buck::Name;
+    use crate::buck::Rule;
+    use crate::buck::RustBinary;
+    use crate::buck::RustCommon;
+    use crate::buck::StringOrPath;
+    use crate::buck::SubtargetOrPath;
+    use crate::buck::Visibility;
+    use crate::cargo::Edition;
+    use crate::cargo::Manifest;
+    use crate::cargo::Metadata;
+    use crate::cargo::PkgId;
+    use crate::collection::SetOrMap;
+    use crate::config::CargoPkgEnv;
+    use crate::config::Config;
+    use crate::index::Index;
+    use crate::platform::PlatformExpr;
+    use crate::testing::cargo_metadata;
+    use crate::testing::paths;
+    use crate::testing::write;
+    use crate::Paths;
+
+    #[test]
+    fn native_linkable_single() {
+        let libs = [("bundled", Name("libz-sys-1.1.8-bundled-libz.a".to_owned()))];
+        assert_eq!(native_linkable(Some("z"), &libs), Some(&libs[0].1));
+    }
+
+    #[test]
+    fn native_linkable_by_links() {
+        let libs = [
+            ("helper", Name("foo-sys-0.1.0-helper".to_owned())),
+            ("foo", Name("foo-sys-0.1.0-foo".to_owned())),
+        ];
+        assert_eq!(native_linkable(Some("foo"), &libs), Some(&libs[1].1));
+        assert_eq!(native_linkable(Some("bar"), &libs), None);
+        assert_eq!(native_linkable(None, &[]), None);
+    }
+
+    #[test]
+    fn check_cfg_flags_values() {
+        let any_value = BTreeSet::from(["from_buildscript".to_owned()]);
+        let flags = check_cfg_flags(
+            [
+                "tokio_unstable",
+                "backend=\"x11\"",
+                "backend",
+                "backend = \"wayland\"",
+                "from_buildscript",
+            ],
+            &any_value,
+            ["default", "std"],
+        );
+        assert_eq!(
+            flags,
+            [
+                "--check-cfg=cfg(docsrs,test)",
+                "--check-cfg=cfg(feature, values(\"default\", \"std\"))",
+                "--check-cfg=cfg(backend, values(none(), \"wayland\", \"x11\"))",
+                "--check-cfg=cfg(tokio_unstable)",
+                "--check-cfg=cfg(from_buildscript, values(any()))",
+            ],
+        );
+    }
+
+    #[test]
+    fn test_substitute_vars() {
+        let lookup = |var: &str| {
+            Ok(match var {
+                "OUT_DIR" => Some("$(location :foo-build-script-run[out_dir])".to_owned()),
+                "CARGO_PKG_VERSION" => Some("1.2.3".to_owned()),
+                _ => None,
+            })
+        };
+        assert_eq!(
+            substitute_vars("$OUT_DIR/gen.rs", lookup).unwrap(),
+            "$(location :foo-build-script-run[out_dir])/gen.rs",
+        );
+        assert_eq!(
+            substitute_vars("v${CARGO_PKG_VERSION}-$HOME-$(exe :bar)-$", lookup).unwrap(),
+            "v1.2.3-$HOME-$(exe :bar)-$",
+        );
+        assert_eq!(substitute_vars("${OUT_DIR", lookup).unwrap(), "${OUT_DIR",);
+        assert_eq!(
+            substitute_vars("$$OUT_DIR/$$${CARGO_PKG_VERSION}", lookup).unwrap(),
+            "$OUT_DIR/$1.2.3",
+        );
+    }
+
+    fn config(toml: &str) -> Config {
+        toml::from_str(toml).unwrap()
+    }
+
+    /// A third-party dir with one vendored package, version 1.2.3-beta, and
+    /// its cargo metadata.
+    struct Package {
+        tempdir: TempDir,
+        metadata: Metadata,
+        name: String,
+    }
+
+    impl Package {
+        /// Package `name` with the given extra `[package]` keys and fixups.
+        fn new(name: &str, package: &str, fixups: &str) -> Self {
+            let tempdir = tempfile::tempdir().unwrap();
+            let third_party_dir = tempdir.path();
+            let write = |path: &str, content: &str| write(&third_party_dir.join(path), content);
+            write(
+                "Cargo.toml",
+                &format!(
+                    "[package]\nname = \"rust-third-party\"\nversion = \"0.0.0\"\n\
+                     edition = \"2021\"\npublish = false\n\n[lib]\npath = \"top/lib.rs\"\n\n\
+                     [dependencies]\n{name} = {{ path = \"vendor/{name}-1.2.3-beta\" }}\n",
+                ),
+            );
+            write("top/lib.rs", "");
+            write(
+                &format!("vendor/{name}-1.2.3-beta/Cargo.toml"),
+                &format!(
+                    "[package]\nname = {name:?}\nversion = \"1.2.3-beta\"\nedition = \"2021\"\n\
+                     authors = [\"A\", \"B\"]\nlicense = \"MIT\"\nhomepage = \"https://foo.rs\"\n\
+                     rust-version = \"1.70\"\n{package}",
+                ),
+            );
+            write(&format!("vendor/{name}-1.2.3-beta/src/lib.rs"), "");
+            write(
+                &format!("vendor/{name}-1.2.3-beta/build.rs"),
+                "fn main() {}\n",
+            );
+            write(&format!("fixups/{name}/fixups.toml"), fixups);
+
+            let metadata = cargo_metadata(third_party_dir);
+            Package {
+                tempdir,
+                metadata,
+                name: name.to_owned(),
+            }
+        }
+
+        /// Package `foo` with the given fixups.
+        fn foo(fixups: &str) -> Self {
+            Package::new("foo", "description = \"Foo\"\n", fixups)
+        }
+
+        fn path(&self, path: &str) -> PathBuf {
+            self.tempdir.path().join(path)
+        }
+
+        /// Write a file in the vendored package.
+        fn write(&self, path: &str, content: &str) {
+            write(
+                &self.path(&format!("vendor/{}-1.2.3-beta/{path}", self.name)),
+                content,
+            );
+        }
+
+        fn paths(&self) -> Paths {
+            paths(self.tempdir.path())
+        }
+
+        fn index(&self) -> Index<'_> {
+            Index::new(false, &self.metadata).unwrap()
+        }
+
+        fn manifest(&self) -> &Manifest {
+            self.metadata
+                .packages
+                .iter()
+                .find(|pkg| pkg.name == self.name)
+                .unwrap()
+        }
+
+        fn try_fixups<'a>(
+            &'a self,
+            config: &'a Config,
+            index: &'a Index<'a>,
+        ) -> anyhow::Result<Fixups<'a>> {
+            let pkg = self.manifest();
+            let lib = pkg.dependency_target().unwrap();
+            Fixups::new(config, &self.paths(), index, pkg, lib)
+        }
+
+        fn fixups<'a>(&'a self, config: &'a Config, index: &'a Index<'a>) -> Fixups<'a> {
+            self.try_fixups(config, index).unwrap()
+        }
+
+        /// Rules for the fixups of the package's build script.
+        fn buildscript_rules(&self, config: &Config) -> Vec<Rule> {
+            emit_buildscript_rules(&self.fixups(config, &self.index()), config)
+        }
+    }
+
+    /// Rules for the build script fixups of package `foo`, with the default
+    /// config.
+    fn buildscript_rules_for(fixups: &str) -> Vec<Rule> {
+        Package::foo(fixups).buildscript_rules(&config(""))
+    }
+
+    /// Rules for the fixups of a package's build script.
+    fn emit_buildscript_rules(fixups: &Fixups, config: &Config) -> Vec<Rule> {
+        try_emit_buildscript_rules(fixups, config).unwrap()
+    }
+
+    fn try_emit_buildscript_rules(fixups: &Fixups, config: &Config) -> anyhow::Result<Vec<Rule>> {
+        let buildscript = RustBinary {
+            common: RustCommon {
+                common: Common {
+                    name: Name(format!("{}-build-script-build", fixups.package)),
+                    visibility: Visibility::Private,
+                    licenses: BTreeSet::new(),
+                    license_expression: None,
+                    compatible_with: Vec::new(),
+                    target_compatible_with: Vec::new(),
+                },
+                krate: "build_script_build".to_owned(),
+                crate_root: BuckPath(PathBuf::from("build.rs")),
+                edition: Edition::Rust2021,
+                base: Default::default(),
+                platform: BTreeMap::new(),
+            },
+        };
+        fixups.emit_buildscript_rules(buildscript, config, &Mutex::new(Vec::new()))
+    }
+
+    fn cxx_libraries(rules: &[Rule]) -> Vec<&CxxLibrary> {
+        rules
+            .iter()
+            .filter_map(|rule| match rule {
+                Rule::CxxLibrary(cxx_library) => Some(cxx_library),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// The only `cxx_library` among the rules.
+    fn cxx_library(rules: &[Rule]) -> &CxxLibrary {
+        let [cxx_library] = cxx_libraries(rules)[..] else {
+            panic!("{rules:?}");
+        };
+        cxx_library
+    }
+
+    /// The build script run among the rules.
+    fn buildscript_genrule(rules: &[Rule]) -> &BuildscriptGenrule {
+        rules
+            .iter()
+            .find_map(|rule| match rule {
+                Rule::BuildscriptGenrule(genrule) => Some(genrule),
+                _ => None,
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn has_fixup_file_own_or_by_glob() {
+        let foo = Package::foo("");
+        let index = foo.index();
+        let has_fixup_file = |config: &Config| foo.fixups(config, &index).has_fixup_file();
+
+        assert!(has_fixup_file(&config("")));
+
+        fs::rename(foo.path("fixups/foo"), foo.path("fixups/shared")).unwrap();
+        assert!(!has_fixup_file(&config("")));
+        assert!(has_fixup_file(&config(
+            "fixup_globs = { \"f*\" = \"shared\" }"
+        )));
+    }
+
+    #[test]
+    fn env_interpolates_cargo_variables() {
+        let foo = Package::foo(
+            "cargo_env = [\"CARGO_PKG_LICENSE\", \"CARGO_PKG_VERSION_PRE\"]\n\
+             env = { GEN = \"$OUT_DIR/gen.rs\", DATA = \"$CARGO_MANIFEST_DIR/data\", \
+             ABOUT = \"${CARGO_PKG_NAME} v$CARGO_PKG_VERSION by $CARGO_PKG_AUTHORS, $HOME\", \
+             MSRV = \"$CARGO_PKG_RUST_VERSION\" }\n\n\
+             [[buildscript]]\n[buildscript.gen_srcs]\n\
+             env = { DATA = \"$CARGO_MANIFEST_DIR/data\", HOMEPAGE = \"$CARGO_PKG_HOMEPAGE\" }\n",
+        );
+        let config = config("");
+        let index = foo.index();
+        let fixups = foo.fixups(&config, &index);
+
+        let env: BTreeMap<_, _> = fixups
+            .compute_env()
+            .unwrap()
+            .into_iter()
+            .flat_map(|(platform, env)| {
+                assert!(platform.is_none());
+                env
+            })
+            .collect();
+        let string = |s: &str| StringOrPath::String(s.to_owned());
+        assert_eq!(
+            env,
+            BTreeMap::from([
+                ("ABOUT".to_owned(), string("foo v1.2.3-beta by A:B, $HOME")),
+                ("CARGO_PKG_LICENSE".to_owned(), string("MIT")),
+                ("CARGO_PKG_VERSION_PRE".to_owned(), string("beta")),
+                (
+                    "DATA".to_owned(),
+                    StringOrPath::Path(BuckPath(PathBuf::from("vendor/foo-1.2.3-beta/data"))),
+                ),
+                (
+                    "GEN".to_owned(),
+                    string("$(location :foo-1.2.3-beta-build-script-run[out_dir])/gen.rs"),
+                ),
+                ("MSRV".to_owned(), string("1.70")),
+            ]),
+        );
+
+        let rules = emit_buildscript_rules(&fixups, &config);
+        assert_eq!(
+            buildscript_genrule(&rules).env,
+            BTreeMap::from([
+                ("DATA".to_owned(), "./data".to_owned()),
+                ("HOMEPAGE".to_owned(), "https://foo.rs".to_owned()),
+            ]),
+        );
+    }
+
+    #[test]
+    fn cargo_pkg_env_sets() {
+        let foo = Package::new(
+            "foo",
+            "description = \"The \\\"foo\\\" crate\\n\\\\o/ $(not a macro)\"\n",
+            "",
+        );
+        let config = config("");
+        assert_eq!(config.cargo.cargo_pkg_env, CargoPkgEnv::Minimal);
+        let index = foo.index();
+        let fixups = foo.fixups(&config, &index);
+
+        let keys = |which| {
+            let env = fixups.compute_cargo_pkg_env(which).unwrap();
+            env.into_keys().collect::<Vec<_>>()
+        };
+        assert!(keys(CargoPkgEnv::None).is_empty());
+        assert_eq!(
+            keys(CargoPkgEnv::Minimal),
+            ["CARGO_PKG_NAME", "CARGO_PKG_VERSION"],
+        );
+
+        let env = fixups.compute_cargo_pkg_env(CargoPkgEnv::Full).unwrap();
+        assert_eq!(
+            env.keys().collect::<Vec<_>>(),
+            [
+                "CARGO_PKG_AUTHORS",
+                "CARGO_PKG_DESCRIPTION",
+                "CARGO_PKG_HOMEPAGE",
+                "CARGO_PKG_LICENSE",
+                "CARGO_PKG_LICENSE_FILE",
+                "CARGO_PKG_NAME",
+                "CARGO_PKG_README",
+                "CARGO_PKG_REPOSITORY",
+                "CARGO_PKG_RUST_VERSION",
+                "CARGO_PKG_VERSION",
+                "CARGO_PKG_VERSION_MAJOR",
+                "CARGO_PKG_VERSION_MINOR",
+                "CARGO_PKG_VERSION_PATCH",
+                "CARGO_PKG_VERSION_PRE",
+            ],
+        );
+        let description = render_starlark(&config.buck, &env["CARGO_PKG_DESCRIPTION"]);
+        assert_eq!(
+            description,
+            concat!(r#""The \"foo\" crate\n\\o/ \\$(not a macro)""#, "\n"),
+        );
+    }
+
+    #[test]
+    fn cxx_library_platform_preprocessor_flags() {
+        let rules = buildscript_rules_for(
+            "[[buildscript]]\n[buildscript.cxx_library]\n\
+             name = \"native\"\nsrcs = [\"src/native.c\"]\n\
+             platform_preprocessor_flags = { \"cfg(windows)\" = [\"-DFOO_WIN32\"], \
+             \"cfg(target_os = \\\"linux\\\")\" = [\"-DFOO_LINUX\", \"-D_GNU_SOURCE\"] }\n",
+        );
+        let platform_flags: Vec<_> = cxx_library(&rules)
+            .platform_preprocessor_flags
+            .iter()
+            .map(|(platform, flags)| (platform.to_string(), flags.join(" ")))
+            .collect();
+        let linux = "-DFOO_LINUX -D_GNU_SOURCE".to_owned();
+        let windows = "-DFOO_WIN32".to_owned();
+        assert_eq!(
+            platform_flags,
+            [
+                ("linux-arm64".to_owned(), linux.clone()),
+                ("linux-x86_64".to_owned(), linux),
+                ("windows-gnu".to_owned(), windows.clone()),
+                ("windows-msvc".to_owned(), windows),
+            ],
+        );
+    }
+
+    #[test]
+    fn header_namespace_defaults_to_links() {
+        let header_namespaces = |name: &str, package: &str| {
+            let pkg = Package::new(
+                name,
+                package,
+                "[[buildscript]]\n[buildscript.cxx_library]\n\
+                 name = \"native\"\nsrcs = [\"src/*.c\"]\n\
+                 exported_headers = [\"include/*.h\"]\n\n\
+                 [[buildscript]]\n[buildscript.cxx_library]\n\
+                 name = \"private\"\nsrcs = [\"src/*.c\"]\n\n\
+                 [[buildscript]]\n[buildscript.cxx_library]\n\
+                 name = \"flat\"\nsrcs = [\"src/*.c\"]\n\
+                 exported_headers = [\"include/*.h\"]\nheader_namespace = \"\"\n",
+            );
+            pkg.write("include/foo.h", "");
+            cxx_libraries(&pkg.buildscript_rules(&config("")))
+                .into_iter()
+                .map(|rule| rule.header_namespace.clone())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            header_namespaces("foo-sys", "links = \"foo\"\n"),
+            [Some("foo".to_owned()), None, Some(String::new())],
+        );
+        assert_eq!(
+            header_namespaces("foo-sys", ""),
+            [None, None, Some(String::new())],
+        );
+        assert_eq!(
+            header_namespaces("foo", "links = \"foo\"\n"),
+            [None, None, Some(String::new())],
+        );
+    }
+
+    #[test]
+    fn mapped_exported_headers_from_globs() {
+        let foo = Package::foo(
+            "[[buildscript]]\n[buildscript.cxx_library]\n\
+             name = \"native\"\nsrcs = [\"src/*.c\"]\n\
+             exported_headers = { \"foo/config.h\" = \"gen/config.h\" }\n\
+             mapped_exported_headers = { \"foo\" = \"c/include/**/*.h\", \
+             \"foo/internal\" = \"c/src/*.h\" }\nexclude = [\"**/test.h\"]\n",
+        );
+        for path in [
+            "c/include/foo.h",
+            "c/include/sys/types.h",
+            "c/include/test.h",
+            "c/src/util.h",
+            "c/src/util.c",
+        ] {
+            foo.write(path, "");
+        }
+
+        let rules = foo.buildscript_rules(&config(""));
+        let exported_headers = &cxx_library(&rules).exported_headers;
+        let SetOrMap::Map(exported_headers) = exported_headers else {
+            panic!("{exported_headers:?}");
+        };
+        let exported_headers: Vec<_> = exported_headers
+            .iter()
+            .map(|(header, path)| match path {
+                SubtargetOrPath::Path(path) => (header.as_str(), path.to_string()),
+                SubtargetOrPath::Subtarget(subtarget) => panic!("{subtarget:?}"),
+            })
+            .collect();
+        let vendored = |path: &str| format!("vendor/foo-1.2.3-beta/{path}");
+        assert_eq!(
+            exported_headers,
+            [
+                ("foo/config.h", vendored("gen/config.h")),
+                ("foo/foo.h", vendored("c/include/foo.h")),
+                ("foo/internal/util.h", vendored("c/src/util.h")),
+                ("foo/sys/types.h", vendored("c/include/sys/types.h")),
+            ],
+        );
+
+        // Overlapping globs may map a header to the same file, but not to
+        // different ones.
+        let foo = Package::foo(
+            "[[buildscript]]\n[buildscript.cxx_library]\n\
+             name = \"native\"\nsrcs = [\"src/*.c\"]\n\
+             mapped_exported_headers = { \"foo\" = \"c/**/*.h\", \"foo/x\" = \"c/x/*.h\", \
+             \"foo/x/\" = \"d/*.h\" }\n",
+        );
+        foo.write("c/x/foo.h", "");
+        foo.write("d/foo.h", "");
+        let config = config("");
+        let index = foo.index();
+        let err = try_emit_buildscript_rules(&foo.fixups(&config, &index), &config).unwrap_err();
+        assert_eq!(
+            format!("{err:#}"),
+            "cxx_library native mapped_exported_headers: `foo/x/foo.h` is mapped to both \
+             Path(BuckPath(\"vendor/foo-1.2.3-beta/c/x/foo.h\")) and \
+             Path(BuckPath(\"vendor/foo-1.2.3-beta/d/foo.h\"))",
+        );
+    }
+
+    #[test]
+    fn cxx_library_compiler_flags_order() {
+        let rules = Package::foo(
+            "[[buildscript]]\n[buildscript.cxx_library]\n\
+             name = \"native\"\nsrcs = [\"src/*.c\"]\n\
+             compiler_flags = [\"-O2\", \"-g\"]\n\
+             platform_compiler_flags = { \"cfg(unix)\" = [\"-fPIC\"], \
+             \"cfg(target_os = \\\"linux\\\")\" = [\"-pthread\"], \
+             \"cfg(target_arch = \\\"x86_64\\\")\" = [\"-msse4.2\"] }\n",
+        )
+        .buildscript_rules(&config(
+            "[buck]\ncxx_compiler_flags = [\"-g\", \"-Wall\"]\n",
+        ));
+        let cxx_library = cxx_library(&rules);
+        assert_eq!(cxx_library.compiler_flags, ["-g", "-Wall", "-O2", "-g"]);
+        let platform_flags = |name: &str| {
+            cxx_library
+                .platform_compiler_flags
+                .iter()
+                .find(|(platform, _)| platform.to_string() == name)
+                .map(|(_, flags)| flags.clone())
+                .unwrap_or_default()
+        };
+        assert_eq!(
+            platform_flags("linux-x86_64"),
+            ["-fPIC", "-pthread", "-msse4.2"],
+        );
+        assert_eq!(platform_flags("linux-arm64"), ["-fPIC", "-pthread"]);
+        assert_eq!(platform_flags("macos-x86_64"), ["-fPIC", "-msse4.2"]);
+        assert_eq!(platform_flags("windows-msvc"), ["-msse4.2"]);
+    }
+
+    #[test]
+    fn prebuilt_cxx_library_exports_headers() {
+        let foo = Package::foo(
+            "[[buildscript]]\n[buildscript.prebuilt_cxx_library]\n\
+             name = \"native\"\nstatic_libs = [\"lib/libnative.a\"]\n\
+             exported_preprocessor_flags = [\"-DNATIVE_STATIC\"]\n\
+             header_dirs = [\"include\"]\n",
+        );
+        foo.write("lib/libnative.a", "");
+
+        let rules = foo.buildscript_rules(&config(""));
+        let [Rule::PrebuiltCxxLibrary(prebuilt)] = &rules[..] else {
+            panic!("{rules:?}");
+        };
+        assert_eq!(prebuilt.exported_preprocessor_flags, ["-DNATIVE_STATIC"]);
+        assert_eq!(
+            prebuilt.header_dirs,
+            [SubtargetOrPath::Path(BuckPath(PathBuf::from(
+                "vendor/foo-1.2.3-beta/include",
+            )))],
+        );
+    }
+
+    #[test]
+    fn sys_linkable_alias() {
+        let foo_sys = Package::new(
+            "foo-sys",
+            "links = \"foo\"\n",
+            "[[buildscript]]\n[buildscript.prebuilt_cxx_library]\n\
+             name = \"bundled\"\nstatic_libs = [\"lib/libfoo.a\"]\n",
+        );
+        foo_sys.write("lib/libfoo.a", "");
+
+        // Off unless asked for.
+        let rules = foo_sys.buildscript_rules(&config(""));
+        let [Rule::PrebuiltCxxLibrary(_)] = &rules[..] else {
+            panic!("{rules:?}");
+        };
+
+        let config = config("sys_linkable_aliases = true");
+        let rules = foo_sys.buildscript_rules(&config);
+        let [Rule::PrebuiltCxxLibrary(_), Rule::Alias(_)] = &rules[..] else {
+            panic!("{rules:?}");
+        };
+        let mut out = Vec::new();
+        crate::buck::write_buckfile(&config.buck, rules.iter(), &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(
+            out.contains(indoc! {r#"
+                alias(
+                    name = "libfoo",
+                    actual = ":foo-sys-1.2.3-beta-bundled-libfoo.a",
+                    visibility = ["PUBLIC"],
+                )
+            "#}),
+            "{out}",
+        );
+    }
+
+    #[test]
+    fn data_filegroup_from_globs() {
+        let foo = Package::foo(
+            "data = [\"migrations/*.sql\", \"templates/**\"]\n\
+             env = { MIGRATIONS = \"$(location :foo-1.2.3-beta-data)/migrations\" }\n",
+        );
+        for path in [
+            "migrations/0001_init.sql",
+            "migrations/README.md",
+            "templates/page.html",
+            "templates/partials/nav.html",
+        ] {
+            foo.write(path, "");
+        }
+        let config = config("");
+        let index = foo.index();
+        let fixups = foo.fixups(&config, &index);
+
+        let filegroup = fixups.data_filegroup().unwrap().unwrap();
+        assert_eq!(filegroup.name, Name("foo-1.2.3-beta-data".to_owned()));
+        let srcs: Vec<_> = filegroup
+            .srcs
+            .iter()
+            .map(|(path, source)| match source {
+                SubtargetOrPath::Path(source) => (path.to_string(), source.to_string()),
+                SubtargetOrPath::Subtarget(subtarget) => panic!("{subtarget:?}"),
+            })
+            .collect();
+        let vendored = |path: &str| (path.to_owned(), format!("vendor/foo-1.2.3-beta/{path}"));
+        assert_eq!(
+            srcs,
+            [
+                vendored("migrations/0001_init.sql"),
+                vendored("templates/page.html"),
+                vendored("templates/partials/nav.html"),
+            ],
+        );
+    }
+
+    #[test]
+    fn buildscript_local_only() {
+        let local_only = |fixups: &str| {
+            let config = config("");
+            let rules = Package::foo(fixups).buildscript_rules(&config);
+            let genrule = buildscript_genrule(&rules);
+            let mut out = Vec::new();
+            Rule::BuildscriptGenrule(genrule.clone())
+                .render(&config.buck, &mut out)
+                .unwrap();
+            let rendered = String::from_utf8(out).unwrap();
+            assert_eq!(
+                rendered.contains("local_only = True"),
+                genrule.local_only,
+                "{rendered}",
+            );
+            genrule.local_only
+        };
+
+        assert!(!local_only("[[buildscript]]\n[buildscript.rustc_flags]\n"));
+        assert!(local_only(
+            "[[buildscript]]\n[buildscript.rustc_flags]\nlocal_only = true\n",
+        ));
+        assert!(local_only(
+            "[[buildscript]]\n[buildscript.rustc_flags]\n\n\
+             [[buildscript]]\n[buildscript.gen_srcs]\nlocal_only = true\n",
+        ));
+    }
+
+    #[test]
+    fn unused_fixups() {
+        let foo = Package::foo(
+            "[platform_fixup.'cfg(unix)']\nrustc_flags = [\"--cfg=unix\"]\n\n\
+             [platform_fixup.'cfg(target_os = \"plan9\")']\nrustc_flags = [\"--cfg=plan9\"]\n\n\
+             [platform_fixup.'cfg(windows)']\nversion = \"<1\"\nrustc_flags = [\"--cfg=old\"]\n",
+        );
+        write(&foo.path("fixups/gone/fixups.toml"), "");
+        let mut config = config("");
+        let paths = foo.paths();
+        let mut usage = FixupUsage::default();
+        usage.record(&foo.metadata.packages);
+
+        let unused = |config: &Config| {
+            usage
+                .unused(config, &paths)
+                .unwrap()
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            unused(&config),
+            [
+                "fixups/foo/fixups.toml [platform_fixup.'cfg(target_os = \"plan9\")']: \
+                 matches none of the configured platforms",
+                "fixups/foo/fixups.toml [platform_fixup.'cfg(windows)']: \
+                 `version = \"<1\"` matches none of the versions in the dependency graph, 1.2.3-beta",
+                "fixups/gone/fixups.toml: no package named `gone` in the dependency graph",
+            ],
+        );
+
+        // The left out platforms might be the ones a section is for.
+        config
+            .restrict_platforms(&["linux-x86_64".to_owned()])
+            .unwrap();
+        assert_eq!(unused(&config).len(), 2);
+    }
+
+    #[test]
+    fn fixup_globs_merge_before_exact_fixups() {
+        let pkg = Package::new("windows_x86_64_gnu", "", "[env]\nC = \"exact\"\n");
+        write(
+            &pkg.path("fixups/windows/fixups.toml"),
+            "[env]\nA = \"all\"\nB = \"all\"\n",
+        );
+        write(
+            &pkg.path("fixups/windows-x86_64/fixups.toml"),
+            "[env]\nB = \"x86_64\"\nC = \"x86_64\"\n",
+        );
+        let config = config(
+            "[fixup_globs]\n\"windows_x86_64_*\" = \"windows-x86_64\"\n\"windows*\" = \"windows\"\n",
+        );
+        let index = pkg.index();
+        let env = || {
+            let fixups = pkg.fixups(&config, &index);
+            let base = fixups.fixup_config.base(&pkg.manifest().version).unwrap();
+            base.env
+                .iter()
+                .map(|(k, v)| format!("{k}={v}"))
+                .collect::<Vec<_>>()
+        };
+
+        // The more specific glob overrides the other, and the package's own
+        // fixups override both.
+        assert_eq!(env(), ["A=all", "B=x86_64", "C=exact"]);
+        fs::remove_file(pkg.path("fixups/windows_x86_64_gnu/fixups.toml")).unwrap();
+        assert_eq!(env(), ["A=all", "B=x86_64", "C=x86_64"]);
+    }
+
+    #[test]
+    fn version_specific_fixup_files() {
+        let foo = Package::foo("rustc_flags = [\"--cfg=unversioned\"]\n");
+        let fixup_dir = foo.path("fixups/foo");
+        let write = |file: &str, content: &str| write(&fixup_dir.join(file), content);
+        write(
+            "fixups-1.toml",
+            "version = \"^1.2.3-beta\"\nrustc_flags = [\"--cfg=one\"]\n",
+        );
+        write(
+            "fixups-0.toml",
+            "version = \"<1\"\nrustc_flags = [\"--cfg=zero\"]\n",
+        );
+        let config = config("");
+        let index = foo.index();
+        let version = &foo.manifest().version;
+
+        let fixups = foo.fixups(&config, &index);
+        assert_eq!(fixups.fixup_file, Path::new("fixups/foo/fixups-1.toml"));
+        assert_eq!(
+            fixups.fixup_config.base(version).unwrap().rustc_flags,
+            ["--cfg=one"],
+        );
+
+        write(
+            "fixups-also.toml",
+            "version = \">=1.2.3-alpha\"\nrustc_flags = [\"--cfg=also\"]\n",
+        );
+        assert_eq!(
+            foo.try_fixups(&config, &index).err().unwrap().to_string(),
+            "foo-1.2.3-beta matches the `version` of more than one fixup file: \
+             fixups/foo/fixups-1.toml, fixups/foo/fixups-also.toml",
+        );
+
+        fs::remove_file(fixup_dir.join("fixups-also.toml")).unwrap();
+        write("fixups-any.toml", "rustc_flags = [\"--cfg=any\"]\n");
+        assert_eq!(
+            foo.try_fixups(&config, &index).err().unwrap().to_string(),
+            "fixups/foo/fixups-any.toml: version-specific fixups need a top-level `version`",
+        );
+
+        // Versioned fixups alone still count as fixups.
+        fs::remove_file(fixup_dir.join("fixups.toml")).unwrap();
+        assert!(has_fixup_files(&fixup_dir));
+        for file in ["fixups-0.toml", "fixups-1.toml", "fixups-any.toml"] {
+            fs::remove_file(fixup_dir.join(file)).unwrap();
+        }
+        assert!(!has_fixup_files(&fixup_dir));
+    }
+
+    #[test]
+    fn rustc_flags_remove() {
+        let foo = Package::foo(
+            "rustc_flags_remove = [\"--cap-lints=allow\", \"--cfg=v{major}\"]\n\
+             rustc_flags = [\"--cap-lints=allow\", \"-Copt-level=3\", \"--cfg=v1\"]\n\n\
+             [platform_fixup.'cfg(unix)']\nrustc_flags = [\"--cap-lints=allow\"]\n",
+        );
+        let config = config("");
+        let index = foo.index();
+
+        // Only the common flags are left; the platform section had nothing
+        // else.
+        assert_eq!(
+            foo.fixups(&config, &index).compute_cmdline(),
+            [(None, (vec!["-Copt-level=3".to_owned()], BTreeMap::new()))],
+        );
+    }
+
+    #[test]
+    fn doc_deps() {
+        let source = "//! ```\n//! use serde_json::json;\n//! ```\n\
+                      /// # #[macro_use] extern crate maplit;\n\
+                      /// Like `my_serde::Value`, see tokio docs.\n\
+                      fn main() { tokio::spawn(f); }\n";
+        assert!(doc_comments_use(source, "serde_json"));
+        assert!(doc_comments_use(source, "maplit"));
+        assert!(!doc_comments_use(source, "serde"));
+        assert!(!doc_comments_use(source, "tokio"));
+
+        let foo = Package::foo(
+            "doc_deps = [\"//third-party:serde_json\"]\n\n\
+             [platform_fixup.'cfg(unix)']\ndoc_deps = [\"//third-party:libc\"]\n",
+        );
+        let config = config("");
+        let index = foo.index();
+        let doc_deps: Vec<_> = foo
+            .fixups(&config, &index)
+            .compute_doc_deps()
+            .unwrap()
+            .into_iter()
+            .map(|(deppkg, dep)| {
+                assert!(deppkg.is_none());
+                (dep.platform().map(ToString::to_string), dep.target)
+            })
+            .collect();
+        assert_eq!(
+            doc_deps,
+            [
+                (None, "//third-party:serde_json".to_owned()),
+                (
+                    Some("cfg(unix)".to_owned()),
+                    "//third-party:libc".to_owned()
+                ),
+            ],
+        );
+    }
+
+    #[test]
+    fn unstable_rustc_flags() {
+        let mut config = config(
+            "[platform.linux-x86_64]\ntarget_arch = [\"x86_64\"]\ntarget_os = [\"linux\"]\n\n\
+             [platform.linux-arm64]\nnightly = true\ntarget_arch = [\"aarch64\"]\n\
+             target_os = [\"linux\"]\n",
+        );
+        let check = |config: &Config, fixups: &str| {
+            let foo = Package::foo(fixups);
+            let index = foo.index();
+            foo.fixups(config, &index)
+                .check_unstable_rustc_flags()
+                .map_err(|err| err.to_string())
+        };
+
+        assert_eq!(
+            check(
+                &config,
+                "[platform_fixup.'cfg(target_arch = \"aarch64\")']\n\
+                 rustc_flags = [\"-Zshare-generics=y\"]\n",
+            ),
+            Ok(()),
+        );
+        assert_eq!(
+            check(&config, "rustc_flags = [\"-Zshare-generics=y\"]\n").unwrap_err(),
+            "foo-1.2.3-beta: unstable rustc flags -Zshare-generics=y in the fixups for every \
+             platform would apply on platforms without `nightly = true`: linux-x86_64",
+        );
+        assert_eq!(
+            check(
+                &config,
+                "[platform_fixup.'cfg(target_os = \"linux\")']\n\
+                 rustc_flags = [\"-Z\", \"share-generics=y\"]\n",
+            )
+            .unwrap_err(),
+            "foo-1.2.3-beta: unstable rustc flags -Z in \
+             `platform_fixup.'cfg(target_os = \"linux\")'` would apply on platforms without \
+             `nightly = true`: linux-x86_64",
+        );
+
+        // Fine at the top level when every platform is nightly.
+        for platform in config.platform.values_mut() {
+            platform.nightly = true;
+        }
+        assert_eq!(
+            check(&config, "rustc_flags = [\"-Zshare-generics=y\"]\n"),
+            Ok(()),
+        );
+    }
+
+    #[test]
+    fn buildscript_skip() {
+        let skip = |omit_cfgs: &str| {
+            let foo = Package::foo(&format!(
+                "[[buildscript]]\n[buildscript.skip]\ncfgs = [\"has_foo\"]\n\
+                 omit_cfgs = [{omit_cfgs}]\nenv = {{ FOO = \"bar\" }}\n",
+            ));
+            foo.write(
+                "build.rs",
+                "fn main() {\n    println!(\"cargo:rustc-cfg=has_foo\");\n    \
+                 println!(\"cargo:rustc-cfg=old_os\");\n}\n",
+            );
+            foo
+        };
+        let config = config("");
+
+        let foo = skip("\"old_os\"");
+        let index = foo.index();
+        let fixups = foo.fixups(&config, &index);
+        assert_eq!(emit_buildscript_rules(&fixups, &config), []);
+        assert_eq!(
+            fixups.compute_cmdline(),
+            [(None, (vec!["--cfg=has_foo".to_owned()], BTreeMap::new()))],
+        );
+        let env = fixups.compute_env().unwrap();
+        assert_eq!(env[0].1["FOO"], StringOrPath::String("bar".to_owned()));
+
+        let foo = skip("");
+        let index = foo.index();
+        assert_eq!(
+            foo.fixups(&config, &index)
+                .check_skipped_buildscript()
+                .unwrap_err()
+                .to_string(),
+            "foo-1.2.3-beta: the build script is skipped, but it sets cfgs which aren't in \
+             `buildscript.skip` `cfgs` or `omit_cfgs`: old_os",
+        );
+    }
+
+    #[test]
+    fn buildscript_static_cfgs() {
+        let foo = Package::foo(
+            "[[buildscript]]\n[buildscript.rustc_flags]\ncfgs = [\"has_feature_x\", \"probed\"]\n",
+        );
+        let config = config("");
+        let index = foo.index();
+
+        // The known cfgs are passed along with whatever the script prints.
+        assert_eq!(
+            foo.fixups(&config, &index).compute_cmdline(),
+            [(
+                None,
+                (
+                    vec![
+                        "@$(location :foo-1.2.3-beta-build-script-run[rustc_flags])".to_owned(),
+                        "--cfg=has_feature_x".to_owned(),
+                        "--cfg=probed".to_owned(),
+                    ],
+                    BTreeMap::new(),
+                ),
+            )],
+        );
+    }
+
+    #[test]
+    fn buildscript_link_libs() {
+        let config = config("");
+        let cmdline = |fixup: &str| {
+            let foo_sys = Package::new("foo-sys", "links = \"foo\"\n", fixup);
+            foo_sys.write(
+                "build.rs",
+                "fn main() {\n    \
+                 let out_dir = std::env::var(\"OUT_DIR\").unwrap();\n    \
+                 println!(\"cargo:rustc-link-search=native={}\", out_dir);\n    \
+                 println!(\"cargo:rustc-link-lib=static=foo\");\n    \
+                 println!(\"cargo:rustc-link-lib=dylib=stdc++\");\n}\n",
+            );
+            let index = foo_sys.index();
+            let fixups = foo_sys.fixups(&config, &index);
+            let flags = match &fixups.compute_cmdline()[..] {
+                [] => Vec::new(),
+                [(None, (flags, _))] => flags.clone(),
+                cmdline => panic!("{cmdline:?}"),
+            };
+            (flags, fixups.mixed_native_linkage())
+        };
+        let rustc_flags = "@$(location :foo-sys-1.2.3-beta-build-script-run[rustc_flags])";
+
+        // Only passed on when asked for.
+        let (flags, warning) = cmdline("[[buildscript]]\n[buildscript.rustc_flags]\n");
+        assert_eq!(flags, [rustc_flags]);
+        assert_eq!(
+            warning.unwrap(),
+            "foo-sys-1.2.3-beta: build script links both static and shared native libraries \
+             (dylib: stdc++; static: foo); set `preferred_linkage` in its fixups to say how \
+             dependents should link it",
+        );
+
+        let (flags, _) = cmdline("[[buildscript]]\n[buildscript.rustc_flags]\nlink_libs = true\n");
+        assert_eq!(
+            flags,
+            [
+                rustc_flags,
+                "-Lnative=$(location :foo-sys-1.2.3-beta-build-script-run[out_dir])",
+                "-ldylib=stdc++",
+                "-lstatic=foo",
+            ],
+        );
+
+        // A skipped build script builds nothing, and whatever stands in for
+        // its libraries, like a `cxx_library` fixup, provides them.
+        assert!(
+            cmdline("[[buildscript]]\n[buildscript.skip]\n")
+                .0
+                .is_empty()
+        );
+
+        // Choosing the linkage settles it.
+        let (_flags, warning) = cmdline(
+            "preferred_linkage = \"static\"\n\n[[buildscript]]\n[buildscript.rustc_flags]\n",
+        );
+        assert_eq!(warning, None);
+    }
+
+    #[test]
+    fn buildscript_rustc_env() {
+        let config = config("");
+        let cmdline = |fixup: &str| {
+            let foo = Package::foo(fixup);
+            foo.write(
+                "build.rs",
+                "fn main() {\n    println!(\"cargo:rustc-env=FOO_GIT_HASH=0123abc\");\n}\n",
+            );
+            foo.write(
+                "src/lib.rs",
+                "pub const GIT_HASH: &str = env!(\"FOO_GIT_HASH\");\n",
+            );
+            let index = foo.index();
+            let fixups = foo.fixups(&config, &index);
+            fixups.check_skipped_buildscript()?;
+            anyhow::Ok(fixups.compute_cmdline())
+        };
+
+        // Whichever fixup runs the build script, the library gets the flags it
+        // printed, which carry the env.
+        let rustc_flags = "@$(location :foo-1.2.3-beta-build-script-run[rustc_flags])".to_owned();
+        for fixup in [
+            "[[buildscript]]\n[buildscript.rustc_flags]\n",
+            "[[buildscript]]\n[buildscript.gen_srcs]\n",
+        ] {
+            assert_eq!(
+                cmdline(fixup).unwrap(),
+                [(None, (vec![rustc_flags.clone()], BTreeMap::new()))],
+            );
+        }
+
+        // Skipping it needs the env supplied instead.
+        assert!(
+            cmdline("[[buildscript]]\n[buildscript.skip]\nenv = { FOO_GIT_HASH = \"0123abc\" }\n")
+                .is_ok()
+        );
+        assert_eq!(
+            cmdline("[[buildscript]]\n[buildscript.skip]\n")
+                .unwrap_err()
+                .to_string(),
+            "foo-1.2.3-beta: the build script is skipped, but it sets environment variables \
+             with `cargo:rustc-env` which aren't in `buildscript.skip` `env`: FOO_GIT_HASH",
+        );
+    }
+
+    #[test]
+    fn gen_srcs_mapped_srcs() {
+        let foo = Package::foo(
+            "[[buildscript]]\n[buildscript.gen_srcs]\n\
+             mapped_srcs = { \"bindings/ffi.rs\" = \"src/ffi.rs\" }\n",
+        );
+        let config = config("");
+        let index = foo.index();
+        let fixups = foo.fixups(&config, &index);
+
+        let output = MappedSrc::OutDirFile(Name(
+            "foo-1.2.3-beta-build-script-run-bindings-ffi.rs".to_owned(),
+        ));
+        assert_eq!(
+            fixups
+                .compute_mapped_srcs(Path::new("foo-1.2.3-beta.crate"))
+                .unwrap(),
+            [(
+                None,
+                BTreeMap::from([(
+                    output.clone(),
+                    BuckPath(PathBuf::from("foo-1.2.3-beta.crate/src/ffi.rs")),
+                )]),
+            )],
+        );
+        assert_eq!(
+            render_starlark(&config.buck, &output),
+            "\":foo-1.2.3-beta-build-script-run-bindings-ffi.rs\"\n",
+        );
+
+        // The file is copied out of the build script run's output directory.
+        let rules = emit_buildscript_rules(&fixups, &config);
+        let copy = rules
+            .iter()
+            .find(|rule| matches!(rule, Rule::OutDirFile(_)))
+            .unwrap();
+        let mut out = Vec::new();
+        copy.render(&config.buck, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            indoc! {r#"
+                genrule(
+                    name = "foo-1.2.3-beta-build-script-run-bindings-ffi.rs",
+                    out = "ffi.rs",
+                    cmd = "cp $(location :foo-1.2.3-beta-build-script-run[out_dir])/bindings/ffi.rs $OUT",
+                    visibility = [],
+                )
+            "#},
+        );
+    }
+
+    #[test]
+    fn per_platform_features() {
+        let foo = Package::new(
+            "foo",
+            "\n[features]\nstd = []\nsimd = []\nwin = []\n",
+            "[platform_fixup.'cfg(target_env = \"musl\")']\nomit_features = [\"simd\"]\n",
+        );
+        let config = config("");
+        let mut index = foo.index();
+        let resolved = |features: &[&str]| {
+            HashMap::from([(
+                PkgId(foo.manifest().id.0.clone()),
+                features
+                    .iter()
+                    .map(|feature| (*feature).to_owned())
+                    .collect(),
+            )])
+        };
+        index.set_platform_features(BTreeMap::from([
+            (
+                "x86_64-pc-windows-msvc".to_owned(),
+                resolved(&["std", "win"]),
+            ),
+            (
+                "x86_64-unknown-linux-gnu".to_owned(),
+                resolved(&["simd", "std"]),
+            ),
+        ]));
+
+        let features = |names: &[&str]| -> BTreeSet<String> {
+            names.iter().map(|name| (*name).to_owned()).collect()
+        };
+        assert_eq!(
+            foo.fixups(&config, &index).compute_features().unwrap(),
+            BTreeMap::from([
+                (None, features(&["std"])),
+                (
+                    Some(PlatformExpr::from("x86_64-pc-windows-msvc".to_owned())),
+                    features(&["win"]),
+                ),
+                (
+                    Some(PlatformExpr::from(
+                        "cfg(all(not(target_env = \"musl\"), x86_64-unknown-linux-gnu))".to_owned(),
+                    )),
+                    features(&["simd"]),
+                ),
+            ]),
+        );
+    }
+
+    #[test]
+    fn emit_binary() {
+        let emit_binary = |buck: &str, fixups: &str| {
+            let foo = Package::foo(fixups);
+            let config = config(&format!("[buck]\n{buck}"));
+            let index = foo.index();
+            foo.fixups(&config, &index).emit_binary()
+        };
+
+        assert!(emit_binary("", ""));
+        assert!(!emit_binary("emit_binaries = false", ""));
+        assert!(emit_binary("emit_binaries = false", "emit_binary = true\n"));
+        assert!(!emit_binary("", "emit_binary = false\n"));
+    }
+}
@@ -6,11 +6,13 @@
  */
 
 use std::collections::BTreeMap;
+use std::collections::BTreeSet;
 use std::fmt;
 use std::marker::PhantomData;
 use std::ops::Deref;
 use std::path::PathBuf;
 
+use indexmap::IndexMap;
 use serde::de::Error as DeError;
 use serde::de::MapAccess;
 use serde::de::Visitor;
@@ -22,6 +24,7 @@ use serde::Serializer;
 
 use crate::cargo::TargetKind;
 use crate::collection::SetOrMap;
+use crate::platform::PlatformExpr;
 
 #[derive(Deserialize, Debug, Serialize)]
 pub struct BuildscriptFixups(pub Vec<BuildscriptFixup>);
@@ -54,8 +57,9 @@ impl Deref for BuildscriptFixups {
 pub enum BuildscriptFixup {
     /// Unresolved build script (string with helpful message)
     Unresolved(String),
-    /// Run the buildscript and extract command line args. Linker -l/-L args ignored so in
-    /// practice this is just --cfg options.
+    /// Run the buildscript and extract command line args. Linker -l/-L args are
+    /// ignored in its output, unless `link_libs` passes on the ones spelled
+    /// out in the build script.
     RustcFlags(RustcFlags),
     /// Generated sources - give list of generated paths which are mapped into target sources
     GenSrcs(GenSrcs),
@@ -63,6 +67,9 @@ pub enum BuildscriptFixup {
     CxxLibrary(CxxLibraryFixup),
     /// Generate a prebuilt C++ library rule
     PrebuiltCxxLibrary(PrebuiltCxxLibraryFixup),
+    /// Don't run the build script, and give the targets the cfgs and env it
+    /// would have set
+    Skip(SkipBuildscript),
 }
 
 impl BuildscriptFixup {
@@ -74,6 +81,7 @@ impl BuildscriptFixup {
             BuildscriptFixup::PrebuiltCxxLibrary(PrebuiltCxxLibraryFixup { targets, .. }) => {
                 targets
             }
+            BuildscriptFixup::Skip(SkipBuildscript { targets, .. }) => targets,
             BuildscriptFixup::Unresolved(_) => return None,
         };
 
@@ -91,6 +99,22 @@ pub struct RustcFlags {
     // Runtime environment for the gensrc program
     #[serde(default)]
     pub env: BTreeMap<String, String>,
+    // Runtime environment variables holding a list of paths, joined with
+    // the target platform's path separator
+    #[serde(default)]
+    pub path_env: BTreeMap<String, Vec<String>>,
+    // Cfgs known to be emitted by the build script, passed to the targets in
+    // addition to whatever the script prints. Useful for build scripts whose
+    // probing of the environment is unreliable under Buck.
+    #[serde(default)]
+    pub cfgs: BTreeSet<String>,
+    // Run the build script locally rather than on remote execution
+    #[serde(default)]
+    pub local_only: bool,
+    // Link the targets with the native libraries the build script names in
+    // literal `cargo:rustc-link-lib` instructions
+    #[serde(default)]
+    pub link_libs: bool,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize, Eq, PartialEq)]
@@ -103,6 +127,39 @@ pub struct GenSrcs {
     // Runtime environment for the gensrc program
     #[serde(default)]
     pub env: BTreeMap<String, String>,
+    // Runtime environment variables holding a list of paths, joined with
+    // the target platform's path separator
+    #[serde(default)]
+    pub path_env: BTreeMap<String, Vec<String>>,
+    // Run the build script locally rather than on remote execution
+    #[serde(default)]
+    pub local_only: bool,
+    // Generated files, relative to OUT_DIR, to map into the target's sources
+    // at the given path relative to the manifest dir
+    #[serde(default)]
+    pub mapped_srcs: BTreeMap<PathBuf, PathBuf>,
+    // Like `RustcFlags::link_libs`
+    #[serde(default)]
+    pub link_libs: bool,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, Eq, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct SkipBuildscript {
+    // Which targets get the cfgs and env instead. List in the form of kind
+    // and name (eg `["bin","cargo"]`). Empty means apply to main lib target.
+    #[serde(default)]
+    pub targets: Vec<(TargetKind, Option<String>)>,
+    // Cfgs the build script would set, passed to rustc directly
+    #[serde(default)]
+    pub cfgs: BTreeSet<String>,
+    // Cfgs the build script spells out which don't apply to the platforms
+    // being built for, so aren't needed
+    #[serde(default)]
+    pub omit_cfgs: BTreeSet<String>,
+    // Environment the build script would set with `cargo:rustc-env`
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
 }
 
 fn set_true() -> bool {
@@ -122,20 +179,36 @@ pub struct CxxLibraryFixup {
     pub headers: Vec<String>, // header globs
     #[serde(default)]
     pub exported_headers: SetOrMap<String>, // exported header globs
+    // Exported header globs keyed by the directory to install their matches
+    // under, each relative to the glob's leading non-wildcard directories
+    #[serde(default)]
+    pub mapped_exported_headers: BTreeMap<String, String>,
     #[serde(default = "set_true")]
     pub add_dep: bool, // add to dependencies
     #[serde(default)]
     pub public: bool, // make public
     #[serde(default)]
     pub include_paths: Vec<PathBuf>,
+    // Include paths exported to dependents, for `#include <foo.h>` lookups
+    #[serde(default)]
+    pub public_include_paths: Vec<PathBuf>,
     #[serde(default)]
     pub fixup_include_paths: Vec<PathBuf>,
     #[serde(default)]
     pub exclude: Vec<String>,
     #[serde(default)]
     pub compiler_flags: Vec<String>,
+    // Additional compiler flags for the platforms matching each predicate,
+    // appended in the order the predicates are written
+    #[serde(default)]
+    pub platform_compiler_flags: IndexMap<PlatformExpr, Vec<String>>,
     #[serde(default)]
     pub preprocessor_flags: Vec<String>,
+    // Additional preprocessor flags for the platforms matching each predicate,
+    // like `platform_compiler_flags`
+    #[serde(default)]
+    pub platform_preprocessor_flags: IndexMap<PlatformExpr, Vec<String>>,
+    // Defaults to `links` for a -sys crate's library which exports headers
     pub header_namespace: Option<String>,
     #[serde(default)]
     pub deps: Vec<String>,
@@ -164,6 +237,12 @@ pub struct PrebuiltCxxLibraryFixup {
     pub public: bool, // make public
     #[serde(default)]
     pub compatible_with: Vec<String>,
+    // Preprocessor flags for dependents of the library
+    #[serde(default)]
+    pub exported_preprocessor_flags: Vec<String>,
+    // Directories of headers shipped with the library, for its dependents
+    #[serde(default)]
+    pub header_dirs: Vec<PathBuf>,
 }
 
 #[derive(Deserialize)]
@@ -186,6 +265,7 @@ impl Serialize for BuildscriptFixup {
             BuildscriptFixup::PrebuiltCxxLibrary(prebuilt_lib) => {
                 map.serialize_entry("prebuilt_prebcxx_library", prebuilt_lib)?
             }
+            BuildscriptFixup::Skip(skip) => map.serialize_entry("skip", skip)?,
         }
         map.end()
     }
@@ -213,6 +293,7 @@ impl<'de> Visitor<'de> for BuildscriptFixupVisitor<'de> {
                 "prebuilt_cxx_library" => {
                     BuildscriptFixup::PrebuiltCxxLibrary(access.next_value()?)
                 }
+                "skip" => BuildscriptFixup::Skip(access.next_value()?),
                 other => {
                     // other keys are unit, which map to an empty map
                     let _ = access.next_value::<Empty>()?;
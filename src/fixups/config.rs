@@ -12,7 +12,10 @@ use std::fmt;
 use std::path::Path;
 use std::path::PathBuf;
 
+use anyhow::anyhow;
+use itertools::Itertools;
 use serde::de::value::SeqAccessDeserializer;
+use serde::de::Error as DeError;
 use serde::de::SeqAccess;
 use serde::de::Visitor;
 use serde::Deserialize;
@@ -23,10 +26,15 @@ use strum::IntoEnumIterator as _;
 use walkdir::WalkDir;
 
 use crate::buckify::relative_path;
+use crate::cargo::Edition;
+use crate::cargo::Manifest;
 use crate::cargo::ManifestTarget;
+use crate::config::merge_config_tables;
+use crate::config::Config;
 use crate::fixups::buildscript::BuildscriptFixup;
 use crate::fixups::buildscript::BuildscriptFixups;
 use crate::glob::SerializableGlobSet as GlobSet;
+use crate::platform::platform_names_for_expr;
 use crate::platform::PlatformExpr;
 
 /// Top-level fixup config file (correspondins to a fixups.toml)
@@ -36,9 +44,10 @@ pub struct FixupConfigFile {
     /// Limit an exposed crate's `alias`'s `visibility` to this.
     /// This only has an effect for top-level crates. Exposed crates
     /// by default get `visibility = ["PUBLIC"]`. Sometimes you want to
-    /// discourage use of some crate by limiting its visibility.
+    /// discourage use of some crate by limiting its visibility. Each entry is
+    /// `PUBLIC` or a target pattern like `//foo/...`, `//foo:` or `//foo:bar`.
     #[serde(default, rename = "visibility")]
-    pub custom_visibility: Option<Vec<String>>,
+    pub custom_visibility: Option<Vec<VisibilityPattern>>,
 
     /// Omit a target
     #[serde(default)]
@@ -50,16 +59,58 @@ pub struct FixupConfigFile {
     /// src detection dominates buckification (e.g. the `windows` crate).
     pub precise_srcs: Option<bool>,
 
+    /// Overrides the global config `emit_binaries` for this crate's binaries.
+    pub emit_binary: Option<bool>,
+
+    /// Replaces the global `[buck] srcs_exclude` globs for this crate.
+    pub srcs_exclude: Option<GlobSet>,
+
     /// If the crate is generating a cdylib which is intended to be
     /// a Python extension module, set this to give the module name.
     /// This is passed as a `python_ext` parameter on the `rust_library`
-    /// rule so it can be mapped to the right underlying rule.
+    /// rule so it can be mapped to the right underlying rule. Cdylibs
+    /// using pyo3's `extension-module` feature get this by default, named
+    /// the way maturin would.
     pub python_ext: Option<String>,
 
+    /// Name of an alias for the native library built by a `-sys` crate's
+    /// `cxx_library` or `prebuilt_cxx_library` fixup, so that other rules can
+    /// link it directly. Defaults to `lib<links>` for `-sys` crates which
+    /// declare `links`, if `sys_linkable_aliases` is set.
+    pub linkable_alias: Option<String>,
+
+    /// Target of a dynamic library, such as a cdylib, to set as the library's
+    /// `crate_dynamic`, so that plugin hosts load it at runtime rather than
+    /// linking the crate statically.
+    pub crate_dynamic: Option<String>,
+
+    /// Build the crate with this edition instead of the one in its manifest,
+    /// e.g. to silence deprecations specific to an older edition.
+    pub edition: Option<Edition>,
+
     /// Make the crate sources available through a `filegroup`.
     /// This is useful for manually handling build scripts.
     pub export_sources: Option<ExportSources>,
 
+    /// Globs, rooted in the manifest dir, for runtime data files such as
+    /// templates or SQL migrations. They're exposed as a `filegroup` named
+    /// after the crate with a `-data` suffix, which rules' `env` can refer to
+    /// with `$(location ...)`.
+    #[serde(default)]
+    pub data: Vec<String>,
+
+    /// Constraints for `target_compatible_with` on the crate's library and
+    /// binaries, so Buck skips them on platforms they can't build for.
+    /// Replaces the constraint Reindeer derives from which platforms the
+    /// crate is reachable on.
+    pub target_compatible_with: Option<Vec<String>>,
+
+    /// Flags to take out of the crate's `rustc_flags` on every platform, once
+    /// everything else has added its flags: the fixups' own `rustc_flags` and
+    /// `cfgs`, build script flags, and `--check-cfg` from `emit_check_cfg`.
+    #[serde(default)]
+    pub rustc_flags_remove: BTreeSet<String>,
+
     /// Common config
     #[serde(flatten)]
     base: FixupConfig,
@@ -69,7 +120,118 @@ pub struct FixupConfigFile {
     platform_fixup: BTreeMap<PlatformExpr, FixupConfig>,
 }
 
+/// An entry of a fixup's `visibility`: `PUBLIC`, or a Buck target pattern
+/// such as `//foo/...`, `//foo:` or `//foo:bar`, optionally with a cell.
+#[derive(Debug, Clone, Serialize)]
+#[serde(transparent)]
+pub struct VisibilityPattern(pub String);
+
+impl<'de> Deserialize<'de> for VisibilityPattern {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let pattern = String::deserialize(deserializer)?;
+        if is_visibility_pattern(&pattern) {
+            Ok(VisibilityPattern(pattern))
+        } else {
+            Err(D::Error::custom(format!(
+                "`{pattern}` is not a visibility pattern, expected `PUBLIC` or a target pattern like `//foo/...`, `//foo:` or `//foo:bar`",
+            )))
+        }
+    }
+}
+
+fn is_visibility_pattern(pattern: &str) -> bool {
+    if pattern == "PUBLIC" {
+        return true;
+    }
+    let Some((cell, rest)) = pattern.split_once("//") else {
+        return false;
+    };
+    if !cell
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        return false;
+    }
+    let (dir, name) = match rest.strip_suffix("...") {
+        Some("") => ("", ""),
+        Some(dir) => match dir.strip_suffix('/') {
+            Some(dir) => (dir, ""),
+            None => return false,
+        },
+        None => match rest.split_once(':') {
+            Some(dir_and_name) => dir_and_name,
+            None => return false,
+        },
+    };
+    let dir_ok = dir.is_empty()
+        || dir.split('/').all(|component| {
+            !matches!(component, "" | "." | "..") && !component.contains([':', '\\'])
+        });
+    dir_ok && !pattern.contains(char::is_whitespace) && !name.contains(':')
+}
+
+/// Keys of `FixupConfigFile` itself, as opposed to the flattened `FixupConfig`.
+/// Used for suggesting corrections of misspelled keys.
+const FIXUP_CONFIG_FILE_KEYS: &[&str] = &[
+    "visibility",
+    "omit_targets",
+    "precise_srcs",
+    "emit_binary",
+    "srcs_exclude",
+    "python_ext",
+    "linkable_alias",
+    "crate_dynamic",
+    "edition",
+    "export_sources",
+    "data",
+    "target_compatible_with",
+    "rustc_flags_remove",
+    "platform_fixup",
+];
+
 impl FixupConfigFile {
+    /// Parse the contents of a fixups.toml. Errors are reported in the form
+    /// `path:line: message`, with a suggestion if the problem is an unknown
+    /// key that looks like a misspelling of a valid one.
+    pub fn parse(path: &Path, content: &str) -> anyhow::Result<Self> {
+        toml::from_str(content).map_err(|err| {
+            let message = err.message();
+            let location = match err.span() {
+                Some(span) => {
+                    // The span of an unknown field error covers the whole
+                    // table, so look for the key itself within it.
+                    let start = unknown_field(message)
+                        .and_then(|field| find_key(&content[span.clone()], field))
+                        .map_or(span.start, |offset| span.start + offset);
+                    let line = content[..start].matches('\n').count() + 1;
+                    format!("{}:{}", path.display(), line)
+                }
+                None => path.display().to_string(),
+            };
+            match suggest_field(message) {
+                Some(suggestion) => {
+                    anyhow!("{location}: {message}; did you mean `{suggestion}`?")
+                }
+                None => anyhow!("{location}: {message}"),
+            }
+        })
+    }
+
+    /// Parse several fixups.toml files applying to the same package, each
+    /// taking priority over the ones before it. Tables are merged key by key,
+    /// and any other value, including a list such as `buildscript`, replaces
+    /// the earlier file's.
+    pub fn parse_merged(files: &[(PathBuf, String)]) -> anyhow::Result<Self> {
+        let mut merged = toml::Table::new();
+        for (path, content) in files {
+            // Parse on its own first, for errors which point into the file.
+            Self::parse(path, content)?;
+            let table: toml::Table = toml::from_str(content)?;
+            merge_config_tables(&mut merged, table);
+        }
+        Ok(Self::deserialize(merged)?)
+    }
+
     /// Generate a template for a fixup.toml as a starting point.
     pub fn template(third_party_path: &Path, target: &ManifestTarget) -> Self {
         if !target.kind_custom_build() {
@@ -91,6 +253,68 @@ impl FixupConfigFile {
         }
     }
 
+    /// Comments to put at the top of a generated template, describing what's
+    /// known about the package's build script.
+    pub fn template_header(
+        third_party_path: &Path,
+        package: &Manifest,
+        target: &ManifestTarget,
+    ) -> String {
+        let mut header = format!("# Fixups for {} {}\n", package.name, package.version);
+        if !target.kind_custom_build() {
+            return header;
+        }
+
+        let relpath = relative_path(third_party_path, &target.src_path);
+        header += &format!(
+            "#\n\
+             # Generated because the package has a build script, {}.\n\
+             # Replace the `unresolved` entry with fixups describing what it does.\n",
+            relpath.display(),
+        );
+
+        if let Some(links) = &package.links {
+            header += &format!(
+                "#\n\
+                 # The package declares `links = \"{links}\"`, so the build script probably\n\
+                 # builds or locates a native library. See `[buildscript.cxx_library]` and\n\
+                 # `[buildscript.prebuilt_cxx_library]`.\n",
+            );
+        }
+
+        let source = std::fs::read_to_string(&target.src_path).unwrap_or_default();
+        let cfgs = buildscript_cfgs(&source);
+        if !cfgs.is_empty() {
+            header += &format!(
+                "#\n\
+                 # The build script appears to set these cfgs:\n\
+                 #\n\
+                 #   [[buildscript]]\n\
+                 #   [buildscript.rustc_flags]\n\
+                 #   cfgs = [{}]\n",
+                cfgs.iter().map(|cfg| format!("{cfg:?}")).join(", "),
+            );
+        }
+
+        let env = buildscript_env(&source);
+        if !env.is_empty() {
+            header += &format!(
+                "#\n\
+                 # The build script appears to set these environment variables for the\n\
+                 # crate with `cargo:rustc-env`, which a `rustc_flags` or `gen_srcs` fixup\n\
+                 # passes on: {}\n",
+                env.iter().join(", "),
+            );
+        }
+
+        header + "\n"
+    }
+
+    /// Versions the file's top-level fixups apply to
+    pub fn version(&self) -> Option<&semver::VersionReq> {
+        self.base.version.as_ref()
+    }
+
     pub fn base(&self, version: &semver::Version) -> Option<&FixupConfig> {
         if self.base.version_applies(version) {
             Some(&self.base)
@@ -108,6 +332,46 @@ impl FixupConfigFile {
             .filter(move |(_, cfg)| cfg.version_applies(version))
     }
 
+    /// Sections which can't apply to any of `versions` of the package, or to
+    /// any configured platform, each with the reason.
+    pub fn unused_sections(
+        &self,
+        config: &Config,
+        versions: &BTreeSet<semver::Version>,
+    ) -> anyhow::Result<Vec<(String, String)>> {
+        let no_version_matches = |cfg: &FixupConfig| {
+            let req = cfg.version.as_ref()?;
+            if versions.iter().any(|version| req.matches(version)) {
+                return None;
+            }
+            Some(format!(
+                "`version = \"{req}\"` matches none of the versions in the dependency graph, {}",
+                versions.iter().join(", "),
+            ))
+        };
+
+        let mut unused = Vec::new();
+        if let Some(reason) = no_version_matches(&self.base) {
+            unused.push(("top level".to_owned(), reason));
+        }
+        for (expr, cfg) in &self.platform_fixup {
+            let section = format!("[platform_fixup.'{expr}']");
+            if let Some(reason) = no_version_matches(cfg) {
+                unused.push((section, reason));
+            } else if !config.platforms_restricted
+                && platform_names_for_expr(config, expr)?.is_empty()
+            {
+                // With `--target-platforms` the section may well be for one of
+                // the platforms left out.
+                unused.push((
+                    section,
+                    "matches none of the configured platforms".to_owned(),
+                ));
+            }
+        }
+        Ok(unused)
+    }
+
     pub fn configs<'a>(
         &'a self,
         version: &'a semver::Version,
@@ -147,7 +411,8 @@ pub struct FixupConfig {
     /// Globs to exclude from srcs, rooted in manifest dir for package
     #[serde(default)]
     pub omit_srcs: GlobSet,
-    /// Extra flags for rustc
+    /// Extra flags for rustc. `{version}`, `{major}`, `{minor}` and `{patch}`
+    /// are replaced with the package's version.
     #[serde(default)]
     pub rustc_flags: Vec<String>,
     /// Select logic for rustc_flags
@@ -166,6 +431,9 @@ pub struct FixupConfig {
     /// Additional Buck dependencies
     #[serde(default)]
     pub extra_deps: BTreeSet<String>,
+    /// Additional Buck dependencies of the library's doctests
+    #[serde(default)]
+    pub doc_deps: BTreeSet<String>,
     /// Omit Cargo dependencies - just bare crate name
     #[serde(default)]
     pub omit_deps: BTreeSet<String>,
@@ -258,19 +526,26 @@ pub enum CargoEnvs {
     Deserialize,
     Serialize,
     strum::Display,
-    strum::EnumIter
+    strum::EnumIter,
+    strum::EnumString
 )]
 #[allow(non_camel_case_types)]
 pub enum CargoEnv {
     CARGO_MANIFEST_DIR,
     CARGO_PKG_AUTHORS,
     CARGO_PKG_DESCRIPTION,
+    CARGO_PKG_HOMEPAGE,
+    CARGO_PKG_LICENSE,
+    CARGO_PKG_LICENSE_FILE,
     CARGO_PKG_NAME,
+    CARGO_PKG_README,
     CARGO_PKG_REPOSITORY,
+    CARGO_PKG_RUST_VERSION,
     CARGO_PKG_VERSION,
     CARGO_PKG_VERSION_MAJOR,
     CARGO_PKG_VERSION_MINOR,
     CARGO_PKG_VERSION_PATCH,
+    CARGO_PKG_VERSION_PRE,
 }
 
 impl CargoEnvs {
@@ -338,3 +613,301 @@ impl Serialize for CargoEnvs {
         }
     }
 }
+
+fn unknown_field(message: &str) -> Option<&str> {
+    let rest = message.strip_prefix("unknown field `")?;
+    let (unknown, _rest) = rest.split_once('`')?;
+    Some(unknown)
+}
+
+/// Byte offset of the line in `table` which sets `key`.
+fn find_key(table: &str, key: &str) -> Option<usize> {
+    let mut offset = 0;
+    for line in table.split_inclusive('\n') {
+        let trimmed = line.trim_start();
+        let rest = trimmed
+            .strip_prefix(key)
+            .or_else(|| trimmed.strip_prefix(&format!("\"{key}\"")));
+        if rest.is_some_and(|rest| rest.trim_start().starts_with('=')) {
+            return Some(offset);
+        }
+        offset += line.len();
+    }
+    None
+}
+
+/// Given a serde "unknown field" error message, find the closest valid key.
+/// Serde lists the expected keys in the message, except for structs with a
+/// flattened field, where we have to supply them ourselves.
+fn suggest_field(message: &str) -> Option<&str> {
+    let unknown = unknown_field(message)?;
+    let rest = &message["unknown field `".len() + unknown.len() + 1..];
+
+    let candidates: Vec<&str> = match rest.strip_prefix(", expected one of ") {
+        Some(expected) => expected
+            .split(", ")
+            .map(|key| key.trim_matches('`'))
+            .collect(),
+        None => match rest.strip_prefix(", expected ") {
+            Some(expected) => vec![expected.trim_matches('`')],
+            None => FIXUP_CONFIG_FILE_KEYS
+                .iter()
+                .chain(struct_fields::<FixupConfig>())
+                .copied()
+                .collect(),
+        },
+    };
+
+    candidates
+        .into_iter()
+        .map(|candidate| (strsim::levenshtein(unknown, candidate), candidate))
+        .filter(|(distance, candidate)| *distance <= 2.max(candidate.len() / 3))
+        .min()
+        .map(|(_distance, candidate)| candidate)
+}
+
+/// The field names serde uses for a struct, found by asking it to deserialize
+/// itself from a deserializer which just records them.
+fn struct_fields<'de, T: Deserialize<'de>>() -> &'static [&'static str] {
+    struct FieldsDeserializer<'a>(&'a mut &'static [&'static str]);
+
+    impl<'de, 'a> Deserializer<'de> for FieldsDeserializer<'a> {
+        type Error = serde::de::value::Error;
+
+        fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Self::Error> {
+            Err(DeError::custom("not a struct"))
+        }
+
+        fn deserialize_struct<V: Visitor<'de>>(
+            self,
+            _name: &'static str,
+            fields: &'static [&'static str],
+            _visitor: V,
+        ) -> Result<V::Value, Self::Error> {
+            *self.0 = fields;
+            Err(DeError::custom("only collecting field names"))
+        }
+
+        serde::forward_to_deserialize_any! {
+            bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+            bytes byte_buf option unit unit_struct newtype_struct seq tuple
+            tuple_struct map enum identifier ignored_any
+        }
+    }
+
+    let mut fields: &'static [&'static str] = &[];
+    let _ = T::deserialize(FieldsDeserializer(&mut fields));
+    fields
+}
+
+/// Names of the environment variables a build script appears to set for the
+/// crate's compilation with `cargo:rustc-env=NAME=value`, going by its source.
+pub fn buildscript_env(source: &str) -> BTreeSet<String> {
+    source
+        .match_indices("rustc-env=")
+        .filter_map(|(i, pat)| {
+            let rest = &source[i + pat.len()..];
+            let (name, _value) = rest.split_once('=')?;
+            if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                None
+            } else {
+                Some(name.to_owned())
+            }
+        })
+        .collect()
+}
+
+/// Native libraries a build script appears to link with
+/// `cargo:rustc-link-lib=[KIND[:MODIFIERS]=]NAME[:RENAME]`, going by its
+/// source. Only whole literal values count, not ones filled in at runtime.
+pub fn buildscript_link_libs(source: &str) -> BTreeSet<String> {
+    source
+        .match_indices("rustc-link-lib=")
+        .filter_map(|(i, pat)| {
+            let rest = &source[i + pat.len()..];
+            let end = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || "_-.:=+,".contains(c)))
+                .unwrap_or(rest.len());
+            let (lib, after) = rest.split_at(end);
+            let name = lib.rsplit('=').next().unwrap_or(lib);
+            let literal = after.is_empty() || after.starts_with(['"', '\n', '\r']);
+            if name.is_empty() || name.starts_with(':') || !literal {
+                None
+            } else {
+                Some(lib.to_owned())
+            }
+        })
+        .collect()
+}
+
+/// Find the names of cfgs a build script unconditionally spells out in a
+/// `cargo:rustc-cfg=` instruction. Computed ones aren't found.
+pub fn buildscript_cfgs(source: &str) -> BTreeSet<String> {
+    source
+        .match_indices("rustc-cfg=")
+        .filter_map(|(i, pat)| {
+            let rest = &source[i + pat.len()..];
+            let end = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            let name = &rest[..end];
+            if name.is_empty() || name.starts_with(|c: char| c.is_ascii_digit()) {
+                None
+            } else {
+                Some(name.to_owned())
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use super::buildscript_cfgs;
+    use super::buildscript_env;
+    use super::buildscript_link_libs;
+    use super::is_visibility_pattern;
+    use super::FixupConfigFile;
+    use crate::cargo::Edition;
+
+    #[test]
+    fn test_buildscript_env() {
+        let source = r#"
+            println!("cargo:rustc-env=GIT_HASH={}", hash);
+            println!("cargo::rustc-env=BUILD_TARGET={target}");
+            println!("cargo:rustc-env={}={}", key, value);
+            println!("cargo:rustc-env=NO_VALUE");
+        "#;
+        assert_eq!(
+            buildscript_env(source).into_iter().collect::<Vec<_>>(),
+            ["BUILD_TARGET", "GIT_HASH"],
+        );
+    }
+
+    #[test]
+    fn test_buildscript_link_libs() {
+        let source = r#"
+            println!("cargo:rustc-link-search=native={}", out_dir.display());
+            println!("cargo:rustc-link-lib=static=foo");
+            println!("cargo:rustc-link-lib=dylib=stdc++");
+            println!("cargo::rustc-link-lib=framework=Security");
+            println!("cargo:rustc-link-lib=static:+whole-archive=bar");
+            println!("cargo:rustc-link-lib=z");
+            println!("cargo:rustc-link-lib=static={}", name);
+            println!("cargo:rustc-link-lib={lib}");
+        "#;
+        assert_eq!(
+            buildscript_link_libs(source)
+                .into_iter()
+                .collect::<Vec<_>>(),
+            [
+                "dylib=stdc++",
+                "framework=Security",
+                "static:+whole-archive=bar",
+                "static=foo",
+                "z",
+            ],
+        );
+    }
+
+    #[test]
+    fn test_parse_suggests_top_level_key() {
+        let err = FixupConfigFile::parse(
+            Path::new("fixups/foo/fixups.toml"),
+            "features = [\"x\"]\nrustc_flag = [\"--cfg=foo\"]\n",
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "fixups/foo/fixups.toml:2: unknown field `rustc_flag`; did you mean `rustc_flags`?",
+        );
+    }
+
+    #[test]
+    fn test_parse_suggests_nested_key() {
+        let err = FixupConfigFile::parse(
+            Path::new("fixups.toml"),
+            "[export_sources]\nname = \"x\"\nsrcs = []\nvisibilty = []\n",
+        )
+        .unwrap_err();
+        assert!(
+            err.to_string().ends_with("did you mean `visibility`?"),
+            "{err}",
+        );
+    }
+
+    #[test]
+    fn test_visibility_patterns() {
+        for pattern in [
+            "PUBLIC",
+            "//...",
+            "//foo/...",
+            "//foo:",
+            "//foo/bar:baz",
+            "//:baz",
+            "fbcode//foo:bar",
+        ] {
+            assert!(is_visibility_pattern(pattern), "{pattern}");
+        }
+        for pattern in [
+            "public",
+            "//foo",
+            "foo:bar",
+            ":bar",
+            "//foo...",
+            "//foo//bar:baz",
+            "//../foo:bar",
+            "//foo:bar:baz",
+            "//foo: bar",
+            "my cell//foo:bar",
+        ] {
+            assert!(!is_visibility_pattern(pattern), "{pattern}");
+        }
+
+        let err = FixupConfigFile::parse(
+            Path::new("fixups.toml"),
+            "visibility = [\"//foo/...\", \"foo:bar\"]\n",
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "fixups.toml:1: `foo:bar` is not a visibility pattern, expected `PUBLIC` or a target pattern like `//foo/...`, `//foo:` or `//foo:bar`",
+        );
+    }
+
+    #[test]
+    fn test_parse_no_suggestion() {
+        let err = FixupConfigFile::parse(Path::new("fixups.toml"), "zzzzzzzz = 1\n").unwrap_err();
+        assert_eq!(err.to_string(), "fixups.toml:1: unknown field `zzzzzzzz`");
+    }
+
+    #[test]
+    fn test_parse_edition() {
+        let fixup =
+            FixupConfigFile::parse(Path::new("fixups.toml"), "edition = \"2021\"\n").unwrap();
+        assert_eq!(fixup.edition, Some(Edition::Rust2021));
+
+        let err =
+            FixupConfigFile::parse(Path::new("fixups.toml"), "edition = \"2020\"\n").unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("fixups.toml:1: unknown edition \"2020\""),
+            "{err}",
+        );
+    }
+
+    #[test]
+    fn test_buildscript_cfgs() {
+        let source = r#"
+            println!("cargo:rustc-cfg=has_i128");
+            println!("cargo::rustc-cfg=tokio_unstable");
+            println!("cargo:rustc-cfg={}", computed);
+            println!("cargo:rustc-cfg=has_i128");
+        "#;
+        assert_eq!(
+            buildscript_cfgs(source).into_iter().collect::<Vec<_>>(),
+            ["has_i128", "tokio_unstable"],
+        );
+    }
+}
@@ -16,13 +16,14 @@ use globset::Glob;
 use globset::GlobBuilder;
 use globset::GlobSet;
 use globset::GlobSetBuilder;
+use ignore::WalkBuilder;
 use serde::Deserialize;
 use serde::Deserializer;
 use serde::Serialize;
 use serde::Serializer;
 use walkdir::WalkDir;
 
-#[derive(Default, Debug)]
+#[derive(Default, Debug, Clone)]
 pub struct SerializableGlobSet {
     vec: Vec<Glob>,
     globset: GlobSet,
@@ -108,13 +109,41 @@ impl Globs {
     /// Returns relative paths (relative to `dir`) of all the matching files.
     pub fn walk(&mut self, dir: impl AsRef<Path>) -> impl Iterator<Item = PathBuf> {
         let dir = dir.as_ref();
-        WalkDir::new(dir)
+        let files = WalkDir::new(dir)
             .into_iter()
             .filter_map(Result::ok)
             .filter(|entry| !entry.file_type().is_dir())
-            .filter_map(move |entry| {
-                let path = entry
-                    .path()
+            .map(|entry| entry.into_path());
+        self.matching(dir, files)
+    }
+
+    /// Like `walk`, but leaving out files ignored by any `.gitignore` within
+    /// `dir`, each applying to its own directory. Ones above `dir` don't count.
+    pub fn walk_unignored(&mut self, dir: impl AsRef<Path>) -> impl Iterator<Item = PathBuf> {
+        let dir = dir.as_ref();
+        let files = WalkBuilder::new(dir)
+            .standard_filters(false)
+            .git_ignore(true)
+            .require_git(false)
+            .build()
+            .filter_map(Result::ok)
+            .filter(|entry| {
+                entry
+                    .file_type()
+                    .is_some_and(|file_type| !file_type.is_dir())
+            })
+            .map(|entry| entry.into_path());
+        self.matching(dir, files)
+    }
+
+    fn matching(
+        &mut self,
+        dir: &Path,
+        files: impl Iterator<Item = PathBuf>,
+    ) -> impl Iterator<Item = PathBuf> {
+        files
+            .filter_map(move |file| {
+                let path = file
                     .strip_prefix(dir)
                     .expect("walkdir produced paths not inside intended dir");
                 let matches = self.globset.matches(path);
@@ -143,3 +172,77 @@ impl Globs {
         bail!("Unmatched globs: {:?}", unmatched);
     }
 }
+
+/// The leading directories of a glob pattern which contain no wildcards, like
+/// `include/openssl` for `include/openssl/**/*.h`.
+pub fn literal_prefix(glob: &str) -> PathBuf {
+    let mut components: Vec<&str> = glob.split('/').collect();
+    components.pop(); // final component is a file name pattern
+    components
+        .into_iter()
+        .take_while(|component| !component.contains(['*', '?', '[', '{', '\\']))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::path::PathBuf;
+
+    use tempfile::tempdir;
+
+    use super::literal_prefix;
+    use super::Globs;
+    use super::NO_EXCLUDE;
+
+    #[test]
+    fn test_walk_unignored() {
+        let dir = tempdir().unwrap();
+        for (path, content) in [
+            (".gitignore", "/target\n*.orig\n"),
+            ("src/lib.rs", ""),
+            ("src/lib.rs.orig", ""),
+            ("src/gen/.gitignore", "out.rs\n"),
+            ("src/gen/mod.rs", ""),
+            ("src/gen/out.rs", ""),
+            ("target/debug/build.rs", ""),
+        ] {
+            let path = dir.path().join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content).unwrap();
+        }
+
+        let mut globs = Globs::new(["**/*.rs*"], NO_EXCLUDE).unwrap();
+        let mut all: Vec<_> = globs.walk(dir.path()).collect();
+        all.sort();
+        assert_eq!(
+            all,
+            [
+                "src/gen/mod.rs",
+                "src/gen/out.rs",
+                "src/lib.rs",
+                "src/lib.rs.orig",
+                "target/debug/build.rs",
+            ]
+            .map(PathBuf::from),
+        );
+
+        let mut unignored: Vec<_> = globs.walk_unignored(dir.path()).collect();
+        unignored.sort();
+        assert_eq!(
+            unignored,
+            ["src/gen/mod.rs", "src/lib.rs"].map(PathBuf::from),
+        );
+    }
+
+    #[test]
+    fn test_literal_prefix() {
+        assert_eq!(
+            literal_prefix("include/openssl/**/*.h"),
+            PathBuf::from("include/openssl"),
+        );
+        assert_eq!(literal_prefix("src/*/api.h"), PathBuf::from("src"));
+        assert_eq!(literal_prefix("include/zlib.h"), PathBuf::from("include"));
+        assert_eq!(literal_prefix("*.h"), PathBuf::new());
+    }
+}
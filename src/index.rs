@@ -10,8 +10,11 @@
 use std::collections::BTreeMap;
 use std::collections::BTreeSet;
 use std::collections::HashMap;
+use std::collections::HashSet;
 
+use anyhow::bail;
 use anyhow::Context as _;
+use itertools::Itertools as _;
 
 use crate::buck::Name;
 use crate::cargo::DepKind;
@@ -23,6 +26,7 @@ use crate::cargo::NodeDep;
 use crate::cargo::NodeDepKind;
 use crate::cargo::PkgId;
 use crate::cargo::TargetReq;
+use crate::cargo::TreeFeatures;
 use crate::platform::PlatformExpr;
 
 /// Index for interesting things in Cargo metadata
@@ -40,10 +44,19 @@ pub struct Index<'meta> {
     /// The (possibly renamed) names of all packages which have at least one
     /// public target.
     public_package_names: BTreeSet<&'meta str>,
+    /// Public rule names for packages whose plain public name is shared with
+    /// another version of the same package, or which are named by a template.
+    disambiguated_names: HashMap<&'meta PkgId, String>,
     /// Set of public targets. These consist of:
     /// - root_pkg, if it is being made public (aka "real", and not just a pseudo package)
     /// - first-order dependencies of root_pkg, including artifact dependencies
     public_targets: BTreeMap<(&'meta PkgId, TargetReq<'meta>), Option<&'meta str>>,
+    /// Features of each package resolved for each target triple, with
+    /// `per_platform_features`
+    platform_features: BTreeMap<String, HashMap<PkgId, BTreeSet<String>>>,
+    /// Resolved features of each package which only dev-dependencies enable,
+    /// left out under resolver 2
+    dev_only_features: HashMap<&'meta PkgId, BTreeSet<&'meta str>>,
 }
 
 #[derive(Debug, Clone)]
@@ -90,7 +103,10 @@ impl<'meta> Index<'meta> {
             workspace_members,
             public_packages: BTreeMap::new(),
             public_package_names: BTreeSet::new(),
+            disambiguated_names: HashMap::new(),
             public_targets: BTreeMap::new(),
+            platform_features: BTreeMap::new(),
+            dev_only_features: HashMap::new(),
         };
 
         // Keep an index of renamed crates, mapping from _ normalized name to actual name.
@@ -134,6 +150,26 @@ impl<'meta> Index<'meta> {
                 });
         }
 
+        // Multiple versions of a package can be public at once, for example
+        // if different workspace members depend on different versions. Their
+        // aliases would all get the same name, so give each of them a suffix.
+        let mut by_public_name: BTreeMap<&str, Vec<&'meta Manifest>> = BTreeMap::new();
+        for (id, rename) in &tmp.public_packages {
+            let pkg = tmp.pkgid_to_pkg[id];
+            let name = rename.unwrap_or(&pkg.name);
+            by_public_name.entry(name).or_default().push(pkg);
+        }
+        for (name, pkgs) in by_public_name {
+            if pkgs.len() < 2 {
+                continue;
+            }
+            let versions: Vec<_> = pkgs.iter().map(|pkg| &pkg.version).collect();
+            let names = disambiguated_names(name, &versions)?;
+            for (pkg, name) in pkgs.into_iter().zip(names) {
+                tmp.disambiguated_names.insert(&pkg.id, name);
+            }
+        }
+
         Ok(Index {
             public_targets,
             ..tmp
@@ -174,20 +210,141 @@ impl<'meta> Index<'meta> {
 
     /// Return the package public rule name.
     pub fn public_rule_name(&self, pkg: &'meta Manifest) -> Name {
+        if let Some(name) = self.disambiguated_names.get(&pkg.id) {
+            return Name(name.clone());
+        }
         Name(match self.public_packages.get(&pkg.id) {
             Some(None) | None => pkg.name.to_owned(), // Package name
             Some(&Some(rename)) => rename.to_owned(), // Rename
         })
     }
 
+    /// Name public rules with a template such as `third-party-{crate}`, in
+    /// which `{crate}` is the name they'd otherwise get and `{version}` is the
+    /// package version. The names must be valid target names, distinct from
+    /// each other and from every private rule name.
+    pub fn apply_alias_name_template(&mut self, template: &str) -> anyhow::Result<()> {
+        let mut names = BTreeMap::new();
+        for id in self.public_packages.keys() {
+            let pkg = self.pkgid_to_pkg[id];
+            let name = template
+                .replace("{crate}", &self.public_rule_name(pkg).0)
+                .replace("{version}", &pkg.version.to_string());
+            if name.contains(['{', '}']) {
+                bail!(
+                    "`alias_name_template = {template:?}` has an unknown placeholder, expected `{{crate}}` or `{{version}}`",
+                );
+            }
+            if name.is_empty()
+                || name.contains(|c: char| matches!(c, '/' | ':') || c.is_whitespace())
+            {
+                bail!(
+                    "`alias_name_template = {template:?}` gives {pkg} the invalid target name {name:?}",
+                );
+            }
+            if let Some(other) = names.insert(name.clone(), pkg) {
+                bail!(
+                    "`alias_name_template = {template:?}` gives both {other} and {pkg} the name {name:?}",
+                );
+            }
+        }
+        for pkg in self.pkgid_to_pkg.values() {
+            // The root package's library gets its public name, not a private one
+            if self.is_root_package(pkg) {
+                continue;
+            }
+            if let Some(public) = names.get(&self.private_rule_name(pkg).0) {
+                bail!(
+                    "`alias_name_template = {template:?}` gives {public} the same name as the rule for {pkg}",
+                );
+            }
+        }
+        for (name, pkg) in names {
+            self.disambiguated_names.insert(&pkg.id, name);
+        }
+        Ok(())
+    }
+
     /// Return the set of features resolved for a particular package
-    pub fn resolved_features(&self, pkg: &Manifest) -> impl Iterator<Item = &'meta str> {
+    pub fn resolved_features(&self, pkg: &Manifest) -> impl Iterator<Item = &'meta str> + '_ {
+        let dev_only = self.dev_only_features.get(&pkg.id);
         self.pkgid_to_node
             .get(&pkg.id)
             .unwrap()
             .features
             .iter()
             .map(String::as_str)
+            .filter(move |feature| !dev_only.is_some_and(|dev_only| dev_only.contains(feature)))
+    }
+
+    /// Use features resolved separately for each target triple, from `cargo
+    /// metadata --filter-platform`.
+    pub fn set_platform_features(
+        &mut self,
+        platform_features: BTreeMap<String, HashMap<PkgId, BTreeSet<String>>>,
+    ) {
+        self.platform_features = platform_features;
+    }
+
+    /// Features resolved for a package for each target triple it's built for,
+    /// if features were resolved per platform.
+    pub fn platform_features(&self, pkg: &Manifest) -> Vec<(&str, BTreeSet<&str>)> {
+        let dev_only = self.dev_only_features.get(&pkg.id);
+        self.platform_features
+            .iter()
+            .filter_map(|(triple, features)| {
+                let features = features
+                    .get(&pkg.id)?
+                    .iter()
+                    .map(String::as_str)
+                    .filter(|feature| !dev_only.is_some_and(|dev_only| dev_only.contains(feature)))
+                    .collect();
+                Some((triple.as_str(), features))
+            })
+            .collect()
+    }
+
+    /// Follow resolver 2, which doesn't unify features enabled only by
+    /// dev-dependencies into normal builds. `cargo metadata` always includes
+    /// them, so leave out whatever a package's `normal_features`, from `cargo
+    /// tree` without dev-dependencies, don't have. Packages only reachable
+    /// through dev-dependencies aren't in it, and keep all theirs.
+    pub fn exclude_dev_features(&mut self, normal_features: &TreeFeatures) {
+        self.dev_only_features = self
+            .pkgid_to_pkg
+            .iter()
+            .filter_map(|(pkgid, pkg)| {
+                let (_key, features) = normal_features
+                    .range((pkg.name.clone(), pkg.version.clone(), None)..)
+                    .take_while(|((name, version, _source), _features)| {
+                        *name == pkg.name && *version == pkg.version
+                    })
+                    .find(|((_name, _version, source), _features)| {
+                        pkg.is_tree_source(source.as_deref())
+                    })?;
+                let dev_only: BTreeSet<&str> = self.pkgid_to_node[pkgid]
+                    .features
+                    .iter()
+                    .filter(|feature| !features.contains(*feature))
+                    .map(String::as_str)
+                    .collect();
+                if dev_only.is_empty() {
+                    None
+                } else {
+                    Some((*pkgid, dev_only))
+                }
+            })
+            .collect();
+    }
+
+    /// The resolved dev-dependencies of a package, which Cargo only resolves
+    /// for workspace members.
+    pub fn resolved_dev_deps(
+        &self,
+        pkg: &Manifest,
+    ) -> impl Iterator<Item = (&'meta str, &'meta NodeDepKind, &'meta Manifest)> + '_ {
+        self.resolved_deps(pkg)
+            .filter(|(_rename, dep_kind, _dep)| dep_kind.kind == DepKind::Dev)
     }
 
     /// Return the resolved dependencies for a package
@@ -218,6 +375,62 @@ impl<'meta> Index<'meta> {
             )
     }
 
+    /// Packages reachable from the workspace members through normal and build
+    /// dependencies, following platform-specific ones only where `active`
+    /// accepts their platform expression.
+    pub fn reachable_packages(
+        &self,
+        mut active: impl FnMut(&PlatformExpr) -> bool,
+    ) -> HashSet<&'meta PkgId> {
+        let mut reachable = HashSet::new();
+        let mut todo = self.workspace_members.clone();
+        while let Some(pkg) = todo.pop() {
+            if !reachable.insert(&pkg.id) {
+                continue;
+            }
+            for (_rename, dep_kind, dep) in self.resolved_deps(pkg) {
+                if dep_kind.kind != DepKind::Dev
+                    && dep_kind.target.as_ref().map_or(true, &mut active)
+                {
+                    todo.push(dep);
+                }
+            }
+        }
+        reachable
+    }
+
+    /// Packages reachable from the workspace members which are built for the
+    /// execution platform: build dependencies, proc macros, and everything
+    /// they depend on.
+    pub fn exec_packages(&self) -> HashSet<&'meta PkgId> {
+        let mut visited = HashSet::new();
+        let mut exec = HashSet::new();
+        let mut todo: Vec<_> = self
+            .workspace_members
+            .iter()
+            .map(|pkg| (*pkg, false))
+            .collect();
+        while let Some((pkg, for_exec)) = todo.pop() {
+            if !visited.insert((&pkg.id, for_exec)) {
+                continue;
+            }
+            if for_exec {
+                exec.insert(&pkg.id);
+            }
+            for (_rename, dep_kind, dep) in self.resolved_deps(pkg) {
+                let proc_macro = dep
+                    .dependency_target()
+                    .is_some_and(|tgt| tgt.kind_proc_macro());
+                match dep_kind.kind {
+                    DepKind::Normal => todo.push((dep, for_exec || proc_macro)),
+                    DepKind::Build => todo.push((dep, true)),
+                    DepKind::Dev => {}
+                }
+            }
+        }
+        exec
+    }
+
     /// Return resolved dependencies for a target.
     pub fn resolved_deps_for_target(
         &self,
@@ -294,3 +507,196 @@ impl<'meta> Index<'meta> {
             })
     }
 }
+
+/// Public names for several versions of a package which would otherwise have
+/// the same name. Each gets the semver-compatible part of its version as a
+/// suffix, e.g. `rand-0.7` and `rand-0.8`. These can't clash with private rule
+/// names, which have the full version.
+fn disambiguated_names(name: &str, versions: &[&semver::Version]) -> anyhow::Result<Vec<String>> {
+    let names: Vec<String> = versions
+        .iter()
+        .map(|version| {
+            let compatible = if version.major > 0 {
+                version.major.to_string()
+            } else if version.minor > 0 {
+                format!("0.{}", version.minor)
+            } else {
+                format!("0.0.{}", version.patch)
+            };
+            format!("{}-{}", name, compatible)
+        })
+        .collect();
+
+    let unique: BTreeSet<&String> = names.iter().collect();
+    if unique.len() != names.len() {
+        bail!(
+            "Multiple semver-compatible versions of `{}` are public ({}). Rename one of them in Cargo.toml.",
+            name,
+            versions.iter().join(", "),
+        );
+    }
+
+    Ok(names)
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::disambiguated_names;
+    use super::Index;
+    use crate::testing::cargo_metadata;
+    use crate::testing::cargo_normal_features;
+    use crate::testing::package;
+    use crate::testing::write;
+
+    #[test]
+    fn test_disambiguated_names() {
+        let v073 = semver::Version::new(0, 7, 3);
+        let v084 = semver::Version::new(0, 8, 4);
+        let v100 = semver::Version::new(1, 0, 0);
+        assert_eq!(
+            disambiguated_names("rand", &[&v073, &v084, &v100]).unwrap(),
+            vec!["rand-0.7", "rand-0.8", "rand-1"],
+        );
+    }
+
+    #[test]
+    fn test_disambiguated_names_compatible() {
+        let v072 = semver::Version::new(0, 7, 2);
+        let v073 = semver::Version::new(0, 7, 3);
+        assert!(disambiguated_names("rand", &[&v072, &v073]).is_err());
+    }
+
+    #[test]
+    fn build_deps_only_for_build_script() {
+        let tempdir = tempfile::tempdir().unwrap();
+        package(tempdir.path(), "cc", "");
+        package(tempdir.path(), "libc", "");
+        package(
+            tempdir.path(),
+            "app",
+            "[dependencies]\nlibc = { path = \"../libc\" }\n\n\
+             [build-dependencies]\ncc = { path = \"../cc\" }\n",
+        );
+        let app = tempdir.path().join("app");
+        write(&app.join("build.rs"), "fn main() { let _ = cc::Build; }\n");
+
+        let metadata = cargo_metadata(&app);
+
+        let index = Index::new(false, &metadata).unwrap();
+        let pkg = index.root_pkg.unwrap();
+        let deps_of = |kind_custom_build: bool| {
+            let tgt = pkg
+                .targets
+                .iter()
+                .find(|tgt| tgt.kind_custom_build() == kind_custom_build)
+                .unwrap();
+            index
+                .resolved_deps_for_target(pkg, tgt)
+                .map(|dep| dep.package.name.as_str())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(deps_of(false), ["libc"]);
+        assert_eq!(deps_of(true), ["cc"]);
+    }
+
+    #[test]
+    fn dev_dependency_features_by_resolver() {
+        let tempdir = tempfile::tempdir().unwrap();
+        package(
+            tempdir.path(),
+            "foo",
+            "[features]\nstd = []\nfast = []\nextra = []\nbuild = []\n",
+        );
+        package(
+            tempdir.path(),
+            "app",
+            "[features]\ndefault = [\"foo/fast\"]\n\n\
+             [dependencies]\nfoo = { path = \"../foo\", features = [\"std\"] }\n\n\
+             [dev-dependencies]\nfoo = { path = \"../foo\", features = [\"extra\"] }\n\n\
+             [build-dependencies]\nfoo = { path = \"../foo\", features = [\"build\"] }\n",
+        );
+        let app = tempdir.path().join("app");
+        write(&app.join("build.rs"), "fn main() {}\n");
+
+        let metadata = cargo_metadata(&app);
+        let foo = metadata
+            .packages
+            .iter()
+            .find(|pkg| pkg.name == "foo")
+            .unwrap();
+
+        // Resolver 1 unifies the dev-dependency's features, like `cargo metadata`.
+        let index = Index::new(false, &metadata).unwrap();
+        assert_eq!(
+            index.resolved_features(foo).collect::<Vec<_>>(),
+            ["build", "extra", "fast", "std"],
+        );
+
+        // Resolver 2 leaves the dev-dependency's out. The build script's copy
+        // of foo is built separately with `build`, but shares its one rule.
+        let mut index = Index::new(false, &metadata).unwrap();
+        index.exclude_dev_features(&cargo_normal_features(&app));
+        assert_eq!(
+            index.resolved_features(foo).collect::<Vec<_>>(),
+            ["build", "fast", "std"],
+        );
+    }
+
+    #[test]
+    fn alias_name_template() {
+        let tempdir = tempfile::tempdir().unwrap();
+        package(tempdir.path(), "libc", "");
+        package(tempdir.path(), "errno", "");
+        package(
+            tempdir.path(),
+            "app",
+            "[dependencies]\nlibc = { path = \"../libc\" }\n\
+             sys_errno = { package = \"errno\", path = \"../errno\" }\n",
+        );
+
+        let metadata = cargo_metadata(&tempdir.path().join("app"));
+        let package = |name: &str| {
+            metadata
+                .packages
+                .iter()
+                .find(|pkg| pkg.name == name)
+                .unwrap()
+        };
+
+        let mut index = Index::new(false, &metadata).unwrap();
+        index
+            .apply_alias_name_template("third-party-rust-{crate}")
+            .unwrap();
+        assert_eq!(
+            index.public_rule_name(package("libc")).0,
+            "third-party-rust-libc",
+        );
+        assert_eq!(
+            index.public_rule_name(package("errno")).0,
+            "third-party-rust-sys_errno",
+        );
+        assert_eq!(index.private_rule_name(package("libc")).0, "libc-1.0.0");
+
+        let apply = |template: &str| {
+            let mut index = Index::new(false, &metadata).unwrap();
+            index
+                .apply_alias_name_template(template)
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(
+            apply("rust-{name}"),
+            "`alias_name_template = \"rust-{name}\"` has an unknown placeholder, expected `{crate}` or `{version}`",
+        );
+        assert_eq!(
+            apply("rust/{crate}"),
+            "`alias_name_template = \"rust/{crate}\"` gives errno-1.0.0 the invalid target name \"rust/sys_errno\"",
+        );
+        assert_eq!(
+            apply("rust-{version}"),
+            "`alias_name_template = \"rust-{version}\"` gives both errno-1.0.0 and libc-1.0.0 the name \"rust-1.0.0\"",
+        );
+        assert!(apply("{crate}-{version}").contains("the same name as the rule for libc-1.0.0"));
+    }
+}
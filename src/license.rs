@@ -0,0 +1,433 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Parsing of the SPDX license expressions found in a package's `license`.
+//!
+//! This is not a complete implementation of the SPDX specification. It checks
+//! the structure of the expression and which identifiers it uses, which is
+//! enough to catch typos and to let config refer to individual licenses.
+
+use std::fmt;
+use std::fmt::Display;
+
+use anyhow::bail;
+
+use crate::cargo::Manifest;
+use crate::config::AuditConfig;
+
+/// License identifiers we recognize. This is the subset of the SPDX license
+/// list which turns up in practice on crates.io, not the whole list.
+const KNOWN_LICENSES: &[&str] = &[
+    "0BSD",
+    "AFL-3.0",
+    "AGPL-3.0",
+    "AGPL-3.0-only",
+    "AGPL-3.0-or-later",
+    "Apache-2.0",
+    "Artistic-2.0",
+    "BlueOak-1.0.0",
+    "BSD-1-Clause",
+    "BSD-2-Clause",
+    "BSD-2-Clause-Patent",
+    "BSD-3-Clause",
+    "BSD-3-Clause-Clear",
+    "BSD-4-Clause",
+    "BSL-1.0",
+    "bzip2-1.0.6",
+    "CC-BY-3.0",
+    "CC-BY-4.0",
+    "CC-BY-SA-4.0",
+    "CC0-1.0",
+    "CDDL-1.0",
+    "CDLA-Permissive-2.0",
+    "EPL-1.0",
+    "EPL-2.0",
+    "EUPL-1.2",
+    "GPL-2.0",
+    "GPL-2.0-only",
+    "GPL-2.0-or-later",
+    "GPL-3.0",
+    "GPL-3.0-only",
+    "GPL-3.0-or-later",
+    "ISC",
+    "LGPL-2.0",
+    "LGPL-2.0-only",
+    "LGPL-2.0-or-later",
+    "LGPL-2.1",
+    "LGPL-2.1-only",
+    "LGPL-2.1-or-later",
+    "LGPL-3.0",
+    "LGPL-3.0-only",
+    "LGPL-3.0-or-later",
+    "MIT",
+    "MIT-0",
+    "MPL-1.1",
+    "MPL-2.0",
+    "MS-PL",
+    "NCSA",
+    "OFL-1.1",
+    "OpenSSL",
+    "PostgreSQL",
+    "Python-2.0",
+    "Unicode-3.0",
+    "Unicode-DFS-2016",
+    "Unlicense",
+    "UPL-1.0",
+    "W3C",
+    "WTFPL",
+    "X11",
+    "Zlib",
+];
+
+/// License exception identifiers we recognize, for use after `WITH`.
+const KNOWN_EXCEPTIONS: &[&str] = &[
+    "Autoconf-exception-3.0",
+    "Bison-exception-2.2",
+    "Classpath-exception-2.0",
+    "GCC-exception-3.1",
+    "LLVM-exception",
+    "OpenSSL-exception",
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LicenseExpr {
+    /// A license identifier, with `+` meaning "or any later version"
+    License {
+        id: String,
+        or_later: bool,
+    },
+    /// `<license> WITH <exception>`
+    With {
+        license: Box<LicenseExpr>,
+        exception: String,
+    },
+    And(Box<LicenseExpr>, Box<LicenseExpr>),
+    Or(Box<LicenseExpr>, Box<LicenseExpr>),
+}
+
+impl LicenseExpr {
+    /// Parse an SPDX license expression. The obsolete `/` separator, which is
+    /// still common in Cargo manifests, is accepted as a synonym for `OR`.
+    pub fn parse(expr: &str) -> Result<Self, String> {
+        let tokens = tokenize(expr);
+        let mut parser = Parser {
+            tokens: &tokens,
+            pos: 0,
+        };
+        let res = parser.or_expr()?;
+        match parser.peek() {
+            None => Ok(res),
+            Some(token) => Err(format!("unexpected `{token}` in license `{expr}`")),
+        }
+    }
+
+    /// All license identifiers used in the expression.
+    pub fn licenses(&self) -> Vec<&str> {
+        let mut res = vec![];
+        self.visit(&mut |expr| {
+            if let LicenseExpr::License { id, .. } = expr {
+                res.push(id.as_str());
+            }
+        });
+        res
+    }
+
+    /// Identifiers, including exceptions, which aren't in our list of known
+    /// SPDX identifiers. `LicenseRef-` and `DocumentRef-` identifiers are
+    /// user-defined and never reported.
+    pub fn unknown_identifiers(&self) -> Vec<&str> {
+        let is_custom = |id: &str| id.starts_with("LicenseRef-") || id.starts_with("DocumentRef-");
+        let mut res = vec![];
+        self.visit(&mut |expr| match expr {
+            LicenseExpr::License { id, .. } => {
+                if !is_custom(id) && !KNOWN_LICENSES.contains(&id.as_str()) {
+                    res.push(id.as_str());
+                }
+            }
+            LicenseExpr::With { exception, .. } => {
+                if !is_custom(exception) && !KNOWN_EXCEPTIONS.contains(&exception.as_str()) {
+                    res.push(exception.as_str());
+                }
+            }
+            LicenseExpr::And(..) | LicenseExpr::Or(..) => {}
+        });
+        res
+    }
+
+    /// Whether the expression can be satisfied using only licenses for which
+    /// `allowed` returns true. Any one alternative of an `OR` will do.
+    pub fn is_satisfied_by(&self, allowed: &impl Fn(&str) -> bool) -> bool {
+        match self {
+            LicenseExpr::License { id, .. } => allowed(id),
+            LicenseExpr::With { license, .. } => license.is_satisfied_by(allowed),
+            LicenseExpr::And(lhs, rhs) => {
+                lhs.is_satisfied_by(allowed) && rhs.is_satisfied_by(allowed)
+            }
+            LicenseExpr::Or(lhs, rhs) => {
+                lhs.is_satisfied_by(allowed) || rhs.is_satisfied_by(allowed)
+            }
+        }
+    }
+
+    fn visit<'a>(&'a self, f: &mut impl FnMut(&'a LicenseExpr)) {
+        f(self);
+        match self {
+            LicenseExpr::License { .. } => {}
+            LicenseExpr::With { license, .. } => license.visit(f),
+            LicenseExpr::And(lhs, rhs) | LicenseExpr::Or(lhs, rhs) => {
+                lhs.visit(f);
+                rhs.visit(f);
+            }
+        }
+    }
+}
+
+impl Display for LicenseExpr {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        // OR binds more loosely than AND, so it's the only case which needs
+        // parentheses.
+        fn and_operand(expr: &LicenseExpr, fmt: &mut fmt::Formatter) -> fmt::Result {
+            match expr {
+                LicenseExpr::Or(..) => write!(fmt, "({expr})"),
+                _ => write!(fmt, "{expr}"),
+            }
+        }
+
+        match self {
+            LicenseExpr::License { id, or_later } => {
+                write!(fmt, "{id}{}", if *or_later { "+" } else { "" })
+            }
+            LicenseExpr::With { license, exception } => {
+                write!(fmt, "{license} WITH {exception}")
+            }
+            LicenseExpr::And(lhs, rhs) => {
+                and_operand(lhs, fmt)?;
+                fmt.write_str(" AND ")?;
+                and_operand(rhs, fmt)
+            }
+            LicenseExpr::Or(lhs, rhs) => write!(fmt, "{lhs} OR {rhs}"),
+        }
+    }
+}
+
+/// Check the declared licenses of `packages` against `[audit] deny_licenses`,
+/// failing with a list of every offending package.
+pub fn check_licenses<'a>(
+    config: &AuditConfig,
+    packages: impl IntoIterator<Item = &'a Manifest>,
+) -> anyhow::Result<()> {
+    if config.deny_licenses.is_empty() {
+        return Ok(());
+    }
+
+    let mut errors = vec![];
+    for pkg in packages {
+        let Some(license) = &pkg.license else {
+            continue;
+        };
+        let exceptions = config.license_exceptions.get(&pkg.name);
+        let allowed = |id: &str| {
+            !config.deny_licenses.contains(id)
+                || exceptions.is_some_and(|exceptions| exceptions.contains(id))
+        };
+        match LicenseExpr::parse(license) {
+            Ok(expr) if expr.is_satisfied_by(&allowed) => {}
+            Ok(_) => errors.push(format!("{pkg} has denied license `{license}`")),
+            Err(err) => errors.push(format!("{pkg} license can't be checked: {err}")),
+        }
+    }
+
+    if !errors.is_empty() {
+        bail!(
+            "Packages failed the license check (see `[audit] deny_licenses`):\n  {}",
+            errors.join("\n  "),
+        );
+    }
+    Ok(())
+}
+
+fn tokenize(expr: &str) -> Vec<&str> {
+    let mut tokens = vec![];
+    let mut start = None;
+    for (i, ch) in expr.char_indices() {
+        if ch.is_whitespace() || matches!(ch, '(' | ')' | '/') {
+            if let Some(start) = start.take() {
+                tokens.push(&expr[start..i]);
+            }
+            if !ch.is_whitespace() {
+                tokens.push(&expr[i..i + 1]);
+            }
+        } else if start.is_none() {
+            start = Some(i);
+        }
+    }
+    if let Some(start) = start {
+        tokens.push(&expr[start..]);
+    }
+    tokens
+}
+
+struct Parser<'a> {
+    tokens: &'a [&'a str],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&'a str> {
+        self.tokens.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Option<&'a str> {
+        let token = self.peek();
+        self.pos += 1;
+        token
+    }
+
+    fn or_expr(&mut self) -> Result<LicenseExpr, String> {
+        let mut lhs = self.and_expr()?;
+        while matches!(self.peek(), Some("OR" | "or" | "/")) {
+            self.next();
+            let rhs = self.and_expr()?;
+            lhs = LicenseExpr::Or(Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn and_expr(&mut self) -> Result<LicenseExpr, String> {
+        let mut lhs = self.with_expr()?;
+        while matches!(self.peek(), Some("AND" | "and")) {
+            self.next();
+            let rhs = self.with_expr()?;
+            lhs = LicenseExpr::And(Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn with_expr(&mut self) -> Result<LicenseExpr, String> {
+        let license = self.primary()?;
+        if !matches!(self.peek(), Some("WITH" | "with")) {
+            return Ok(license);
+        }
+        if !matches!(license, LicenseExpr::License { .. }) {
+            return Err("`WITH` must follow a single license identifier".to_owned());
+        }
+        self.next();
+        match self.next() {
+            Some(exception) if is_idstring(exception) => Ok(LicenseExpr::With {
+                license: Box::new(license),
+                exception: exception.to_owned(),
+            }),
+            Some(token) => Err(format!("expected license exception, found `{token}`")),
+            None => Err("expected license exception after `WITH`".to_owned()),
+        }
+    }
+
+    fn primary(&mut self) -> Result<LicenseExpr, String> {
+        match self.next() {
+            Some("(") => {
+                let expr = self.or_expr()?;
+                match self.next() {
+                    Some(")") => Ok(expr),
+                    Some(token) => Err(format!("expected `)`, found `{token}`")),
+                    None => Err("unbalanced `(`".to_owned()),
+                }
+            }
+            Some(token) => {
+                let (id, or_later) = match token.strip_suffix('+') {
+                    Some(id) => (id, true),
+                    None => (token, false),
+                };
+                if is_idstring(id) && !is_operator(id) {
+                    Ok(LicenseExpr::License {
+                        id: id.to_owned(),
+                        or_later,
+                    })
+                } else {
+                    Err(format!("expected license identifier, found `{token}`"))
+                }
+            }
+            None => Err("expected license identifier".to_owned()),
+        }
+    }
+}
+
+/// SPDX identifiers consist of letters, digits, `-` and `.`.
+fn is_idstring(token: &str) -> bool {
+    !token.is_empty()
+        && token
+            .chars()
+            .all(|ch| ch.is_ascii_alphanumeric() || ch == '-' || ch == '.')
+}
+
+fn is_operator(token: &str) -> bool {
+    matches!(
+        token,
+        "AND" | "and" | "OR" | "or" | "WITH" | "with" | "(" | ")" | "/"
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::LicenseExpr;
+
+    #[test]
+    fn test_dual_license() {
+        let expr = LicenseExpr::parse("MIT OR Apache-2.0").unwrap();
+        assert_eq!(expr.licenses(), vec!["MIT", "Apache-2.0"]);
+        assert!(expr.unknown_identifiers().is_empty());
+        assert_eq!(expr.to_string(), "MIT OR Apache-2.0");
+    }
+
+    #[test]
+    fn test_legacy_slash() {
+        let expr = LicenseExpr::parse("MIT/Apache-2.0").unwrap();
+        assert_eq!(expr.to_string(), "MIT OR Apache-2.0");
+    }
+
+    #[test]
+    fn test_precedence() {
+        let expr = LicenseExpr::parse("(MIT OR Apache-2.0) AND Unicode-DFS-2016").unwrap();
+        assert_eq!(expr.to_string(), "(MIT OR Apache-2.0) AND Unicode-DFS-2016");
+
+        let expr = LicenseExpr::parse("MIT OR Apache-2.0 AND BSD-3-Clause").unwrap();
+        assert_eq!(expr.to_string(), "MIT OR Apache-2.0 AND BSD-3-Clause");
+        assert!(matches!(expr, LicenseExpr::Or(..)));
+    }
+
+    #[test]
+    fn test_with_exception() {
+        let expr = LicenseExpr::parse("Apache-2.0 WITH LLVM-exception OR MIT").unwrap();
+        assert_eq!(expr.licenses(), vec!["Apache-2.0", "MIT"]);
+        assert!(expr.unknown_identifiers().is_empty());
+    }
+
+    #[test]
+    fn test_unknown() {
+        let expr = LicenseExpr::parse("MIT OR Apache2 OR LicenseRef-Custom").unwrap();
+        assert_eq!(expr.unknown_identifiers(), vec!["Apache2"]);
+    }
+
+    #[test]
+    fn test_satisfied_by() {
+        let not_gpl = |id: &str| !id.starts_with("GPL");
+        let expr = LicenseExpr::parse("MIT OR GPL-3.0-only").unwrap();
+        assert!(expr.is_satisfied_by(&not_gpl));
+        let expr = LicenseExpr::parse("MIT AND GPL-3.0-only").unwrap();
+        assert!(!expr.is_satisfied_by(&not_gpl));
+        let expr = LicenseExpr::parse("GPL-2.0-only WITH Classpath-exception-2.0").unwrap();
+        assert!(!expr.is_satisfied_by(&not_gpl));
+    }
+
+    #[test]
+    fn test_malformed() {
+        assert!(LicenseExpr::parse("").is_err());
+        assert!(LicenseExpr::parse("MIT OR").is_err());
+        assert!(LicenseExpr::parse("(MIT").is_err());
+        assert!(LicenseExpr::parse("MIT Apache-2.0").is_err());
+        assert!(LicenseExpr::parse("MIT WITH").is_err());
+    }
+}
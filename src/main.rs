@@ -22,6 +22,7 @@
 
 use std::path::PathBuf;
 
+use anyhow::Context as _;
 use clap::Parser;
 use clap::Subcommand;
 
@@ -35,10 +36,14 @@ mod config;
 mod fixups;
 mod glob;
 mod index;
+mod license;
 mod lockfile;
 mod platform;
 mod remap;
 mod srcfiles;
+mod stats;
+#[cfg(test)]
+mod testing;
 mod universe;
 mod vendor;
 
@@ -57,6 +62,13 @@ pub struct Args {
     /// Path to third-party dir
     #[arg(long, default_value = ".", value_name = "PATH")]
     third_party_dir: PathBuf,
+    /// Path to the config file, instead of `reindeer.toml` in the third-party dir
+    #[arg(long, value_name = "PATH")]
+    config: Option<PathBuf>,
+    /// Don't access the network. Cargo.lock must be up to date, and packages
+    /// and the advisory database already downloaded.
+    #[arg(long)]
+    offline: bool,
     #[command(subcommand)]
     subcommand: SubCommand,
 }
@@ -73,7 +85,7 @@ enum SubCommand {
         /// Show reported security problems for crates as they're being vendored
         #[arg(long)]
         audit_sec: bool,
-        /// Use cached version of the advisory repo
+        /// Use cached versions of the advisory repo and registry index
         #[arg(long)]
         no_fetch: bool,
     },
@@ -84,12 +96,60 @@ enum SubCommand {
         /// Suppresses generation of other output files.
         #[arg(long)]
         stdout: bool,
+        /// Only generate rules for these platforms (comma-separated names
+        /// from the `platform` config).
+        ///
+        /// This is for quick local iteration; the output is not suitable for
+        /// committing.
+        #[arg(long, value_delimiter = ',', value_name = "PLATFORM")]
+        target_platforms: Vec<String>,
+        /// Print to stderr how the platform predicates of this crate's
+        /// dependencies evaluate for each configured platform.
+        #[arg(long, value_name = "CRATE")]
+        explain_platform: Option<String>,
+        /// Generate the rules a second time with differently ordered hash
+        /// maps, and fail if the output differs. For debugging
+        /// nondeterministic output.
+        #[arg(long)]
+        check_determinism: bool,
+        /// Fail if any generated rule refers to a target which is neither
+        /// generated nor matched by `[buck] external_targets`.
+        #[arg(long)]
+        verify: bool,
+        /// Print a summary of the generated rules: how many of each kind,
+        /// crates with platform-specific attributes, and packages with
+        /// fixup files.
+        #[arg(long, conflicts_with = "stdout")]
+        stats: bool,
+        /// Print the `--stats` summary as JSON.
+        #[arg(long, conflicts_with = "stdout")]
+        stats_json: bool,
+        /// Also write every generated rule to this file as JSON, for BXL
+        /// scripts which define the targets themselves: a list of
+        /// `{"rule_type": ..., "attrs": {...}}`.
+        #[arg(long, value_name = "PATH", conflicts_with = "stdout")]
+        rules_json: Option<PathBuf>,
+        /// Warn about fixup files for packages which aren't in the
+        /// dependency graph, and sections of fixup files which can't apply
+        /// to any version of their package or any configured platform.
+        #[arg(long)]
+        report_unused_fixups: bool,
     },
+    /// Check that the generated rules are up to date and complete, for CI.
+    ///
+    /// Generates the rules without writing anything, and fails with a report
+    /// of every generated file which differs from what's committed, every
+    /// dangling target reference, and every build script without a fixup.
+    Check {},
     /// Show security report for vendored crates
     Auditsec {
-        /// Use cached version of the advisory repo
+        /// Use cached versions of the advisory repo and registry index
         #[arg(long, short = 'n')]
         no_fetch: bool,
+        /// Bump yanked packages in Cargo.lock to the nearest compatible
+        /// version which isn't yanked, except those in `never_autofix`
+        #[arg(long)]
+        autofix: bool,
     },
 }
 
@@ -106,7 +166,15 @@ fn try_main() -> anyhow::Result<()> {
     let args = Args::parse();
 
     let third_party_dir = dunce::canonicalize(&args.third_party_dir)?;
-    let mut config = config::read_config(&third_party_dir)?;
+    let mut config = match &args.config {
+        Some(path) => {
+            let path = dunce::canonicalize(path)
+                .with_context(|| format!("Failed to read config {}", path.display()))?;
+            config::read_config_from(&path)?
+        }
+        None => config::read_config(&third_party_dir)?,
+    };
+    config.cargo.offline |= args.offline;
 
     let paths = Paths {
         manifest_path: third_party_dir.join("Cargo.toml"),
@@ -126,8 +194,8 @@ fn try_main() -> anyhow::Result<()> {
             vendor::cargo_vendor(&config, *no_delete, *audit_sec, *no_fetch, &args, &paths)?;
         }
 
-        SubCommand::Auditsec { no_fetch } => {
-            audit_sec::audit_sec(&paths, *no_fetch)?;
+        SubCommand::Auditsec { no_fetch, autofix } => {
+            audit_sec::audit_sec(&config, &args, &paths, *no_fetch, *autofix)?;
         }
 
         SubCommand::Update { .. } => {
@@ -144,13 +212,53 @@ fn try_main() -> anyhow::Result<()> {
             )?;
         }
 
-        SubCommand::Buckify { stdout } => {
+        SubCommand::Check {} => {
+            if config.vendor.is_some() && !vendor::is_vendored(&paths)? {
+                config.vendor = None;
+            }
+            config.collect_unresolved_fixups = true;
+            buckify::check(&config, &args, &paths)?;
+        }
+
+        SubCommand::Buckify {
+            stdout,
+            target_platforms,
+            explain_platform,
+            check_determinism,
+            verify,
+            stats,
+            stats_json,
+            rules_json,
+            report_unused_fixups,
+        } => {
             if config.vendor.is_some() && !vendor::is_vendored(&paths)? {
                 // If you ran `reindeer buckify` without `reindeer vendor`, then
                 // default to generating non-vendored targets.
                 config.vendor = None;
             }
-            buckify::buckify(&config, &args, &paths, *stdout)?;
+            config.report_unused_fixups = *report_unused_fixups;
+            config.rules_json = rules_json.clone();
+            if !target_platforms.is_empty() {
+                config.restrict_platforms(target_platforms)?;
+                log::warn!(
+                    "Only generating rules for platforms {}; the output is not suitable for committing",
+                    target_platforms.join(", "),
+                );
+            }
+            let rule_stats = buckify::buckify(
+                &config,
+                &args,
+                &paths,
+                *stdout,
+                explain_platform.as_deref(),
+                *check_determinism,
+                *verify,
+            )?;
+            if *stats_json {
+                println!("{}", serde_json::to_string_pretty(&rule_stats)?);
+            } else if *stats {
+                print!("{rule_stats}");
+            }
         }
     }
 
@@ -5,14 +5,17 @@
  * LICENSE file in the root directory of this source tree.
  */
 
+use std::collections::BTreeSet;
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::error;
 use std::fmt;
 use std::fmt::Display;
+use std::sync::Mutex;
 
 use nom::error::convert_error;
 use nom::error::VerboseError;
+use nom::error::VerboseErrorKind;
 use serde::Deserialize;
 use serde::Serialize;
 
@@ -23,7 +26,93 @@ use crate::config::Config;
 /// platform attributes which are true for this platform. A non-present attribute means
 /// "doesn't matter" or "all possible values".
 #[derive(Debug, Clone, Default, Deserialize)]
-pub struct PlatformConfig(HashMap<String, HashSet<String>>);
+pub struct PlatformConfig {
+    /// The platform builds with a nightly toolchain, so fixups may give its
+    /// crates unstable `-Z` rustc flags.
+    #[serde(default)]
+    pub nightly: bool,
+    #[serde(flatten)]
+    cfgs: HashMap<String, HashSet<String>>,
+}
+
+/// Cfg names rustc sets itself. Keys of a `PlatformConfig` are expected to be
+/// one of these, or a target triple (which has hyphens) for a boolean test.
+const KNOWN_CFG_KEYS: &[&str] = &[
+    "debug_assertions",
+    "overflow_checks",
+    "panic",
+    "proc_macro",
+    "relocation_model",
+    "target_abi",
+    "target_arch",
+    "target_endian",
+    "target_env",
+    "target_family",
+    "target_feature",
+    "target_has_atomic",
+    "target_has_atomic_equal_alignment",
+    "target_has_atomic_load_store",
+    "target_os",
+    "target_pointer_width",
+    "target_thread_local",
+    "target_vendor",
+    "unix",
+    "windows",
+];
+
+impl PlatformConfig {
+    /// Copy with the sets rebuilt, so they iterate in a different order.
+    pub fn rehash(&self) -> PlatformConfig {
+        PlatformConfig {
+            nightly: self.nightly,
+            cfgs: self
+                .cfgs
+                .iter()
+                .map(|(key, values)| (key.clone(), values.iter().cloned().collect()))
+                .collect(),
+        }
+    }
+
+    /// The platform's `target_os`, if it has exactly one.
+    pub fn target_os(&self) -> Option<&str> {
+        match self.cfgs.get("target_os") {
+            Some(values) if values.len() == 1 => values.iter().next().map(String::as_str),
+            _ => None,
+        }
+    }
+
+    /// The platform's target triple: its one boolean key with hyphens, if it
+    /// has exactly one.
+    pub fn target_triple(&self) -> Option<&str> {
+        let mut triples = self.cfgs.keys().filter(|key| key.contains('-'));
+        match (triples.next(), triples.next()) {
+            (Some(triple), None) => Some(triple),
+            _ => None,
+        }
+    }
+
+    /// Keys which are neither a cfg rustc knows about nor a target triple,
+    /// each with the known key it's probably a misspelling of, if any.
+    pub fn unrecognized_keys(&self) -> Vec<(&str, Option<&'static str>)> {
+        let mut unrecognized: Vec<_> = self
+            .cfgs
+            .keys()
+            .map(String::as_str)
+            .filter(|key| !key.contains('-') && !KNOWN_CFG_KEYS.contains(key))
+            .map(|key| {
+                let suggestion = KNOWN_CFG_KEYS
+                    .iter()
+                    .map(|known| (strsim::levenshtein(key, known), *known))
+                    .filter(|(distance, known)| *distance <= 2.max(known.len() / 3))
+                    .min()
+                    .map(|(_distance, known)| known);
+                (key, suggestion)
+            })
+            .collect();
+        unrecognized.sort();
+        unrecognized
+    }
+}
 
 pub fn platform_names_for_expr<'config>(
     config: &'config Config,
@@ -95,6 +184,10 @@ pub enum PlatformPredicate<'a> {
     // Helpers
     Unix,
     Windows,
+
+    // Literals
+    True,
+    False,
 }
 
 #[derive(Debug, Clone)]
@@ -102,6 +195,8 @@ pub enum PredicateParseError {
     TrailingJunk(String),
     Incomplete,
     ParseError(String),
+    /// Unbalanced parentheses, with the input left where parsing stopped
+    MalformedNesting(String),
 }
 
 impl Display for PredicateParseError {
@@ -110,6 +205,12 @@ impl Display for PredicateParseError {
             PredicateParseError::TrailingJunk(junk) => write!(fmt, "trailing junk: {}", junk),
             PredicateParseError::ParseError(msg) => write!(fmt, "parse error: {}", msg),
             PredicateParseError::Incomplete => write!(fmt, "incomplete input"),
+            PredicateParseError::MalformedNesting(rest) if rest.is_empty() => {
+                write!(fmt, "unbalanced parentheses: unexpected end of input")
+            }
+            PredicateParseError::MalformedNesting(rest) => {
+                write!(fmt, "unbalanced parentheses: unparsed `{}`", rest)
+            }
         }
     }
 }
@@ -118,13 +219,34 @@ impl error::Error for PredicateParseError {}
 
 impl<'a> PlatformPredicate<'a> {
     pub fn parse(input: &'a PlatformExpr) -> Result<PlatformPredicate<'a>, PredicateParseError> {
+        let balanced = parens_balanced(&input.0);
         match cfg::parse::<VerboseError<&str>>(&input.0) {
             Ok(("", pred)) => Ok(pred),
+            Ok((rest, _)) if !balanced => Err(PredicateParseError::MalformedNesting(
+                rest.trim().to_string(),
+            )),
             Ok((rest, _)) => Err(PredicateParseError::TrailingJunk(rest.to_string())),
             Err(nom::Err::Incomplete(_)) => Err(PredicateParseError::Incomplete),
-            Err(nom::Err::Error(err)) | Err(nom::Err::Failure(err)) => Err(
-                PredicateParseError::ParseError(convert_error(input.0.as_str(), err)),
-            ),
+            Err(nom::Err::Error(err)) | Err(nom::Err::Failure(err)) => {
+                // Where a `)` was missing, or else the first error, which is
+                // the innermost, where parsing gave up. Parentheses which
+                // balance are some other mistake, like a missing comma.
+                let unclosed = err
+                    .errors
+                    .iter()
+                    .find(|(_, kind)| *kind == VerboseErrorKind::Char(')'))
+                    .or(err.errors.first())
+                    .filter(|_| !balanced);
+                match unclosed {
+                    Some((rest, _)) => Err(PredicateParseError::MalformedNesting(
+                        rest.trim().to_string(),
+                    )),
+                    None => Err(PredicateParseError::ParseError(convert_error(
+                        input.0.as_str(),
+                        err,
+                    ))),
+                }
+            }
         }
     }
 
@@ -132,27 +254,83 @@ impl<'a> PlatformPredicate<'a> {
         use PlatformPredicate::*;
 
         match self {
-            Bool { key } => config.0.contains_key(*key),
+            Bool { key } => config.cfgs.contains_key(*key),
             Value { key: "feature", .. } => {
                 // [target.'cfg(feature = "...")'.dependencies] never get applied by Cargo
                 false
             }
-            Value { key, value } => config.0.get(*key).map_or(false, |set| set.contains(*value)),
+            Value { key, value } => match config.cfgs.get(*key) {
+                Some(set) => set.contains(*value),
+                None => {
+                    warn_unconfigured(key);
+                    false
+                }
+            },
             Not(pred) => !pred.eval(config),
             Any(preds) => preds.iter().any(|pred| pred.eval(config)),
             All(preds) => preds.iter().all(|pred| pred.eval(config)),
-            Unix => PlatformPredicate::Value {
-                key: "target_family",
-                value: "unix",
+            // Shorthands for `target_family`, which a platform can also list
+            // as boolean keys.
+            Unix => {
+                config.cfgs.contains_key("unix")
+                    || PlatformPredicate::Value {
+                        key: "target_family",
+                        value: "unix",
+                    }
+                    .eval(config)
             }
-            .eval(config),
-            Windows => PlatformPredicate::Value {
-                key: "target_family",
-                value: "windows",
+            Windows => {
+                config.cfgs.contains_key("windows")
+                    || PlatformPredicate::Value {
+                        key: "target_family",
+                        value: "windows",
+                    }
+                    .eval(config)
+            }
+            True => true,
+            False => false,
+        }
+    }
+}
+
+/// Whether every `(` outside of string literals has a matching `)`.
+fn parens_balanced(input: &str) -> bool {
+    let mut depth = 0usize;
+    let mut chars = input.chars();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '(' => depth += 1,
+            ')' => match depth.checked_sub(1) {
+                Some(outer) => depth = outer,
+                None => return false,
+            },
+            '"' => {
+                while let Some(ch) = chars.next() {
+                    match ch {
+                        '\\' => {
+                            chars.next();
+                        }
+                        '"' => break,
+                        _ => {}
+                    }
+                }
             }
-            .eval(config),
+            _ => {}
         }
     }
+    depth == 0
+}
+
+/// Warn, once per key, about evaluating a `key = "value"` predicate against a
+/// platform whose config doesn't list `key`.
+fn warn_unconfigured(key: &str) {
+    static WARNED: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+
+    if WARNED.lock().unwrap().insert(key.to_owned()) {
+        log::warn!(
+            "Treating `{key} = \"...\"` predicates as false for platforms which don't configure `{key}`"
+        );
+    }
 }
 
 impl<'a> Display for PlatformPredicate<'a> {
@@ -167,6 +345,245 @@ impl<'a> Display for PlatformPredicate<'a> {
             Not(pred) => write!(fmt, "not({})", pred),
             Unix => fmt.write_str("unix"),
             Windows => fmt.write_str("windows"),
+            True => fmt.write_str("true"),
+            False => fmt.write_str("false"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::collections::HashSet;
+
+    use super::platform_names_for_expr;
+    use super::PlatformConfig;
+    use super::PlatformExpr;
+    use super::PlatformPredicate;
+    use super::PredicateParseError;
+    use crate::buck::RuleRef;
+    use crate::config::Config;
+
+    fn eval(expr: &str, config: &PlatformConfig) -> bool {
+        let expr = PlatformExpr::from(expr.to_owned());
+        PlatformPredicate::parse(&expr).unwrap().eval(config)
+    }
+
+    #[test]
+    fn test_eval_less_common_keys() {
+        let config = PlatformConfig {
+            cfgs: HashMap::from([
+                (
+                    "target_has_atomic".to_owned(),
+                    HashSet::from(["64".to_owned(), "ptr".to_owned()]),
+                ),
+                ("panic".to_owned(), HashSet::from(["unwind".to_owned()])),
+            ]),
+            ..Default::default()
+        };
+        assert!(eval("cfg(target_has_atomic = \"ptr\")", &config));
+        assert!(!eval("cfg(target_has_atomic = \"128\")", &config));
+        assert!(eval("cfg(panic = \"unwind\")", &config));
+        assert!(eval("cfg(not(panic = \"abort\"))", &config));
+        assert!(eval("cfg(true)", &config));
+        assert!(!eval("cfg(false)", &config));
+        // Not configured at all
+        assert!(!eval("cfg(target_abi = \"eabihf\")", &config));
+        assert!(eval("cfg(not(target_abi = \"eabihf\"))", &config));
+    }
+
+    fn parse_error(expr: &str) -> PredicateParseError {
+        PlatformPredicate::parse(&PlatformExpr::from(expr.to_owned())).unwrap_err()
+    }
+
+    #[test]
+    fn test_malformed_nesting() {
+        assert_eq!(
+            parse_error("cfg(all(any(unix, windows), not(target_os = \"ios\"))").to_string(),
+            "unbalanced parentheses: unexpected end of input",
+        );
+        assert_eq!(
+            parse_error("cfg(all(any(unix, windows))), not(target_os = \"ios\"))").to_string(),
+            "unbalanced parentheses: unparsed `, not(target_os = \"ios\"))`",
+        );
+        assert_eq!(
+            parse_error("cfg(any(unix, target_os = \"(\")").to_string(),
+            "unbalanced parentheses: unexpected end of input",
+        );
+        assert!(matches!(
+            parse_error("cfg(unix) junk"),
+            PredicateParseError::TrailingJunk(_),
+        ));
+        // Balanced, but `cfg()` only takes one predicate.
+        assert!(matches!(
+            parse_error("cfg(all(any(unix, windows)), not(target_os = \"ios\"))"),
+            PredicateParseError::ParseError(_),
+        ));
+        assert!(matches!(
+            parse_error("cfg(all(unix windows))"),
+            PredicateParseError::ParseError(_),
+        ));
+    }
+
+    #[test]
+    fn test_eval_nested() {
+        // From getrandom 0.3's Cargo.toml
+        let linux = PlatformConfig {
+            cfgs: HashMap::from([("target_os".to_owned(), HashSet::from(["linux".to_owned()]))]),
+            ..Default::default()
+        };
+        let expr = "cfg(all(any(target_os = \"linux\", target_os = \"android\"), \
+                    not(any(getrandom_backend = \"custom\", getrandom_backend = \"rdrand\"))))";
+        assert!(eval(expr, &linux));
+        let custom = PlatformConfig {
+            cfgs: HashMap::from([
+                (
+                    "target_os".to_owned(),
+                    HashSet::from(["android".to_owned()]),
+                ),
+                (
+                    "getrandom_backend".to_owned(),
+                    HashSet::from(["custom".to_owned()]),
+                ),
+            ]),
+            ..Default::default()
+        };
+        assert!(!eval(expr, &custom));
+    }
+
+    #[test]
+    fn test_custom_embedded_platform() {
+        let config: Config = toml::from_str(
+            r#"
+            [platform.my-embedded]
+            thumbv7em-none-eabihf = []
+            panic = ["abort"]
+            target_abi = ["eabihf"]
+            target_arch = ["arm"]
+            target_endian = ["little"]
+            target_env = []
+            target_has_atomic = ["8", "16", "32", "ptr"]
+            target_os = ["none"]
+            target_pointer_width = ["32"]
+            target_vender = ["unknown"]
+            "#,
+        )
+        .unwrap();
+        let (name, platform) = config.platform.iter().next().unwrap();
+        assert_eq!(name.to_string(), "my-embedded");
+        assert_eq!(
+            platform.unrecognized_keys(),
+            [("target_vender", Some("target_vendor"))],
+        );
+
+        let dep = |expr: &str| {
+            RuleRef::new(":dep".to_owned())
+                .with_platform(Some(&PlatformExpr::from(expr.to_owned())))
+        };
+        assert!(
+            dep("cfg(all(target_arch = \"arm\", target_os = \"none\"))")
+                .filter(platform)
+                .unwrap()
+        );
+        assert!(dep("thumbv7em-none-eabihf").filter(platform).unwrap());
+        assert!(
+            !dep("cfg(target_has_atomic = \"64\")")
+                .filter(platform)
+                .unwrap()
+        );
+        assert!(!dep("cfg(unix)").filter(platform).unwrap());
+        let expr = PlatformExpr::from("cfg(panic = \"abort\")".to_owned());
+        assert_eq!(platform_names_for_expr(&config, &expr).unwrap(), [name]);
+    }
+
+    #[test]
+    fn test_target_family() {
+        let config: Config = toml::from_str(
+            r#"
+            [platform.linux]
+            target_family = ["unix"]
+            target_os = ["linux"]
+
+            [platform.emscripten]
+            target_family = ["unix", "wasm"]
+            target_os = ["emscripten"]
+
+            [platform.wasi]
+            target_family = ["wasm"]
+            target_os = ["wasi"]
+
+            [platform.windows]
+            target_family = ["windows"]
+            target_os = ["windows"]
+
+            [platform.sgx]
+            target_family = []
+            target_os = ["unknown"]
+
+            [platform.legacy]
+            unix = []
+            "#,
+        )
+        .unwrap();
+        let names = |expr: &str| {
+            let expr = PlatformExpr::from(expr.to_owned());
+            let mut names: Vec<_> = platform_names_for_expr(&config, &expr)
+                .unwrap()
+                .into_iter()
+                .map(|name| name.to_string())
+                .collect();
+            names.sort();
+            names
+        };
+        assert_eq!(names("cfg(unix)"), ["emscripten", "legacy", "linux"]);
+        assert_eq!(
+            names("cfg(target_family = \"unix\")"),
+            ["emscripten", "linux"]
+        );
+        assert_eq!(
+            names("cfg(target_family = \"wasm\")"),
+            ["emscripten", "wasi"]
+        );
+        assert_eq!(names("cfg(windows)"), ["windows"]);
+        assert_eq!(names("cfg(not(any(unix, windows)))"), ["sgx", "wasi"]);
+
+        let defaults: Config = toml::from_str("").unwrap();
+        let expr = PlatformExpr::from("cfg(unix)".to_owned());
+        let mut unix: Vec<_> = platform_names_for_expr(&defaults, &expr)
+            .unwrap()
+            .into_iter()
+            .map(|name| name.to_string())
+            .collect();
+        unix.sort();
+        assert_eq!(
+            unix,
+            ["linux-arm64", "linux-x86_64", "macos-arm64", "macos-x86_64"],
+        );
+    }
+
+    #[test]
+    fn platform_order_is_deterministic() {
+        let names = |config: &Config| {
+            let expr = PlatformExpr::from("cfg(unix)".to_owned());
+            platform_names_for_expr(config, &expr)
+                .unwrap()
+                .into_iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+        };
+
+        // Default platforms are declared linux, macos, windows, with x86_64
+        // before arm64 in each.
+        let config: Config = toml::from_str("").unwrap();
+        let unix = names(&config);
+        assert_eq!(
+            unix,
+            ["linux-arm64", "linux-x86_64", "macos-arm64", "macos-x86_64"],
+        );
+        for _ in 0..10 {
+            let config: Config = toml::from_str("").unwrap();
+            assert_eq!(names(&config), unix);
+            assert_eq!(names(&config.rehash()), unix);
         }
     }
 }
@@ -13,8 +13,10 @@ use serde::Serialize;
 
 #[derive(Serialize, Deserialize)]
 pub struct RemapConfig {
-    #[serde(rename = "source", default)]
+    #[serde(rename = "source", default, skip_serializing_if = "Map::is_empty")]
     pub sources: Map<String, RemapSource>,
+    #[serde(default, skip_serializing_if = "Map::is_empty")]
+    pub env: Map<String, CargoEnvValue>,
 }
 
 #[derive(Serialize, Deserialize, Default)]
@@ -27,3 +29,38 @@ pub struct RemapSource {
     #[serde(rename = "replace-with")]
     pub replace_with: Option<String>,
 }
+
+/// An entry in Cargo's `[env]` table, either `NAME = "value"` or
+/// `NAME = { value = "...", force = true, relative = true }`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum CargoEnvValue {
+    Value(String),
+    Table {
+        value: String,
+        #[serde(default)]
+        force: bool,
+        #[serde(default)]
+        relative: bool,
+    },
+}
+
+impl CargoEnvValue {
+    pub fn value(&self) -> &str {
+        match self {
+            CargoEnvValue::Value(value) | CargoEnvValue::Table { value, .. } => value,
+        }
+    }
+
+    /// Whether this overrides an environment variable that is already set
+    /// (for us: one set by a fixup).
+    pub fn force(&self) -> bool {
+        matches!(self, CargoEnvValue::Table { force: true, .. })
+    }
+
+    /// Whether the value is a path relative to the directory containing the
+    /// `.cargo` directory.
+    pub fn relative(&self) -> bool {
+        matches!(self, CargoEnvValue::Table { relative: true, .. })
+    }
+}
@@ -0,0 +1,218 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Summary of the generated rules, for `reindeer buckify --stats`.
+
+use std::fmt;
+
+use serde::Serialize;
+
+use crate::buck::Rule;
+use crate::buck::RustBinary;
+use crate::buck::RustLibrary;
+
+/// Counts of the rules buckify generated.
+#[derive(Debug, Default, PartialEq, Eq, Serialize)]
+pub struct RuleStats {
+    pub rust_library: usize,
+    pub rust_binary: usize,
+    pub cxx_library: usize,
+    pub prebuilt_cxx_library: usize,
+    /// Build script binaries, the genrules which run them, and ones copying
+    /// files out of their output
+    pub buildscript: usize,
+    pub alias: usize,
+    /// Rust libraries and binaries, not counting build scripts
+    pub crates: usize,
+    /// Crates with any platform-specific attributes
+    pub platform_specific: usize,
+    /// Packages with fixup files, filled in by buckify since the rules alone
+    /// don't say
+    pub need_fixups: usize,
+}
+
+impl RuleStats {
+    pub fn tally<'a>(rules: impl IntoIterator<Item = &'a Rule>) -> Self {
+        let mut stats = RuleStats::default();
+        for rule in rules {
+            let common = match rule {
+                Rule::Library(RustLibrary { common, .. }) => {
+                    stats.rust_library += 1;
+                    Some(common)
+                }
+                Rule::Binary(RustBinary { common }) => {
+                    stats.rust_binary += 1;
+                    Some(common)
+                }
+                Rule::BuildscriptBinary(_) => {
+                    stats.buildscript += 1;
+                    None
+                }
+                Rule::BuildscriptGenrule(_) => {
+                    stats.buildscript += 1;
+                    None
+                }
+                Rule::OutDirFile(_) => {
+                    stats.buildscript += 1;
+                    None
+                }
+                Rule::CxxLibrary(_) => {
+                    stats.cxx_library += 1;
+                    None
+                }
+                Rule::PrebuiltCxxLibrary(_) => {
+                    stats.prebuilt_cxx_library += 1;
+                    None
+                }
+                Rule::Alias(_) => {
+                    stats.alias += 1;
+                    None
+                }
+                Rule::Filegroup(_)
+                | Rule::HttpArchive(_)
+                | Rule::GitFetch(_)
+                | Rule::RootPackage(_) => None,
+            };
+            if let Some(common) = common {
+                stats.crates += 1;
+                if !common.platform.is_empty() {
+                    stats.platform_specific += 1;
+                }
+            }
+        }
+
+        stats
+    }
+}
+
+impl fmt::Display for RuleStats {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let RuleStats {
+            rust_library,
+            rust_binary,
+            cxx_library,
+            prebuilt_cxx_library,
+            buildscript,
+            alias,
+            crates,
+            platform_specific,
+            need_fixups,
+        } = self;
+        writeln!(fmt, "rust_library:          {rust_library}")?;
+        writeln!(fmt, "rust_binary:           {rust_binary}")?;
+        writeln!(fmt, "cxx_library:           {cxx_library}")?;
+        writeln!(fmt, "prebuilt_cxx_library:  {prebuilt_cxx_library}")?;
+        writeln!(fmt, "build script rules:    {buildscript}")?;
+        writeln!(fmt, "aliases:               {alias}")?;
+        writeln!(fmt, "crates:                {crates}")?;
+        writeln!(fmt, "  platform-specific:   {platform_specific}")?;
+        writeln!(fmt, "packages with fixups:  {need_fixups}")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use super::RuleStats;
+    use crate::buck::Alias;
+    use crate::buck::BuckPath;
+    use crate::buck::BuildscriptGenrule;
+    use crate::buck::Common;
+    use crate::buck::Name;
+    use crate::buck::PlatformRustCommon;
+    use crate::buck::Rule;
+    use crate::buck::RustBinary;
+    use crate::buck::RustCommon;
+    use crate::buck::RustLibrary;
+    use crate::buck::Visibility;
+    use crate::cargo::Edition;
+    use crate::platform::PlatformName;
+
+    fn rust_common(name: &str, crate_root: &str) -> RustCommon {
+        RustCommon {
+            common: Common {
+                name: Name(name.to_owned()),
+                visibility: Visibility::Private,
+                licenses: Default::default(),
+                license_expression: None,
+                compatible_with: vec![],
+                target_compatible_with: vec![],
+            },
+            krate: name.split('-').next().unwrap().to_owned(),
+            crate_root: BuckPath(PathBuf::from(crate_root)),
+            edition: Edition::Rust2021,
+            base: Default::default(),
+            platform: Default::default(),
+        }
+    }
+
+    fn library(name: &str) -> RustLibrary {
+        RustLibrary {
+            common: rust_common(name, &format!("vendor/{name}/src/lib.rs")),
+            proc_macro: false,
+            dlopen_enable: false,
+            python_ext: None,
+            linkable_alias: None,
+            crate_dynamic: None,
+            metadata: None,
+        }
+    }
+
+    #[test]
+    fn tally_rules() {
+        let mut foo = library("foo-1.0.0");
+        foo.common.platform.insert(
+            serde_json::from_value::<PlatformName>(serde_json::json!("linux-x86_64")).unwrap(),
+            PlatformRustCommon::default(),
+        );
+        let rules = [
+            Rule::Alias(Alias {
+                name: Name("foo".to_owned()),
+                actual: Name("foo-1.0.0".to_owned()),
+                fallback_actual: None,
+                visibility: Visibility::Public,
+            }),
+            Rule::Library(foo),
+            Rule::Library(library("foo-sys-1.0.0")),
+            Rule::Binary(RustBinary {
+                common: rust_common("foo-1.0.0-cli", "vendor/foo-1.0.0/src/main.rs"),
+            }),
+            Rule::BuildscriptBinary(RustBinary {
+                common: rust_common(
+                    "foo-sys-1.0.0-build-script-build",
+                    "vendor/foo-sys-1.0.0/build.rs",
+                ),
+            }),
+            Rule::BuildscriptGenrule(BuildscriptGenrule {
+                name: Name("foo-sys-1.0.0-build-script-run".to_owned()),
+                buildscript_rule: Name("foo-sys-1.0.0-build-script-build".to_owned()),
+                package_name: "foo-sys".to_owned(),
+                version: "1.0.0".parse().unwrap(),
+                features: Default::default(),
+                env: Default::default(),
+                path_env: Default::default(),
+                local_only: false,
+            }),
+        ];
+
+        assert_eq!(
+            RuleStats::tally(&rules),
+            RuleStats {
+                rust_library: 2,
+                rust_binary: 1,
+                cxx_library: 0,
+                prebuilt_cxx_library: 0,
+                buildscript: 2,
+                alias: 1,
+                crates: 3,
+                platform_specific: 1,
+                need_fixups: 0,
+            },
+        );
+    }
+}
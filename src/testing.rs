@@ -0,0 +1,72 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Fixtures shared by unit tests which need real `cargo metadata` output.
+
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+use crate::cargo::parse_tree_features;
+use crate::cargo::Metadata;
+use crate::cargo::TreeFeatures;
+use crate::cargo::TREE_FEATURES_FLAGS;
+use crate::Paths;
+
+/// Write a file, creating its parent directories.
+pub fn write(path: &Path, content: &str) {
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(path, content).unwrap();
+}
+
+/// Write a library package version 1.0.0 to `dir/name`, with `extra` appended
+/// to its Cargo.toml, such as a `[dependencies]` table.
+pub fn package(dir: &Path, name: &str, extra: &str) {
+    let dir = dir.join(name);
+    write(
+        &dir.join("Cargo.toml"),
+        &format!(
+            "[package]\nname = \"{name}\"\nversion = \"1.0.0\"\nedition = \"2021\"\n\n{extra}"
+        ),
+    );
+    write(&dir.join("src/lib.rs"), "");
+}
+
+/// Run an offline Cargo command on the package in `dir`, returning stdout.
+pub fn cargo(dir: &Path, args: &[&str]) -> Vec<u8> {
+    let output = Command::new(env::var_os("CARGO").unwrap_or_else(|| "cargo".into()))
+        .current_dir(dir)
+        .args(args)
+        .arg("--offline")
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{output:?}");
+    output.stdout
+}
+
+/// Run `cargo metadata --offline` on the package in `dir`.
+pub fn cargo_metadata(dir: &Path) -> Metadata {
+    serde_json::from_slice(&cargo(dir, &["metadata", "--format-version=1"])).unwrap()
+}
+
+/// Features of each package in `dir`'s dependency graph without
+/// dev-dependencies, as buckify gets them under resolver 2.
+pub fn cargo_normal_features(dir: &Path) -> TreeFeatures {
+    let output = cargo(dir, TREE_FEATURES_FLAGS);
+    parse_tree_features(&String::from_utf8(output).unwrap()).unwrap()
+}
+
+/// Paths for a third-party dir holding its Cargo.toml.
+pub fn paths(third_party_dir: &Path) -> Paths {
+    Paths {
+        third_party_dir: third_party_dir.to_owned(),
+        manifest_path: third_party_dir.join("Cargo.toml"),
+        lockfile_path: third_party_dir.join("Cargo.lock"),
+        cargo_home: third_party_dir.join(".cargo"),
+    }
+}
@@ -102,7 +102,15 @@ pub fn merge_universes(
         old.named_deps.merge(new.named_deps);
         old.env.merge(new.env);
     }
+    /// A crate reachable on different platforms in different universes is
+    /// left ungated, rather than skipped where some universe needs it.
+    fn merge_gates(old: &mut buck::Common, new: &buck::Common) {
+        if old.target_compatible_with != new.target_compatible_with {
+            old.target_compatible_with.clear();
+        }
+    }
     fn merge_library(old: &mut RustLibrary, new: RustLibrary) {
+        merge_gates(&mut old.common.common, &new.common.common);
         // TODO: merge platform maps instead of unwrapping (I think platform
         // keys can differ? They certainly will if we add per-universe fixups)
         for (platform, select) in new.common.platform {
@@ -111,6 +119,7 @@ pub fn merge_universes(
         merge_selects(&mut old.common.base, new.common.base);
     }
     fn merge_binary(old: &mut RustBinary, new: RustBinary) {
+        merge_gates(&mut old.common.common, &new.common.common);
         // TODO: merge platform maps
         for (platform, select) in new.common.platform {
             merge_selects(old.common.platform.get_mut(&platform).unwrap(), select);
@@ -119,6 +128,7 @@ pub fn merge_universes(
     }
     fn merge_genrule(old: &mut BuildscriptGenrule, new: BuildscriptGenrule) {
         old.features.merge(new.features);
+        old.local_only |= new.local_only;
     }
 
     /// Simplify select maps to values when all universes have the same value.
@@ -204,7 +214,15 @@ pub fn merge_universes(
                         let Rule::Alias(new) = rule else {
                             panic!("expected alias")
                         };
-                        if *old != new {
+                        // Like the target it refers to, an alias is only
+                        // gated if it's gated the same in every universe.
+                        if old.fallback_actual != new.fallback_actual {
+                            old.fallback_actual = None;
+                        }
+                        if old.name != new.name
+                            || old.actual != new.actual
+                            || old.visibility != new.visibility
+                        {
                             panic!("expected alias rules to be identical in every universe")
                         }
                     }
@@ -5,27 +5,46 @@
  * LICENSE file in the root directory of this source tree.
  */
 
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::collections::HashSet;
 use std::fs;
 use std::io::ErrorKind;
 use std::path::Path;
+use std::path::PathBuf;
 
 use anyhow::Context;
 use cargo_toml::OptionalFile;
 use globset::GlobBuilder;
 use globset::GlobSetBuilder;
+use ignore::gitignore::Gitignore;
 use ignore::gitignore::GitignoreBuilder;
+use ignore::gitignore::Glob;
+use ignore::Match;
 use indexmap::IndexMap;
 use serde::Deserialize;
 use serde::Serialize;
+use walkdir::WalkDir;
 
+use crate::buck::Rule;
+use crate::buck::RustBinary;
+use crate::buck::RustLibrary;
+use crate::buckify::normalize_path;
 use crate::buckify::relative_path;
 use crate::cargo;
 use crate::config::Config;
 use crate::config::VendorConfig;
+use crate::fixups::fixup_glob_dirs;
+use crate::fixups::has_fixup_files;
+use crate::glob::Globs;
+use crate::glob::NO_EXCLUDE;
 use crate::remap::RemapConfig;
 use crate::Args;
 use crate::Paths;
 
+/// Name of the source which `registry_url` replaces crates.io with.
+const MIRROR_SOURCE: &str = "reindeer-mirror";
+
 #[derive(Debug, Deserialize, Serialize)]
 struct CargoChecksums {
     files: IndexMap<String, String>,
@@ -52,36 +71,113 @@ pub(crate) fn cargo_vendor(
     if no_delete {
         cmdline.push("--no-delete");
     }
+    cmdline.extend(cargo::offline_flags(config, paths)?);
+
+    let registry_url = registry_url(config);
+    let mirror_config = mirror_config(config);
+    if !mirror_config.is_empty() {
+        // `cargo vendor` drops all source config, even from `--config`,
+        // unless told otherwise.
+        cmdline.push("--respect-source-config");
+    }
+    for config in &mirror_config {
+        cmdline.extend(["--config", config]);
+    }
 
     fs::create_dir_all(&paths.cargo_home)?;
 
+    // Pruned crates are vendored afresh even with `minimal_sources` on, since
+    // the rules or fixups may have changed to need files which were deleted.
+    remove_pruned_crates(&paths.third_party_dir.join(vendordir))?;
+
+    // `cargo vendor` only prints the source replacement config, so carry over
+    // any `[env]` table from the existing config when we overwrite it.
+    let existing_env = match read_cargo_config(paths)? {
+        Some(remap_config) => remap_config.env,
+        None => Default::default(),
+    };
+
     log::info!("Running cargo {:?}", cmdline);
-    let cargoconfig = cargo::run_cargo(
+    let mut cargoconfig = cargo::run_cargo(
         config,
         Some(&paths.cargo_home),
         &paths.third_party_dir,
         args,
         &cmdline,
-    )?;
+    )
+    .map_err(|err| match registry_url {
+        Some(registry_url) if err.to_string().contains("checksum") => err.context(format!(
+            "Checksum mismatch vendoring from registry mirror {registry_url}: \
+             it must serve the same crates as crates.io, whose checksums are in Cargo.lock",
+        )),
+        _ => err,
+    })?;
+
+    let vendored = !cargoconfig.is_empty();
+    if !existing_env.is_empty() {
+        let env_config = RemapConfig {
+            sources: Default::default(),
+            env: existing_env,
+        };
+        cargoconfig.push(b'\n');
+        cargoconfig.extend_from_slice(toml::to_string(&env_config)?.as_bytes());
+    }
 
     fs::write(paths.cargo_home.join("config.toml"), &cargoconfig)?;
-    if !cargoconfig.is_empty() {
+    if vendored {
         assert!(is_vendored(paths)?);
     }
 
     if let Some(vendor_config) = &config.vendor {
         filter_checksum_files(&paths.third_party_dir, vendordir, vendor_config)?;
         write_excluded_build_scripts(&paths.third_party_dir, vendordir)?;
+        if vendor_config.minimal_sources {
+            prune_unused_sources(config, args, paths, vendordir)?;
+        }
     }
 
     if audit_sec {
-        crate::audit_sec::audit_sec(paths, no_fetch).context("doing audit_sec")?;
+        crate::audit_sec::audit_sec(config, args, paths, no_fetch, false)
+            .context("doing audit_sec")?;
     }
 
     Ok(())
 }
 
+/// The `registry_url` mirror to fetch crates.io packages from, if any.
+pub(crate) fn registry_url(config: &Config) -> Option<&str> {
+    config
+        .vendor
+        .as_ref()
+        .and_then(|vendor_config| vendor_config.registry_url.as_deref())
+}
+
+/// `--config` values which make Cargo fetch crates.io packages from the
+/// `registry_url` mirror, if there is one.
+pub(crate) fn mirror_config(config: &Config) -> Vec<String> {
+    match registry_url(config) {
+        Some(registry_url) => vec![
+            format!("source.crates-io.replace-with={MIRROR_SOURCE:?}"),
+            format!("source.{MIRROR_SOURCE}.registry={registry_url:?}"),
+        ],
+        None => Vec::new(),
+    }
+}
+
 pub(crate) fn is_vendored(paths: &Paths) -> anyhow::Result<bool> {
+    let remap_config = match read_cargo_config(paths)? {
+        Some(remap_config) => remap_config,
+        None => return Ok(false),
+    };
+
+    match remap_config.sources.get("vendored-sources") {
+        Some(vendored_sources) => Ok(vendored_sources.directory.is_some()),
+        None => Ok(false),
+    }
+}
+
+/// Read the third-party directory's `.cargo/config.toml`, if there is one.
+pub(crate) fn read_cargo_config(paths: &Paths) -> anyhow::Result<Option<RemapConfig>> {
     // .cargo/config.toml is Cargo's preferred name for the config, but .cargo/config
     // is the older name so it takes priority if present.
     let mut cargo_config_path = paths.cargo_home.join("config");
@@ -91,7 +187,7 @@ pub(crate) fn is_vendored(paths: &Paths) -> anyhow::Result<bool> {
             cargo_config_path = paths.cargo_home.join("config.toml");
             match fs::read_to_string(&cargo_config_path) {
                 Ok(content) => Ok(content),
-                Err(err) if err.kind() == ErrorKind::NotFound => return Ok(false),
+                Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
                 Err(err) => Err(err),
             }
         }
@@ -108,10 +204,7 @@ pub(crate) fn is_vendored(paths: &Paths) -> anyhow::Result<bool> {
     let remap_config: RemapConfig = toml::from_str(&content)
         .context(format!("Failed to parse {}", cargo_config_path.display()))?;
 
-    match remap_config.sources.get("vendored-sources") {
-        Some(vendored_sources) => Ok(vendored_sources.directory.is_some()),
-        None => Ok(false),
-    }
+    Ok(Some(remap_config))
 }
 
 fn filter_checksum_files(
@@ -140,17 +233,7 @@ fn filter_checksum_files(
     }
     let remove_globs = remove_globs.build()?;
 
-    let mut gitignore = GitignoreBuilder::new(third_party_dir);
-    for ignore in &config.gitignore_checksum_exclude {
-        if let Some(err) = gitignore.add(third_party_dir.join(ignore)) {
-            log::warn!(
-                "Failed to read ignore file {}: {}; skipping",
-                ignore.display(),
-                err
-            );
-        }
-    }
-    let gitignore = gitignore.build()?;
+    let gitignore = ChecksumIgnores::new(third_party_dir, &config.gitignore_checksum_exclude)?;
 
     log::debug!(
         "remove_globs {:#?}, gitignore {:#?}",
@@ -187,10 +270,7 @@ fn filter_checksum_files(
 
         checksums.files.retain(|k, _| {
             log::trace!("{}: checking {}", checksum.display(), k);
-            let del = remove_globs.is_match(k)
-                || gitignore
-                    .matched_path_or_any_parents(pkgdir.join(k), false)
-                    .is_ignore();
+            let del = remove_globs.is_match(k) || gitignore.is_ignored(&pkgdir.join(k));
             if del {
                 log::debug!("{}: removing {}", checksum.display(), k);
                 changed = true;
@@ -207,6 +287,73 @@ fn filter_checksum_files(
     Ok(())
 }
 
+/// The `gitignore_checksum_exclude` files, with the precedence git gives them:
+/// within a file the last matching pattern wins, so `!` can re-include what an
+/// earlier pattern excluded; a file deeper in the tree overrides one above it;
+/// and nothing can be re-included once a directory containing it is excluded.
+#[derive(Debug)]
+struct ChecksumIgnores {
+    third_party_dir: PathBuf,
+    /// Deepest first, each rooted at the directory its file is in.
+    ignores: Vec<Gitignore>,
+}
+
+impl ChecksumIgnores {
+    fn new(third_party_dir: &Path, ignore_files: &HashSet<PathBuf>) -> anyhow::Result<Self> {
+        // Paths are normalized so that each file's root, like `vendor` for
+        // `vendor/foo/../.gitignore`, is a prefix of the paths it's checked
+        // against.
+        let third_party_dir = normalize_path(third_party_dir);
+        let mut ignores = Vec::new();
+        for ignore in ignore_files {
+            let path = normalize_path(&third_party_dir.join(ignore));
+            let mut builder = GitignoreBuilder::new(path.parent().unwrap_or(&third_party_dir));
+            if let Some(err) = builder.add(&path) {
+                log::warn!(
+                    "Failed to read ignore file {}: {}; skipping",
+                    ignore.display(),
+                    err
+                );
+                continue;
+            }
+            ignores.push(builder.build()?);
+        }
+        ignores.sort_by_key(|ignore| Reverse(ignore.path().components().count()));
+
+        Ok(ChecksumIgnores {
+            third_party_dir,
+            ignores,
+        })
+    }
+
+    /// Whether `path`, relative to the third-party dir, is ignored.
+    fn is_ignored(&self, path: &Path) -> bool {
+        let path = self.third_party_dir.join(path);
+        let mut ancestors: Vec<&Path> = path
+            .ancestors()
+            .skip(1)
+            .take_while(|dir| dir.starts_with(&self.third_party_dir))
+            .collect();
+        ancestors.reverse();
+        ancestors
+            .into_iter()
+            .any(|dir| self.matched(dir, true).is_ignore())
+            || self.matched(&path, false).is_ignore()
+    }
+
+    fn matched(&self, path: &Path, is_dir: bool) -> Match<&Glob> {
+        for ignore in &self.ignores {
+            if path.starts_with(ignore.path()) {
+                let matched = ignore.matched(path, is_dir);
+                if !matched.is_none() {
+                    return matched;
+                }
+            }
+        }
+        Match::None
+    }
+}
+
 // Work around https://github.com/rust-lang/cargo/issues/14348.
 //
 // This step can be deleted if that `cargo vendor` bug is fixed in a future
@@ -265,3 +412,388 @@ fn write_excluded_build_scripts(third_party_dir: &Path, vendordir: &Path) -> any
 
     Ok(())
 }
+
+/// Marks a vendored crate whose unused files were deleted by `minimal_sources`.
+const MINIMAL_SOURCES_MARKER: &str = ".reindeer-minimal-sources";
+
+/// Delete vendored crates which `minimal_sources` pruned. `cargo vendor`
+/// leaves existing versioned dirs alone, so this is what gets it to restore
+/// their full sources, to be pruned again if `minimal_sources` is still on.
+fn remove_pruned_crates(third_party_vendor: &Path) -> anyhow::Result<()> {
+    if !third_party_vendor.try_exists()? {
+        return Ok(());
+    }
+
+    for entry in fs::read_dir(third_party_vendor)? {
+        let manifest_dir = entry?.path();
+        if manifest_dir.join(MINIMAL_SOURCES_MARKER).exists() {
+            log::info!("Removing pruned {} to restore it", manifest_dir.display());
+            fs::remove_dir_all(&manifest_dir)
+                .with_context(|| format!("Failed to remove {}", manifest_dir.display()))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Delete the files in vendored crates which none of the generated rules use,
+/// other than licenses and the manifest. Crates with a build script or fixups
+/// are left alone, since those can read arbitrary files. Fixups count whether
+/// they're the package's own, for any version, or shared by `fixup_globs`.
+fn prune_unused_sources(
+    config: &Config,
+    args: &Args,
+    paths: &Paths,
+    vendordir: &Path,
+) -> anyhow::Result<()> {
+    if !config.precise_srcs {
+        log::warn!("Not pruning vendored sources, because `minimal_sources` needs `precise_srcs`");
+        return Ok(());
+    }
+    if !paths.third_party_dir.join(vendordir).try_exists()? {
+        return Ok(());
+    }
+
+    let rules = crate::buckify::generate_all_rules(config, args, paths)
+        .context("Generating rules to find which vendored files are used")?;
+
+    // Crate dir (e.g. vendor/foo-1.2.3) => files its rules use
+    let mut used: HashMap<PathBuf, HashSet<&Path>> = HashMap::new();
+    let mut has_build_script = HashSet::new();
+    for rule in &rules {
+        let (common, is_build_script) = match rule {
+            Rule::Library(RustLibrary { common, .. }) | Rule::Binary(RustBinary { common }) => {
+                (common, false)
+            }
+            Rule::BuildscriptBinary(RustBinary { common }) => (common, true),
+            _ => continue,
+        };
+        let Ok(path_in_vendor) = common.crate_root.0.strip_prefix(vendordir) else {
+            continue;
+        };
+        let Some(dir_name) = path_in_vendor.iter().next() else {
+            continue;
+        };
+        let crate_dir = vendordir.join(dir_name);
+        if is_build_script {
+            has_build_script.insert(crate_dir);
+        } else {
+            used.entry(crate_dir)
+                .or_default()
+                .extend(common.source_paths());
+        }
+    }
+
+    let mut files_removed = 0;
+    let mut bytes_removed = 0;
+    for (crate_dir, used) in &used {
+        if has_build_script.contains(crate_dir) {
+            continue;
+        }
+        let manifest_dir = paths.third_party_dir.join(crate_dir);
+        let package: cargo_toml::Manifest<serde::de::IgnoredAny> =
+            cargo_toml::Manifest::from_path_with_metadata(manifest_dir.join("Cargo.toml"))
+                .with_context(|| format!("Failed to read manifest in {}", crate_dir.display()))?;
+        let Some(package) = package.package else {
+            continue;
+        };
+        let fixup_dir = paths.third_party_dir.join("fixups").join(&package.name);
+        if has_fixup_files(&fixup_dir) || !fixup_glob_dirs(config, &package.name)?.is_empty() {
+            continue;
+        }
+
+        let mut keep: HashSet<PathBuf> = used
+            .iter()
+            .filter_map(|path| path.strip_prefix(crate_dir).ok())
+            .map(Path::to_owned)
+            .collect();
+        keep.insert(PathBuf::from("Cargo.toml"));
+        keep.insert(PathBuf::from(".cargo-checksum.json"));
+        keep.extend(Globs::new(&config.license_patterns, NO_EXCLUDE)?.walk(&manifest_dir));
+
+        let mut removed = 0;
+        for entry in WalkDir::new(&manifest_dir) {
+            let entry = entry?;
+            if entry.file_type().is_dir() {
+                continue;
+            }
+            let path = entry.path().strip_prefix(&manifest_dir)?;
+            if keep.contains(path) {
+                continue;
+            }
+            bytes_removed += entry.metadata()?.len();
+            fs::remove_file(entry.path())?;
+            removed += 1;
+        }
+        if removed == 0 {
+            continue;
+        }
+        files_removed += removed;
+        log::info!(
+            "Removed {} unused files from {}",
+            removed,
+            crate_dir.display()
+        );
+
+        // Empty directories left behind
+        for entry in WalkDir::new(&manifest_dir).contents_first(true) {
+            let entry = entry?;
+            if entry.file_type().is_dir() {
+                let _ = fs::remove_dir(entry.path());
+            }
+        }
+
+        recompute_checksums(&manifest_dir)?;
+        fs::write(manifest_dir.join(MINIMAL_SOURCES_MARKER), "")?;
+    }
+
+    log::info!(
+        "minimal_sources: removed {} unused vendored files, saving {} bytes",
+        files_removed,
+        bytes_removed,
+    );
+
+    Ok(())
+}
+
+/// Bring a vendored crate's `.cargo-checksum.json` back in line with the files
+/// actually present, after some have been pruned. Cargo refuses to build a
+/// directory source which lists files it can't find, though it doesn't mind
+/// unlisted ones. The `package` checksum is that of the original .crate, which
+/// Cargo.lock has to go on matching, so it's left as is.
+fn recompute_checksums(manifest_dir: &Path) -> anyhow::Result<()> {
+    let checksum = manifest_dir.join(".cargo-checksum.json");
+    let file =
+        fs::read(&checksum).with_context(|| format!("Failed to read {}", checksum.display()))?;
+    let mut checksums: CargoChecksums = serde_json::from_slice(&file)
+        .with_context(|| format!("Failed to deserialize {}", checksum.display()))?;
+
+    let before = checksums.files.len();
+    checksums
+        .files
+        .retain(|file, _| manifest_dir.join(file).is_file());
+
+    if checksums.files.len() != before {
+        log::debug!(
+            "{}: dropping {} pruned files",
+            checksum.display(),
+            before - checksums.files.len(),
+        );
+        fs::write(&checksum, serde_json::to_vec(&checksums)?)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs;
+    use std::path::Path;
+    use std::process::Command;
+
+    use clap::Parser;
+
+    use super::prune_unused_sources;
+    use super::recompute_checksums;
+    use super::remove_pruned_crates;
+    use super::ChecksumIgnores;
+    use super::MINIMAL_SOURCES_MARKER;
+    use crate::config::Config;
+    use crate::testing::package;
+    use crate::testing::paths;
+    use crate::testing::write;
+    use crate::Args;
+
+    // sha256 of an empty file
+    const EMPTY: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+    #[test]
+    fn cargo_accepts_pruned_vendor_dir() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let vendored = tempdir.path().join("vendor/foo-0.1.0");
+        write(
+            &vendored.join("Cargo.toml"),
+            "[package]\nname = \"foo\"\nversion = \"0.1.0\"\nedition = \"2021\"\n",
+        );
+        write(&vendored.join("src/lib.rs"), "");
+        write(&vendored.join("tests/test.rs"), "");
+        write(
+            &vendored.join(".cargo-checksum.json"),
+            &format!(
+                r#"{{"files":{{"src/lib.rs":"{EMPTY}","tests/test.rs":"{EMPTY}"}},"package":null}}"#,
+            ),
+        );
+
+        let app = tempdir.path().join("app");
+        write(
+            &app.join("Cargo.toml"),
+            "[package]\nname = \"app\"\nversion = \"0.0.0\"\nedition = \"2021\"\n\n[dependencies]\nfoo = \"0.1\"\n",
+        );
+        write(&app.join("src/lib.rs"), "");
+        write(
+            &app.join(".cargo/config.toml"),
+            "[source.crates-io]\nreplace-with = \"vendored-sources\"\n\n[source.vendored-sources]\ndirectory = \"../vendor\"\n",
+        );
+
+        let cargo = |subcommand: &[&str]| {
+            Command::new(env::var_os("CARGO").unwrap_or_else(|| "cargo".into()))
+                .current_dir(&app)
+                .args(subcommand)
+                .arg("--offline")
+                .env("CARGO_TARGET_DIR", tempdir.path().join("target"))
+                .output()
+                .unwrap()
+        };
+
+        // Cargo picks up .cargo/config.toml from the working directory. Only a
+        // build verifies the files listed in the checksums, so `cargo metadata`
+        // alone doesn't notice they've gone.
+        fs::remove_file(vendored.join("tests/test.rs")).unwrap();
+        assert!(cargo(&["metadata", "--format-version=1"]).status.success());
+        assert!(!cargo(&["check"]).status.success());
+
+        recompute_checksums(&vendored).unwrap();
+        let output = cargo(&["check"]);
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr),
+        );
+    }
+
+    #[test]
+    fn prune_spares_crates_with_fixups() {
+        let tempdir = tempfile::tempdir().unwrap();
+        package(
+            tempdir.path(),
+            "third-party",
+            "[dependencies]\nfoo = \"1\"\nbar = \"1\"\nbaz = \"1\"\n",
+        );
+        let third_party_dir = tempdir.path().join("third-party");
+        write(
+            &third_party_dir.join(".cargo/config.toml"),
+            "[source.crates-io]\nreplace-with = \"vendored-sources\"\n\n\
+             [source.vendored-sources]\ndirectory = \"vendor\"\n",
+        );
+        for name in ["foo", "bar", "baz"] {
+            package(&third_party_dir.join("vendor"), name, "");
+            let vendored = third_party_dir.join(format!("vendor/{name}"));
+            write(&vendored.join("benches/bench.rs"), "");
+            write(
+                &vendored.join(".cargo-checksum.json"),
+                &format!(
+                    r#"{{"files":{{"benches/bench.rs":"{EMPTY}","src/lib.rs":"{EMPTY}"}},"package":null}}"#,
+                ),
+            );
+            fs::rename(
+                &vendored,
+                third_party_dir.join(format!("vendor/{name}-1.0.0")),
+            )
+            .unwrap();
+        }
+        // `bar` only has fixups for its version, and `baz` only shared ones.
+        write(
+            &third_party_dir.join("fixups/bar/fixups-1.toml"),
+            "version = \"^1\"\n",
+        );
+        write(&third_party_dir.join("fixups/shared/fixups.toml"), "");
+
+        let output = Command::new(env::var_os("CARGO").unwrap_or_else(|| "cargo".into()))
+            .current_dir(&third_party_dir)
+            .args(["generate-lockfile", "--offline"])
+            .env("CARGO_HOME", third_party_dir.join(".cargo"))
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{output:?}");
+
+        let config: Config = toml::from_str(
+            "precise_srcs = true\n\
+             fixup_globs = { \"baz*\" = \"shared\" }\n\
+             [vendor]\nminimal_sources = true\n",
+        )
+        .unwrap();
+        let args = Args::parse_from(["reindeer", "vendor"]);
+        let vendor = third_party_dir.join("vendor");
+        prune_unused_sources(
+            &config,
+            &args,
+            &paths(&third_party_dir),
+            Path::new("vendor"),
+        )
+        .unwrap();
+        let pruned = |name: &str| {
+            let dir = vendor.join(format!("{name}-1.0.0"));
+            assert!(dir.join("src/lib.rs").exists());
+            assert_eq!(
+                dir.join(MINIMAL_SOURCES_MARKER).exists(),
+                !dir.join("benches/bench.rs").exists(),
+            );
+            dir.join(MINIMAL_SOURCES_MARKER).exists()
+        };
+        assert!(pruned("foo"));
+        assert!(!pruned("bar"));
+        assert!(!pruned("baz"));
+
+        // The next vendoring starts over from the full sources of pruned crates.
+        remove_pruned_crates(&vendor).unwrap();
+        assert!(!vendor.join("foo-1.0.0").exists());
+        assert!(vendor.join("bar-1.0.0").exists());
+        assert!(vendor.join("baz-1.0.0").exists());
+    }
+
+    #[test]
+    fn gitignore_path_with_parent_dir() {
+        let tempdir = tempfile::tempdir().unwrap();
+        write(
+            &tempdir.path().join("vendor/.gitignore"),
+            "/foo-1.0.0/*.txt\n",
+        );
+
+        let ignore_files = ["vendor/bar-1.0.0/../.gitignore".into()].into();
+        let ignores = ChecksumIgnores::new(tempdir.path(), &ignore_files).unwrap();
+        let is_ignored = |path: &str| ignores.is_ignored(Path::new(path));
+
+        assert!(is_ignored("vendor/foo-1.0.0/README.txt"));
+        assert!(!is_ignored("vendor/bar-1.0.0/foo-1.0.0/README.txt"));
+        assert!(!is_ignored("vendor/foo-1.0.0/src/lib.rs"));
+    }
+
+    #[test]
+    fn gitignore_negation() {
+        let tempdir = tempfile::tempdir().unwrap();
+        write(
+            &tempdir.path().join(".gitignore"),
+            "*.bin\n!keep.bin\n/vendor/*/tests/\n",
+        );
+        write(
+            &tempdir.path().join("vendor/.gitignore"),
+            "*.txt\n!notes.txt\n",
+        );
+        write(
+            &tempdir.path().join("vendor/foo-1.0.0/.gitignore"),
+            "notes.txt\n",
+        );
+
+        let ignore_files = [
+            ".gitignore",
+            "vendor/.gitignore",
+            "vendor/foo-1.0.0/.gitignore",
+        ]
+        .into_iter()
+        .map(Into::into)
+        .collect();
+        let ignores = ChecksumIgnores::new(tempdir.path(), &ignore_files).unwrap();
+        let is_ignored = |path: &str| ignores.is_ignored(Path::new(path));
+
+        assert!(is_ignored("vendor/foo-1.0.0/data.bin"));
+        assert!(!is_ignored("vendor/foo-1.0.0/keep.bin"));
+        assert!(!is_ignored("vendor/foo-1.0.0/src/lib.rs"));
+        // Can't re-include a file in an excluded directory.
+        assert!(is_ignored("vendor/foo-1.0.0/tests/keep.bin"));
+        // Deeper ignore files take precedence.
+        assert!(is_ignored("vendor/foo-1.0.0/README.txt"));
+        assert!(!is_ignored("vendor/bar-1.0.0/notes.txt"));
+        assert!(is_ignored("vendor/foo-1.0.0/notes.txt"));
+    }
+}